@@ -1,9 +1,17 @@
-use glam::{Mat4, Vec3};
+use glam::{Mat4, Vec2, Vec3};
 use winit::event::*;
 use winit::dpi::PhysicalPosition;
 use instant::Duration;
 use std::f32::consts::FRAC_PI_2;
 
+pub mod path;
+pub mod bindings;
+pub mod frustum;
+#[cfg(feature = "gamepad")]
+pub mod gamepad;
+
+use bindings::Action;
+
 const SAFE_FRAC_PI_2: f32 = FRAC_PI_2 - 0.0001;
 
 // Taken from learn wgpu tutorial and modified to use the glam math library instead of cgmath.
@@ -13,6 +21,30 @@ pub struct Camera {
     pub projection: Projection,
     pub view: View,
     pub controller: CameraController,
+    pub path: path::CameraPath, // keyframes recorded from the viewer
+    pub playback: Option<path::Playback>, // when set, the camera follows a path instead of the controller
+    pub lens: Lens,
+    frame: u32, // number of frames rendered, used to seed random numbers in the shader
+    accumulated_frames: u32, // number of frames the camera has been still for
+    last_uniform: Option<CameraUniform>, // used to find out if the camera moved
+}
+
+// Thin lens model for depth of field. An aperture of zero gives a pinhole camera where everything is in focus.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lens {
+    pub aperture: f32, // radius of the lens, in scene units
+    pub focus_distance: f32, // distance from the camera to the plane that's in focus
+    pub autofocus: bool, // focus on whatever is in the middle of the screen
+}
+
+impl Default for Lens {
+    fn default() -> Self {
+        Self {
+            aperture: 0.0,
+            focus_distance: 4.0,
+            autofocus: false,
+        }
+    }
 }
 
 
@@ -25,16 +57,85 @@ impl Camera {
             projection,
             view,
             controller,
+            path: path::CameraPath::default(),
+            playback: None,
+            lens: Lens::default(),
+            frame: 0,
+            accumulated_frames: 0,
+            last_uniform: None,
         }
     }
     pub fn uniform(&self) -> CameraUniform {
-        CameraUniform::from_view_proj(&self.view, &self.projection)
+        let mut uniform = CameraUniform::from_view_proj(&self.view, &self.projection);
+        uniform.lens = [self.lens.aperture, self.lens.focus_distance, 0.0, 0.0];
+        uniform.frame = [self.accumulated_frames, self.frame, 0, 0];
+        uniform
+    }
+    // what the camera can see right now
+    pub fn frustum(&self) -> frustum::Frustum {
+        frustum::Frustum::from_matrix(self.projection.calc_matrix() * self.view.calc_matrix())
+    }
+    // move on to the next frame. Frames are accumulated while the camera is still and reset_accumulation isn't called,
+    // which is what makes depth of field converge
+    pub fn advance_frame(&mut self) {
+        let mut uniform = self.uniform();
+        uniform.frame = [0; 4];
+        let moved = self.last_uniform.is_none_or(|last| bytemuck::bytes_of(&last) != bytemuck::bytes_of(&uniform));
+        self.accumulated_frames = if moved { 0 } else { self.accumulated_frames + 1 };
+        self.last_uniform = Some(uniform);
+        self.frame = self.frame.wrapping_add(1);
+    }
+    // start accumulating from scratch, for when something other than the camera changes what it sees
+    pub fn reset_accumulation(&mut self) {
+        self.accumulated_frames = 0;
     }
 
     pub fn update(&mut self, dt: Duration) {
-        self.controller.update_camera(&mut self.view, dt);
+        match &mut self.playback {
+            Some(playback) => {
+                playback.update(&mut self.view, &mut self.projection, dt);
+                if playback.is_finished() {
+                    self.playback = None;
+                }
+            }
+            None => self.controller.update_camera(&mut self.view, dt),
+        }
+        // the camera position doesn't change how big things look in an orthographic projection,
+        // so when orbiting, the view plane is scaled with the distance to the target instead
+        if let CameraMode::Orbit { distance, .. } | CameraMode::Turntable { distance, .. } = self.controller.mode {
+            if self.projection.is_orthographic() {
+                self.projection.kind = ProjectionKind::Orthographic { height: self.projection.fovy_height_at(distance) };
+            }
+        }
+    }
+    // orbit target from the classic isometric angle, with an orthographic projection
+    // that shows a view plane of the given height around it
+    pub fn isometric(&mut self, target: Vec3, height: f32) {
+        // back off as far as a perspective camera would need to, to show the same area
+        let distance = height / (2.0 * (self.projection.fovy / 2.0).tan());
+        self.view.yaw = 45.0f32.to_radians();
+        self.view.pitch = -(1.0 / 2.0f32.sqrt()).atan(); // ~35.264 degrees down
+        self.view.position = target - self.view.direction() * distance;
+        self.projection.kind = ProjectionKind::Orthographic { height };
+        self.controller.orbit(&mut self.view, target);
+    }
+    // switch to a perspective projection, keeping the view where it is
+    pub fn perspective(&mut self) {
+        self.projection.kind = ProjectionKind::Perspective;
+    }
+    // add the current camera as a keyframe to the recorded path
+    pub fn record_keyframe(&mut self) {
+        self.path.record(&self.view, &self.projection);
     }
-    
+    // start playing the recorded path in a loop, or stop playing if it already is
+    pub fn toggle_playback(&mut self) {
+        self.playback = match self.playback {
+            Some(_) => None,
+            None if !self.path.is_empty() => Some(path::Playback::new(self.path.clone(), true)),
+            None => None,
+        };
+    }
+
 }
 
 pub struct View {
@@ -59,26 +160,49 @@ impl View {
             pitch: pitch.into(),
         }
     }
-    pub fn calc_matrix(&self) -> Mat4 {
+    // the direction the camera is looking in
+    pub fn direction(&self) -> Vec3 {
         let (sin_pitch, cos_pitch) = self.pitch.sin_cos();
         let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
+        Vec3::new(
+            cos_pitch * cos_yaw,
+            sin_pitch,
+            cos_pitch * sin_yaw
+        ).normalize()
+    }
+    // turn to face target, returning the distance to it
+    pub fn look_at(&mut self, target: Vec3) -> f32 {
+        let offset = target - self.position;
+        let distance = offset.length();
+        if distance > 0.0 {
+            self.yaw = offset.z.atan2(offset.x);
+            self.pitch = (offset.y / distance).asin().clamp(-SAFE_FRAC_PI_2, SAFE_FRAC_PI_2);
+        }
+        distance
+    }
+    pub fn calc_matrix(&self) -> Mat4 {
         Mat4::look_to_lh(
             self.position,
-            Vec3::new(
-                cos_pitch * cos_yaw,
-                sin_pitch,
-                cos_pitch * sin_yaw
-            ).normalize(),
+            self.direction(),
             Vec3::Y,
         )
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProjectionKind {
+    Perspective,
+    // parallel rays, each pixel's ray starts at a different point on the view plane.
+    // height is the size of the view plane in scene units
+    Orthographic { height: f32 },
+}
+
 pub struct Projection {
     aspect: f32,
     fovy: f32,
     znear: f32,
     zfar: f32,
+    pub kind: ProjectionKind,
 }
 
 impl Projection {
@@ -92,14 +216,35 @@ impl Projection {
             aspect,
             fovy,
             znear, 
-            zfar
+            zfar,
+            kind: ProjectionKind::Perspective,
         }
     }
+    pub fn is_orthographic(&self) -> bool {
+        matches!(self.kind, ProjectionKind::Orthographic { .. })
+    }
+    // the height of the view plane that shows as much of the scene at distance as the perspective projection does
+    pub fn fovy_height_at(&self, distance: f32) -> f32 {
+        2.0 * distance * (self.fovy / 2.0).tan()
+    }
     pub fn resize(&mut self, width: u32, height: u32) {
         self.aspect = width as f32 / height as f32;
     }
+    pub fn fovy(&self) -> f32 {
+        self.fovy
+    }
+    pub fn set_fovy(&mut self, fovy: f32) {
+        self.fovy = fovy;
+    }
     pub fn calc_matrix(&self) -> Mat4 {
-        Mat4::perspective_lh(self.fovy, self.aspect, self.znear, self.zfar)
+        match self.kind {
+            ProjectionKind::Perspective => Mat4::perspective_lh(self.fovy, self.aspect, self.znear, self.zfar),
+            ProjectionKind::Orthographic { height } => {
+                let half_height = height / 2.0;
+                let half_width = half_height * self.aspect;
+                Mat4::orthographic_lh(-half_width, half_width, -half_height, half_height, self.znear, self.zfar)
+            }
+        }
     }
 }
 
@@ -107,14 +252,26 @@ impl Projection {
 #[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct CameraUniform {
     position: [f32;4],
+    direction: [f32;4], // the direction the camera looks in, which is the direction of every ray when orthographic
+    projection: [u32;4], // x: projection kind (0 = perspective, 1 = orthographic), the rest is padding
+    lens: [f32;4], // x: aperture radius, y: focus distance
+    frame: [u32;4], // x: frames accumulated since the camera or the scene last changed, y: frame number
     inv_view: [[f32;4];4],
     inv_proj: [[f32;4];4],
 }
 
 impl CameraUniform {
+    pub const PERSPECTIVE: u32 = 0;
+    pub const ORTHOGRAPHIC: u32 = 1;
+
     pub fn from_view_proj(view: &View, proj: &Projection) -> Self {
+        let kind = if proj.is_orthographic() { Self::ORTHOGRAPHIC } else { Self::PERSPECTIVE };
         Self { 
             position: view.position.extend(0.0).to_array(), 
+            direction: view.direction().extend(0.0).to_array(),
+            projection: [kind, 0, 0, 0],
+            lens: [0.0; 4],
+            frame: [0; 4],
             inv_view: view.calc_matrix().inverse().to_cols_array_2d(), 
             inv_proj: proj.calc_matrix().inverse().to_cols_array_2d(),
         }
@@ -122,6 +279,14 @@ impl CameraUniform {
 }
 
 
+// How the controller moves the camera
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CameraMode {
+    Fly, // free-flying FPS style camera
+    Orbit { target: Vec3, distance: f32 }, // rotate around a point, scrolling zooms in and out
+    Turntable { target: Vec3, distance: f32 }, // like orbit, but slowly spins around the target by itself
+}
+
 #[derive(Debug)]
 pub struct CameraController {
     amount_left: f32,
@@ -130,14 +295,28 @@ pub struct CameraController {
     amount_backward: f32,
     amount_up: f32,
     amount_down: f32,
+    amount_zoom_in: f32,
+    amount_zoom_out: f32,
     rotate_horizontal: f32,
     rotate_vertical: f32,
+    key_rotate_horizontal: f32, // kept separately from the mouse rotation, since it lasts as long as the key is held
     scroll: f32,
+    gamepad_move: Vec3, // (right, up, forward) from the gamepad sticks and triggers
+    gamepad_look: Vec2,
+    gamepad_zoom: f32,
     speed: f32,
     sensitivity: f32,
+    pub turntable_speed: f32, // radians per second
+    pub mode: CameraMode,
+    pub bindings: bindings::KeyBindings,
 }
 
 impl CameraController {
+    const GAMEPAD_LOOK_SPEED: f32 = 2.0; // radians per second at full stick
+    const ZOOM_SPEED: f32 = 0.1;
+    const HELD_ZOOM_SPEED: f32 = 1.0; // the orbit distance changes by a factor of e per second while zooming with the keys or gamepad
+    const MIN_ORBIT_DISTANCE: f32 = 0.1;
+
     pub fn new(speed: f32, sensitivity: f32) -> Self {
         Self {
             amount_left: 0.0,
@@ -146,51 +325,58 @@ impl CameraController {
             amount_backward: 0.0,
             amount_up: 0.0,
             amount_down: 0.0,
+            amount_zoom_in: 0.0,
+            amount_zoom_out: 0.0,
             rotate_horizontal: 0.0,
             rotate_vertical: 0.0,
+            key_rotate_horizontal: 0.0,
             scroll: 0.0,
+            gamepad_move: Vec3::ZERO,
+            gamepad_look: Vec2::ZERO,
+            gamepad_zoom: 0.0,
             speed,
             sensitivity,
+            turntable_speed: 0.3,
+            mode: CameraMode::Fly,
+            bindings: bindings::KeyBindings::default(),
         }
     }
 
+    pub fn fly(&mut self) {
+        self.mode = CameraMode::Fly;
+    }
+    // orbit around target, turning the camera to face it
+    pub fn orbit(&mut self, view: &mut View, target: Vec3) {
+        let distance = view.look_at(target);
+        self.mode = CameraMode::Orbit { target, distance };
+    }
+    // spin around target, turning the camera to face it
+    pub fn turntable(&mut self, view: &mut View, target: Vec3) {
+        let distance = view.look_at(target);
+        self.mode = CameraMode::Turntable { target, distance };
+    }
+
     pub fn process_keyboard(&mut self, key: VirtualKeyCode, state: ElementState) -> bool{
         let amount = if state == ElementState::Pressed { 1.0 } else { 0.0 };
-        match key {
-            VirtualKeyCode::W | VirtualKeyCode::Up => {
-                self.amount_forward = amount;
-                true
-            }
-            VirtualKeyCode::S | VirtualKeyCode::Down => {
-                self.amount_backward = amount;
-                true
-            }
-            VirtualKeyCode::A | VirtualKeyCode::Left => {
-                self.amount_left = amount;
-                true
-            }
-            VirtualKeyCode::D | VirtualKeyCode::Right => {
-                self.amount_right = amount;
-                true
-            }
-            VirtualKeyCode::Space => {
-                self.amount_up = amount;
-                true
-            }
-            VirtualKeyCode::LShift => {
-                self.amount_down = amount;
-                true
-            }
-            VirtualKeyCode::Q => { // quick mouseless rotation, may need to be tuned
-                self.rotate_horizontal = amount * 0.5;
-                false
-            }
-            VirtualKeyCode::E => {
-                self.rotate_horizontal = -amount * 0.5;
-                false
-            }
-            _ => false,
+        let Some(action) = self.bindings.action(key) else {
+            return false;
+        };
+        match action {
+            Action::Forward => self.amount_forward = amount,
+            Action::Backward => self.amount_backward = amount,
+            Action::Left => self.amount_left = amount,
+            Action::Right => self.amount_right = amount,
+            Action::Up => self.amount_up = amount,
+            Action::Down => self.amount_down = amount,
+            // quick mouseless rotation, may need to be tuned
+            Action::RotateLeft => self.key_rotate_horizontal = amount * 0.5,
+            Action::RotateRight => self.key_rotate_horizontal = -amount * 0.5,
+            Action::ZoomIn => self.amount_zoom_in = amount,
+            Action::ZoomOut => self.amount_zoom_out = amount,
+            // the rest need to know about the scene or aren't about moving, so they're up to the caller
+            _ => return false,
         }
+        true
     }
 
     pub fn process_mouse(&mut self, mouse_dx: f64, mouse_dy: f64) {
@@ -209,34 +395,21 @@ impl CameraController {
         };
     }
 
+    // analog input, in the range -1..1. movement is (right, up, forward), look is (horizontal, vertical)
+    pub fn process_gamepad(&mut self, movement: Vec3, look: Vec2, zoom: f32) {
+        self.gamepad_move = movement;
+        self.gamepad_look = look;
+        self.gamepad_zoom = zoom;
+    }
+
     pub fn update_camera(&mut self, camera: &mut View, dt: Duration) {
         let dt = dt.as_secs_f32();
 
-        //eprintln!("Pos: {} Yaw:{} Pitch: {}", camera.position, camera.yaw.to_degrees(), camera.pitch.to_degrees());
-        // Move forward/backward and left/right
-        let (yaw_sin, yaw_cos) = camera.yaw.sin_cos();
-        let forward = Vec3::new(yaw_cos, 0.0, yaw_sin).normalize();
-        //eprintln!("Facing: ({},{},{})", forward.x, forward.y, forward.z);
-        let right = -Vec3::new(-yaw_sin, 0.0, yaw_cos).normalize();
-        camera.position += forward * (self.amount_forward - self.amount_backward) * self.speed * dt;
-        camera.position += right * (self.amount_right - self.amount_left) * self.speed * dt;
-
-        // Move in/out (aka. "zoom")
-        // Note: this isn't an actual zoom. The camera's position
-        // changes when zooming. I've added this to make it easier
-        // to get closer to an object you want to focus on.
-        let (pitch_sin, pitch_cos) = camera.pitch.sin_cos();
-        let scrollward = Vec3::new(pitch_cos * yaw_cos, pitch_sin, pitch_cos * yaw_sin).normalize();
-        camera.position += scrollward * self.scroll * self.speed * self.sensitivity * dt;
-        self.scroll = 0.0;
-
-        // Move up/down. Since we don't use roll, we can just
-        // modify the y coordinate directly.
-        camera.position.y += (self.amount_up - self.amount_down) * self.speed * dt;
-
         // Rotate
-        camera.yaw += self.rotate_horizontal * self.sensitivity * dt;
-        camera.pitch += self.rotate_vertical * self.sensitivity * dt;
+        let rotate_horizontal = self.rotate_horizontal + self.key_rotate_horizontal + self.gamepad_look.x * Self::GAMEPAD_LOOK_SPEED;
+        let rotate_vertical = self.rotate_vertical + self.gamepad_look.y * Self::GAMEPAD_LOOK_SPEED;
+        camera.yaw += rotate_horizontal * self.sensitivity * dt;
+        camera.pitch += rotate_vertical * self.sensitivity * dt;
 
         // If process_mouse isn't called every frame, these values
         // will not get set to zero, and the camera will rotate
@@ -245,12 +418,47 @@ impl CameraController {
         self.rotate_vertical = 0.0;
 
         // Keep the camera's angle from going too high/low.
-        if camera.pitch < -SAFE_FRAC_PI_2 {
-            camera.pitch = -SAFE_FRAC_PI_2;
-        } else if camera.pitch > SAFE_FRAC_PI_2 {
-            camera.pitch = SAFE_FRAC_PI_2;
+        camera.pitch = camera.pitch.clamp(-SAFE_FRAC_PI_2, SAFE_FRAC_PI_2);
+
+        //eprintln!("Pos: {} Yaw:{} Pitch: {}", camera.position, camera.yaw.to_degrees(), camera.pitch.to_degrees());
+        // Move forward/backward and left/right
+        let (yaw_sin, yaw_cos) = camera.yaw.sin_cos();
+        let forward = Vec3::new(yaw_cos, 0.0, yaw_sin).normalize();
+        //eprintln!("Facing: ({},{},{})", forward.x, forward.y, forward.z);
+        let right = -Vec3::new(-yaw_sin, 0.0, yaw_cos).normalize();
+        let movement = Vec3::new(
+            self.amount_right - self.amount_left,
+            self.amount_up - self.amount_down,
+            self.amount_forward - self.amount_backward,
+        ) + self.gamepad_move;
+        let translation = (forward * movement.z + right * movement.x + Vec3::Y * movement.y) * self.speed * dt;
+        let scroll = self.scroll * self.sensitivity * dt;
+        self.scroll = 0.0;
+        let zoom_keys = (self.amount_zoom_out - self.amount_zoom_in + self.gamepad_zoom) * dt;
+        let zoom = scroll * Self::ZOOM_SPEED + zoom_keys * Self::HELD_ZOOM_SPEED;
+
+        match &mut self.mode {
+            CameraMode::Fly => {
+                camera.position += translation;
+                // Move in/out (aka. "zoom")
+                // Note: this isn't an actual zoom. The camera's position
+                // changes when zooming. I've added this to make it easier
+                // to get closer to an object you want to focus on.
+                camera.position += camera.direction() * scroll * self.speed;
+                // the zoom keys move forward at the movement speed when zooming in, the same way they get closer to the target when orbiting
+                camera.position -= camera.direction() * zoom_keys * self.speed;
+            }
+            CameraMode::Orbit { target, distance } => {
+                // moving pans the point we're orbiting around
+                *target += translation;
+                *distance = (*distance * zoom.exp()).max(Self::MIN_ORBIT_DISTANCE);
+                camera.position = *target - camera.direction() * *distance;
+            }
+            CameraMode::Turntable { target, distance } => {
+                camera.yaw += self.turntable_speed * dt;
+                *distance = (*distance * zoom.exp()).max(Self::MIN_ORBIT_DISTANCE);
+                camera.position = *target - camera.direction() * *distance;
+            }
         }
     }
 }
-
- 
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use winit::event::VirtualKeyCode;
+use anyhow::*;
+
+// Configurable key bindings for the camera and the viewer.
+// Bindings are stored as a map from key to action, so a file only needs to list the keys it wants, e.g.
+// {
+//     W: Forward,
+//     Space: Up,
+//     O: OrbitMode,
+// }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Action {
+    Forward,
+    Backward,
+    Left,
+    Right,
+    Up,
+    Down,
+    RotateLeft,
+    RotateRight,
+    ZoomIn,
+    ZoomOut,
+    FlyMode,
+    OrbitMode,
+    TurntableMode,
+    ToggleIsometric, // switch between a perspective and an isometric orthographic projection
+    ApertureUp, // more depth of field blur
+    ApertureDown,
+    ToggleAutofocus, // keep whatever is in the middle of the screen in focus
+    RecordKeyframe, // add the current view to the camera path
+    TogglePlayback, // fly along the camera path
+    SavePath, // write the camera path to a file
+    LoadPath,
+    ToggleFog,
+    NextSampling, // cycle through the lighting sampling modes
+    ToggleAmbientOcclusion,
+    ToggleRecording, // write every frame to a numbered image
+    Screenshot,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct KeyBindings {
+    keys: HashMap<VirtualKeyCode, Action>,
+}
+
+impl KeyBindings {
+    pub fn action(&self, key: VirtualKeyCode) -> Option<Action> {
+        self.keys.get(&key).copied()
+    }
+    pub fn bind(&mut self, key: VirtualKeyCode, action: Action) {
+        self.keys.insert(key, action);
+    }
+    pub fn unbind(&mut self, key: VirtualKeyCode) {
+        self.keys.remove(&key);
+    }
+    // load bindings from a RON file. Keys that aren't mentioned in the file are unbound.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read key bindings '{}'", path.display()))?;
+        ron::from_str(&text).with_context(|| format!("Could not parse key bindings '{}'", path.display()))
+    }
+    // load bindings from a file if it exists, falling back to the defaults
+    pub fn load_or_default(path: &Path) -> Self {
+        if !path.exists() {
+            return Self::default();
+        }
+        Self::load(path).unwrap_or_else(|e| {
+            log::error!("{:?}", e);
+            Self::default()
+        })
+    }
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+        std::fs::write(path, text).with_context(|| format!("Could not write key bindings '{}'", path.display()))
+    }
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        let keys = HashMap::from([
+            (VirtualKeyCode::W, Action::Forward),
+            (VirtualKeyCode::Up, Action::Forward),
+            (VirtualKeyCode::S, Action::Backward),
+            (VirtualKeyCode::Down, Action::Backward),
+            (VirtualKeyCode::A, Action::Left),
+            (VirtualKeyCode::Left, Action::Left),
+            (VirtualKeyCode::D, Action::Right),
+            (VirtualKeyCode::Right, Action::Right),
+            (VirtualKeyCode::Space, Action::Up),
+            (VirtualKeyCode::LShift, Action::Down),
+            (VirtualKeyCode::Q, Action::RotateLeft),
+            (VirtualKeyCode::E, Action::RotateRight),
+            (VirtualKeyCode::Equals, Action::ZoomIn),
+            (VirtualKeyCode::Minus, Action::ZoomOut),
+            (VirtualKeyCode::Key1, Action::FlyMode),
+            (VirtualKeyCode::Key2, Action::OrbitMode),
+            (VirtualKeyCode::Key3, Action::TurntableMode),
+            (VirtualKeyCode::I, Action::ToggleIsometric),
+            (VirtualKeyCode::RBracket, Action::ApertureUp),
+            (VirtualKeyCode::LBracket, Action::ApertureDown),
+            (VirtualKeyCode::F, Action::ToggleAutofocus),
+            (VirtualKeyCode::K, Action::RecordKeyframe),
+            (VirtualKeyCode::P, Action::TogglePlayback),
+            (VirtualKeyCode::F5, Action::SavePath),
+            (VirtualKeyCode::F6, Action::LoadPath),
+            (VirtualKeyCode::F7, Action::ToggleFog),
+            (VirtualKeyCode::F8, Action::NextSampling),
+            (VirtualKeyCode::F9, Action::ToggleAmbientOcclusion),
+            (VirtualKeyCode::F10, Action::ToggleRecording),
+            (VirtualKeyCode::F12, Action::Screenshot),
+        ]);
+        Self { keys }
+    }
+}
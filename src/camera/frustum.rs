@@ -0,0 +1,28 @@
+use glam::{Mat4, Vec3, Vec4, Vec4Swizzles};
+
+// The volume the camera can see, as six planes facing inward.
+// Used on the CPU to find out which chunks are on screen, see scene::schedule
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frustum {
+    planes: [Vec4; 6], // xyz is the normal, w the distance, points p with dot(xyz, p) + w >= 0 are inside
+}
+
+impl Frustum {
+    // the planes of a view-projection matrix with a depth range of 0..1, like the ones glam makes for wgpu
+    pub fn from_matrix(view_proj: Mat4) -> Self {
+        let [r0, r1, r2, r3] = [view_proj.row(0), view_proj.row(1), view_proj.row(2), view_proj.row(3)];
+        let planes = [r3 + r0, r3 - r0, r3 + r1, r3 - r1, r2, r3 - r2]
+            .map(|plane| plane / plane.xyz().length());
+        Self { planes }
+    }
+    // whether any part of an axis aligned box might be visible.
+    // Boxes near the corners of the frustum can be let through even though they are outside
+    pub fn intersects_box(&self, min: Vec3, max: Vec3) -> bool {
+        self.planes.iter().all(|plane| {
+            // the corner furthest along the normal
+            let corner = Vec3::select(plane.xyz().cmpge(Vec3::ZERO), max, min);
+            plane.xyz().dot(corner) + plane.w >= 0.0
+        })
+    }
+}
@@ -0,0 +1,60 @@
+use gilrs::{Axis, Button, Event, GamepadId, Gilrs};
+use glam::{Vec2, Vec3};
+
+use super::CameraController;
+
+// Gamepad input for the camera controller.
+// Left stick moves, right stick looks around, the triggers move up and down and the bumpers zoom.
+
+pub struct Gamepad {
+    gilrs: Gilrs,
+    active: Option<GamepadId>, // the gamepad that was used last
+}
+
+impl Gamepad {
+    const DEADZONE: f32 = 0.15;
+
+    // returns None if gamepads aren't supported on this platform
+    pub fn new() -> Option<Self> {
+        match Gilrs::new() {
+            Ok(gilrs) => {
+                let active = gilrs.gamepads().next().map(|(id, _)| id);
+                Some(Self { gilrs, active })
+            }
+            Err(e) => {
+                log::warn!("Gamepad support unavailable: {}", e);
+                None
+            }
+        }
+    }
+
+    // read the gamepad state and pass it on to the controller. Should be called once per frame
+    pub fn update(&mut self, controller: &mut CameraController) {
+        while let Some(Event { id, .. }) = self.gilrs.next_event() {
+            self.active = Some(id);
+        }
+        let Some(id) = self.active else {
+            return;
+        };
+        let pad = self.gilrs.gamepad(id);
+        if !pad.is_connected() {
+            self.active = None;
+            controller.process_gamepad(Vec3::ZERO, Vec2::ZERO, 0.0);
+            return;
+        }
+        let axis = |axis: Axis| {
+            let value = pad.axis_data(axis).map_or(0.0, |data| data.value());
+            if value.abs() < Self::DEADZONE { 0.0 } else { value }
+        };
+        let button = |button: Button| pad.button_data(button).map_or(0.0, |data| data.value());
+
+        let movement = Vec3::new(
+            axis(Axis::LeftStickX),
+            button(Button::RightTrigger2) - button(Button::LeftTrigger2),
+            axis(Axis::LeftStickY),
+        );
+        let look = Vec2::new(-axis(Axis::RightStickX), axis(Axis::RightStickY));
+        let zoom = button(Button::LeftTrigger) - button(Button::RightTrigger);
+        controller.process_gamepad(movement, look, zoom);
+    }
+}
@@ -0,0 +1,174 @@
+use glam::Vec3;
+use instant::Duration;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::{PI, TAU};
+use std::path::Path;
+use anyhow::*;
+
+use super::{Projection, View};
+
+// Scripted camera motion for demos and benchmark runs.
+// A path is a list of keyframes that is played back through the same Camera::update hook as the interactive controller.
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Keyframe {
+    pub time: f32, // seconds since the start of the path
+    pub position: Vec3,
+    pub yaw: f32,
+    pub pitch: f32,
+    pub fov: f32, // vertical field of view in radians
+}
+
+impl Keyframe {
+    pub fn from_camera(time: f32, view: &View, projection: &Projection) -> Self {
+        Self {
+            time,
+            position: view.position,
+            yaw: view.yaw,
+            pitch: view.pitch,
+            fov: projection.fovy(),
+        }
+    }
+    // move the camera to this keyframe
+    pub fn apply(&self, view: &mut View, projection: &mut Projection) {
+        view.position = self.position;
+        view.yaw = self.yaw;
+        view.pitch = self.pitch;
+        projection.set_fovy(self.fov);
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CameraPath {
+    keyframes: Vec<Keyframe>, // sorted by time
+}
+
+impl CameraPath {
+    // time between keyframes recorded from the viewer
+    pub const RECORD_INTERVAL: f32 = 2.0;
+
+    pub fn new(mut keyframes: Vec<Keyframe>) -> Self {
+        keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+        Self { keyframes }
+    }
+    pub fn keyframes(&self) -> &[Keyframe] {
+        &self.keyframes
+    }
+    pub fn is_empty(&self) -> bool {
+        self.keyframes.is_empty()
+    }
+    pub fn clear(&mut self) {
+        self.keyframes.clear();
+    }
+    // length of the path in seconds
+    pub fn duration(&self) -> f32 {
+        self.keyframes.last().map_or(0.0, |k| k.time)
+    }
+    pub fn push(&mut self, keyframe: Keyframe) {
+        let idx = self.keyframes.partition_point(|k| k.time <= keyframe.time);
+        self.keyframes.insert(idx, keyframe);
+    }
+    // add a keyframe for the current camera, RECORD_INTERVAL seconds after the last one
+    pub fn record(&mut self, view: &View, projection: &Projection) {
+        let time = if self.is_empty() { 0.0 } else { self.duration() + Self::RECORD_INTERVAL };
+        self.push(Keyframe::from_camera(time, view, projection));
+    }
+
+    // the interpolated camera at a time along the path. Position uses a Catmull-Rom spline through the keyframes,
+    // angles take the shortest way around the circle.
+    pub fn sample(&self, time: f32) -> Option<Keyframe> {
+        let first = self.keyframes.first()?;
+        let last = self.keyframes.last()?;
+        if time <= first.time {
+            return Some(*first);
+        }
+        if time >= last.time {
+            return Some(*last);
+        }
+        // the segment is between keyframes i and i + 1
+        let i = self.keyframes.partition_point(|k| k.time <= time) - 1;
+        let k1 = self.keyframes[i];
+        let k2 = self.keyframes[i + 1];
+        // the endpoints are repeated so the spline passes through the first and last keyframes
+        let k0 = self.keyframes[i.saturating_sub(1)];
+        let k3 = self.keyframes[(i + 2).min(self.keyframes.len() - 1)];
+        let segment_length = k2.time - k1.time;
+        let t = if segment_length > 0.0 { (time - k1.time) / segment_length } else { 0.0 };
+        Some(Keyframe {
+            time,
+            position: catmull_rom(k0.position, k1.position, k2.position, k3.position, t),
+            yaw: lerp_angle(k1.yaw, k2.yaw, t),
+            pitch: lerp_angle(k1.pitch, k2.pitch, t),
+            fov: k1.fov + (k2.fov - k1.fov) * t,
+        })
+    }
+
+    // load a path from a .ron or .json file
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read camera path '{}'", path.display()))?;
+        let loaded: Self = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::from_str(&text)?,
+            Some("ron") => ron::from_str(&text)?,
+            _ => bail!("Unknown camera path format '{}', expected .ron or .json", path.display()),
+        };
+        Ok(Self::new(loaded.keyframes))
+    }
+    // save a path to a .ron or .json file
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::to_string_pretty(self)?,
+            Some("ron") => ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?,
+            _ => bail!("Unknown camera path format '{}', expected .ron or .json", path.display()),
+        };
+        std::fs::write(path, text)
+            .with_context(|| format!("Could not write camera path '{}'", path.display()))
+    }
+}
+
+// Plays a path back, optionally looping.
+#[derive(Debug, Clone)]
+pub struct Playback {
+    path: CameraPath,
+    time: f32,
+    looping: bool,
+}
+
+impl Playback {
+    pub fn new(path: CameraPath, looping: bool) -> Self {
+        Self {
+            path,
+            time: 0.0,
+            looping,
+        }
+    }
+    pub fn is_finished(&self) -> bool {
+        !self.looping && self.time >= self.path.duration()
+    }
+    // advance the playback and move the camera to the new point on the path
+    pub fn update(&mut self, view: &mut View, projection: &mut Projection, dt: Duration) {
+        self.time += dt.as_secs_f32();
+        let duration = self.path.duration();
+        if self.looping && duration > 0.0 {
+            self.time %= duration;
+        }
+        if let Some(keyframe) = self.path.sample(self.time) {
+            keyframe.apply(view, projection);
+        }
+    }
+}
+
+fn catmull_rom(p0: Vec3, p1: Vec3, p2: Vec3, p3: Vec3, t: f32) -> Vec3 {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    0.5 * ((2.0 * p1)
+        + (p2 - p0) * t
+        + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t2
+        + (3.0 * p1 - p0 - 3.0 * p2 + p3) * t3)
+}
+
+// interpolate between two angles in radians along the shortest arc
+fn lerp_angle(a: f32, b: f32, t: f32) -> f32 {
+    let difference = (b - a + PI).rem_euclid(TAU) - PI;
+    a + difference * t
+}
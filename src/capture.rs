@@ -0,0 +1,74 @@
+use std::path::{Path, PathBuf};
+use instant::Duration;
+use anyhow::*;
+
+// Saving what the viewer shows, either as single screenshots or as a numbered sequence of frames.
+
+// milliseconds since the unix epoch, used to give captures unique names
+fn timestamp() -> u128 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0)
+}
+
+// a timestamped path that nothing has been saved at yet, with a counter on the end if the name is taken
+fn unused_path(prefix: &str, extension: &str) -> PathBuf {
+    let name = format!("{}_{}", prefix, timestamp());
+    (0..)
+        .map(|i| PathBuf::from(if i == 0 { format!("{}{}", name, extension) } else { format!("{}_{}{}", name, i, extension) }))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+// where a screenshot taken right now should be saved, without overwriting an earlier one
+pub fn screenshot_path() -> PathBuf {
+    unused_path("screenshot", ".png")
+}
+
+pub fn save_image(image: &image::RgbaImage, path: &Path) -> Result<()> {
+    image.save(path).with_context(|| format!("Could not save image to '{}'", path.display()))
+}
+
+// Records frames into a directory. While recording, the simulation advances by a fixed
+// timestep per frame instead of the wall clock, so the same recording comes out every time.
+pub struct Recorder {
+    directory: PathBuf,
+    frame: u32,
+    timestep: Duration,
+}
+
+impl Recorder {
+    pub const DEFAULT_FPS: u32 = 60;
+
+    pub fn new(directory: PathBuf, fps: u32) -> Result<Self> {
+        std::fs::create_dir_all(&directory)
+            .with_context(|| format!("Could not create recording directory '{}'", directory.display()))?;
+        Ok(Self {
+            directory,
+            frame: 0,
+            timestep: Duration::from_secs(1) / fps,
+        })
+    }
+    // start a recording in a new timestamped directory
+    pub fn start() -> Result<Self> {
+        Self::new(unused_path("recording", ""), Self::DEFAULT_FPS)
+    }
+    // the simulated time between two recorded frames
+    pub fn timestep(&self) -> Duration {
+        self.timestep
+    }
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+    pub fn frames_written(&self) -> u32 {
+        self.frame
+    }
+    // write the next numbered frame
+    pub fn write_frame(&mut self, image: &image::RgbaImage) -> Result<()> {
+        let path = self.directory.join(format!("frame_{:05}.png", self.frame));
+        save_image(image, &path)?;
+        self.frame += 1;
+        Ok(())
+    }
+}
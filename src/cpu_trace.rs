@@ -0,0 +1,1163 @@
+use std::fmt;
+use std::path::Path;
+use glam::{IVec3, Mat3, Mat4, UVec3, Vec2, Vec3, Vec3Swizzles, Vec4Swizzles};
+use bytemuck::Zeroable;
+use rayon::prelude::*;
+use anyhow::*;
+
+use crate::camera::Camera;
+use crate::scene::{Chunk, CompressedVoxel, Fog, Material, SamplingMode, Scene, CHUNK_SIZE};
+use crate::scene::faces::{self, FaceLayout, FaceLight};
+use crate::scene::objects::{ObjectBvh, BVH_INNER};
+
+// A CPU reference implementation of raytracing.wgsl.
+// Every function here mirrors the shader function of the same name as closely as Rust allows, including its
+// quirks, so the images it renders can be used as an oracle for the GPU output and as a fallback renderer.
+// Lighting is emulated frame by frame: the lighting pass runs `lighting_frames` times with the same seeds the
+// GPU would use for those frames, and then the final image is traced with the resulting lighting cache.
+
+const EPSILON: f32 = 0.0001;
+const MATERIAL_EMPTY: u32 = 255;
+const ORTHOGRAPHIC: u32 = crate::camera::CameraUniform::ORTHOGRAPHIC;
+const ICHUNK_SIZE: i32 = CHUNK_SIZE as i32;
+const DIFFUSE_RANGE: f32 = 4.0; // the brightest diffuse light that can be stored
+const AO_RADIUS: i32 = 3; // how many voxels in front of a face can occlude it
+const SPECULAR_HISTORY: f32 = 15.0; // how many frames of specular light are kept, since reflections move with the camera
+const SEQUENCE_SALT: u32 = 0x9E3779B9; // used instead of a frame number to get seeds that are the same every frame
+const BVH_STACK_SIZE: usize = 32; // the size of the stack of nodes still to visit in trace_objects
+// the shader's value of pi, which rounds to a different f32 than std::f32::consts::PI
+#[allow(clippy::approx_constant)]
+const SHADER_PI: f32 = 3.1415926;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceSettings {
+    pub width: u32,
+    pub height: u32,
+    pub lighting_frames: u32, // how many frames of the lighting pass to run before tracing the image
+}
+
+impl Default for TraceSettings {
+    fn default() -> Self {
+        Self {
+            width: 320,
+            height: 180,
+            lighting_frames: 1,
+        }
+    }
+}
+
+// What rays that leave the scene see
+pub enum Skybox {
+    Color(Vec3), // a constant color, which is multiplied by the sun strength like the cubemap is
+    Cubemap(Box<[image::RgbaImage; 6]>), // faces in the order right, left, top, bottom, front, back
+}
+
+impl Skybox {
+    // load the faces of a cubemap from a directory, named the same way as for the GPU skybox
+    pub fn load(directory: &Path) -> Result<Self> {
+        let names = ["right", "left", "top", "bottom", "front", "back"];
+        let mut faces = Vec::with_capacity(6);
+        for name in names {
+            let path = directory.join(format!("{}.jpg", name));
+            faces.push(image::open(&path).with_context(|| format!("Could not open skybox face '{}'", path.display()))?.to_rgba8());
+        }
+        let faces: [image::RgbaImage; 6] = faces.try_into().map_err(|_| anyhow!("Expected 6 skybox faces"))?;
+        Ok(Self::Cubemap(Box::new(faces)))
+    }
+    // sample like textureSampleLevel on an sRGB cube texture with linear filtering
+    fn sample(&self, direction: Vec3) -> Vec3 {
+        let faces = match self {
+            Self::Color(color) => return *color,
+            Self::Cubemap(faces) => faces,
+        };
+        // pick the face and the coordinates on it, following the cube map conventions of the graphics APIs
+        let abs = direction.abs();
+        let (face, sc, tc, ma) = if abs.x >= abs.y && abs.x >= abs.z {
+            if direction.x > 0.0 { (0, -direction.z, -direction.y, abs.x) } else { (1, direction.z, -direction.y, abs.x) }
+        } else if abs.y >= abs.z {
+            if direction.y > 0.0 { (2, direction.x, direction.z, abs.y) } else { (3, direction.x, -direction.z, abs.y) }
+        } else if direction.z > 0.0 {
+            (4, direction.x, -direction.y, abs.z)
+        } else {
+            (5, -direction.x, -direction.y, abs.z)
+        };
+        let uv = (Vec2::new(sc, tc) / ma + 1.0) / 2.0;
+        let image = &faces[face];
+        let size = Vec2::new(image.width() as f32, image.height() as f32);
+        let texel = uv * size - 0.5;
+        let base = texel.floor();
+        let t = texel - base;
+        let fetch = |x: f32, y: f32| {
+            let x = (x as i32).clamp(0, image.width() as i32 - 1) as u32;
+            let y = (y as i32).clamp(0, image.height() as i32 - 1) as u32;
+            let pixel = image.get_pixel(x, y);
+            Vec3::new(srgb_to_linear(pixel[0]), srgb_to_linear(pixel[1]), srgb_to_linear(pixel[2]))
+        };
+        let top = fetch(base.x, base.y).lerp(fetch(base.x + 1.0, base.y), t.x);
+        let bottom = fetch(base.x, base.y + 1.0).lerp(fetch(base.x + 1.0, base.y + 1.0), t.x);
+        top.lerp(bottom, t.y)
+    }
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let c = value as f32 / 255.0;
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+// render an image of the scene as seen from the camera, the way the GPU would after settings.lighting_frames frames.
+// The image is the right way up, like a screenshot from the viewer.
+pub fn render(scene: &Scene, camera: &Camera, skybox: &Skybox, settings: &TraceSettings) -> image::RgbaImage {
+    let mut tracer = Tracer::new(scene, camera, skybox);
+    for _ in 0..settings.lighting_frames {
+        tracer.lighting_pass();
+        tracer.frame = tracer.frame.wrapping_add(1);
+    }
+
+    let (width, height) = (settings.width, settings.height);
+    let mut pixels = vec![0u8; (width * height * 4) as usize];
+    pixels.par_chunks_mut((width * 4) as usize).enumerate().for_each(|(row_idx, row)| {
+        let y = height - 1 - row_idx as u32; // the screen texture is upside down, so flip it like screenshots are
+        for x in 0..width {
+            let color = tracer.main(Vec2::new(x as f32, y as f32), Vec2::new(width as f32, height as f32));
+            let color = (color.clamp(Vec3::ZERO, Vec3::ONE) * 255.0).round();
+            let i = (x * 4) as usize;
+            row[i..i + 4].copy_from_slice(&[color.x as u8, color.y as u8, color.z as u8, 255]);
+        }
+    });
+    image::RgbaImage::from_raw(width, height, pixels).expect("pixel buffer has the size of the image")
+}
+
+// The per-pixel difference between two images
+pub struct ImageDifference {
+    pub tolerance: u8, // the largest per-channel difference that's still considered equal
+    pub max_difference: u8,
+    pub worst_pixel: (u32, u32),
+    pub mean_difference: f32, // per channel, in 0..255
+    pub rmse: f32, // root mean square error per channel, in 0..255
+    pub pixels_over_tolerance: usize,
+    pub pixel_count: usize,
+    pub diff_image: image::RgbaImage, // the amplified absolute difference, pixels over the tolerance are red
+}
+
+impl ImageDifference {
+    pub fn within_tolerance(&self) -> bool {
+        self.pixels_over_tolerance == 0
+    }
+}
+
+impl fmt::Display for ImageDifference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} pixels differ by more than {} (max {} at {:?}, mean {:.3}, rmse {:.3})",
+            self.pixels_over_tolerance, self.pixel_count, self.tolerance,
+            self.max_difference, self.worst_pixel, self.mean_difference, self.rmse,
+        )
+    }
+}
+
+// compare two images of the same size channel by channel, ignoring alpha
+pub fn compare_images(expected: &image::RgbaImage, actual: &image::RgbaImage, tolerance: u8) -> Result<ImageDifference> {
+    ensure!(
+        expected.dimensions() == actual.dimensions(),
+        "Image sizes differ: expected {:?}, got {:?}", expected.dimensions(), actual.dimensions()
+    );
+    let mut diff_image = image::RgbaImage::new(expected.width(), expected.height());
+    let mut max_difference = 0;
+    let mut worst_pixel = (0, 0);
+    let mut sum = 0.0f64;
+    let mut sum_squared = 0.0f64;
+    let mut pixels_over_tolerance = 0;
+    for (x, y, diff_pixel) in diff_image.enumerate_pixels_mut() {
+        let a = expected.get_pixel(x, y);
+        let b = actual.get_pixel(x, y);
+        let difference: [u8; 3] = std::array::from_fn(|c| a[c].abs_diff(b[c]));
+        let pixel_max = difference.into_iter().max().unwrap_or(0);
+        for d in difference {
+            sum += d as f64;
+            sum_squared += (d as f64).powi(2);
+        }
+        if pixel_max > max_difference {
+            max_difference = pixel_max;
+            worst_pixel = (x, y);
+        }
+        *diff_pixel = if pixel_max > tolerance {
+            pixels_over_tolerance += 1;
+            image::Rgba([255, 0, 0, 255])
+        } else {
+            let [r, g, b] = difference.map(|d| d.saturating_mul(8));
+            image::Rgba([r, g, b, 255])
+        };
+    }
+    let pixel_count = (expected.width() * expected.height()) as usize;
+    let samples = (pixel_count * 3).max(1) as f64;
+    Ok(ImageDifference {
+        tolerance,
+        max_difference,
+        worst_pixel,
+        mean_difference: (sum / samples) as f32,
+        rmse: (sum_squared / samples).sqrt() as f32,
+        pixels_over_tolerance,
+        pixel_count,
+        diff_image,
+    })
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Ray {
+    direction: Vec3,
+    inv_direction: Vec3,
+    position: Vec3,
+}
+
+impl Ray {
+    fn new(position: Vec3, direction: Vec3) -> Self {
+        Self {
+            direction,
+            inv_direction: 1.0 / direction,
+            position,
+        }
+    }
+}
+
+struct Dda {
+    ray: Ray,
+    pos: IVec3, // the position in the chunk / the position of the chunk in the scene
+    delta_dist: Vec3, // distance ray has to travel to reach next cell in each direction
+    step_dir: IVec3, // direction the ray will step
+    side_dist: Vec3, // total distance ray has to travel to reach one additional step in each direction
+}
+
+// WGSL's sign, which unlike f32::signum is zero for zero
+fn sign(v: Vec3) -> Vec3 {
+    Vec3::select(v.cmpeq(Vec3::ZERO), Vec3::ZERO, v.signum())
+}
+
+fn reflect(incident: Vec3, normal: Vec3) -> Vec3 {
+    incident - 2.0 * normal.dot(incident) * normal
+}
+
+fn min_element(v: Vec3) -> f32 {
+    v.x.min(v.y).min(v.z)
+}
+
+fn init_dda(ray: Ray) -> Dda {
+    let pos = ray.position.floor().as_ivec3();
+    let delta_dist = ray.inv_direction.abs();
+    let direction_sign = sign(ray.direction);
+    Dda {
+        ray,
+        pos,
+        delta_dist,
+        step_dir: direction_sign.as_ivec3(),
+        side_dist: (direction_sign * (pos.as_vec3() - ray.position) + (direction_sign * 0.5) + 0.5) * delta_dist,
+    }
+}
+
+// steps the DDA state one unit along the initial ray direction. Returns the normal of the voxel that was "hit"
+fn step_dda(state: &mut Dda) -> Vec3 {
+    let side_dist = state.side_dist;
+    let mask = side_dist.cmple(side_dist.yzx().min(side_dist.zxy()));
+    let mask_f = Vec3::select(mask, Vec3::ONE, Vec3::ZERO);
+    state.side_dist += mask_f * state.delta_dist;
+    state.pos += mask_f.as_ivec3() * state.step_dir;
+    mask_f * -state.step_dir.as_vec3()
+}
+
+fn in_chunk_bounds(pos: IVec3) -> bool {
+    pos.cmplt(IVec3::splat(ICHUNK_SIZE)).all() && pos.cmpge(IVec3::ZERO).all()
+}
+
+fn get_chunk_index(pos: IVec3) -> usize {
+    (pos.x + ICHUNK_SIZE * (pos.y + ICHUNK_SIZE * pos.z)) as usize
+}
+
+fn intersect_box(ray: &Ray, box_min: Vec3, box_max: Vec3) -> Vec2 {
+    let t_min = (box_min - ray.position) * ray.inv_direction;
+    let t_max = (box_max - ray.position) * ray.inv_direction;
+    let t1 = t_min.min(t_max);
+    let t2 = t_min.max(t_max);
+    Vec2::new(t1.max_element(), t2.min_element())
+}
+
+fn box_normal(intersect_pos: Vec3, box_min: Vec3, box_max: Vec3) -> Vec3 {
+    let c = (box_min + box_max) * 0.5;
+    let p = intersect_pos - c;
+    let d = (box_max - box_min) * 0.5;
+    let bias = 1.0 + EPSILON;
+    let v = p / d * bias;
+    Vec3::new(v.x.trunc(), v.y.trunc(), v.z.trunc()).normalize()
+}
+
+// the shader's decompressed voxel, including the lighting stored in it
+#[derive(Debug, Clone, Copy, Default)]
+struct Voxel {
+    material: u32,
+    normal: Vec3,
+    albedo: Vec3,
+    diffuse: Vec3,
+    specular: Vec3,
+    occlusion: u32, // packed ambient occlusion of the faces, see face_occlusion
+    faces: u32, // which faces have light stored, see face_slot
+}
+
+fn decompress_uvec4(value: u32) -> [u32; 4] {
+    [(value >> 24) & 0xFF, (value >> 16) & 0xFF, (value >> 8) & 0xFF, value & 0xFF]
+}
+
+fn compress_uvec4(value: [u32; 4]) -> u32 {
+    let [x, y, z, w] = value.map(|v| v & 0xFF);
+    (x << 24) | (y << 16) | (z << 8) | w
+}
+
+fn decompress_voxel(compressed: &CompressedVoxel) -> Voxel {
+    let nr = decompress_uvec4(compressed.normal);
+    let ar = decompress_uvec4(compressed.albedo);
+    Voxel {
+        material: nr[0],
+        normal: Vec3::new(
+            (nr[1] * 2) as f32 - 255.0,
+            (nr[2] * 2) as f32 - 255.0,
+            (nr[3] * 2) as f32 - 255.0,
+        ) / 255.0,
+        albedo: Vec3::new(ar[0] as f32, ar[1] as f32, ar[2] as f32) / 255.0,
+        diffuse: Vec3::ZERO, // the light is stored per face, see with_face_light
+        specular: Vec3::ZERO,
+        occlusion: compressed.occlusion,
+        faces: compressed.faces,
+    }
+}
+
+// the face a normal points out of, in the order +x, -x, +y, -y, +z, -z
+fn face_index(normal: Vec3) -> u32 {
+    let a = normal.abs();
+    if a.x >= a.y && a.x >= a.z {
+        if normal.x > 0.0 { 0 } else { 1 }
+    } else if a.y >= a.z {
+        if normal.y > 0.0 { 2 } else { 3 }
+    } else if normal.z > 0.0 { 4 } else { 5 }
+}
+
+// the voxels and face lights of a chunk after lighting_main
+struct LitChunk {
+    voxels: [CompressedVoxel; CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE],
+    face_lights: Vec<FaceLight>, // the chunk's block
+    accumulated_light_samples: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct StepResult {
+    hit: bool, // if this is false, the rest of the data is invalid
+    new_pos: Vec3, // where the ray hit after stepping
+    normal: Vec3, // the face normal of the hit voxel
+    voxel: Voxel,
+    color_add: Vec3,
+    color_mul: f32,
+    object: bool, // whether a dynamic object was hit instead of the world
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ObjectHit {
+    hit: bool, // if this is false, t is the largest distance and the rest of the data is invalid
+    t: f32, // how far along the ray the hit is, in units of the ray direction
+    normal: Vec3, // the face normal of the hit voxel, in scene space
+    voxel: Voxel,
+}
+
+// the scene and camera, in the form the shader sees them
+struct Tracer<'a> {
+    size: Vec3,
+    sun_direction: Vec3,
+    sun_strength: Vec3,
+    ambient_light: Vec3,
+    seed: u32,
+    frame: u32,
+    sampling: SamplingMode,
+    specular_samples: u32,
+    ambient_occlusion: bool,
+    fog: Fog,
+    chunks: Vec<Chunk>, // a copy, since the lighting pass writes to it
+    face_lights: Vec<FaceLight>,
+    objects: ObjectBvh, // the instances of the dynamic objects
+    object_voxels: Vec<CompressedVoxel>,
+    materials: &'a [Material],
+    skybox: &'a Skybox,
+    camera_position: Vec3,
+    camera_direction: Vec3,
+    camera_projection: u32,
+    camera_frame: u32,
+    inv_view: Mat4,
+    inv_proj: Mat4,
+}
+
+// the private variables of one shader invocation
+struct Invocation<'t, 'a> {
+    tracer: &'t Tracer<'a>,
+    last_vox_id: u32, // the last hit voxel's albedo and material, used for transparency
+    last_vox_refract: f32, // last hit voxel's refraction index
+    first_sample: bool,
+    sequence_offsets: [u32; 4], // per-face shift of the R2 sequence, [0..2] for diffuse rays and [2..4] for shadow rays
+}
+
+impl<'a> Tracer<'a> {
+    fn new(scene: &'a Scene, camera: &Camera, skybox: &'a Skybox) -> Self {
+        let uniform = camera.uniform();
+        let uniform: &[u32] = bytemuck::cast_slice(bytemuck::bytes_of(&uniform));
+        // lay out the faces of changed chunks like Scene::update_faces would, without changing the scene
+        let mut chunks = scene.chunks.clone();
+        let mut face_allocator = scene.face_allocator.clone();
+        FaceLayout {
+            chunks: &mut chunks,
+            stale: &mut scene.stale_faces.clone(),
+            allocator: &mut face_allocator,
+            materials: &scene.materials,
+            size: scene.size.xyz().as_uvec3(),
+        }.update();
+        Self {
+            size: scene.size.xyz(),
+            sun_direction: scene.sun_direction.xyz(),
+            sun_strength: scene.sun_strength.xyz(),
+            ambient_light: scene.ambient_light.xyz(),
+            seed: scene.seed,
+            frame: scene.frame,
+            sampling: scene.sampling,
+            specular_samples: scene.specular_samples,
+            ambient_occlusion: scene.ambient_occlusion,
+            fog: scene.fog,
+            chunks,
+            face_lights: vec![FaceLight::default(); face_allocator.capacity() as usize],
+            objects: scene.object_bvh(),
+            object_voxels: scene.object_voxels(),
+            materials: &scene.materials,
+            skybox,
+            camera_position: camera.view.position,
+            camera_direction: camera.view.direction(),
+            camera_projection: uniform[8], // CameraUniform::projection.x
+            camera_frame: uniform[17], // CameraUniform::frame.y
+            inv_view: camera.view.calc_matrix().inverse(),
+            inv_proj: camera.projection.calc_matrix().inverse(),
+        }
+    }
+
+    fn invocation(&self) -> Invocation<'_, 'a> {
+        Invocation {
+            tracer: self,
+            last_vox_id: 255,
+            last_vox_refract: 1.0,
+            first_sample: false,
+            sequence_offsets: [0; 4],
+        }
+    }
+
+    // whether or not a position is within the scene
+    fn in_scene_bounds(&self, pos: IVec3) -> bool {
+        let fpos = pos.as_vec3();
+        fpos.cmplt(self.size).all() && fpos.cmpge(Vec3::ZERO).all()
+    }
+    // the index into the scene array that corresponds to a 3d position
+    fn get_scene_index(&self, pos: IVec3) -> usize {
+        let isize = self.size.as_ivec3();
+        (pos.x + isize.x * (pos.y + isize.y * pos.z)) as usize
+    }
+    fn compressed_voxel_at(&self, chunk_id: usize, pos_in_chunk: IVec3) -> &CompressedVoxel {
+        &self.chunks[chunk_id].voxels[get_chunk_index(pos_in_chunk)]
+    }
+    // whether a voxel blocks ambient light, in voxel space so neighbours in other chunks can be looked up
+    fn is_occluder(&self, voxel_pos: IVec3) -> bool {
+        let scene_pos = voxel_pos / ICHUNK_SIZE;
+        if voxel_pos.cmplt(IVec3::ZERO).any() || !self.in_scene_bounds(scene_pos) {
+            return false;
+        }
+        let chunk_id = self.get_scene_index(scene_pos);
+        if self.chunks[chunk_id].pos.w == 0.0 {
+            return false;
+        }
+        let material = self.compressed_voxel_at(chunk_id, voxel_pos % ICHUNK_SIZE).normal >> 24;
+        material != MATERIAL_EMPTY && self.material(material).density <= 0.0
+    }
+    // how much of the space in front of each face of a voxel is empty, from 0 for an enclosed face to 1 for an open one
+    fn ambient_occlusion(&self, voxel_pos: IVec3) -> u32 {
+        let mut packed = 0;
+        for face in 0..6 {
+            let axis = face / 2;
+            let normal = IVec3::new((axis == 0) as i32, (axis == 1) as i32, (axis == 2) as i32) * (1 - 2 * (face % 2));
+            let tangent = normal.yzx().abs();
+            let bitangent = normal.zxy().abs();
+            let mut occluded = 0.0;
+            let mut total = 0.0;
+            for depth in 1..=AO_RADIUS {
+                let weight = 1.0 / depth as f32;
+                for a in 1 - depth..depth {
+                    for b in 1 - depth..depth {
+                        if self.is_occluder(voxel_pos + normal * depth + tangent * a + bitangent * b) {
+                            occluded += weight;
+                        }
+                        total += weight;
+                    }
+                }
+            }
+            packed |= (((1.0 - occluded / total) * 31.0).round() as u32) << (5 * face);
+        }
+        packed
+    }
+    // the ambient occlusion of the face of a voxel with the given normal, or 1 if it's turned off
+    fn face_occlusion(&self, occlusion: u32, face_normal: Vec3) -> f32 {
+        if !self.ambient_occlusion {
+            return 1.0;
+        }
+        ((occlusion >> (5 * face_index(face_normal))) & 31) as f32 / 31.0
+    }
+    // where the light of a face of a voxel in chunk_id is in face_lights, if the face is exposed
+    fn face_slot(&self, chunk_id: usize, faces: u32, face: u32) -> Option<usize> {
+        let mask = faces & 0x3F;
+        if mask & (1 << face) == 0 {
+            return None;
+        }
+        Some((self.chunks[chunk_id].face_offset + (faces >> 6) + (mask & ((1 << face) - 1)).count_ones()) as usize)
+    }
+    // a voxel of chunk_id with the light of one of its faces
+    fn with_face_light(&self, vox: Voxel, chunk_id: usize, face: u32) -> Voxel {
+        let Some(slot) = self.face_slot(chunk_id, vox.faces, face) else {
+            return vox;
+        };
+        let light = &self.face_lights[slot];
+        let specular = decompress_uvec4(light.specular);
+        Voxel {
+            specular: Vec3::new(specular[0] as f32, specular[1] as f32, specular[2] as f32) / 255.0,
+            diffuse: Vec3::new((light.diffuse_rg >> 16) as f32, (light.diffuse_rg & 0xFFFF) as f32, (light.diffuse_b >> 16) as f32) / 65535.0 * DIFFUSE_RANGE,
+            ..vox
+        }
+    }
+    // the diffuse and ambient light on the face of a voxel
+    fn diffuse_light(&self, vox: &Voxel, face_normal: Vec3) -> Vec3 {
+        (vox.diffuse + self.ambient_light) * self.face_occlusion(vox.occlusion, face_normal)
+    }
+    fn material(&self, index: u32) -> &Material {
+        // out of bounds indices are clamped, like array accesses in the shader
+        &self.materials[(index as usize).min(self.materials.len() - 1)]
+    }
+
+    // a point in the unit square, either random or the index-th point of the R2 sequence shifted by sequence_offset
+    fn sample_square(&self, seed: &mut u32, sequence_offset: [u32; 2], index: u32) -> Vec2 {
+        if self.sampling != SamplingMode::R2 {
+            let x = rand(seed);
+            let y = rand(seed);
+            return Vec2::new(x, y);
+        }
+        // wrapping around in fixed point keeps the sequence exact for any index
+        let r2_alpha = [3242174889u32, 2447445414]; // 1/g and 1/g^2 of the R2 sequence in 0.32 fixed point
+        let u: [f32; 2] = std::array::from_fn(|i| sequence_offset[i].wrapping_add(index.wrapping_mul(r2_alpha[i])) as f32);
+        Vec2::from_array(u) / 4294967296.0 // 2^32
+    }
+    // a seed that is different for every position and frame, but the same between runs with the same scene seed
+    fn hashed_seed(&self, pos: UVec3, frame: u32) -> u32 {
+        hash(pos.x ^ hash(pos.y ^ hash(pos.z ^ hash(frame ^ hash(self.seed)))))
+    }
+    // the albedo of a voxel multiplied by its material's base color
+    fn surface_color(&self, vox: &Voxel) -> Vec3 {
+        vox.albedo * self.material(vox.material).base_color
+    }
+    // the light emitted by a voxel, which can be brighter than white
+    fn emitted_light(&self, vox: &Voxel) -> Vec3 {
+        let material = self.material(vox.material);
+        self.surface_color(vox) * material.emission * material.emission_strength
+    }
+
+    fn fog_density(&self, height: f32) -> f32 {
+        self.fog.density * (-self.fog.falloff * (height - self.fog.height).max(0.0)).exp()
+    }
+
+    fn skybox_color(&self, direction: Vec3) -> Vec3 {
+        self.skybox.sample(direction) * self.sun_strength
+    }
+    fn voxel_color(&self, info: &StepResult) -> Vec3 {
+        let vox = info.voxel;
+        let material = self.material(vox.material);
+        let solid_color = if material.emission_strength > 0.0 {
+            self.emitted_light(&vox)
+        } else {
+            // dynamic objects get all of their light from object_light
+            let light = if info.object { vox.diffuse } else { self.diffuse_light(&vox, info.normal) };
+            self.surface_color(&vox) * light * (1.0 - material.metallic) + vox.specular
+        };
+        solid_color * info.color_mul + info.color_add
+    }
+
+    // the ray through a pixel, in screen space
+    fn camera_ray(&self, screen_pos: Vec2) -> Ray {
+        if self.camera_projection == ORTHOGRAPHIC {
+            let view_pos = self.inv_proj * screen_pos.extend(0.0).extend(1.0);
+            let position = (self.inv_view * (view_pos.xyz() / view_pos.w).extend(1.0)).xyz();
+            Ray::new(position, self.camera_direction + EPSILON)
+        } else {
+            let mut inv_view_centered = self.inv_view;
+            inv_view_centered.w_axis = glam::Vec4::W;
+            let direction = (inv_view_centered * self.inv_proj * screen_pos.extend(0.0).extend(1.0)).xyz().normalize() + EPSILON;
+            Ray::new(self.camera_position, direction)
+        }
+    }
+    // the direction from the camera to a point in the scene
+    fn view_direction(&self, pos: Vec3) -> Vec3 {
+        if self.camera_projection == ORTHOGRAPHIC {
+            return self.camera_direction;
+        }
+        (pos - self.camera_position).normalize()
+    }
+
+    // the color of one pixel
+    fn main(&self, texture_pos: Vec2, texture_dim: Vec2) -> Vec3 {
+        let mut invocation = self.invocation();
+        let screen_pos = (texture_pos / texture_dim) * 2.0 - 1.0;
+        let mut ray = self.camera_ray(screen_pos);
+        let mut rng = self.hashed_seed(texture_pos.as_uvec2().extend(0), self.camera_frame);
+        let scene_intersection = intersect_box(&ray, Vec3::ZERO, self.size);
+        if scene_intersection.x > scene_intersection.y || scene_intersection.y < 0.0 { // missed map if near > far or far < 0
+            return self.skybox_color(ray.direction);
+        }
+        if scene_intersection.x > 0.0 { // move the ray to the edge of the map so it can DDA inside it
+            ray.position += ray.direction * (scene_intersection.x + EPSILON);
+        }
+        let mut final_info = invocation.step_scene(ray, false);
+        let mut out_color = if final_info.hit {
+            if final_info.object {
+                final_info.voxel.diffuse = invocation.object_light(&final_info);
+            }
+            self.voxel_color(&final_info)
+        } else {
+            self.skybox_color(ray.direction) * final_info.color_mul + final_info.color_add
+        };
+        if self.fog.density > 0.0 {
+            // the fog between the camera and the hit voxel, or the edge of the scene
+            let fog_distance = if final_info.hit {
+                ray.position.distance(final_info.new_pos)
+            } else {
+                scene_intersection.y - scene_intersection.x.max(0.0)
+            };
+            let fog = invocation.march_fog(ray, fog_distance, &mut rng);
+            out_color = out_color * fog.transmittance + fog.light;
+        }
+        out_color
+    }
+
+    // the closest voxel of any instance along a ray, found by walking the BVH
+    fn trace_objects(&self, ray: &Ray) -> ObjectHit {
+        let mut closest = ObjectHit {
+            t: 1e30, // farther than anything in the scene
+            ..Default::default()
+        };
+        let mut stack = [0u32; BVH_STACK_SIZE];
+        let mut stack_size = 1;
+        while stack_size > 0 {
+            stack_size -= 1;
+            let node_id = stack[stack_size];
+            let node = &self.objects.nodes[node_id as usize];
+            let span = intersect_box(ray, node.min, node.max);
+            if span.x > span.y || span.y < 0.0 || span.x > closest.t { // missed, or behind something closer
+                continue;
+            }
+            if node.count != BVH_INNER {
+                for i in node.index..node.index + node.count {
+                    closest = self.trace_object(ray, i as usize, closest);
+                }
+            } else if stack_size + 2 <= BVH_STACK_SIZE {
+                stack[stack_size] = node.index;
+                stack[stack_size + 1] = node_id + 1;
+                stack_size += 2;
+            }
+        }
+        closest
+    }
+    // the closest voxel of an instance along a ray, if it's closer than the closest hit so far
+    fn trace_object(&self, ray: &Ray, instance_id: usize, closest: ObjectHit) -> ObjectHit {
+        let instance = &self.objects.instances[instance_id];
+        let size = instance.size.as_vec3();
+        // not normalized, so distances along it are the same as along the ray
+        let mut object_ray = Ray::new(instance.world_to_object.transform_point3(ray.position), instance.world_to_object.transform_vector3(ray.direction));
+        let span = intersect_box(&object_ray, Vec3::ZERO, size);
+        if span.x > span.y || span.y < 0.0 || span.x > closest.t {
+            return closest;
+        }
+        // DDA through the object from where the ray enters it
+        let start = span.x.max(0.0);
+        object_ray.position = (object_ray.position + object_ray.direction * start).clamp(Vec3::splat(EPSILON), size - EPSILON);
+        let mut dda = init_dda(object_ray);
+        let mut normal = box_normal(object_ray.position, Vec3::ZERO, size);
+        let mut t = start;
+        while dda.pos.cmpge(IVec3::ZERO).all() && dda.pos.as_uvec3().cmplt(instance.size).all() && t < closest.t {
+            let pos = dda.pos.as_uvec3();
+            let idx = pos.x + instance.size.x * (pos.y + instance.size.y * pos.z);
+            let compressed = &self.object_voxels[(instance.voxel_offset + idx) as usize];
+            if compressed.normal >> 24 != MATERIAL_EMPTY {
+                // normals are turned with the inverse transpose of the transform, which is the transpose of world_to_object
+                let to_world = Mat3::from_mat4(instance.world_to_object).transpose();
+                let mut voxel = decompress_voxel(compressed);
+                voxel.normal = (to_world * voxel.normal).normalize();
+                return ObjectHit { hit: true, t, normal: (to_world * normal).normalize(), voxel };
+            }
+            t = start + min_element(dda.side_dist); // where the ray enters the next voxel
+            normal = step_dda(&mut dda);
+        }
+        closest
+    }
+
+    // one dispatch of lighting_main over every chunk, as if the lighting schedule had budget for all of them.
+    // Reads the lighting of the previous frame and writes the next one
+    fn lighting_pass(&mut self) {
+        let chunk_count = self.chunks.len();
+        let lit: Vec<Option<LitChunk>> = (0..chunk_count)
+            .into_par_iter()
+            .map(|scene_idx| self.light_chunk(scene_idx))
+            .collect();
+        for (chunk, lit) in self.chunks.iter_mut().zip(lit) {
+            if let Some(lit) = lit {
+                chunk.voxels = lit.voxels;
+                chunk.accumulated_light_samples = lit.accumulated_light_samples;
+                let block = chunk.face_offset as usize..(chunk.face_offset + chunk.face_count) as usize;
+                self.face_lights[block].copy_from_slice(&lit.face_lights);
+            }
+        }
+    }
+
+    // the chunk after lighting_main, or None if the chunk is empty
+    fn light_chunk(&self, scene_idx: usize) -> Option<LitChunk> {
+        let chunk = &self.chunks[scene_idx];
+        if chunk.pos.w == 0.0 { // don't bother with lighting for empty chunks
+            return None;
+        }
+        let scene_pos = chunk.pos.xyz().as_ivec3();
+        let mut voxels = chunk.voxels;
+        let block = chunk.face_offset as usize..(chunk.face_offset + chunk.face_count) as usize;
+        let mut face_lights = self.face_lights[block].to_vec();
+        for (chunk_idx, voxel) in voxels.iter_mut().enumerate() {
+            if voxel.normal >> 24 == MATERIAL_EMPTY { // empty voxels are lit on the GPU too, but never seen
+                continue;
+            }
+            let pos_in_chunk = IVec3::new(
+                chunk_idx as i32 % ICHUNK_SIZE,
+                chunk_idx as i32 / ICHUNK_SIZE % ICHUNK_SIZE,
+                chunk_idx as i32 / (ICHUNK_SIZE * ICHUNK_SIZE),
+            );
+            *voxel = self.lighting_main(scene_pos, scene_idx, pos_in_chunk, &mut face_lights);
+        }
+        Some(LitChunk {
+            voxels,
+            face_lights,
+            accumulated_light_samples: chunk.accumulated_light_samples + 1,
+        })
+    }
+
+    // Performs lighting calculations for the faces of one voxel, storing their light in the chunk's block of face lights.
+    // Returns the voxel with its new ambient occlusion
+    fn lighting_main(&self, scene_pos: IVec3, scene_idx: usize, pos_in_chunk: IVec3, face_lights: &mut [FaceLight]) -> CompressedVoxel {
+        let num_diffuse_samples = 1;
+
+        let mut invocation = self.invocation();
+        let voxel_pos = (scene_pos * ICHUNK_SIZE + pos_in_chunk).as_uvec3();
+        let voxel_seed = self.hashed_seed(voxel_pos, self.frame);
+        let voxel_offsets: [u32; 4] = std::array::from_fn(|i| self.hashed_seed(voxel_pos, SEQUENCE_SALT.wrapping_add(i as u32)));
+        let accumulated_samples = self.chunks[scene_idx].accumulated_light_samples.min(1000) as f32;
+        invocation.first_sample = accumulated_samples == 0.0;
+        let compressed = *self.compressed_voxel_at(scene_idx, pos_in_chunk);
+        let voxel = decompress_voxel(&compressed);
+        let this_material = self.material(voxel.material);
+        let is_volume = this_material.density > 0.0;
+        let inv_chunk_size = Vec3::splat(1.0 / CHUNK_SIZE as f32);
+        let half_inv_chunk_size = inv_chunk_size / 2.0;
+        let voxel_center = pos_in_chunk.as_vec3() * inv_chunk_size + scene_pos.as_vec3() + half_inv_chunk_size;
+
+        // light every exposed face from its own normal, so light doesn't leak through thin walls. Media are lit once, from the center
+        for face in 0..faces::FACE_COUNT {
+            let Some(slot) = self.face_slot(scene_idx, compressed.faces, face) else {
+                continue;
+            };
+            let mut this_voxel = self.with_face_light(voxel, scene_idx, face);
+            this_voxel.normal = if is_volume { Vec3::ZERO } else { faces::face_normal(face).as_vec3() };
+            let seed = hash(voxel_seed.wrapping_add(face));
+            // every face walks its own shift of the sequences, so the faces of a voxel don't sample the same directions
+            let face_salt = hash(SEQUENCE_SALT.wrapping_add(face));
+            invocation.sequence_offsets = voxel_offsets.map(|offset| offset ^ face_salt);
+            // start the ray at the center of the face
+            let ray_pos = voxel_center + (half_inv_chunk_size - EPSILON) * this_voxel.normal;
+
+            let mut spec_light = Vec3::ZERO;
+            let mut diff_light = Vec3::ZERO;
+
+            let view_dir = self.view_direction(ray_pos);
+            // specular rays
+            // rough dielectrics reflect so little that their specular light is skipped
+            let has_specular = !is_volume && (this_material.metallic > 0.0 || this_material.roughness < 1.0);
+            if has_specular && view_dir.dot(this_voxel.normal) < 0.0 {
+                let alpha = this_material.roughness * this_material.roughness;
+                let f0 = Vec3::splat(0.08 * this_material.specular).lerp(self.surface_color(&this_voxel), this_material.metallic); // reflectance when looking straight at the surface
+                let specular_offset = [self.hashed_seed(voxel_pos, SEQUENCE_SALT.wrapping_add(4)) ^ face_salt, self.hashed_seed(voxel_pos, SEQUENCE_SALT.wrapping_add(5)) ^ face_salt];
+                let mut spec_rng = hash(seed.wrapping_add(2 * num_diffuse_samples)); // after the seeds of the diffuse rays
+                let samples = self.specular_samples.max(1);
+                let mut new_spec = Vec3::ZERO;
+                for i in 0..samples {
+                    let u = self.sample_square(&mut spec_rng, specular_offset, self.frame.wrapping_mul(samples).wrapping_add(i));
+                    let half_vector = sample_ggx(this_voxel.normal, alpha, u);
+                    let specular_dir = reflect(view_dir, half_vector);
+                    if specular_dir.dot(this_voxel.normal) <= 0.0 { // the sample went into the surface, so its light is absorbed
+                        continue;
+                    }
+                    let spec_ray = Ray::new(ray_pos, specular_dir + EPSILON);
+                    let weight = ggx_weight(this_voxel.normal, -view_dir, specular_dir, half_vector, alpha, f0);
+                    new_spec = invocation.specular_ray(spec_ray, weight, new_spec);
+                }
+                new_spec /= samples as f32;
+                let history = accumulated_samples.min(SPECULAR_HISTORY);
+                spec_light = (new_spec + this_voxel.specular * history) / (history + 1.0);
+            }
+            // diffuse + shadow rays
+            if !is_volume && this_material.metallic < 1.0 {
+                for i in 0..num_diffuse_samples {
+                    diff_light = invocation.diffuse_ray(ray_pos, &this_voxel, hash(seed.wrapping_add(2 * i)), diff_light);
+                    diff_light = invocation.shadow_ray(ray_pos, hash(seed.wrapping_add(2 * i + 1)), diff_light);
+                }
+                diff_light = (diff_light + this_voxel.diffuse * accumulated_samples) / (accumulated_samples + num_diffuse_samples as f32);
+            }
+            // light scattered toward the camera by media, which depends on the view direction like specular light
+            if is_volume {
+                let sun_light = invocation.shadow_ray(ray_pos, hash(seed.wrapping_add(1)), Vec3::ZERO);
+                let in_scattered = self.ambient_light + sun_light * henyey_greenstein(view_dir.dot(self.sun_direction), this_material.anisotropy);
+                let history = accumulated_samples.min(SPECULAR_HISTORY);
+                diff_light = (in_scattered + this_voxel.diffuse * history) / (history + 1.0);
+            }
+            spec_light = spec_light.clamp(Vec3::ZERO, Vec3::ONE);
+            diff_light = diff_light.clamp(Vec3::ZERO, Vec3::splat(DIFFUSE_RANGE));
+
+            let store_diffuse = (diff_light / DIFFUSE_RANGE * 65535.0).round().as_uvec3(); // stored as 16-bit x, y, z
+            let store_specular = (spec_light * 255.0).round().as_uvec3();
+            face_lights[slot - self.chunks[scene_idx].face_offset as usize] = FaceLight {
+                specular: compress_uvec4([store_specular.x, store_specular.y, store_specular.z, 0]),
+                diffuse_rg: (store_diffuse.x << 16) | store_diffuse.y,
+                diffuse_b: store_diffuse.z << 16,
+            };
+        }
+
+        // the ambient occlusion only changes with the voxels around, so it's found once when the lighting starts over
+        let occlusion = if invocation.first_sample {
+            self.ambient_occlusion(voxel_pos.as_ivec3())
+        } else {
+            compressed.occlusion
+        };
+        CompressedVoxel {
+            occlusion,
+            ..compressed
+        }
+    }
+}
+
+impl Invocation<'_, '_> {
+    fn step_scene(&mut self, ray: Ray, ignore_first: bool) -> StepResult {
+        let tracer = self.tracer;
+        let mut ignore_first = ignore_first;
+        let mut result = StepResult {
+            color_mul: 1.0,
+            ..Default::default()
+        };
+        // the world is only traced up to the closest object
+        let object_hit = tracer.trace_objects(&ray);
+        let mut last_side_dist = Vec3::ZERO;
+        let mut dda = init_dda(ray);
+        while tracer.in_scene_bounds(dda.pos) && min_element(last_side_dist) <= object_hit.t {
+            let chunk_id = tracer.get_scene_index(dda.pos);
+            let chunk = &tracer.chunks[chunk_id];
+            if chunk.pos.w != 0.0 { // the chunk has non-empty voxels
+                let mut chunk_ray = dda.ray; // ray to use for traversing in the chunk
+                let chunk_t = min_element(last_side_dist) - EPSILON;
+                let updated_ray_pos = dda.ray.position + dda.ray.direction * chunk_t; // move to the chunk bounds
+                chunk_ray.position = ((updated_ray_pos - dda.pos.as_vec3()) * CHUNK_SIZE as f32)
+                    .clamp(Vec3::splat(EPSILON), Vec3::splat(CHUNK_SIZE as f32 - EPSILON)); // set position relative to chunk bounds
+                result = self.step_chunk(chunk_ray, chunk_id, ignore_first, (object_hit.t - chunk_t) * CHUNK_SIZE as f32, result);
+                if result.hit {
+                    result.new_pos = dda.pos.as_vec3() + result.new_pos / CHUNK_SIZE as f32; // hit position in scene space
+                    return result;
+                }
+            }
+            last_side_dist = dda.side_dist;
+            step_dda(&mut dda);
+            ignore_first = false;
+        }
+        if object_hit.hit {
+            result.hit = true;
+            result.object = true;
+            result.new_pos = ray.position + ray.direction * (object_hit.t - EPSILON);
+            result.normal = object_hit.normal;
+            result.voxel = object_hit.voxel;
+        }
+        result
+    }
+
+    // max_t is how far along the chunk ray voxels can be hit before something closer than them was already found
+    fn step_chunk(&mut self, chunk_ray: Ray, chunk_id: usize, ignore_first: bool, max_t: f32, partial_result: StepResult) -> StepResult {
+        let tracer = self.tracer;
+        let mut ignore_first = ignore_first;
+        let mut result = partial_result;
+        result.hit = false;
+        let mut last_side_dist = Vec3::ZERO;
+        let mut dda = init_dda(chunk_ray);
+        let mut normal = box_normal(chunk_ray.position, Vec3::ZERO, Vec3::splat(CHUNK_SIZE as f32));
+        while in_chunk_bounds(dda.pos) && min_element(last_side_dist) <= max_t {
+            let compressed = tracer.compressed_voxel_at(chunk_id, dda.pos);
+            let vox_id = (compressed.albedo & 0xFFFFFF00) | (compressed.normal >> 24);
+            if (vox_id & 0xFF) != MATERIAL_EMPTY && !ignore_first {
+                let vox = decompress_voxel(compressed);
+                let material = tracer.material(vox.material);
+                if material.density > 0.0 { // participating media scatter and absorb light along the ray instead of stopping it
+                    let ray_length = min_element(dda.side_dist) - min_element(last_side_dist); // length of the ray in this voxel
+                    let transmittance = (-material.density * ray_length).exp();
+                    let in_scattered = tracer.surface_color(&vox) * (1.0 - material.absorption) * tracer.with_face_light(vox, chunk_id, 0).diffuse + tracer.emitted_light(&vox); // media are lit as a whole, in the slot of the first face
+                    result.color_add += result.color_mul * (1.0 - transmittance) * in_scattered;
+                    result.color_mul *= transmittance;
+                    self.last_vox_id = vox_id;
+                } else if material.transmission <= 0.0 {
+                    result.hit = true;
+                    result.new_pos = dda.pos.as_vec3() + dda.ray.direction * (min_element(last_side_dist) - EPSILON);
+                    result.normal = normal;
+                    result.voxel = tracer.with_face_light(vox, chunk_id, face_index(normal));
+                    return result;
+                } else if self.last_vox_id != vox_id {
+                    result.color_add += result.color_mul * (1.0 - material.transmission) * tracer.surface_color(&vox) * tracer.sun_strength;
+                    result.color_mul *= material.transmission;
+                    self.last_vox_id = vox_id;
+                    self.last_vox_refract = material.ior;
+                }
+            } else if self.last_vox_id != MATERIAL_EMPTY {
+                self.last_vox_id = MATERIAL_EMPTY;
+                self.last_vox_refract = 1.0;
+            }
+            last_side_dist = dda.side_dist;
+            normal = step_dda(&mut dda);
+            ignore_first = false;
+        }
+        result
+    }
+
+    // the light on a voxel of a dynamic object, which isn't in the lighting cache: the ambient light, the sky straight
+    // out from the face and the sun, with hard shadows so it doesn't have to be averaged over frames
+    fn object_light(&mut self, info: &StepResult) -> Vec3 {
+        let tracer = self.tracer;
+        let mut light = tracer.ambient_light;
+        let sky = self.step_scene(Ray::new(info.new_pos, info.normal + EPSILON), false);
+        if !sky.hit {
+            light += tracer.skybox_color(info.normal) * sky.color_mul + sky.color_add;
+        }
+        let facing = info.normal.dot(tracer.sun_direction);
+        if facing <= 0.0 {
+            return light;
+        }
+        let shadow = self.step_scene(Ray::new(info.new_pos, tracer.sun_direction + EPSILON), false);
+        if !shadow.hit {
+            light += (tracer.sun_strength * shadow.color_mul + shadow.color_add) * facing;
+        }
+        light
+    }
+
+    // march through the fog along the first ray_length of a ray, casting shadow rays toward the sun at every sample
+    fn march_fog(&mut self, ray: Ray, ray_length: f32, rng: &mut u32) -> FogResult {
+        let tracer = self.tracer;
+        let mut result = FogResult {
+            transmittance: 1.0,
+            light: Vec3::ZERO,
+        };
+        let samples = tracer.fog.samples.max(1);
+        let step = ray_length / samples as f32;
+        let phase = henyey_greenstein(ray.direction.dot(tracer.sun_direction), tracer.fog.anisotropy);
+        let jitter = rand(rng); // offset the samples so the steps don't show as bands
+        for i in 0..samples {
+            let pos = ray.position + ray.direction * ((i as f32 + jitter) * step);
+            let transmittance = (-tracer.fog_density(pos.y) * step).exp();
+            let mut in_scattered = tracer.ambient_light;
+            let info = self.step_scene(Ray::new(pos, tracer.sun_direction + EPSILON), false);
+            if !info.hit {
+                in_scattered += (tracer.sun_strength * info.color_mul + info.color_add) * phase;
+            }
+            result.light += result.transmittance * (1.0 - transmittance) * tracer.fog.color * in_scattered;
+            result.transmittance *= transmittance;
+        }
+        result
+    }
+
+    // cast a specular ray, accumulating color times the BRDF weight in spec_light, which is returned
+    fn specular_ray(&mut self, ray: Ray, weight: Vec3, spec_light: Vec3) -> Vec3 {
+        let tracer = self.tracer;
+        let spec_bounce_limit = 2;
+        let mut spec_light = spec_light;
+        let mut last_pos = ray.position;
+        let mut multiplier = weight; // accumulate color over the bounces
+        let mut mut_ray = ray;
+        for _ in 0..spec_bounce_limit {
+            let info = self.step_scene(mut_ray, true);
+            if info.hit {
+                let dist = ((info.new_pos * CHUNK_SIZE as f32).floor() - (last_pos * CHUNK_SIZE as f32).floor()).abs();
+                if dist.dot(dist) <= 1.0 { // ray hit the voxel next to the one being lit, meaning it's occluded from here
+                    return spec_light;
+                }
+                let mut hit_voxel = info.voxel;
+                let hit_material = tracer.material(hit_voxel.material);
+                hit_voxel.diffuse = tracer.diffuse_light(&hit_voxel, info.normal) * (1.0 - hit_material.metallic);
+                if hit_material.emission_strength > 0.0 {
+                    return spec_light + (tracer.emitted_light(&hit_voxel) * info.color_mul + info.color_add) * multiplier;
+                }
+                let hit_color = hit_voxel.diffuse * tracer.surface_color(&hit_voxel);
+                spec_light += (hit_color * info.color_mul + info.color_add) * multiplier;
+                if hit_material.metallic == 0.0 { // no more reflections needed
+                    return spec_light;
+                }
+                // bounce the ray
+                multiplier *= tracer.surface_color(&hit_voxel) * info.color_mul * hit_material.metallic;
+                last_pos = info.new_pos;
+                mut_ray = Ray::new(info.new_pos, reflect(mut_ray.direction, hit_voxel.normal));
+            } else if mut_ray.direction.dot(tracer.sun_direction) > 0.99 { // specular highlight
+                return spec_light + (tracer.sun_strength * info.color_mul + info.color_add) * multiplier;
+            } else { // reflect sky color
+                return spec_light + (tracer.skybox_color(mut_ray.direction) * info.color_mul + info.color_add) * multiplier;
+            }
+        }
+        spec_light
+    }
+
+    // cast a diffuse ray from vox at ray_pos, accumulating color in diff_light
+    fn diffuse_ray(&mut self, ray_pos: Vec3, vox: &Voxel, rng: u32, diff_light: Vec3) -> Vec3 {
+        let tracer = self.tracer;
+        let diffuse_bounce_limit = 6;
+        let mut rng = rng;
+        let mut new_color = Vec3::ONE;
+        let last_pos = ray_pos;
+        let mut last_dir = Vec3::ZERO;
+        let mut hit_normal = vox.normal;
+        let mut hit_mat = Material::zeroed();
+        let mut mut_ray = Ray { position: ray_pos, ..Default::default() };
+        let sequence_index = tracer.frame.wrapping_mul(diffuse_bounce_limit); // every bounce takes its own point of the sequence
+        for i in 0..diffuse_bounce_limit {
+            if i > 0 { // sometimes reflect ray on later bounces
+                if rand(&mut rng) < hit_mat.metallic {
+                    let u = tracer.sample_square(&mut rng, [self.sequence_offsets[0], self.sequence_offsets[1]], sequence_index.wrapping_add(i));
+                    mut_ray.direction = reflect(last_dir, sample_ggx(hit_normal, hit_mat.roughness * hit_mat.roughness, u));
+                }
+            } else if self.first_sample {
+                mut_ray.direction = hit_normal.normalize() + EPSILON;
+            } else {
+                // randomize after first sample, spherically
+                mut_ray.direction = (hit_normal + self.sample_unit_sphere(&mut rng, [self.sequence_offsets[0], self.sequence_offsets[1]], sequence_index)).normalize() + EPSILON;
+            }
+            mut_ray.inv_direction = 1.0 / mut_ray.direction;
+            let info = self.step_scene(mut_ray, true);
+            hit_normal = info.voxel.normal;
+            hit_mat = *tracer.material(info.voxel.material);
+            if info.hit {
+                mut_ray.position = info.new_pos;
+                let dist = ((last_pos * CHUNK_SIZE as f32).floor() - (info.new_pos * CHUNK_SIZE as f32).floor()).abs();
+                if dist.dot(dist) <= 1.0 { // hit adjacent voxel, meaning it's occluded
+                    return diff_light;
+                }
+                if hit_mat.emission_strength > 0.0 {
+                    return diff_light + new_color * (tracer.emitted_light(&info.voxel) * info.color_mul + info.color_add);
+                }
+                new_color *= tracer.surface_color(&info.voxel) * info.color_mul + info.color_add;
+            } else {
+                return diff_light + new_color * mut_ray.direction.dot(tracer.sun_direction).max(0.0) * tracer.sun_strength * info.color_mul + info.color_add;
+            }
+            last_dir = mut_ray.direction;
+        }
+        diff_light
+    }
+
+    // cast a ray toward the sun, adding the sun's light if the ray doesn't hit the world
+    fn shadow_ray(&mut self, ray_pos: Vec3, rng: u32, diff_light: Vec3) -> Vec3 {
+        let tracer = self.tracer;
+        let mut rng = rng;
+        let direction = if self.first_sample {
+            tracer.sun_direction + EPSILON
+        } else {
+            (tracer.sun_direction * 10.0 + self.sample_unit_sphere(&mut rng, [self.sequence_offsets[2], self.sequence_offsets[3]], tracer.frame)).normalize()
+        };
+        let info = self.step_scene(Ray::new(ray_pos, direction), true);
+        if !info.hit {
+            return diff_light + tracer.sun_strength * info.color_mul + info.color_add;
+        }
+        diff_light
+    }
+    // a point on the unit sphere, either random or from the index-th point of the R2 sequence shifted by sequence_offset
+    fn sample_unit_sphere(&self, seed: &mut u32, sequence_offset: [u32; 2], index: u32) -> Vec3 {
+        let tracer = self.tracer;
+        if tracer.sampling != SamplingMode::R2 {
+            return rand_unit_sphere(seed);
+        }
+        let u = tracer.sample_square(seed, sequence_offset, index);
+        let z = 1.0 - 2.0 * u.x;
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * SHADER_PI * u.y;
+        Vec3::new(r * phi.cos(), r * phi.sin(), z)
+    }
+}
+
+struct FogResult {
+    transmittance: f32, // how much of the light behind the fog gets through
+    light: Vec3, // light scattered toward the ray origin
+}
+
+// the Henyey-Greenstein phase function relative to scattering the same amount in every direction
+fn henyey_greenstein(cos_theta: f32, g: f32) -> f32 {
+    let g2 = g * g;
+    (1.0 - g2) / (1.0 + g2 - 2.0 * g * cos_theta).max(EPSILON).powf(1.5)
+}
+
+// a half vector from the GGX distribution around normal with roughness alpha, from a point u in the unit square
+fn sample_ggx(normal: Vec3, alpha: f32, u: Vec2) -> Vec3 {
+    let alpha2 = (alpha * alpha).max(EPSILON); // a perfect mirror would divide by zero
+    let cos_theta = ((1.0 - u.x) / (1.0 + (alpha2 - 1.0) * u.x)).sqrt();
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * SHADER_PI * u.y;
+    let up = if normal.x.abs() > 0.9 { Vec3::Y } else { Vec3::X };
+    let tangent = up.cross(normal).normalize();
+    let bitangent = normal.cross(tangent);
+    (tangent * phi.cos() * sin_theta + bitangent * phi.sin() * sin_theta + normal * cos_theta).normalize()
+}
+
+// the Cook-Torrance BRDF times the cosine term divided by the probability of sampling to_light with sample_ggx
+fn ggx_weight(normal: Vec3, to_view: Vec3, to_light: Vec3, half_vector: Vec3, alpha: f32, f0: Vec3) -> Vec3 {
+    let n_dot_v = normal.dot(to_view).max(EPSILON);
+    let n_dot_l = normal.dot(to_light).max(EPSILON);
+    let n_dot_h = normal.dot(half_vector).max(EPSILON);
+    let v_dot_h = to_view.dot(half_vector).max(0.0);
+    let fresnel = f0 + (1.0 - f0) * (1.0 - v_dot_h).powf(5.0); // Schlick
+    let geometry = smith_g1(n_dot_v, alpha) * smith_g1(n_dot_l, alpha);
+    fresnel * geometry * v_dot_h / (n_dot_v * n_dot_h)
+}
+
+fn smith_g1(n_dot_x: f32, alpha: f32) -> f32 {
+    let alpha2 = alpha * alpha;
+    2.0 * n_dot_x / (n_dot_x + (alpha2 + (1.0 - alpha2) * n_dot_x * n_dot_x).sqrt())
+}
+
+fn hash(value: u32) -> u32 {
+    let mut state = value;
+    next_random_number(&mut state)
+}
+
+// random float in [0..1]
+fn rand(seed: &mut u32) -> f32 {
+    next_random_number(seed) as f32 / 4294967295.0 // 2^32 - 1
+}
+
+fn rand_unit_sphere(seed: &mut u32) -> Vec3 {
+    let x = rand_normal_dist(seed);
+    let y = rand_normal_dist(seed);
+    let z = rand_normal_dist(seed);
+    Vec3::new(x, y, z).normalize()
+}
+
+fn rand_normal_dist(seed: &mut u32) -> f32 {
+    let theta = 2.0 * SHADER_PI * rand(seed);
+    let rho = (-2.0 * rand(seed).ln()).sqrt();
+    rho * theta.cos()
+}
+
+fn next_random_number(seed: &mut u32) -> u32 {
+    *seed = seed.wrapping_mul(747796405).wrapping_add(2891336453);
+    let mut result = ((*seed >> ((*seed >> 28) + 4)) ^ *seed).wrapping_mul(277803737);
+    result = (result >> 22) ^ result;
+    result
+}
@@ -1,4 +1,3 @@
-use glam::uvec3;
 #[cfg(target_arch="wasm32")]
 use wasm_bindgen::prelude::*;
 use wgpu::{util::DeviceExt, include_wgsl};
@@ -9,15 +8,28 @@ use winit::{
 };
 
 
-mod camera;
+pub mod camera;
 use camera::Camera;
+use camera::bindings::Action;
 mod texture;
-mod model;
-mod scene;
+pub mod model;
+pub mod scene;
+pub mod cpu_trace;
 mod resources;
+mod capture;
 
 
 
+// where the viewer saves and loads its camera path
+#[cfg(not(target_arch="wasm32"))]
+const CAMERA_PATH_FILE: &str = "camera_path.ron";
+// key bindings for the camera are loaded from here if the file exists
+#[cfg(not(target_arch="wasm32"))]
+const KEY_BINDINGS_FILE: &str = "keybindings.ron";
+
+// how much the aperture changes per key press, in scene units
+const APERTURE_STEP: f32 = 0.01;
+
 struct State {
     surface: wgpu::Surface,
     device: wgpu::Device,
@@ -35,6 +47,7 @@ struct State {
     srgb_format: wgpu::TextureFormat,
     depth_texture: texture::Texture,
     screen_texture: texture::Texture,
+    accumulation_buffer: wgpu::Buffer,
     skybox: texture::Texture,
     
     is_mouse_pressed: bool,
@@ -46,8 +59,20 @@ struct State {
     clear_color: wgpu::Color,
 
     scene_bind_group: wgpu::BindGroup,
+    scene_bind_group_layout: wgpu::BindGroupLayout,
     scene_buffer: wgpu::Buffer,
+    face_light_buffer: wgpu::Buffer,
+    lighting_chunk_buffer: wgpu::Buffer,
+    lighting_chunk_count: u32, // the number of chunks in lighting_chunk_buffer this frame
+    object_buffers: ObjectBuffers,
+    lighting_schedule: scene::schedule::LightingSchedule,
+    simulation: scene::simulation::Simulation, // without rules until a scene sets them up
     scene: scene::Scene,
+
+    screenshot_requested: bool,
+    recorder: Option<capture::Recorder>,
+    #[cfg(feature = "gamepad")]
+    gamepad: Option<camera::gamepad::Gamepad>,
 }
 
 impl State {
@@ -100,8 +125,7 @@ impl State {
         // sRGB surfaces, you'll need to account for that when drawing to the frame.
         let surface_format = surface_caps.formats.iter()
             .copied()
-            .filter(|f| f.is_srgb())
-            .next()
+            .find(|f| f.is_srgb())
             .unwrap_or(surface_caps.formats[0]);
         let config = wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
@@ -115,7 +139,7 @@ impl State {
         surface.configure(&device, &config);
 
         // CAMERA --------------------
-        let camera = Camera::new(
+        let mut camera = Camera::new(
             (-4.0, 4.0, -4.0).into(), // slightly away from scene
             45.0f32.to_radians(), // looking diagonally along xz
             -25.0f32.to_radians(), // looking slightly down
@@ -124,6 +148,10 @@ impl State {
             0.1,
             100.0,
         );
+        #[cfg(not(target_arch="wasm32"))]
+        {
+            camera.controller.bindings = camera::bindings::KeyBindings::load_or_default(std::path::Path::new(KEY_BINDINGS_FILE));
+        }
        
         let camera_buffer = device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
@@ -165,33 +193,34 @@ impl State {
         // TEXTURES -----------------
         let srgb_format = wgpu::TextureFormat::Rgba8Unorm;
         let screen_texture = texture::Texture::create_screen_texture(&device, &config, srgb_format);
+        let accumulation_buffer = create_accumulation_buffer(&device, &config);
         let skybox = texture::Texture::create_cubemap(&device, &queue, "skybox").await;
         
         // LIGHTS -------------
         
 
         // WORLD -----------------
-        let mut scene = scene::Scene::new();
-        scene.spawn_ground_plane();
-        scene.spawn_far_walls();
-        scene.chunk_at(uvec3(0, 0, 0)).fill_sphere(0, uvec3(180, 180, 180));
-        scene.chunk_at(uvec3(1, 0, 1)).fill_sphere(1, uvec3(180, 180, 180));
-        scene.chunk_at(uvec3(2, 1, 1)).fill_borders(2, uvec3(255, 255, 84));
-        scene.chunk_at(uvec3(2, 2, 2)).fill_sphere(2, uvec3(210, 115, 80));
-        scene.chunk_at(uvec3(5, 1, 3)).fill_sphere(3, uvec3(0, 190, 0));
-        scene.chunk_at(uvec3(6, 0, 6)).fill_sphere(1, uvec3(0, 250, 40));
-        scene.chunk_at(uvec3(4, 0, 6)).fill_sphere(1, uvec3(240, 0, 40));
-        scene.chunk_at(uvec3(1, 0, 6)).fill_sphere(1, uvec3(240, 40, 0));
-        scene.chunk_at(uvec3(6, 0, 1)).fill_sphere(1, uvec3(0, 40, 250));
-        scene.chunk_at(uvec3(5, 0, 4)).fill_sphere(2, uvec3(10, 40, 50));
-        scene.chunk_at(uvec3(4, 0, 3)).fill_borders(1, uvec3(110, 140, 150));
+        let mut scene = scene::Scene::demo();
+        scene.update_faces();
         let scene_buffer = device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
                 label: Some("scene buffer"),
-                contents: &scene.into_buffer(),
+                contents: &scene.to_buffer(),
                 usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST, // must be storage, so we can read and write in shader
             }
         );
+        let face_light_buffer = create_face_light_buffer(&device, &scene);
+        // the indices of the chunks to light, written every frame from the lighting schedule
+        let lighting_chunk_buffer = device.create_buffer(
+            &wgpu::BufferDescriptor {
+                label: Some("lighting chunk buffer"),
+                size: (scene::SCENE_SIZE * scene::SCENE_SIZE * scene::SCENE_SIZE * std::mem::size_of::<u32>()) as wgpu::BufferAddress,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }
+        );
+        let mut object_buffers = ObjectBuffers::new(&device);
+        object_buffers.write(&device, &queue, &mut scene);
         let scene_bind_group_layout = device.create_bind_group_layout(
             &wgpu::BindGroupLayoutDescriptor { 
                 label: Some("scene bind group layout"), 
@@ -205,22 +234,62 @@ impl State {
                             min_binding_size: None,
                         },
                         count: None,
-                    }
-                ],
-            }
-        );
-        let scene_bind_group = device.create_bind_group(
-            &wgpu::BindGroupDescriptor { 
-                label: Some("scene bind group"),
-                layout: &scene_bind_group_layout,
-                entries: &[
-                    wgpu::BindGroupEntry { 
-                        binding: 0,
-                        resource: scene_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Storage { read_only: false },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    // the instances, BVH and voxels of the dynamic objects
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 4,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 5,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
                     },
                 ],
             }
         );
+        let scene_bind_group = create_scene_bind_group(&device, &scene_bind_group_layout, &scene_buffer, &face_light_buffer, &lighting_chunk_buffer, &object_buffers);
 
         // SHADERS AND RENDER PIPELINES ------------------------
         
@@ -249,7 +318,7 @@ impl State {
             )
         };
 
-        let (raytrace_bind_group, raytrace_bind_group_layout) = create_raytrace_bind_group(&device, &screen_texture, srgb_format, &skybox);
+        let (raytrace_bind_group, raytrace_bind_group_layout) = create_raytrace_bind_group(&device, &screen_texture, &accumulation_buffer, srgb_format, &skybox);
 
         let compute_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: Some("Raytracing compute pipeline layout"),
@@ -304,6 +373,7 @@ impl State {
             srgb_format,
             depth_texture,
             screen_texture,
+            accumulation_buffer,
             skybox,
             
             is_mouse_pressed,
@@ -313,8 +383,20 @@ impl State {
             camera_bind_group,
             
             scene_bind_group,
+            scene_bind_group_layout,
             scene_buffer,
+            face_light_buffer,
+            lighting_chunk_buffer,
+            lighting_chunk_count: 0,
+            object_buffers,
+            lighting_schedule: scene::schedule::LightingSchedule::default(),
+            simulation: scene::simulation::Simulation::default(),
             scene,
+
+            screenshot_requested: false,
+            recorder: None,
+            #[cfg(feature = "gamepad")]
+            gamepad: camera::gamepad::Gamepad::new(),
         }
     }
     // get a referece to the state's window
@@ -331,8 +413,10 @@ impl State {
             self.camera.projection.resize(new_size.width, new_size.height);
             self.depth_texture = texture::Texture::create_depth_texture(&self.device, &self.config, "depth_texture");
             self.screen_texture = texture::Texture::create_screen_texture(&self.device, &self.config, self.srgb_format);
+            self.accumulation_buffer = create_accumulation_buffer(&self.device, &self.config);
             self.screen_render_bind_group = create_screen_bind_group(&self.device, &self.screen_texture).0;
-            self.raytrace_bind_group = create_raytrace_bind_group(&self.device, &self.screen_texture, self.srgb_format, &self.skybox).0;
+            self.raytrace_bind_group = create_raytrace_bind_group(&self.device, &self.screen_texture, &self.accumulation_buffer, self.srgb_format, &self.skybox).0;
+            self.camera.reset_accumulation();
         }
     }
     // handle user input
@@ -340,8 +424,8 @@ impl State {
         match event {
             // TODO: remove this interpolation stuff, it's unused
             WindowEvent::CursorMoved { position, .. } => {
-                let xt = position.x as f64 / self.size.width as f64;
-                let yt = position.y as f64 / self.size.height as f64;
+                let xt = position.x / self.size.width as f64;
+                let yt = position.y / self.size.height as f64;
                 let tl = glam::dvec3(1., 0., 0.);
                 let tr = glam::dvec3(0., 0., 1.);
                 let bl = glam::dvec3(0., 1., 0.);
@@ -364,7 +448,7 @@ impl State {
                     .. 
                 },
                 .. 
-            } => {self.camera.controller.process_keyboard(*key, *state)},
+            } => self.process_hotkey(*key, *state) || self.camera.controller.process_keyboard(*key, *state),
             WindowEvent::MouseInput{
                 button: MouseButton::Left,
                 state,
@@ -380,14 +464,157 @@ impl State {
             _ => false,
         }
     }
+    // keys that control the viewer itself rather than the camera. Returns true if the key was used
+    fn process_hotkey(&mut self, key: VirtualKeyCode, state: ElementState) -> bool {
+        if state != ElementState::Pressed {
+            return false;
+        }
+        let Some(action) = self.camera.controller.bindings.action(key) else {
+            return false;
+        };
+        match action {
+            Action::FlyMode => self.camera.controller.fly(),
+            Action::OrbitMode => {
+                let target = self.camera_target();
+                self.camera.controller.orbit(&mut self.camera.view, target);
+            }
+            Action::TurntableMode => {
+                let target = self.camera_target();
+                self.camera.controller.turntable(&mut self.camera.view, target);
+            }
+            Action::ApertureUp => self.camera.lens.aperture += APERTURE_STEP,
+            Action::ApertureDown => self.camera.lens.aperture = (self.camera.lens.aperture - APERTURE_STEP).max(0.0),
+            Action::ToggleAutofocus => self.camera.lens.autofocus = !self.camera.lens.autofocus,
+            Action::ToggleIsometric => {
+                if self.camera.projection.is_orthographic() {
+                    self.camera.perspective();
+                } else {
+                    let target = self.camera_target();
+                    self.camera.isometric(target, self.scene.center().length() * 2.0);
+                }
+            }
+            Action::RecordKeyframe => self.camera.record_keyframe(),
+            Action::TogglePlayback => self.camera.toggle_playback(),
+            Action::NextSampling => {
+                self.scene.set_sampling(self.scene.sampling().next());
+                log::info!("Lighting sampling mode: {:?}", self.scene.sampling());
+            }
+            Action::ToggleFog => {
+                let fog = if self.scene.fog().is_enabled() { scene::Fog::default() } else { scene::Fog::haze() };
+                self.scene.set_fog(fog);
+                log::info!("Fog: {}", if fog.is_enabled() { "on" } else { "off" });
+            }
+            Action::ToggleAmbientOcclusion => {
+                self.scene.set_ambient_occlusion(!self.scene.ambient_occlusion());
+                log::info!("Ambient occlusion: {}", if self.scene.ambient_occlusion() { "on" } else { "off" });
+            }
+            #[cfg(not(target_arch="wasm32"))]
+            Action::SavePath => if let Err(e) = self.camera.path.save(std::path::Path::new(CAMERA_PATH_FILE)) {
+                log::error!("{:?}", e);
+            },
+            #[cfg(not(target_arch="wasm32"))]
+            Action::LoadPath => match camera::path::CameraPath::load(std::path::Path::new(CAMERA_PATH_FILE)) {
+                Ok(path) => self.camera.path = path,
+                Err(e) => log::error!("{:?}", e),
+            },
+            #[cfg(not(target_arch="wasm32"))]
+            Action::ToggleRecording => self.toggle_recording(),
+            #[cfg(not(target_arch="wasm32"))]
+            Action::Screenshot => self.screenshot_requested = true,
+            // moving the camera is up to the camera controller
+            _ => return false,
+        }
+        true
+    }
+    // the point to orbit around: the voxel in the middle of the screen, or the center of the scene if there is none
+    fn camera_target(&self) -> glam::Vec3 {
+        self.scene.raycast(self.camera.view.position, self.camera.view.direction())
+            .map_or(self.scene.center(), |hit| hit.position)
+    }
+    // start recording frames, or stop if already recording
+    fn toggle_recording(&mut self) {
+        match self.recorder.take() {
+            Some(recorder) => log::info!("Wrote {} frames to '{}'", recorder.frames_written(), recorder.directory().display()),
+            None => match capture::Recorder::start() {
+                Ok(recorder) => {
+                    log::info!("Recording to '{}'", recorder.directory().display());
+                    self.recorder = Some(recorder);
+                },
+                Err(e) => log::error!("{:?}", e),
+            },
+        }
+    }
+    // the time step to simulate for this frame. Fixed while recording, so recordings are deterministic
+    fn frame_time(&self, elapsed: instant::Duration) -> instant::Duration {
+        self.recorder.as_ref().map_or(elapsed, |recorder| recorder.timestep())
+    }
+    // save the frame that was just rendered, if a screenshot was requested or we're recording
+    fn capture(&mut self) {
+        if !self.screenshot_requested && self.recorder.is_none() {
+            return;
+        }
+        let image = match self.screen_texture.read_to_image(&self.device, &self.queue) {
+            Ok(image) => image,
+            Err(e) => {
+                log::error!("{:?}", e);
+                return;
+            }
+        };
+        if self.screenshot_requested {
+            self.screenshot_requested = false;
+            let path = capture::screenshot_path();
+            match capture::save_image(&image, &path) {
+                Ok(()) => log::info!("Saved screenshot to '{}'", path.display()),
+                Err(e) => log::error!("{:?}", e),
+            }
+        }
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = recorder.write_frame(&image) {
+                log::error!("{:?}", e);
+                self.recorder = None;
+            }
+        }
+    }
     // update the state of the application with the time since the last frame
     fn update(&mut self, dt: instant::Duration) {
+        #[cfg(feature = "gamepad")]
+        if let Some(gamepad) = &mut self.gamepad {
+            gamepad.update(&mut self.camera.controller);
+        }
         self.camera.update(dt);
-        self.queue.write_buffer(&self.camera_buffer, 0, bytemuck::bytes_of(&self.camera.uniform()));
+        if self.camera.lens.autofocus {
+            if let Some(hit) = self.scene.raycast(self.camera.view.position, self.camera.view.direction()) {
+                self.camera.lens.focus_distance = hit.distance;
+            }
+        }
         self.scene.update(dt);
-        self.queue.write_buffer(&self.scene_buffer, 64, bytemuck::bytes_of(&self.scene.time()));
+        self.simulation.update(&mut self.scene, dt);
+        let scene_changed = self.upload_scene_changes();
+        self.camera.advance_frame();
+        if scene_changed {
+            self.camera.reset_accumulation(); // the frames so far show the scene the way it was
+        }
+        self.queue.write_buffer(&self.camera_buffer, 0, bytemuck::bytes_of(&self.camera.uniform()));
+        self.queue.write_buffer(&self.scene_buffer, scene::Scene::FRAME_DATA_OFFSET, bytemuck::bytes_of(&self.scene.frame_data()));
+        let lighting_chunks = self.lighting_schedule.plan(&self.scene, &self.camera.frustum(), self.camera.view.position);
+        self.queue.write_buffer(&self.lighting_chunk_buffer, 0, bytemuck::cast_slice(&lighting_chunks));
+        self.lighting_chunk_count = lighting_chunks.len() as u32;
         self.window.set_title(&format!("Voxel Raytracing -- Frame time: {:05.2}ms", dt.as_secs_f32()*1000.0));
     }
+    // write the parts of the scene that changed and the objects of this frame to the GPU, making room for more lit faces
+    // and objects if needed. Returns whether the scene or its lighting changed
+    fn upload_scene_changes(&mut self) -> bool {
+        let mut rebind = self.object_buffers.write(&self.device, &self.queue, &mut self.scene);
+        if self.scene.update_faces() {
+            self.face_light_buffer = create_face_light_buffer(&self.device, &self.scene);
+            self.scene.invalidate_lighting(scene::LightingRegion::All); // the light of the old buffer is gone
+            rebind = true;
+        }
+        if rebind {
+            self.scene_bind_group = create_scene_bind_group(&self.device, &self.scene_bind_group_layout, &self.scene_buffer, &self.face_light_buffer, &self.lighting_chunk_buffer, &self.object_buffers);
+        }
+        self.scene.write_changes(&self.queue, &self.scene_buffer)
+    }
     // do all the rendering
     fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
         let output = self.surface.get_current_texture()?;
@@ -396,15 +623,15 @@ impl State {
             label: Some("Main Encoder"),
         });
 
-        // update lighting for this frame
-        {
+        // update lighting for this frame, in the chunks the schedule picked
+        if self.lighting_chunk_count > 0 {
             let mut lighting_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {label: Some("Lighting pass")});
             lighting_pass.set_pipeline(&self.lighting_compute_pipeline);
             lighting_pass.set_bind_group(0, &self.raytrace_bind_group, &[]); // TODO: remove this, it's unused
             lighting_pass.set_bind_group(1, &self.camera_bind_group, &[]);
             lighting_pass.set_bind_group(2, &self.scene_bind_group, &[]);
-            // One workgroup per chunk 
-            lighting_pass.dispatch_workgroups(8, 8, 8); // 8x8x8 chunks
+            // One workgroup per chunk
+            lighting_pass.dispatch_workgroups(self.lighting_chunk_count, 1, 1);
         }
         // raytrace the scene to the render texture
         {
@@ -449,6 +676,7 @@ impl State {
         }
         
         self.queue.submit([encoder.finish()]); // tell the GPU to do all the things
+        self.capture(); // the screen texture is only overwritten by the next frame, so it can be read back here
         output.present(); // present the final image to the screen
 
         Ok(())
@@ -554,7 +782,110 @@ fn create_screen_bind_group(device: &wgpu::Device, texture: &texture::Texture) -
     (screen_render_bind_group, screen_render_bind_group_layout)
 }
 
-fn create_raytrace_bind_group(device: &wgpu::Device, screen_texture: &texture::Texture, srgb_format: wgpu::TextureFormat, skybox: &texture::Texture) -> (wgpu::BindGroup, wgpu::BindGroupLayout) {
+// the lighting cache of every exposed voxel face, only written by the lighting pass
+fn create_face_light_buffer(device: &wgpu::Device, scene: &scene::Scene) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("face light buffer"),
+        size: scene.face_capacity().max(1) as wgpu::BufferAddress * std::mem::size_of::<scene::faces::FaceLight>() as wgpu::BufferAddress,
+        usage: wgpu::BufferUsages::STORAGE,
+        mapped_at_creation: false,
+    })
+}
+
+fn create_scene_bind_group(device: &wgpu::Device, layout: &wgpu::BindGroupLayout, scene_buffer: &wgpu::Buffer, face_light_buffer: &wgpu::Buffer, lighting_chunk_buffer: &wgpu::Buffer, objects: &ObjectBuffers) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("scene bind group"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: scene_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: face_light_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: lighting_chunk_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: objects.instances.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: objects.bvh.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 5,
+                resource: objects.voxels.as_entire_binding(),
+            },
+        ],
+    })
+}
+
+// The dynamic objects on the GPU, see scene/objects.rs. The buffers grow when they run out of room
+struct ObjectBuffers {
+    instances: wgpu::Buffer,
+    bvh: wgpu::Buffer,
+    voxels: wgpu::Buffer,
+}
+
+impl ObjectBuffers {
+    fn new(device: &wgpu::Device) -> Self {
+        // bindings can't be empty, so there is always room for one element
+        Self {
+            instances: create_object_buffer(device, "instance buffer", std::mem::size_of::<scene::objects::GpuInstance>()),
+            bvh: create_object_buffer(device, "BVH buffer", std::mem::size_of::<scene::objects::BvhNode>()),
+            voxels: create_object_buffer(device, "object voxel buffer", std::mem::size_of::<scene::CompressedVoxel>()),
+        }
+    }
+    // write the instances and BVH of this frame, and the voxels if objects were added.
+    // Returns true if a buffer had to grow, so the scene bind group has to be made again
+    fn write(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, scene: &mut scene::Scene) -> bool {
+        let bvh = scene.object_bvh();
+        let mut grown = write_object_buffer(device, queue, &mut self.instances, "instance buffer", bytemuck::cast_slice(&bvh.instances));
+        grown |= write_object_buffer(device, queue, &mut self.bvh, "BVH buffer", bytemuck::cast_slice(&bvh.nodes));
+        if scene.take_dirty_objects() {
+            grown |= write_object_buffer(device, queue, &mut self.voxels, "object voxel buffer", bytemuck::cast_slice(&scene.object_voxels()));
+        }
+        grown
+    }
+}
+
+fn create_object_buffer(device: &wgpu::Device, label: &str, size: usize) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some(label),
+        size: size as wgpu::BufferAddress,
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+// write data to the start of an object buffer, replacing the buffer with a bigger one if it doesn't fit. Returns true if it was replaced
+fn write_object_buffer(device: &wgpu::Device, queue: &wgpu::Queue, buffer: &mut wgpu::Buffer, label: &str, data: &[u8]) -> bool {
+    let grow = data.len() as wgpu::BufferAddress > buffer.size();
+    if grow {
+        *buffer = create_object_buffer(device, label, data.len().next_power_of_two());
+    }
+    if !data.is_empty() {
+        queue.write_buffer(buffer, 0, data);
+    }
+    grow
+}
+
+// one vec4<f32> per pixel, for averaging samples over several frames
+fn create_accumulation_buffer(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Accumulation buffer"),
+        size: (config.width * config.height) as wgpu::BufferAddress * 16,
+        usage: wgpu::BufferUsages::STORAGE,
+        mapped_at_creation: false,
+    })
+}
+
+fn create_raytrace_bind_group(device: &wgpu::Device, screen_texture: &texture::Texture, accumulation_buffer: &wgpu::Buffer, srgb_format: wgpu::TextureFormat, skybox: &texture::Texture) -> (wgpu::BindGroup, wgpu::BindGroupLayout) {
     let raytracing_bind_group_layout = device.create_bind_group_layout(
         &wgpu::BindGroupLayoutDescriptor { 
             label: Some("raytracing_bind_group_layout"),
@@ -584,7 +915,17 @@ fn create_raytrace_bind_group(device: &wgpu::Device, screen_texture: &texture::T
                     visibility: wgpu::ShaderStages::COMPUTE,
                     ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                     count: None,
-                }
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
             ],
         }
     );
@@ -605,6 +946,10 @@ fn create_raytrace_bind_group(device: &wgpu::Device, screen_texture: &texture::T
                     binding: 2,
                     resource: wgpu::BindingResource::Sampler(&skybox.sampler),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: accumulation_buffer.as_entire_binding(),
+                },
             ],
         }
     );
@@ -650,7 +995,7 @@ pub async fn run() {
         match event {
             Event::RedrawRequested(window_id) if window_id == state.window().id() => {
                 let now = instant::Instant::now();
-                let dt = now - last_render_time;
+                let dt = state.frame_time(now - last_render_time);
                 last_render_time = now;
                 match state.render() {
                     Ok(_) => {}
@@ -668,15 +1013,13 @@ pub async fn run() {
                 // request it.
                 state.window().request_redraw();
             }
-            Event::DeviceEvent { event: DeviceEvent::MouseMotion { delta },.. } => {
-                if state.is_mouse_pressed {
-                    state.camera.controller.process_mouse(delta.0, delta.1)
-                }
+            Event::DeviceEvent { event: DeviceEvent::MouseMotion { delta },.. } if state.is_mouse_pressed => {
+                state.camera.controller.process_mouse(delta.0, delta.1)
             }
             Event::WindowEvent {
                 ref event,
                 window_id,
-            } if window_id == state.window().id() => if !state.input(event) {
+            } if window_id == state.window().id() && !state.input(event) => {
                 match event {
                     #[cfg(not(target_arch="wasm32"))]
                     WindowEvent::CloseRequested
@@ -1 +1,345 @@
-// to be filled with stuff to generate sets of voxels and chunks from SDF:s
\ No newline at end of file
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::Path;
+use anyhow::*;
+use glam::{IVec3, UVec3, Vec2, Vec3, Vec4, Vec4Swizzles};
+
+use crate::scene::{Material, MaterialId, Scene, Voxel, CHUNK_SIZE};
+
+pub mod gltf;
+pub mod heightmap;
+pub mod mesh;
+pub mod obj;
+pub mod ply;
+pub mod slices;
+pub mod vox;
+
+// Triangle meshes loaded from OBJ or glTF files, and voxelized into a scene.
+// Voxelizing is conservative: every voxel a triangle touches is filled, taking its normal, color and material
+// from the closest point of the closest triangle.
+// Heightmaps and stacks of slice images are imported by the heightmap and slices modules. Regions of a scene are
+// exported as greedy meshes (mesh, written by obj and gltf), MagicaVoxel files (vox) and point clouds (ply).
+
+// A triangle with everything needed to color the voxels it touches
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Triangle {
+    pub positions: [Vec3; 3],
+    pub normals: Option<[Vec3; 3]>, // the face normal is used without them
+    pub uvs: Option<[Vec2; 3]>, // with (0, 0) at the top left of the texture
+    pub colors: Option<[Vec3; 3]>, // vertex colors, in 0..1
+    pub material: Option<usize>, // index into Model::materials
+}
+
+impl Triangle {
+    fn face_normal(&self) -> Vec3 {
+        let [a, b, c] = self.positions;
+        (b - a).cross(c - a).normalize_or_zero()
+    }
+}
+
+// The look of a part of a model, following the glTF metallic roughness model
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelMaterial {
+    pub name: String,
+    pub base_color: Vec4, // rgba, multiplied with the texture and the vertex colors
+    pub texture: Option<image::RgbaImage>, // base color texture
+    pub metallic: f32,
+    pub roughness: f32,
+    pub emission: Vec3, // can be above 1
+    pub transmission: f32,
+    pub ior: f32,
+}
+
+impl Default for ModelMaterial {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            base_color: Vec4::ONE,
+            texture: None,
+            metallic: 0.0,
+            roughness: 1.0,
+            emission: Vec3::ZERO,
+            transmission: 0.0,
+            ior: 1.5,
+        }
+    }
+}
+
+impl ModelMaterial {
+    // the scene material closest to this one. The base color goes into the voxel albedo instead
+    pub fn material(&self) -> Material {
+        let emission_strength = self.emission.max_element();
+        Material {
+            metallic: self.metallic,
+            roughness: self.roughness,
+            emission: if emission_strength > 0.0 { self.emission / emission_strength } else { Vec3::ONE },
+            emission_strength,
+            transmission: self.transmission.max(1.0 - self.base_color.w),
+            ior: self.ior,
+            ..Default::default()
+        }
+    }
+    // a scene material for exporting, the inverse of material
+    pub fn from_material(name: &str, material: &Material) -> Self {
+        Self {
+            name: name.to_string(),
+            base_color: material.base_color.extend(1.0),
+            texture: None,
+            metallic: material.metallic,
+            roughness: material.roughness,
+            emission: material.emission * material.emission_strength,
+            transmission: material.transmission,
+            ior: material.ior,
+        }
+    }
+    // the base color at a texture coordinate, nearest texel and repeating
+    fn color_at(&self, uv: Option<Vec2>) -> Vec3 {
+        let color = self.base_color.xyz();
+        match (&self.texture, uv) {
+            (Some(texture), Some(uv)) => {
+                let size = Vec2::new(texture.width() as f32, texture.height() as f32);
+                let texel = (uv.fract() * size).floor().as_uvec2().min(size.as_uvec2() - 1);
+                let pixel = texture.get_pixel(texel.x, texel.y).0;
+                color * Vec3::new(pixel[0] as f32, pixel[1] as f32, pixel[2] as f32) / 255.0
+            }
+            _ => color,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Model {
+    pub triangles: Vec<Triangle>,
+    pub materials: Vec<ModelMaterial>,
+}
+
+// What to do with the inside of closed meshes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Fill {
+    #[default]
+    Surface, // only the voxels the triangles touch
+    Solid, // also everything the surface encloses
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoxelizeOptions {
+    pub scale: f32, // voxels per model unit
+    pub offset: Vec3, // where the origin of the model goes, in voxel space
+    pub fill: Fill,
+    pub materials: Vec<MaterialId>, // the scene material of every model material, see ModelMaterial::material
+    pub default_material: MaterialId, // for triangles without a material, or with one that's not in materials
+}
+
+impl Default for VoxelizeOptions {
+    fn default() -> Self {
+        Self {
+            scale: 1.0,
+            offset: Vec3::ZERO,
+            fill: Fill::Surface,
+            materials: Vec::new(),
+            default_material: 0,
+        }
+    }
+}
+
+impl Model {
+    // load an OBJ (with its MTL files), glTF or GLB file, depending on the extension
+    pub fn load(path: &Path) -> Result<Self> {
+        let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or_default().to_lowercase();
+        match extension.as_str() {
+            "obj" => obj::load(path),
+            "gltf" | "glb" => gltf::load(path),
+            _ => bail!("Unknown model format '{}'", path.display()),
+        }
+        .with_context(|| format!("Could not load model '{}'", path.display()))
+    }
+    // the scene materials of the model's materials, in order
+    pub fn scene_materials(&self) -> Vec<Material> {
+        self.materials.iter().map(ModelMaterial::material).collect()
+    }
+
+    // write the model into a scene. Returns the chunks that changed, like Scene::paint
+    pub fn voxelize(&self, scene: &mut Scene, options: &VoxelizeOptions) -> Vec<UVec3> {
+        let scene_size = scene.size.xyz().as_uvec3() * CHUNK_SIZE as u32;
+        // the voxel each triangle is closest to, with the squared distance from the voxel center
+        let mut surface: HashMap<UVec3, (f32, Voxel)> = HashMap::new();
+        for triangle in &self.triangles {
+            let corners = triangle.positions.map(|p| p * options.scale + options.offset);
+            let min = corners[0].min(corners[1]).min(corners[2]).floor().max(Vec3::ZERO);
+            let max = corners[0].max(corners[1]).max(corners[2]).floor() + 1.0;
+            let max = max.min(scene_size.as_vec3());
+            if min.cmpge(max).any() {
+                continue; // outside the scene
+            }
+            let (min, max) = (min.as_uvec3(), max.as_uvec3());
+            for z in min.z..max.z {
+                for y in min.y..max.y {
+                    for x in min.x..max.x {
+                        let pos = UVec3::new(x, y, z);
+                        let center = pos.as_vec3() + 0.5;
+                        if !triangle_overlaps_voxel(corners, center) {
+                            continue;
+                        }
+                        let weights = closest_point_weights(corners, center);
+                        let closest = corners[0] * weights.x + corners[1] * weights.y + corners[2] * weights.z;
+                        let distance = closest.distance_squared(center);
+                        if surface.get(&pos).is_some_and(|(best, _)| *best <= distance) {
+                            continue;
+                        }
+                        surface.insert(pos, (distance, self.surface_voxel(triangle, weights, options)));
+                    }
+                }
+            }
+        }
+        let mut voxels: Vec<(UVec3, Voxel)> = surface.into_iter().map(|(pos, (_, voxel))| (pos, voxel)).collect();
+        if options.fill == Fill::Solid {
+            let inside = fill_inside(&voxels);
+            voxels.extend(inside);
+        }
+        scene.write_voxels(voxels)
+    }
+    // the voxel at a point of a triangle, given by its barycentric weights
+    fn surface_voxel(&self, triangle: &Triangle, weights: Vec3, options: &VoxelizeOptions) -> Voxel {
+        let interpolate3 = |values: [Vec3; 3]| values[0] * weights.x + values[1] * weights.y + values[2] * weights.z;
+        let normal = triangle.normals
+            .map(interpolate3)
+            .and_then(|normal| normal.try_normalize())
+            .unwrap_or_else(|| triangle.face_normal());
+        let uv = triangle.uvs.map(|uvs| uvs[0] * weights.x + uvs[1] * weights.y + uvs[2] * weights.z);
+        let material = triangle.material.and_then(|index| self.materials.get(index));
+        let mut color = material.map_or(Vec3::ONE, |material| material.color_at(uv));
+        if let Some(colors) = triangle.colors {
+            color *= interpolate3(colors);
+        }
+        let albedo = (color.clamp(Vec3::ZERO, Vec3::ONE) * 255.0).round().as_uvec3();
+        let scene_material = triangle.material
+            .and_then(|index| options.materials.get(index).copied())
+            .unwrap_or(options.default_material);
+        Voxel::new(scene_material, albedo, normal)
+    }
+}
+
+// whether a triangle touches the voxel with the given center (separating axis test by Akenine-Möller)
+fn triangle_overlaps_voxel(corners: [Vec3; 3], center: Vec3) -> bool {
+    let half = Vec3::splat(0.5);
+    let v = corners.map(|corner| corner - center);
+    let edges = [v[1] - v[0], v[2] - v[1], v[0] - v[2]];
+    // does the axis separate the triangle from the box?
+    let separates = |axis: Vec3| {
+        let projections = v.map(|vertex| vertex.dot(axis));
+        let radius = half.dot(axis.abs());
+        projections.iter().copied().fold(f32::MAX, f32::min) > radius || projections.iter().copied().fold(f32::MIN, f32::max) < -radius
+    };
+    // the axes of the box, the normal of the triangle and the cross products of their edges
+    let box_axes = [Vec3::X, Vec3::Y, Vec3::Z];
+    if box_axes.iter().any(|&axis| separates(axis)) || separates(edges[0].cross(edges[1])) {
+        return false;
+    }
+    !box_axes.iter().any(|axis| edges.iter().any(|edge| separates(axis.cross(*edge))))
+}
+
+// the barycentric weights of the point of a triangle closest to p (from Real-Time Collision Detection)
+fn closest_point_weights([a, b, c]: [Vec3; 3], p: Vec3) -> Vec3 {
+    let (ab, ac, ap) = (b - a, c - a, p - a);
+    let (d1, d2) = (ab.dot(ap), ac.dot(ap));
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return Vec3::X;
+    }
+    let bp = p - b;
+    let (d3, d4) = (ab.dot(bp), ac.dot(bp));
+    if d3 >= 0.0 && d4 <= d3 {
+        return Vec3::Y;
+    }
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        let v = d1 / (d1 - d3);
+        return Vec3::new(1.0 - v, v, 0.0);
+    }
+    let cp = p - c;
+    let (d5, d6) = (ab.dot(cp), ac.dot(cp));
+    if d6 >= 0.0 && d5 <= d6 {
+        return Vec3::Z;
+    }
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        let w = d2 / (d2 - d6);
+        return Vec3::new(1.0 - w, 0.0, w);
+    }
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && d4 - d3 >= 0.0 && d5 - d6 >= 0.0 {
+        let w = (d4 - d3) / ((d4 - d3) + (d5 - d6));
+        return Vec3::new(0.0, 1.0 - w, w);
+    }
+    let denominator = va + vb + vc;
+    if denominator.abs() < f32::EPSILON {
+        return Vec3::X; // a degenerate triangle
+    }
+    let (v, w) = (vb / denominator, vc / denominator);
+    Vec3::new(1.0 - v - w, v, w)
+}
+
+// a normal for an imported voxel that came without one, pointing away from its filled neighbours, or up when it's
+// surrounded
+pub(crate) fn exposed_normal(filled: &HashSet<IVec3>, pos: IVec3) -> Vec3 {
+    let mut normal = Vec3::ZERO;
+    for z in -1..=1 {
+        for y in -1..=1 {
+            for x in -1..=1 {
+                let offset = IVec3::new(x, y, z);
+                if offset != IVec3::ZERO && !filled.contains(&(pos + offset)) {
+                    normal += offset.as_vec3().normalize();
+                }
+            }
+        }
+    }
+    normal.try_normalize().unwrap_or(Vec3::Y)
+}
+
+// the voxels enclosed by a surface: everything in its bounds that can't be reached from outside of them.
+// They look like the surface voxel before them along -x, which is what encloses them from that side
+fn fill_inside(surface: &[(UVec3, Voxel)]) -> Vec<(UVec3, Voxel)> {
+    let Some(min) = surface.iter().map(|(pos, _)| *pos).reduce(UVec3::min) else {
+        return Vec::new();
+    };
+    let max = surface.iter().map(|(pos, _)| *pos).fold(min, UVec3::max);
+    // a grid around the surface with a border of one cell, so the outside is connected
+    let size = (max - min + 3).as_ivec3();
+    let index = |cell: IVec3| (cell.x + size.x * (cell.y + size.y * cell.z)) as usize;
+    let mut solid = vec![None; (size.x * size.y * size.z) as usize];
+    for (pos, voxel) in surface {
+        solid[index((*pos - min).as_ivec3() + 1)] = Some(*voxel);
+    }
+    let mut outside = vec![false; solid.len()];
+    let mut queue = VecDeque::from([IVec3::ZERO]);
+    outside[0] = true;
+    while let Some(cell) = queue.pop_front() {
+        for offset in [IVec3::X, IVec3::NEG_X, IVec3::Y, IVec3::NEG_Y, IVec3::Z, IVec3::NEG_Z] {
+            let next = cell + offset;
+            if next.cmplt(IVec3::ZERO).any() || next.cmpge(size).any() {
+                continue;
+            }
+            let i = index(next);
+            if !outside[i] && solid[i].is_none() {
+                outside[i] = true;
+                queue.push_back(next);
+            }
+        }
+    }
+    let mut inside = Vec::new();
+    for z in 1..size.z - 1 {
+        for y in 1..size.y - 1 {
+            let mut last_surface = None;
+            for x in 1..size.x - 1 {
+                let i = index(IVec3::new(x, y, z));
+                if let Some(voxel) = solid[i] {
+                    last_surface = Some(voxel);
+                } else if !outside[i] {
+                    if let Some(voxel) = last_surface {
+                        let pos = (IVec3::new(x, y, z) - 1).as_uvec3() + min;
+                        inside.push((pos, Voxel::new(voxel.material(), voxel.albedo(), Vec3::Y))); // never seen
+                    }
+                }
+            }
+        }
+    }
+    inside
+}
@@ -0,0 +1,584 @@
+use std::collections::HashMap;
+use std::path::Path;
+use anyhow::*;
+use base64::Engine;
+use glam::{Mat3, Mat4, Quat, Vec2, Vec3, Vec4};
+use serde::Deserialize;
+
+use super::mesh::{Quad, VoxelMesh};
+use super::{obj, Model, ModelMaterial, Triangle};
+
+// glTF 2.0 files, both .gltf (JSON with external or base64 embedded buffers) and .glb (binary).
+// Every triangle primitive of the default scene is read, placed by its node transforms. Materials read the metallic
+// roughness model with its base color texture, emission and the transmission, ior and emissive strength extensions.
+// Sparse accessors, morph targets and skins are not supported.
+// Voxel meshes are written with one primitive and material per scene material, and the voxel albedo in COLOR_0.
+
+// the most elements an accessor can have, far more than any model that can be voxelized
+const MAX_ELEMENTS: usize = 1 << 26;
+
+pub fn load(path: &Path) -> Result<Model> {
+    let bytes = std::fs::read(path)?;
+    let directory = path.parent().unwrap_or(Path::new("")).to_path_buf();
+    parse(&bytes, |uri| Ok(std::fs::read(directory.join(uri))?))
+}
+
+// parse a .gltf or .glb file, loading external buffers and images by uri with load_file
+pub fn parse(bytes: &[u8], load_file: impl Fn(&str) -> Result<Vec<u8>>) -> Result<Model> {
+    let (json, binary) = if bytes.starts_with(b"glTF") { split_glb(bytes)? } else { (bytes, None) };
+    let document: Document = serde_json::from_slice(json).context("Invalid glTF JSON")?;
+    let buffers = document.buffers.iter()
+        .enumerate()
+        .map(|(i, buffer)| match &buffer.uri {
+            Some(uri) => read_uri(uri, &load_file),
+            // only the first buffer of a .glb can leave out its uri, it's the binary chunk
+            None if i == 0 => binary.map(<[u8]>::to_vec).context("Buffer 0 has no uri and there's no binary chunk"),
+            None => bail!("Buffer {} has no uri", i),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let reader = Reader { document: &document, buffers };
+
+    let mut images = HashMap::new();
+    let mut model = Model::default();
+    for material in &document.materials {
+        model.materials.push(reader.material(material, &mut images, &load_file)?);
+    }
+    let roots = match document.scenes.get(document.scene.unwrap_or(0)) {
+        Some(scene) => scene.nodes.clone(),
+        // without scenes, every node that isn't a child is a root
+        None => (0..document.nodes.len())
+            .filter(|&i| !document.nodes.iter().any(|node| node.children.contains(&i)))
+            .collect(),
+    };
+    for root in roots {
+        reader.node(root, Mat4::IDENTITY, &mut model.triangles, 0)?;
+    }
+    Ok(model)
+}
+
+// the JSON and binary chunks of a .glb file
+fn split_glb(bytes: &[u8]) -> Result<(&[u8], Option<&[u8]>)> {
+    let word = |offset: usize| -> Result<u32> {
+        let word = bytes.get(offset..offset + 4).context("Truncated glb file")?;
+        Ok(u32::from_le_bytes(word.try_into().unwrap()))
+    };
+    ensure!(word(4)? == 2, "Only glTF 2.0 is supported");
+    let length = (word(8)? as usize).min(bytes.len());
+    let mut json = None;
+    let mut binary = None;
+    let mut offset = 12;
+    while offset + 8 <= length {
+        let chunk_length = word(offset)? as usize;
+        let chunk = bytes.get(offset + 8..offset + 8 + chunk_length).context("Truncated glb chunk")?;
+        match word(offset + 4)? {
+            0x4E4F_534A => json = Some(chunk), // "JSON"
+            0x004E_4942 => binary = Some(chunk), // "BIN\0"
+            _ => {} // unknown chunks are skipped
+        }
+        offset += 8 + chunk_length;
+    }
+    Ok((json.context("The glb file has no JSON chunk")?, binary))
+}
+
+// the contents of a base64 data uri or an external file
+fn read_uri(uri: &str, load_file: &impl Fn(&str) -> Result<Vec<u8>>) -> Result<Vec<u8>> {
+    if let Some(data) = uri.strip_prefix("data:") {
+        let (_, encoded) = data.split_once(";base64,").context("Only base64 data uris are supported")?;
+        return base64::engine::general_purpose::STANDARD.decode(encoded).context("Invalid base64 data uri");
+    }
+    load_file(uri).with_context(|| format!("Could not load '{}'", uri))
+}
+
+// nodes can't be nested deeper than this, to stop cycles in broken files
+const MAX_NODE_DEPTH: usize = 64;
+
+struct Reader<'a> {
+    document: &'a Document,
+    buffers: Vec<Vec<u8>>,
+}
+
+impl Reader<'_> {
+    // add the triangles of a node and its children, with the transform of its parent
+    fn node(&self, index: usize, parent: Mat4, triangles: &mut Vec<Triangle>, depth: usize) -> Result<()> {
+        ensure!(depth < MAX_NODE_DEPTH, "Nodes are nested too deep");
+        let node = self.document.nodes.get(index).with_context(|| format!("Missing node {}", index))?;
+        let transform = parent * node.transform();
+        if let Some(mesh) = node.mesh {
+            let mesh = self.document.meshes.get(mesh).with_context(|| format!("Missing mesh {}", mesh))?;
+            for primitive in &mesh.primitives {
+                self.primitive(primitive, transform, triangles).with_context(|| format!("In mesh '{}'", mesh.name))?;
+            }
+        }
+        for &child in &node.children {
+            self.node(child, transform, triangles, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    fn primitive(&self, primitive: &Primitive, transform: Mat4, triangles: &mut Vec<Triangle>) -> Result<()> {
+        if primitive.mode.unwrap_or(TRIANGLES) != TRIANGLES {
+            return Ok(()); // points, lines and strips don't fill any voxels
+        }
+        let attribute = |name: &str| primitive.attributes.get(name).map(|&accessor| self.accessor(accessor)).transpose();
+        let positions = attribute("POSITION")?.context("A primitive has no positions")?;
+        let normals = attribute("NORMAL")?;
+        let colors = attribute("COLOR_0")?;
+        let material = primitive.material.and_then(|index| self.document.materials.get(index));
+        let uv_set = material
+            .and_then(|material| material.pbr_metallic_roughness.base_color_texture.as_ref())
+            .map_or(0, |texture| texture.tex_coord);
+        let uvs = attribute(&format!("TEXCOORD_{}", uv_set))?;
+        let indices: Vec<usize> = match primitive.indices {
+            Some(accessor) => self.indices(accessor)?,
+            None => (0..positions.len()).collect(),
+        };
+        ensure!(indices.iter().all(|&index| index < positions.len()), "A vertex index is out of range");
+
+        let normal_transform = Mat3::from_mat4(transform).inverse().transpose();
+        // mirroring transforms turn the triangles inside out
+        let winding = if transform.determinant() < 0.0 { [0, 2, 1] } else { [0, 1, 2] };
+        for corners in indices.chunks_exact(3) {
+            let corners = winding.map(|i| corners[i]);
+            let vertex = |values: &Option<Vec<Vec4>>| -> Option<[Vec4; 3]> {
+                let values = values.as_ref()?;
+                corners.iter().all(|&i| i < values.len()).then(|| corners.map(|i| values[i]))
+            };
+            triangles.push(Triangle {
+                positions: corners.map(|i| transform.transform_point3(positions[i].truncate())),
+                normals: vertex(&normals).map(|normals| normals.map(|normal| (normal_transform * normal.truncate()).normalize_or_zero())),
+                uvs: vertex(&uvs).map(|uvs| uvs.map(|uv| Vec2::new(uv.x, uv.y))),
+                colors: vertex(&colors).map(|colors| colors.map(Vec4::truncate)),
+                material: primitive.material,
+            });
+        }
+        Ok(())
+    }
+
+    // the elements of an accessor, with missing components left at 0 and normalized integers in 0..1 or -1..1
+    fn accessor(&self, index: usize) -> Result<Vec<Vec4>> {
+        let (accessor, size, elements) = self.elements(index)?;
+        let read: fn(&[u8]) -> f32 = match accessor.component_type {
+            5120 => |b| b[0] as i8 as f32,
+            5121 => |b| b[0] as f32,
+            5122 => |b| i16::from_le_bytes([b[0], b[1]]) as f32,
+            5123 => |b| u16::from_le_bytes([b[0], b[1]]) as f32,
+            5125 => |b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32,
+            _ => |b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+        };
+        let scale = match (accessor.normalized, accessor.component_type) {
+            (true, 5120) => 1.0 / 127.0,
+            (true, 5121) => 1.0 / 255.0,
+            (true, 5122) => 1.0 / 32767.0,
+            (true, 5123) => 1.0 / 65535.0,
+            _ => 1.0,
+        };
+        Ok(elements.iter()
+            .map(|element| {
+                let mut value = [0.0; 4];
+                for (component, bytes) in value.iter_mut().zip(element.chunks_exact(size)) {
+                    let value = read(bytes) * scale;
+                    *component = if accessor.normalized { value.max(-1.0) } else { value };
+                }
+                Vec4::from_array(value)
+            })
+            .collect())
+    }
+    // the elements of an index accessor, read as integers so large indices stay exact
+    fn indices(&self, index: usize) -> Result<Vec<usize>> {
+        let (accessor, _, elements) = self.elements(index)?;
+        elements.iter()
+            .map(|b| Ok(match accessor.component_type {
+                5121 => b[0] as usize,
+                5123 => u16::from_le_bytes([b[0], b[1]]) as usize,
+                5125 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize,
+                component_type => bail!("Unsupported index component type {}", component_type),
+            }))
+            .collect()
+    }
+    // the bytes of every element of an accessor, with the size of one component
+    fn elements(&self, index: usize) -> Result<(&Accessor, usize, Vec<&[u8]>)> {
+        const ZEROS: [u8; 16] = [0; 16];
+        let accessor = self.document.accessors.get(index).with_context(|| format!("Missing accessor {}", index))?;
+        let components = match accessor.kind.as_str() {
+            "SCALAR" => 1,
+            "VEC2" => 2,
+            "VEC3" => 3,
+            "VEC4" => 4,
+            kind => bail!("Unsupported accessor type {}", kind),
+        };
+        let size = match accessor.component_type {
+            5120 | 5121 => 1,
+            5122 | 5123 => 2,
+            5125 | 5126 => 4,
+            component_type => bail!("Unsupported component type {}", component_type),
+        };
+        let element_size = components * size;
+        // the count comes from the file, so it is checked before anything is allocated for it
+        ensure!(accessor.count <= MAX_ELEMENTS, "Accessor {} has too many elements ({})", index, accessor.count);
+        let Some(view) = accessor.buffer_view else {
+            return Ok((accessor, size, vec![&ZEROS[..element_size]; accessor.count])); // all zeros without a buffer view
+        };
+        let view = self.document.buffer_views.get(view).with_context(|| format!("Missing buffer view {}", view))?;
+        let data = self.view_data(view)?;
+        let stride = view.byte_stride.unwrap_or(element_size);
+        let end = match accessor.count.checked_sub(1) {
+            Some(last) => last.checked_mul(stride)
+                .and_then(|start| start.checked_add(accessor.byte_offset))
+                .and_then(|start| start.checked_add(element_size)),
+            None => Some(0),
+        };
+        ensure!(end.is_some_and(|end| end <= data.len()), "Accessor {} is out of range of its buffer view", index);
+        let elements = (0..accessor.count)
+            .map(|i| {
+                let start = accessor.byte_offset + i * stride;
+                &data[start..start + element_size]
+            })
+            .collect();
+        Ok((accessor, size, elements))
+    }
+    fn view_data(&self, view: &BufferView) -> Result<&[u8]> {
+        let buffer = self.buffers.get(view.buffer).with_context(|| format!("Missing buffer {}", view.buffer))?;
+        let end = view.byte_offset.checked_add(view.byte_length).context("A buffer view is out of range")?;
+        buffer.get(view.byte_offset..end).context("A buffer view is out of range")
+    }
+
+    fn material(&self, material: &MaterialDef, images: &mut HashMap<usize, image::RgbaImage>, load_file: &impl Fn(&str) -> Result<Vec<u8>>) -> Result<ModelMaterial> {
+        let pbr = &material.pbr_metallic_roughness;
+        let texture = match &pbr.base_color_texture {
+            Some(info) => {
+                let texture = self.document.textures.get(info.index).with_context(|| format!("Missing texture {}", info.index))?;
+                match texture.source {
+                    Some(source) => Some(self.image(source, images, load_file)?),
+                    None => None,
+                }
+            }
+            None => None,
+        };
+        let extensions = &material.extensions;
+        let emissive_strength = extensions.emissive_strength.as_ref().map_or(1.0, |extension| extension.emissive_strength);
+        Ok(ModelMaterial {
+            name: material.name.clone(),
+            base_color: Vec4::from_array(pbr.base_color_factor),
+            texture,
+            metallic: pbr.metallic_factor,
+            roughness: pbr.roughness_factor,
+            emission: Vec3::from_array(material.emissive_factor) * emissive_strength,
+            transmission: extensions.transmission.as_ref().map_or(0.0, |extension| extension.transmission_factor),
+            ior: extensions.ior.as_ref().map_or(1.5, |extension| extension.ior),
+        })
+    }
+
+    // an image decoded once and shared by the materials using it
+    fn image(&self, index: usize, images: &mut HashMap<usize, image::RgbaImage>, load_file: &impl Fn(&str) -> Result<Vec<u8>>) -> Result<image::RgbaImage> {
+        if let Some(image) = images.get(&index) {
+            return Ok(image.clone());
+        }
+        let definition = self.document.images.get(index).with_context(|| format!("Missing image {}", index))?;
+        let bytes = match (&definition.uri, definition.buffer_view) {
+            (Some(uri), _) => read_uri(uri, load_file)?,
+            (None, Some(view)) => {
+                let view = self.document.buffer_views.get(view).with_context(|| format!("Missing buffer view {}", view))?;
+                self.view_data(view)?.to_vec()
+            }
+            (None, None) => bail!("Image {} has no data", index),
+        };
+        let image = image::load_from_memory(&bytes).with_context(|| format!("Could not decode image {}", index))?.to_rgba8();
+        images.insert(index, image.clone());
+        Ok(image)
+    }
+}
+
+const TRIANGLES: u32 = 4;
+
+// the parts of the glTF JSON that are read, with the defaults of the specification
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Document {
+    scene: Option<usize>,
+    scenes: Vec<SceneDef>,
+    nodes: Vec<Node>,
+    meshes: Vec<Mesh>,
+    accessors: Vec<Accessor>,
+    buffer_views: Vec<BufferView>,
+    buffers: Vec<Buffer>,
+    materials: Vec<MaterialDef>,
+    textures: Vec<Texture>,
+    images: Vec<Image>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct SceneDef {
+    nodes: Vec<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Node {
+    children: Vec<usize>,
+    mesh: Option<usize>,
+    matrix: Option<[f32; 16]>, // column major
+    translation: Option<[f32; 3]>,
+    rotation: Option<[f32; 4]>, // xyzw quaternion
+    scale: Option<[f32; 3]>,
+}
+
+impl Node {
+    fn transform(&self) -> Mat4 {
+        match self.matrix {
+            Some(matrix) => Mat4::from_cols_array(&matrix),
+            None => Mat4::from_scale_rotation_translation(
+                self.scale.map_or(Vec3::ONE, Vec3::from_array),
+                self.rotation.map_or(Quat::IDENTITY, Quat::from_array),
+                self.translation.map_or(Vec3::ZERO, Vec3::from_array),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Mesh {
+    name: String,
+    primitives: Vec<Primitive>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Primitive {
+    attributes: HashMap<String, usize>,
+    indices: Option<usize>,
+    material: Option<usize>,
+    mode: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Accessor {
+    buffer_view: Option<usize>,
+    byte_offset: usize,
+    component_type: u32,
+    normalized: bool,
+    count: usize,
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct BufferView {
+    buffer: usize,
+    byte_offset: usize,
+    byte_length: usize,
+    byte_stride: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Buffer {
+    uri: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct MaterialDef {
+    name: String,
+    pbr_metallic_roughness: PbrMetallicRoughness,
+    emissive_factor: [f32; 3],
+    extensions: MaterialExtensions,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct PbrMetallicRoughness {
+    base_color_factor: [f32; 4],
+    base_color_texture: Option<TextureInfo>,
+    metallic_factor: f32,
+    roughness_factor: f32,
+}
+
+impl Default for PbrMetallicRoughness {
+    fn default() -> Self {
+        Self {
+            base_color_factor: [1.0; 4],
+            base_color_texture: None,
+            metallic_factor: 1.0,
+            roughness_factor: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct TextureInfo {
+    index: usize,
+    tex_coord: usize,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct MaterialExtensions {
+    #[serde(rename = "KHR_materials_transmission")]
+    transmission: Option<Transmission>,
+    #[serde(rename = "KHR_materials_ior")]
+    ior: Option<Ior>,
+    #[serde(rename = "KHR_materials_emissive_strength")]
+    emissive_strength: Option<EmissiveStrength>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Transmission {
+    transmission_factor: f32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct Ior {
+    ior: f32,
+}
+
+impl Default for Ior {
+    fn default() -> Self {
+        Self { ior: 1.5 }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct EmissiveStrength {
+    emissive_strength: f32,
+}
+
+impl Default for EmissiveStrength {
+    fn default() -> Self {
+        Self { emissive_strength: 1.0 }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Texture {
+    source: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Image {
+    uri: Option<String>,
+    buffer_view: Option<usize>,
+}
+
+// write a mesh as a .glb file, with a primitive for every scene material and the voxel albedo in the vertex colors
+pub fn write(mesh: &VoxelMesh) -> Vec<u8> {
+    let (mut json, binary) = document(mesh);
+    json["buffers"] = serde_json::json!([{ "byteLength": binary.len() }]);
+    let mut json = serde_json::to_vec(&json).unwrap();
+    // chunks are padded to 4 bytes, JSON with spaces and the binary with zeros
+    json.resize(json.len().next_multiple_of(4), b' ');
+    let mut binary = binary;
+    binary.resize(binary.len().next_multiple_of(4), 0);
+    let mut glb = Vec::with_capacity(28 + json.len() + binary.len());
+    glb.extend(b"glTF");
+    glb.extend(2u32.to_le_bytes());
+    glb.extend(((28 + json.len() + binary.len()) as u32).to_le_bytes());
+    glb.extend((json.len() as u32).to_le_bytes());
+    glb.extend(b"JSON");
+    glb.extend(json);
+    glb.extend((binary.len() as u32).to_le_bytes());
+    glb.extend(b"BIN\0");
+    glb.extend(binary);
+    glb
+}
+
+// save a mesh as .glb, or as .gltf with the buffer embedded as base64
+pub fn save(mesh: &VoxelMesh, path: &Path) -> Result<()> {
+    let bytes = if path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("gltf")) {
+        let (mut json, binary) = document(mesh);
+        let uri = format!("data:application/octet-stream;base64,{}", base64::engine::general_purpose::STANDARD.encode(&binary));
+        json["buffers"] = serde_json::json!([{ "byteLength": binary.len(), "uri": uri }]);
+        serde_json::to_vec_pretty(&json)?
+    } else {
+        write(mesh)
+    };
+    std::fs::write(path, bytes).with_context(|| format!("Could not write '{}'", path.display()))
+}
+
+// the JSON of a mesh without its buffers, and the contents of its only buffer
+fn document(mesh: &VoxelMesh) -> (serde_json::Value, Vec<u8>) {
+    use serde_json::json;
+    let mut binary = Vec::new();
+    let mut views = Vec::new();
+    let mut accessors = Vec::new();
+    // add a buffer view and an accessor for it, returning the accessor
+    let mut add = |data: &[u8], component_type: u32, kind: &str, count: usize, bounds: Option<(Vec3, Vec3)>| {
+        views.push(json!({ "buffer": 0, "byteOffset": binary.len(), "byteLength": data.len() }));
+        binary.extend(data);
+        let mut accessor = json!({ "bufferView": views.len() - 1, "componentType": component_type, "count": count, "type": kind });
+        if let Some((min, max)) = bounds {
+            accessor["min"] = json!(min.to_array());
+            accessor["max"] = json!(max.to_array());
+        }
+        accessors.push(accessor);
+        accessors.len() - 1
+    };
+    let mut primitives = Vec::new();
+    let mut materials = Vec::new();
+    for (&id, material) in &mesh.materials {
+        let quads: Vec<&Quad> = mesh.quads.iter().filter(|quad| quad.material == id).collect();
+        let corners = || quads.iter().flat_map(|quad| quad.corners);
+        let positions: Vec<u8> = corners().flat_map(|corner| corner.to_array()).flat_map(f32::to_le_bytes).collect();
+        let normals: Vec<u8> = quads.iter().flat_map(|quad| [quad.normal; 4]).flat_map(|normal| normal.to_array()).flat_map(f32::to_le_bytes).collect();
+        let colors: Vec<u8> = quads.iter()
+            .flat_map(|quad| [quad.albedo.as_vec3() / 255.0; 4])
+            .flat_map(|color| color.to_array())
+            .flat_map(f32::to_le_bytes)
+            .collect();
+        let indices: Vec<u8> = (0..quads.len() as u32)
+            .flat_map(|quad| [0, 1, 2, 0, 2, 3].map(|corner| quad * 4 + corner))
+            .flat_map(u32::to_le_bytes)
+            .collect();
+        let bounds = (corners().reduce(Vec3::min).unwrap_or_default(), corners().reduce(Vec3::max).unwrap_or_default());
+        let vertex_count = quads.len() * 4;
+        let position = add(&positions, 5126, "VEC3", vertex_count, Some(bounds));
+        let normal = add(&normals, 5126, "VEC3", vertex_count, None);
+        let color = add(&colors, 5126, "VEC3", vertex_count, None);
+        let indices = add(&indices, 5125, "SCALAR", quads.len() * 6, None);
+        primitives.push(json!({
+            "attributes": { "POSITION": position, "NORMAL": normal, "COLOR_0": color },
+            "indices": indices,
+            "material": materials.len(),
+        }));
+
+        let material = ModelMaterial::from_material(&obj::material_name(id), material);
+        let emission_strength = material.emission.max_element();
+        let emission = if emission_strength > 1.0 { material.emission / emission_strength } else { material.emission };
+        materials.push(json!({
+            "name": material.name,
+            "pbrMetallicRoughness": {
+                "baseColorFactor": material.base_color.to_array(),
+                "metallicFactor": material.metallic,
+                "roughnessFactor": material.roughness,
+            },
+            "emissiveFactor": emission.to_array(),
+            "extensions": {
+                "KHR_materials_emissive_strength": { "emissiveStrength": emission_strength.max(1.0) },
+                "KHR_materials_transmission": { "transmissionFactor": material.transmission },
+                "KHR_materials_ior": { "ior": material.ior },
+            },
+        }));
+    }
+    let json = json!({
+        "asset": { "version": "2.0", "generator": "voxel_raytracer" },
+        "extensionsUsed": ["KHR_materials_emissive_strength", "KHR_materials_transmission", "KHR_materials_ior"],
+        "scene": 0,
+        "scenes": [{ "nodes": [0] }],
+        "nodes": [{ "mesh": 0 }],
+        "meshes": [{ "name": "voxels", "primitives": primitives }],
+        "materials": materials,
+        "bufferViews": views,
+        "accessors": accessors,
+    });
+    (json, binary)
+}
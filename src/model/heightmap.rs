@@ -0,0 +1,98 @@
+use std::path::Path;
+use anyhow::*;
+use glam::{uvec2, uvec3, vec3, UVec3, Vec2, Vec4Swizzles};
+use image::{ImageBuffer, Luma, RgbaImage};
+
+use crate::scene::{MaterialId, Scene, Voxel, CHUNK_SIZE};
+
+// Grayscale heightmaps turned into columns of voxels, white being the highest. Heights are read with 16 bits so
+// 16 bit PNGs keep their precision, and an optional color image of any size is stretched over the heightmap
+// for the albedo. Every voxel of a column gets the normal of the surface above it.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heightmap {
+    pub heights: ImageBuffer<Luma<u16>, Vec<u16>>,
+    pub colors: Option<RgbaImage>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeightmapOptions {
+    pub offset: UVec3, // the voxel of the top left pixel at height 0
+    pub scale: f32, // voxels per pixel, horizontally
+    pub height: f32, // the height of white pixels, in voxels
+    pub material: MaterialId,
+    pub albedo: UVec3, // used without a color image
+}
+
+impl Default for HeightmapOptions {
+    fn default() -> Self {
+        Self {
+            offset: UVec3::ZERO,
+            scale: 1.0,
+            height: 32.0,
+            material: 0,
+            albedo: UVec3::splat(200),
+        }
+    }
+}
+
+impl Heightmap {
+    pub fn new(heights: ImageBuffer<Luma<u16>, Vec<u16>>, colors: Option<RgbaImage>) -> Result<Self> {
+        ensure!(heights.width() > 0 && heights.height() > 0, "The heightmap is empty");
+        if let Some(colors) = &colors {
+            ensure!(colors.width() > 0 && colors.height() > 0, "The color image is empty");
+        }
+        Ok(Self { heights, colors })
+    }
+    pub fn load(heights: &Path, colors: Option<&Path>) -> Result<Self> {
+        let open = |path: &Path| image::open(path).with_context(|| format!("Could not load '{}'", path.display()));
+        let colors = colors.map(|path| Ok(open(path)?.to_rgba8())).transpose()?;
+        Self::new(open(heights)?.to_luma16(), colors)
+    }
+
+    // the height in 0..1 at a point in pixels, bilinearly filtered between the pixel centers
+    pub fn height_at(&self, p: Vec2) -> f32 {
+        let max = Vec2::new(self.heights.width() as f32, self.heights.height() as f32) - 1.0;
+        let p = (p - 0.5).clamp(Vec2::ZERO, max);
+        let (low, t) = (p.floor(), p.fract());
+        let high = (low + 1.0).min(max);
+        let sample = |x: f32, y: f32| self.heights.get_pixel(x as u32, y as u32).0[0] as f32 / u16::MAX as f32;
+        let top = sample(low.x, low.y) * (1.0 - t.x) + sample(high.x, low.y) * t.x;
+        let bottom = sample(low.x, high.y) * (1.0 - t.x) + sample(high.x, high.y) * t.x;
+        top * (1.0 - t.y) + bottom * t.y
+    }
+    // the albedo at a point in pixels of the heightmap, from the nearest pixel of the color image
+    fn albedo_at(&self, p: Vec2, default: UVec3) -> UVec3 {
+        let Some(colors) = &self.colors else {
+            return default;
+        };
+        let stretch = Vec2::new(colors.width() as f32 / self.heights.width() as f32, colors.height() as f32 / self.heights.height() as f32);
+        let texel = (p * stretch).floor().max(Vec2::ZERO).as_uvec2().min(uvec2(colors.width() - 1, colors.height() - 1));
+        let pixel = colors.get_pixel(texel.x, texel.y).0;
+        uvec3(pixel[0] as u32, pixel[1] as u32, pixel[2] as u32)
+    }
+
+    // write the columns into a scene, everything below the surface is filled. Returns the chunks that changed,
+    // like Scene::paint
+    pub fn import(&self, scene: &mut Scene, options: &HeightmapOptions) -> Vec<UVec3> {
+        let scene_size = scene.size.xyz().as_uvec3() * CHUNK_SIZE as u32;
+        let footprint = (vec3(self.heights.width() as f32, 0.0, self.heights.height() as f32) * options.scale).ceil().as_uvec3();
+        let max = (options.offset + footprint).min(scene_size);
+        let mut edits = Vec::new();
+        for z in options.offset.z..max.z {
+            for x in options.offset.x..max.x {
+                // the column center in pixels, and the height there in voxels
+                let pixel = (Vec2::new((x - options.offset.x) as f32, (z - options.offset.z) as f32) + 0.5) / options.scale;
+                let height = |p: Vec2| self.height_at(p) * options.height;
+                let surface = height(pixel);
+                // the slope in voxels per voxel, from the heights a pixel away
+                let dx = (height(pixel + Vec2::X) - height(pixel - Vec2::X)) / (2.0 * options.scale);
+                let dz = (height(pixel + Vec2::Y) - height(pixel - Vec2::Y)) / (2.0 * options.scale);
+                let voxel = Voxel::new(options.material, self.albedo_at(pixel, options.albedo), vec3(-dx, 1.0, -dz).normalize());
+                let top = (options.offset.y as f32 + surface.round()).min(scene_size.y as f32) as u32;
+                edits.extend((options.offset.y..top).map(|y| (uvec3(x, y, z), voxel)));
+            }
+        }
+        scene.write_voxels(edits)
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+use glam::{IVec3, UVec3, Vec3};
+
+use crate::scene::{Material, MaterialId, Scene, Voxel};
+
+// The visible faces of a region of a scene as a mesh, for exporting to other tools with obj::write and gltf::write.
+// Neighbouring faces with the same albedo and material are merged into larger rectangles (greedy meshing),
+// and faces facing out of the region are kept, so a region cut out of a larger scene is closed.
+// Positions are in voxels, relative to the minimum corner of the region.
+
+// A rectangle of voxel faces with one look
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quad {
+    pub corners: [Vec3; 4], // counter clockwise seen from the outside
+    pub normal: Vec3,
+    pub albedo: UVec3,
+    pub material: MaterialId,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VoxelMesh {
+    pub quads: Vec<Quad>,
+    pub materials: BTreeMap<MaterialId, Material>, // the scene materials of the quads
+}
+
+impl VoxelMesh {
+    // the faces of the voxels from min up to max, max excluded
+    pub fn from_scene(scene: &Scene, min: UVec3, max: UVec3) -> Self {
+        let max = max.max(min);
+        let size = max - min;
+        let filled = |pos: UVec3| (pos.cmplt(max).all() && pos.cmpge(min).all()).then(|| scene.get_voxel(pos)).filter(|voxel| !voxel.is_empty());
+        let opaque = |voxel: &Voxel| scene.materials.get(voxel.material() as usize).is_some_and(Material::is_opaque);
+        let mut mesh = Self::default();
+        for axis in 0..3 {
+            // the axes along the faces, in the order that makes u x v point along axis
+            let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+            for positive in [false, true] {
+                let mut mask = vec![None; (size[u] * size[v]) as usize];
+                for layer in 0..size[axis] {
+                    // the faces of this layer that can be seen. Glass, water and media show the faces behind them,
+                    // except for the faces inside a body of one of them
+                    for j in 0..size[v] {
+                        for i in 0..size[u] {
+                            let mut pos = min;
+                            pos[axis] += layer;
+                            pos[u] += i;
+                            pos[v] += j;
+                            let voxel = filled(pos);
+                            let mut next = pos.as_ivec3();
+                            next[axis] += if positive { 1 } else { -1 };
+                            let covered = next.cmpge(IVec3::ZERO).all() && filled(next.as_uvec3())
+                                .is_some_and(|next| opaque(&next) || voxel.is_some_and(|voxel| voxel.material() == next.material()));
+                            mask[(i + j * size[u]) as usize] = voxel.filter(|_| !covered).map(|voxel| (voxel.material(), voxel.albedo()));
+                        }
+                    }
+                    // grow rectangles of the same look, first along u and then along v
+                    for j in 0..size[v] {
+                        let mut i = 0;
+                        while i < size[u] {
+                            let Some(look) = mask[(i + j * size[u]) as usize] else {
+                                i += 1;
+                                continue;
+                            };
+                            let width = (i..size[u]).take_while(|&x| mask[(x + j * size[u]) as usize] == Some(look)).count() as u32;
+                            let height = (j..size[v])
+                                .take_while(|&y| (i..i + width).all(|x| mask[(x + y * size[u]) as usize] == Some(look)))
+                                .count() as u32;
+                            for y in j..j + height {
+                                for x in i..i + width {
+                                    mask[(x + y * size[u]) as usize] = None;
+                                }
+                            }
+                            mesh.add_quad(axis, positive, layer, (i, j, width, height), look);
+                            i += width;
+                        }
+                    }
+                }
+            }
+        }
+        for quad in &mesh.quads {
+            let material = scene.materials.get(quad.material as usize).copied().unwrap_or_default();
+            mesh.materials.insert(quad.material, material);
+        }
+        mesh
+    }
+
+    fn add_quad(&mut self, axis: usize, positive: bool, layer: u32, (i, j, width, height): (u32, u32, u32, u32), (material, albedo): (MaterialId, UVec3)) {
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+        let corner = |a: u32, b: u32| {
+            let mut corner = Vec3::ZERO;
+            corner[axis] = (layer + positive as u32) as f32;
+            corner[u] = a as f32;
+            corner[v] = b as f32;
+            corner
+        };
+        let mut corners = [corner(i, j), corner(i + width, j), corner(i + width, j + height), corner(i, j + height)];
+        let mut normal = Vec3::ZERO;
+        normal[axis] = 1.0;
+        if !positive {
+            corners.reverse();
+            normal = -normal;
+        }
+        self.quads.push(Quad { corners, normal, albedo, material });
+    }
+
+    // the number of voxel faces covered by the quads
+    pub fn face_count(&self) -> usize {
+        self.quads.iter()
+            .map(|quad| {
+                // one side of the diagonal is 0, along the normal
+                let size = (quad.corners[2] - quad.corners[0]).abs();
+                (size.x * size.y + size.y * size.z + size.z * size.x).round() as usize
+            })
+            .sum()
+    }
+}
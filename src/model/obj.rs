@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::Path;
+use anyhow::*;
+use glam::{Vec2, Vec3};
+
+use super::mesh::{Quad, VoxelMesh};
+use super::{Model, ModelMaterial, Triangle};
+use crate::scene::MaterialId;
+
+// Wavefront OBJ files with their MTL material libraries.
+// Polygons are split into fans of triangles, and vertex colors are read from the common "v x y z r g b" extension.
+// Materials read the diffuse color and texture, emission, dissolve, index of refraction and the PBR extension
+// (Pr, Pm); without Pr the roughness comes from the specular exponent.
+// Voxel meshes are written with one material per scene material, and the voxel albedo in the vertex colors.
+
+pub fn load(path: &Path) -> Result<Model> {
+    let source = std::fs::read_to_string(path)?;
+    let directory = path.parent().unwrap_or(Path::new("")).to_path_buf();
+    parse(&source, |file| load_material_library(&directory.join(file)))
+}
+
+// parse an OBJ file, loading its material libraries by name with load_library
+pub fn parse(source: &str, load_library: impl Fn(&str) -> Result<Vec<ModelMaterial>>) -> Result<Model> {
+    let mut positions: Vec<Vec3> = Vec::new();
+    let mut colors: Vec<Vec3> = Vec::new();
+    let mut normals: Vec<Vec3> = Vec::new();
+    let mut uvs: Vec<Vec2> = Vec::new();
+    let mut model = Model::default();
+    let mut material_indices: HashMap<String, usize> = HashMap::new();
+    let mut material = None;
+    for (line_number, line) in source.lines().enumerate() {
+        let mut words = line.split_whitespace();
+        let Some(keyword) = words.next() else {
+            continue;
+        };
+        let words: Vec<&str> = words.collect();
+        let context = || format!("Line {}: '{}'", line_number + 1, line);
+        match keyword {
+            "v" => {
+                let values = floats(&words).with_context(context)?;
+                ensure!(values.len() >= 3, "{}: a vertex needs 3 coordinates", context());
+                positions.push(Vec3::new(values[0], values[1], values[2]));
+                colors.push(if values.len() >= 6 { Vec3::new(values[3], values[4], values[5]) } else { Vec3::ONE });
+            }
+            "vn" => {
+                let values = floats(&words).with_context(context)?;
+                ensure!(values.len() >= 3, "{}: a normal needs 3 coordinates", context());
+                normals.push(Vec3::new(values[0], values[1], values[2]));
+            }
+            "vt" => {
+                let values = floats(&words).with_context(context)?;
+                ensure!(!values.is_empty(), "{}: a texture coordinate needs at least 1 coordinate", context());
+                // OBJ has v going up from the bottom of the texture
+                uvs.push(Vec2::new(values[0], 1.0 - values.get(1).copied().unwrap_or(0.0)));
+            }
+            "f" => {
+                let corners = words.iter()
+                    .map(|word| Corner::parse(word, positions.len(), uvs.len(), normals.len()))
+                    .collect::<Result<Vec<_>>>()
+                    .with_context(context)?;
+                ensure!(corners.len() >= 3, "{}: a face needs at least 3 corners", context());
+                for i in 1..corners.len() - 1 {
+                    let fan = [corners[0], corners[i], corners[i + 1]];
+                    model.triangles.push(Triangle {
+                        positions: fan.map(|corner| positions[corner.position]),
+                        normals: fan.iter().all(|corner| corner.normal.is_some()).then(|| fan.map(|corner| normals[corner.normal.unwrap()])),
+                        uvs: fan.iter().all(|corner| corner.uv.is_some()).then(|| fan.map(|corner| uvs[corner.uv.unwrap()])),
+                        colors: Some(fan.map(|corner| colors[corner.position])).filter(|colors| colors.iter().any(|&color| color != Vec3::ONE)),
+                        material,
+                    });
+                }
+            }
+            "mtllib" => {
+                for library in load_library(&words.join(" ")).with_context(context)? {
+                    material_indices.insert(library.name.clone(), model.materials.len());
+                    model.materials.push(library);
+                }
+            }
+            "usemtl" => {
+                material = material_indices.get(&words.join(" ")).copied();
+            }
+            _ => {} // groups, objects, smoothing groups and lines don't change the voxels
+        }
+    }
+    Ok(model)
+}
+
+// one corner of a face: indices of its position, texture coordinate and normal
+#[derive(Debug, Clone, Copy)]
+struct Corner {
+    position: usize,
+    uv: Option<usize>,
+    normal: Option<usize>,
+}
+
+impl Corner {
+    // parse "v", "v/vt", "v//vn" or "v/vt/vn", where negative indices count back from the last element
+    fn parse(word: &str, position_count: usize, uv_count: usize, normal_count: usize) -> Result<Self> {
+        let mut parts = word.split('/');
+        let position = index(parts.next(), position_count)?.context("a face corner needs a position")?;
+        let uv = index(parts.next(), uv_count)?;
+        let normal = index(parts.next(), normal_count)?;
+        Ok(Self { position, uv, normal })
+    }
+}
+
+// a 1-based or negative OBJ index as a 0-based one
+fn index(part: Option<&str>, count: usize) -> Result<Option<usize>> {
+    let Some(part) = part.filter(|part| !part.is_empty()) else {
+        return Ok(None);
+    };
+    let index: i64 = part.parse()?;
+    let index = if index < 0 { count as i64 + index } else { index - 1 };
+    ensure!((0..count as i64).contains(&index), "Index {} is out of range", part);
+    Ok(Some(index as usize))
+}
+
+fn floats(words: &[&str]) -> Result<Vec<f32>> {
+    Ok(words.iter().map(|word| word.parse()).collect::<Result<Vec<f32>, _>>()?)
+}
+
+pub fn load_material_library(path: &Path) -> Result<Vec<ModelMaterial>> {
+    let source = std::fs::read_to_string(path).with_context(|| format!("Could not read '{}'", path.display()))?;
+    let directory = path.parent().unwrap_or(Path::new("")).to_path_buf();
+    parse_material_library(&source, |file| Ok(image::open(directory.join(file))?.to_rgba8()))
+}
+
+// parse an MTL file, loading its textures by name with load_texture
+pub fn parse_material_library(source: &str, load_texture: impl Fn(&str) -> Result<image::RgbaImage>) -> Result<Vec<ModelMaterial>> {
+    let mut materials: Vec<ModelMaterial> = Vec::new();
+    let mut shininess = None;
+    let mut roughness = None;
+    for (line_number, line) in source.lines().enumerate() {
+        let mut words = line.split_whitespace();
+        let Some(keyword) = words.next() else {
+            continue;
+        };
+        let words: Vec<&str> = words.collect();
+        let context = || format!("Line {}: '{}'", line_number + 1, line);
+        if keyword == "newmtl" {
+            finish_material(materials.last_mut(), shininess.take(), roughness.take());
+            materials.push(ModelMaterial { name: words.join(" "), ..Default::default() });
+            continue;
+        }
+        let Some(material) = materials.last_mut() else {
+            continue; // nothing to set before the first material
+        };
+        let value = || -> Result<f32> { Ok(floats(&words).with_context(context)?.first().copied().with_context(context)?) };
+        let color = || -> Result<Vec3> {
+            let values = floats(&words).with_context(context)?;
+            ensure!(values.len() >= 3, "{}: a color needs 3 values", context());
+            Ok(Vec3::new(values[0], values[1], values[2]))
+        };
+        match keyword {
+            "Kd" => material.base_color = color()?.extend(material.base_color.w),
+            "Ke" => material.emission = color()?,
+            "d" => material.base_color.w = value()?,
+            "Tr" => material.base_color.w = 1.0 - value()?,
+            "Ni" => material.ior = value()?,
+            "Ns" => shininess = Some(value()?),
+            "Pr" => roughness = Some(value()?),
+            "Pm" => material.metallic = value()?,
+            "map_Kd" => {
+                // the file name is last, after any options
+                let file = words.last().with_context(context)?;
+                material.texture = Some(load_texture(file).with_context(context)?);
+            }
+            _ => {}
+        }
+    }
+    finish_material(materials.last_mut(), shininess, roughness);
+    Ok(materials)
+}
+
+// set the roughness once all lines of a material are read
+fn finish_material(material: Option<&mut ModelMaterial>, shininess: Option<f32>, roughness: Option<f32>) {
+    let Some(material) = material else {
+        return;
+    };
+    material.roughness = match (roughness, shininess) {
+        (Some(roughness), _) => roughness,
+        // a Phong exponent of n is about a GGX alpha of sqrt(2 / (n + 2)), and roughness is sqrt(alpha)
+        (None, Some(shininess)) => (2.0 / (shininess.max(0.0) + 2.0)).sqrt().sqrt(),
+        (None, None) => 1.0,
+    };
+}
+
+// the name of a scene material in exported files
+pub(crate) fn material_name(material: MaterialId) -> String {
+    format!("material_{}", material)
+}
+
+// write a mesh as OBJ, with its materials in an MTL file called library. Quads keep their albedo as vertex colors
+pub fn write(mesh: &VoxelMesh, library: &str) -> (String, String) {
+    let mut obj = format!("# {} quads exported from a voxel scene\nmtllib {}\n", mesh.quads.len(), library);
+    let normals = [Vec3::X, Vec3::NEG_X, Vec3::Y, Vec3::NEG_Y, Vec3::Z, Vec3::NEG_Z];
+    for normal in normals {
+        writeln!(obj, "vn {} {} {}", normal.x, normal.y, normal.z).unwrap();
+    }
+    let mut quads: Vec<&Quad> = mesh.quads.iter().collect();
+    quads.sort_by_key(|quad| quad.material);
+    let mut material = None;
+    for (i, quad) in quads.iter().enumerate() {
+        if material != Some(quad.material) {
+            material = Some(quad.material);
+            writeln!(obj, "usemtl {}", material_name(quad.material)).unwrap();
+        }
+        let color = quad.albedo.as_vec3() / 255.0;
+        for corner in quad.corners {
+            writeln!(obj, "v {} {} {} {} {} {}", corner.x, corner.y, corner.z, color.x, color.y, color.z).unwrap();
+        }
+        let normal = normals.iter().position(|&normal| normal == quad.normal).unwrap_or(0) + 1;
+        let first = i * 4 + 1;
+        writeln!(obj, "f {}//{n} {}//{n} {}//{n} {}//{n}", first, first + 1, first + 2, first + 3, n = normal).unwrap();
+    }
+
+    let mut mtl = String::new();
+    for (&id, material) in &mesh.materials {
+        let material = ModelMaterial::from_material(&material_name(id), material);
+        let [r, g, b, _] = material.base_color.to_array();
+        writeln!(mtl, "newmtl {}", material.name).unwrap();
+        writeln!(mtl, "Kd {} {} {}", r, g, b).unwrap();
+        writeln!(mtl, "Ke {} {} {}", material.emission.x, material.emission.y, material.emission.z).unwrap();
+        writeln!(mtl, "d {}", 1.0 - material.transmission).unwrap();
+        writeln!(mtl, "Ni {}", material.ior).unwrap();
+        writeln!(mtl, "Pr {}", material.roughness).unwrap();
+        writeln!(mtl, "Pm {}\n", material.metallic).unwrap();
+    }
+    (obj, mtl)
+}
+
+// save a mesh as an OBJ file, with its MTL file next to it
+pub fn save(mesh: &VoxelMesh, path: &Path) -> Result<()> {
+    let library = path.with_extension("mtl");
+    let library_name = library.file_name().and_then(|name| name.to_str()).context("The path has no file name")?;
+    let (obj, mtl) = write(mesh, library_name);
+    std::fs::write(path, obj).with_context(|| format!("Could not write '{}'", path.display()))?;
+    std::fs::write(&library, mtl).with_context(|| format!("Could not write '{}'", library.display()))
+}
@@ -0,0 +1,223 @@
+use std::collections::HashSet;
+use std::path::Path;
+use anyhow::*;
+use glam::{uvec3, IVec3, UVec3, Vec3, Vec4Swizzles};
+
+use crate::scene::{MaterialId, Scene, Voxel, CHUNK_SIZE};
+
+// PLY point clouds with a point at the center of every voxel, in voxels relative to the minimum corner of the
+// exported region. Points are written as binary with their normal, color and an extra "material" property holding
+// the scene material. Reading takes ASCII and binary files, and the vertex properties that are missing get
+// defaults: white, material 0 and normals from the points around them.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Point {
+    pub position: Vec3,
+    pub normal: Vec3, // zero when unknown
+    pub albedo: UVec3,
+    pub material: MaterialId,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PointCloud {
+    pub points: Vec<Point>,
+}
+
+impl PointCloud {
+    // the voxels from min up to max, max excluded
+    pub fn from_scene(scene: &Scene, min: UVec3, max: UVec3) -> Self {
+        let mut points = Vec::new();
+        for z in min.z..max.z {
+            for y in min.y..max.y {
+                for x in min.x..max.x {
+                    let voxel = scene.get_voxel(uvec3(x, y, z));
+                    if !voxel.is_empty() {
+                        points.push(Point {
+                            position: (uvec3(x, y, z) - min).as_vec3() + 0.5,
+                            normal: voxel.normal().normalize_or_zero(),
+                            albedo: voxel.albedo(),
+                            material: voxel.material(),
+                        });
+                    }
+                }
+            }
+        }
+        Self { points }
+    }
+
+    pub fn write(&self) -> Vec<u8> {
+        let mut bytes = format!(
+            "ply\nformat binary_little_endian 1.0\ncomment exported from a voxel scene\nelement vertex {}\n\
+            property float x\nproperty float y\nproperty float z\nproperty float nx\nproperty float ny\nproperty float nz\n\
+            property uchar red\nproperty uchar green\nproperty uchar blue\nproperty uchar material\nend_header\n",
+            self.points.len()
+        )
+        .into_bytes();
+        for point in &self.points {
+            for value in point.position.to_array().into_iter().chain(point.normal.to_array()) {
+                bytes.extend(value.to_le_bytes());
+            }
+            bytes.extend([point.albedo.x as u8, point.albedo.y as u8, point.albedo.z as u8, point.material as u8]);
+        }
+        bytes
+    }
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, self.write()).with_context(|| format!("Could not write '{}'", path.display()))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path).with_context(|| format!("Could not read '{}'", path.display()))?;
+        Self::parse(&bytes).with_context(|| format!("Could not load '{}'", path.display()))
+    }
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let end = bytes.windows(11).position(|window| window == b"end_header\n").context("The PLY header has no end")?;
+        let header = std::str::from_utf8(&bytes[..end]).context("The PLY header isn't text")?;
+        let body = &bytes[end + 11..];
+        let mut lines = header.lines().map(str::trim);
+        ensure!(lines.next() == Some("ply"), "Not a PLY file");
+        let mut format = None;
+        let mut elements: Vec<Element> = Vec::new();
+        for line in lines {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                ["format", kind, _] => format = Some(kind.to_string()),
+                ["element", name, count] => elements.push(Element { name: name.to_string(), count: count.parse()?, properties: Vec::new() }),
+                ["property", "list", ..] => bail!("List properties aren't supported"),
+                ["property", kind, name] => {
+                    let element = elements.last_mut().context("A property comes before any element")?;
+                    element.properties.push((name.to_string(), Type::parse(kind)?));
+                }
+                _ => {} // comments and obj_info
+            }
+        }
+        let format = format.context("The PLY header has no format")?;
+        let mut reader: Box<dyn FnMut(Type) -> Result<f64>> = match format.as_str() {
+            "ascii" => {
+                let mut values = std::str::from_utf8(body)?.split_whitespace();
+                Box::new(move |_| Ok(values.next().context("Truncated PLY file")?.parse()?))
+            }
+            "binary_little_endian" | "binary_big_endian" => {
+                let big_endian = format == "binary_big_endian";
+                let mut offset = 0;
+                Box::new(move |kind| {
+                    let mut value = body.get(offset..offset + kind.size()).context("Truncated PLY file")?.to_vec();
+                    offset += kind.size();
+                    if big_endian {
+                        value.reverse();
+                    }
+                    Ok(kind.read(&value))
+                })
+            }
+            format => bail!("Unknown PLY format {}", format),
+        };
+
+        let mut points = Vec::new();
+        for element in &elements {
+            for _ in 0..element.count {
+                let mut point = Point { position: Vec3::ZERO, normal: Vec3::ZERO, albedo: UVec3::splat(255), material: 0 };
+                for (property, kind) in &element.properties {
+                    let value = reader(*kind)?;
+                    // float colors are in 0..1
+                    let color = if kind.is_float() { (value * 255.0).round() } else { value }.clamp(0.0, 255.0) as u32;
+                    match property.as_str() {
+                        "x" => point.position.x = value as f32,
+                        "y" => point.position.y = value as f32,
+                        "z" => point.position.z = value as f32,
+                        "nx" => point.normal.x = value as f32,
+                        "ny" => point.normal.y = value as f32,
+                        "nz" => point.normal.z = value as f32,
+                        "red" | "r" => point.albedo.x = color,
+                        "green" | "g" => point.albedo.y = color,
+                        "blue" | "b" => point.albedo.z = color,
+                        "material" => point.material = value as MaterialId,
+                        _ => {}
+                    }
+                }
+                if element.name == "vertex" {
+                    points.push(point);
+                }
+            }
+        }
+        Ok(Self { points })
+    }
+
+    // write a voxel for every point into a scene, offset by offset voxels. Returns the chunks that changed,
+    // like Scene::paint
+    pub fn import(&self, scene: &mut Scene, offset: UVec3) -> Vec<UVec3> {
+        let scene_size = scene.size.xyz().as_uvec3() * CHUNK_SIZE as u32;
+        let voxel_of = |point: &Point| (point.position.floor() + offset.as_vec3()).as_ivec3();
+        let filled: HashSet<_> = self.points.iter().map(voxel_of).collect();
+        let edits = self.points.iter()
+            .filter(|point| {
+                let pos = voxel_of(point);
+                pos.cmpge(IVec3::ZERO).all() && pos.cmplt(scene_size.as_ivec3()).all()
+            })
+            .map(|point| {
+                let pos = voxel_of(point);
+                let normal = point.normal.try_normalize().unwrap_or_else(|| super::exposed_normal(&filled, pos));
+                (pos.as_uvec3(), Voxel::new(point.material, point.albedo, normal))
+            })
+            .collect();
+        scene.write_voxels(edits)
+    }
+}
+
+// A kind of thing in a PLY file, like vertices or faces
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<(String, Type)>,
+}
+
+// the scalar types of PLY properties
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Type {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Type {
+    fn parse(name: &str) -> Result<Self> {
+        Ok(match name {
+            "char" | "int8" => Self::I8,
+            "uchar" | "uint8" => Self::U8,
+            "short" | "int16" => Self::I16,
+            "ushort" | "uint16" => Self::U16,
+            "int" | "int32" => Self::I32,
+            "uint" | "uint32" => Self::U32,
+            "float" | "float32" => Self::F32,
+            "double" | "float64" => Self::F64,
+            name => bail!("Unknown PLY type {}", name),
+        })
+    }
+    fn size(self) -> usize {
+        match self {
+            Self::I8 | Self::U8 => 1,
+            Self::I16 | Self::U16 => 2,
+            Self::I32 | Self::U32 | Self::F32 => 4,
+            Self::F64 => 8,
+        }
+    }
+    fn is_float(self) -> bool {
+        matches!(self, Self::F32 | Self::F64)
+    }
+    // a little endian value
+    fn read(self, b: &[u8]) -> f64 {
+        match self {
+            Self::I8 => b[0] as i8 as f64,
+            Self::U8 => b[0] as f64,
+            Self::I16 => i16::from_le_bytes([b[0], b[1]]) as f64,
+            Self::U16 => u16::from_le_bytes([b[0], b[1]]) as f64,
+            Self::I32 => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            Self::U32 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            Self::F32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            Self::F64 => f64::from_le_bytes(b.try_into().unwrap()),
+        }
+    }
+}
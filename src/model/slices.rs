@@ -0,0 +1,108 @@
+use std::path::Path;
+use anyhow::*;
+use glam::{uvec3, IVec3, UVec3, Vec3, Vec4Swizzles};
+use image::{ImageBuffer, Luma};
+
+use crate::scene::{MaterialId, Scene, Voxel, CHUNK_SIZE};
+
+// Volumes from stacks of grayscale slice images, like CT or MRI scans. Every pixel becomes one voxel: the image x
+// goes along x, the image rows along z, and the slices are stacked up along y. A transfer function decides which
+// intensities are solid and what they look like, and the normals point down the intensity gradient.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageStack {
+    pub slices: Vec<ImageBuffer<Luma<u16>, Vec<u16>>>, // all the same size
+}
+
+// The look of voxels with intensities from threshold up to the threshold of the next band
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Band {
+    pub threshold: f32, // in 0..1
+    pub material: MaterialId,
+    pub albedo: (UVec3, UVec3), // at the bottom and the top of the band, blended in between
+}
+
+// Maps intensities to materials and albedos. Intensities below the first band stay empty
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TransferFunction {
+    pub bands: Vec<Band>, // sorted by threshold
+}
+
+impl TransferFunction {
+    // everything from threshold up looks the same
+    pub fn threshold(threshold: f32, material: MaterialId, albedo: UVec3) -> Self {
+        Self { bands: vec![Band { threshold, material, albedo: (albedo, albedo) }] }
+    }
+    // the material and albedo of an intensity in 0..1, None for empty voxels
+    pub fn map(&self, intensity: f32) -> Option<(MaterialId, UVec3)> {
+        let index = self.bands.iter().rposition(|band| intensity >= band.threshold)?;
+        let band = &self.bands[index];
+        let top = self.bands.get(index + 1).map_or(1.0, |next| next.threshold);
+        let t = if top > band.threshold { ((intensity - band.threshold) / (top - band.threshold)).clamp(0.0, 1.0) } else { 0.0 };
+        let albedo = band.albedo.0.as_vec3().lerp(band.albedo.1.as_vec3(), t).round().as_uvec3();
+        Some((band.material, albedo))
+    }
+}
+
+impl ImageStack {
+    pub fn new(slices: Vec<ImageBuffer<Luma<u16>, Vec<u16>>>) -> Result<Self> {
+        let first = slices.first().context("The image stack has no slices")?;
+        let size = first.dimensions();
+        ensure!(slices.iter().all(|slice| slice.dimensions() == size), "The slices have different sizes");
+        Ok(Self { slices })
+    }
+    // the PNG and JPEG images in a directory, bottom slice first in the order of their file names.
+    // Numbered files need leading zeros to sort right
+    pub fn load(directory: &Path) -> Result<Self> {
+        let mut paths = std::fs::read_dir(directory)
+            .with_context(|| format!("Could not read '{}'", directory.display()))?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<Result<Vec<_>>>()?;
+        paths.retain(|path| {
+            let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or_default().to_lowercase();
+            matches!(extension.as_str(), "png" | "jpg" | "jpeg")
+        });
+        paths.sort();
+        let slices = paths.iter()
+            .map(|path| Ok(image::open(path).with_context(|| format!("Could not load '{}'", path.display()))?.to_luma16()))
+            .collect::<Result<Vec<_>>>()?;
+        Self::new(slices).with_context(|| format!("In '{}'", directory.display()))
+    }
+    // the size of the volume in voxels
+    pub fn size(&self) -> UVec3 {
+        let (width, depth) = self.slices[0].dimensions();
+        uvec3(width, self.slices.len() as u32, depth)
+    }
+    // the intensity in 0..1 of a voxel of the volume, clamped to its edges
+    pub fn intensity(&self, pos: IVec3) -> f32 {
+        let pos = pos.clamp(IVec3::ZERO, self.size().as_ivec3() - 1).as_uvec3();
+        self.slices[pos.y as usize].get_pixel(pos.x, pos.z).0[0] as f32 / u16::MAX as f32
+    }
+
+    // write the volume into a scene with its first voxel at offset. Empty voxels of the volume are left alone.
+    // Returns the chunks that changed, like Scene::paint
+    pub fn import(&self, scene: &mut Scene, offset: UVec3, transfer: &TransferFunction) -> Vec<UVec3> {
+        let scene_size = scene.size.xyz().as_uvec3() * CHUNK_SIZE as u32;
+        let max = (offset + self.size()).min(scene_size);
+        let mut edits = Vec::new();
+        for z in offset.z..max.z {
+            for y in offset.y..max.y {
+                for x in offset.x..max.x {
+                    let pos = (uvec3(x, y, z) - offset).as_ivec3();
+                    let Some((material, albedo)) = transfer.map(self.intensity(pos)) else {
+                        continue;
+                    };
+                    // the intensity falls off out of the solid parts
+                    let gradient = Vec3::new(
+                        self.intensity(pos + IVec3::X) - self.intensity(pos - IVec3::X),
+                        self.intensity(pos + IVec3::Y) - self.intensity(pos - IVec3::Y),
+                        self.intensity(pos + IVec3::Z) - self.intensity(pos - IVec3::Z),
+                    );
+                    let normal = (-gradient).try_normalize().unwrap_or(Vec3::Y);
+                    edits.push((uvec3(x, y, z), Voxel::new(material, albedo, normal)));
+                }
+            }
+        }
+        scene.write_voxels(edits)
+    }
+}
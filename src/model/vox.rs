@@ -0,0 +1,359 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use anyhow::*;
+use glam::{uvec3, IVec3, UVec3, Vec3, Vec4Swizzles};
+
+use super::ModelMaterial;
+use crate::scene::{MaterialId, Scene, Voxel, CHUNK_SIZE};
+
+// MagicaVoxel .vox files, with their palette and MATL materials.
+// A .vox file has z up, so its axes are (x, -z, y) of the scene. Every palette entry has one color and one material,
+// and regions with more than 255 of those are quantized with a median cut that never mixes materials.
+// Materials keep their metalness, roughness, transmission, index of refraction and emission strength, which is
+// written as _emit * 2^_flux. Files with more than one model, like MagicaVoxel animations, are read as frames that
+// share the palette and materials. Files without a palette are white.
+// Voxels from a file get their normals from the empty voxels around them.
+
+// the largest model MagicaVoxel can open, along every axis
+pub const MAX_SIZE: u32 = 256;
+
+// The color and material of a palette index
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PaletteEntry {
+    pub albedo: UVec3,
+    pub material: usize, // index into VoxModel::materials
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoxModel {
+    pub size: UVec3, // in scene axes
+    pub voxels: Vec<(UVec3, u8)>, // positions in scene axes with their palette index, 1 to 255
+    pub palette: Vec<PaletteEntry>, // 256 entries, index 0 is empty space
+    pub materials: Vec<ModelMaterial>,
+}
+
+impl VoxModel {
+    // the voxels from min up to max, max excluded, with the scene materials they use in the order of their ids
+    pub fn from_scene(scene: &Scene, min: UVec3, max: UVec3) -> Result<Self> {
+        let max = max.max(min);
+        let size = max - min;
+        ensure!(size.cmple(UVec3::splat(MAX_SIZE)).all(), "A .vox model can't be larger than {} voxels along any axis", MAX_SIZE);
+        let mut counts: HashMap<(MaterialId, UVec3), u32> = HashMap::new();
+        let mut voxels = Vec::new();
+        for z in min.z..max.z {
+            for y in min.y..max.y {
+                for x in min.x..max.x {
+                    let voxel = scene.get_voxel(uvec3(x, y, z));
+                    if !voxel.is_empty() {
+                        *counts.entry((voxel.material(), voxel.albedo())).or_default() += 1;
+                        voxels.push((uvec3(x, y, z) - min, (voxel.material(), voxel.albedo())));
+                    }
+                }
+            }
+        }
+        let mut ids: Vec<MaterialId> = counts.keys().map(|(material, _)| *material).collect::<HashSet<_>>().into_iter().collect();
+        ids.sort();
+        ensure!(ids.len() < 256, "A .vox model can't have more than 255 materials");
+        let materials = ids.iter()
+            .map(|&id| ModelMaterial::from_material(&super::obj::material_name(id), &scene.materials.get(id as usize).copied().unwrap_or_default()))
+            .collect();
+
+        let boxes = median_cut(counts.into_iter().collect(), 255);
+        let mut palette = vec![PaletteEntry { albedo: UVec3::ZERO, material: 0 }; 256];
+        let mut indices = HashMap::new();
+        for (i, colors) in boxes.iter().enumerate() {
+            let material = colors[0].0 .0;
+            let total: u32 = colors.iter().map(|(_, count)| count).sum();
+            let sum: Vec3 = colors.iter().map(|((_, albedo), count)| albedo.as_vec3() * *count as f32).sum();
+            palette[i + 1] = PaletteEntry {
+                albedo: (sum / total as f32).round().as_uvec3(),
+                material: ids.binary_search(&material).unwrap(),
+            };
+            indices.extend(colors.iter().map(|(key, _)| (*key, i as u8 + 1)));
+        }
+        let voxels = voxels.into_iter().map(|(pos, key)| (pos, indices[&key])).collect();
+        Ok(Self { size, voxels, palette, materials })
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path).with_context(|| format!("Could not read '{}'", path.display()))?;
+        Self::parse(&bytes).with_context(|| format!("Could not load '{}'", path.display()))
+    }
+    // the first model of a file
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        Ok(Self::parse_frames(bytes)?.swap_remove(0))
+    }
+    pub fn load_frames(path: &Path) -> Result<Vec<Self>> {
+        let bytes = std::fs::read(path).with_context(|| format!("Could not read '{}'", path.display()))?;
+        Self::parse_frames(&bytes).with_context(|| format!("Could not load '{}'", path.display()))
+    }
+    // every model of a file in order, at least one
+    pub fn parse_frames(bytes: &[u8]) -> Result<Vec<Self>> {
+        ensure!(bytes.starts_with(b"VOX "), "Not a .vox file");
+        let mut reader = Reader { bytes, offset: 8 };
+        let (id, _, children) = reader.chunk()?;
+        ensure!(id == *b"MAIN", "The file has no MAIN chunk");
+        let mut reader = Reader { bytes: children, offset: 0 };
+        let mut sizes = Vec::new();
+        let mut models = Vec::new();
+        let mut colors = [[255u8; 4]; 256];
+        let mut properties: HashMap<u8, HashMap<String, String>> = HashMap::new();
+        while reader.offset < reader.bytes.len() {
+            let (id, content, _) = reader.chunk()?;
+            let mut content = Reader { bytes: content, offset: 0 };
+            match &id {
+                b"SIZE" => sizes.push(uvec3(content.u32()?, content.u32()?, content.u32()?)),
+                b"XYZI" => {
+                    let count = content.u32()?;
+                    models.push((0..count).map(|_| Ok(content.take(4)?.try_into().unwrap())).collect::<Result<Vec<[u8; 4]>>>()?);
+                }
+                // the colors of indices 1 to 255, and then an unused one
+                b"RGBA" => {
+                    for color in colors.iter_mut().skip(1) {
+                        *color = content.take(4)?.try_into().unwrap();
+                    }
+                }
+                b"MATL" => {
+                    let index = content.u32()?;
+                    let dictionary = content.dictionary()?;
+                    if (1..256).contains(&index) {
+                        properties.insert(index as u8, dictionary);
+                    }
+                }
+                _ => {} // the scene graph, layers and cameras
+            }
+        }
+        ensure!(!sizes.is_empty(), "The file has no SIZE chunk");
+        ensure!(!models.is_empty(), "The file has no XYZI chunk");
+        ensure!(sizes.len() == models.len(), "The file has {} SIZE chunks but {} XYZI chunks", sizes.len(), models.len());
+        // the materials of the palette entries used by any model, the same ones merged, in the order of the palette.
+        // Unused entries point at the first material
+        let used: HashSet<u8> = models.iter().flatten().map(|voxel| voxel[3]).collect();
+        let mut materials: Vec<ModelMaterial> = Vec::new();
+        let palette = (0..256)
+            .map(|i| {
+                let [r, g, b, _] = colors[i];
+                let mut entry = PaletteEntry { albedo: uvec3(r as u32, g as u32, b as u32), material: 0 };
+                if used.contains(&(i as u8)) && i > 0 {
+                    let material = properties.get(&(i as u8)).map(read_material).unwrap_or_default();
+                    entry.material = materials.iter().position(|known| *known == material).unwrap_or_else(|| {
+                        materials.push(material);
+                        materials.len() - 1
+                    });
+                }
+                entry
+            })
+            .collect::<Vec<_>>();
+        let frames = sizes.into_iter().zip(models)
+            .map(|(vox_size, voxels)| {
+                let voxels = voxels.into_iter()
+                    .filter(|&[x, y, z, index]| index != 0 && (x as u32) < vox_size.x && (y as u32) < vox_size.y && (z as u32) < vox_size.z)
+                    .map(|[x, y, z, index]| (uvec3(x as u32, z as u32, vox_size.y - 1 - y as u32), index))
+                    .collect();
+                Self { size: uvec3(vox_size.x, vox_size.z, vox_size.y), voxels, palette: palette.clone(), materials: materials.clone() }
+            })
+            .collect();
+        Ok(frames)
+    }
+
+    pub fn write(&self) -> Vec<u8> {
+        let mut children = Vec::new();
+        let vox_size = uvec3(self.size.x, self.size.z, self.size.y);
+        let mut size = Vec::new();
+        for axis in vox_size.to_array() {
+            size.extend(axis.to_le_bytes());
+        }
+        children.extend(chunk(b"SIZE", &size, &[]));
+        let mut voxels = (self.voxels.len() as u32).to_le_bytes().to_vec();
+        for &(pos, index) in &self.voxels {
+            voxels.extend([pos.x as u8, (self.size.z - 1 - pos.z) as u8, pos.y as u8, index]);
+        }
+        children.extend(chunk(b"XYZI", &voxels, &[]));
+        let mut colors = Vec::new();
+        for i in 1..=256 {
+            let albedo = self.palette.get(i % 256).map_or(UVec3::ZERO, |entry| entry.albedo);
+            colors.extend([albedo.x as u8, albedo.y as u8, albedo.z as u8, 255]);
+        }
+        children.extend(chunk(b"RGBA", &colors, &[]));
+        let used: HashSet<u8> = self.voxels.iter().map(|(_, index)| *index).collect();
+        let mut used: Vec<u8> = used.into_iter().collect();
+        used.sort();
+        for index in used {
+            let Some(material) = self.palette.get(index as usize).and_then(|entry| self.materials.get(entry.material)) else {
+                continue;
+            };
+            let mut content = (index as u32).to_le_bytes().to_vec();
+            write_dictionary(&mut content, &write_material(material));
+            children.extend(chunk(b"MATL", &content, &[]));
+        }
+        let mut file = b"VOX ".to_vec();
+        file.extend(150u32.to_le_bytes());
+        file.extend(chunk(b"MAIN", &[], &children));
+        file
+    }
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, self.write()).with_context(|| format!("Could not write '{}'", path.display()))
+    }
+
+    // the scene materials of the model's materials, in order
+    pub fn scene_materials(&self) -> Vec<crate::scene::Material> {
+        self.materials.iter().map(ModelMaterial::material).collect()
+    }
+    // write the voxels into a scene with the first one at offset. materials has the scene material of every model
+    // material, the ones that are missing get material 0. Returns the chunks that changed, like Scene::paint
+    pub fn import(&self, scene: &mut Scene, offset: UVec3, materials: &[MaterialId]) -> Vec<UVec3> {
+        let scene_size = scene.size.xyz().as_uvec3() * CHUNK_SIZE as u32;
+        let filled: HashSet<IVec3> = self.voxels.iter().map(|(pos, _)| pos.as_ivec3()).collect();
+        let edits = self.voxels.iter()
+            .filter(|(pos, _)| (*pos + offset).cmplt(scene_size).all())
+            .map(|&(pos, index)| {
+                let entry = self.palette[index as usize];
+                let material = materials.get(entry.material).copied().unwrap_or(0);
+                (pos + offset, Voxel::new(material, entry.albedo, super::exposed_normal(&filled, pos.as_ivec3())))
+            })
+            .collect();
+        scene.write_voxels(edits)
+    }
+}
+
+// a material and albedo, with the number of voxels that have them
+type ColorCount = ((MaterialId, UVec3), u32);
+
+// split the colors into at most count groups with close colors and the same material, median cut style:
+// every material starts in its own group, and the group with the widest range of colors is split in half until
+// there are enough groups
+fn median_cut(colors: Vec<ColorCount>, count: usize) -> Vec<Vec<ColorCount>> {
+    let mut groups: HashMap<MaterialId, Vec<ColorCount>> = HashMap::new();
+    for color in colors {
+        groups.entry(color.0 .0).or_default().push(color);
+    }
+    let mut groups: Vec<_> = groups.into_values().collect();
+    groups.sort_by_key(|group| group[0].0 .0);
+    // the widest channel of a group and its range
+    let range = |group: &[ColorCount]| {
+        let min = group.iter().map(|((_, albedo), _)| *albedo).fold(UVec3::splat(u32::MAX), UVec3::min);
+        let max = group.iter().map(|((_, albedo), _)| *albedo).fold(UVec3::ZERO, UVec3::max);
+        let extent = max - min;
+        let channel = if extent.x >= extent.y && extent.x >= extent.z { 0 } else if extent.y >= extent.z { 1 } else { 2 };
+        (channel, extent[channel])
+    };
+    if groups.iter().map(Vec::len).sum::<usize>() <= count {
+        // no need to merge anything
+        return groups.into_iter().flatten().map(|color| vec![color]).collect();
+    }
+    while groups.len() < count {
+        let Some((widest, _)) = groups.iter()
+            .enumerate()
+            .filter(|(_, group)| group.len() > 1)
+            .max_by_key(|(_, group)| range(group).1)
+        else {
+            break; // every group has one color
+        };
+        let mut group = groups.swap_remove(widest);
+        let channel = range(&group).0;
+        group.sort_by_key(|((_, albedo), _)| albedo[channel]);
+        // split where half of the voxels are on either side, leaving at least one color in each half
+        let half = group.iter().map(|(_, count)| count).sum::<u32>() / 2;
+        let mut seen = 0;
+        let split = group.iter().position(|(_, count)| {
+            seen += count;
+            seen >= half
+        });
+        let split = split.unwrap_or(0).min(group.len() - 2) + 1;
+        let upper = group.split_off(split);
+        groups.push(group);
+        groups.push(upper);
+    }
+    // palette entries of the same material next to each other
+    groups.sort_by_key(|group| group[0].0 .0);
+    groups
+}
+
+// a .vox chunk: its id, content and child chunks
+fn chunk(id: &[u8; 4], content: &[u8], children: &[u8]) -> Vec<u8> {
+    let mut chunk = id.to_vec();
+    chunk.extend((content.len() as u32).to_le_bytes());
+    chunk.extend((children.len() as u32).to_le_bytes());
+    chunk.extend(content);
+    chunk.extend(children);
+    chunk
+}
+
+fn write_dictionary(bytes: &mut Vec<u8>, dictionary: &[(&str, String)]) {
+    bytes.extend((dictionary.len() as u32).to_le_bytes());
+    for (key, value) in dictionary {
+        for string in [key.as_bytes(), value.as_bytes()] {
+            bytes.extend((string.len() as u32).to_le_bytes());
+            bytes.extend(string);
+        }
+    }
+}
+
+// the MATL properties of a material. MagicaVoxel only looks at the ones of the type, but all are written
+fn write_material(material: &ModelMaterial) -> Vec<(&'static str, String)> {
+    let emission = material.emission.max_element();
+    let kind = if emission > 0.0 {
+        "_emit"
+    } else if material.transmission > 0.0 {
+        "_glass"
+    } else if material.metallic > 0.0 {
+        "_metal"
+    } else {
+        "_diffuse"
+    };
+    let flux = emission.log2().ceil().clamp(0.0, 4.0);
+    vec![
+        ("_type", kind.to_string()),
+        ("_rough", material.roughness.to_string()),
+        ("_metal", material.metallic.to_string()),
+        ("_trans", material.transmission.to_string()),
+        ("_ior", (material.ior - 1.0).to_string()),
+        ("_emit", (emission / flux.exp2()).to_string()),
+        ("_flux", flux.to_string()),
+    ]
+}
+
+fn read_material(properties: &HashMap<String, String>) -> ModelMaterial {
+    let value = |key: &str| properties.get(key).and_then(|value| value.parse::<f32>().ok());
+    let default = ModelMaterial::default();
+    let emission = value("_emit").unwrap_or(0.0) * value("_flux").unwrap_or(0.0).exp2();
+    ModelMaterial {
+        metallic: value("_metal").unwrap_or(default.metallic),
+        roughness: value("_rough").unwrap_or(default.roughness),
+        transmission: value("_trans").unwrap_or(default.transmission),
+        ior: value("_ior").map_or(default.ior, |ior| ior + 1.0),
+        emission: Vec3::splat(emission),
+        ..default
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8]> {
+        let bytes = self.bytes.get(self.offset..self.offset + length).context("Truncated .vox file")?;
+        self.offset += length;
+        Ok(bytes)
+    }
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    // the id, content and children of the next chunk
+    fn chunk(&mut self) -> Result<([u8; 4], &'a [u8], &'a [u8])> {
+        let id = self.take(4)?.try_into().unwrap();
+        let content_length = self.u32()? as usize;
+        let children_length = self.u32()? as usize;
+        Ok((id, self.take(content_length)?, self.take(children_length)?))
+    }
+    fn string(&mut self) -> Result<String> {
+        let length = self.u32()? as usize;
+        Ok(String::from_utf8_lossy(self.take(length)?).into_owned())
+    }
+    fn dictionary(&mut self) -> Result<HashMap<String, String>> {
+        let count = self.u32()?;
+        (0..count).map(|_| Ok((self.string()?, self.string()?))).collect()
+    }
+}
@@ -7,8 +7,16 @@ var skybox_t: texture_cube<f32>;
 @group(0) @binding(2)
 var skybox_s: sampler; 
 
+// running average of the color of every pixel over the frames the camera has been still for
+@group(0) @binding(3)
+var<storage, read_write> accumulation: array<vec4<f32>>;
+
 struct Camera {
     position: vec4<f32>,
+    direction: vec4<f32>, // the direction the camera is looking in
+    projection: vec4<u32>, // x: 0 = perspective, 1 = orthographic
+    lens: vec4<f32>, // x: aperture radius, y: focus distance
+    frame: vec4<u32>, // x: frames accumulated since the camera or the scene last changed, y: frame number
     inv_view: mat4x4<f32>,
     inv_proj: mat4x4<f32>,
 }
@@ -34,42 +42,103 @@ struct Voxel {
     albedo: vec3<f32>,
     diffuse: vec3<f32>,
     specular: vec3<f32>,
+    occlusion: u32, // packed ambient occlusion of the faces, see face_occlusion
+    faces: u32, // which faces have light stored, see face_slot
 }
 struct CompressedVoxel {
     normal: u32, // material index(8), x(8), y(8), z(8)
-    albedo: u32, // r(8), g(8), b(8), spec.x(8)
-    spec_light: u32, // spec.y(8), spec.z(8), diff.x(16)
-    diff_light: u32, // diff.y(16), diff.z(16)
+    albedo: u32, // r(8), g(8), b(8), unused(8)
+    faces: u32, // the slot of the first exposed face in the chunk's block (26), which of the +x, -x, +y, -y, +z and -z faces are exposed (6)
+    occlusion: u32, // ambient occlusion of the +x, -x, +y, -y, +z and -z faces (5 bits each, from the lowest bits)
 }
 fn decompress_voxel(in: CompressedVoxel) -> Voxel {
     var out: Voxel;
     let nr = decompress_uvec4(in.normal);
     let ar = decompress_uvec4(in.albedo);
-    let sr = decompress_uvec4(in.spec_light);
-    let dr = decompress_uvec4(in.diff_light);
 
     out.normal = vec3<f32>(vec3<i32>(nr.yzw * 2u) - 255) / 255.0; // [0..255] -> [-1..1]
     out.material = nr.x;
     out.albedo = vec3<f32>(ar.xyz) / 255.0; // [0..255] -> [0..1]
-    let diffuse_high = vec3(sr.z, dr.x, dr.z) << vec3(8u);
-    let diffuse_low = vec3(sr.w, dr.y, dr.w);
-    let diffuse = diffuse_high | diffuse_low;
+    out.diffuse = vec3(0.0); // the light is stored per face, see with_face_light
+    out.specular = vec3(0.0);
+    out.occlusion = in.occlusion;
+    out.faces = in.faces;
+    return out;
+}
+
+struct FaceLight {
+    specular: u32, // r(8), g(8), b(8), unused(8)
+    diffuse_rg: u32, // r(16), g(16)
+    diffuse_b: u32, // b(16), unused(16)
+}
+// the lighting cache, with the light of every exposed voxel face. Every chunk owns a block of it
+@group(2) @binding(1)
+var<storage, read_write> face_lights: array<FaceLight>;
+// the chunks the lighting pass updates this frame, one workgroup each. See scene/schedule.rs
+@group(2) @binding(2)
+var<storage, read> lighting_chunks: array<u32>;
 
-    out.diffuse = vec3<f32>(diffuse) / 65535.0;
-    out.specular = vec3(f32(ar.w), vec2<f32>(sr.xy)) / 255.0;
+// the face a normal points out of, in the order +x, -x, +y, -y, +z, -z
+fn face_index(normal: vec3<f32>) -> u32 {
+    let a = abs(normal);
+    if a.x >= a.y && a.x >= a.z {
+        return select(1u, 0u, normal.x > 0.0);
+    } else if a.y >= a.z {
+        return select(3u, 2u, normal.y > 0.0);
+    }
+    return select(5u, 4u, normal.z > 0.0);
+}
+fn face_normal(face: u32) -> vec3<f32> {
+    let axis = face / 2u;
+    return vec3(f32(axis == 0u), f32(axis == 1u), f32(axis == 2u)) * (1.0 - 2.0 * f32(face % 2u));
+}
+// where the light of a face of a voxel in chunk_id is in face_lights, or -1 if the face isn't exposed
+fn face_slot(chunk_id: i32, faces: u32, face: u32) -> i32 {
+    let mask = faces & 0x3Fu;
+    if (mask & (1u << face)) == 0u {
+        return -1;
+    }
+    return i32(scene.chunk_map[chunk_id].face_offset + (faces >> 6u) + countOneBits(mask & ((1u << face) - 1u)));
+}
+// a voxel of chunk_id with the light of one of its faces
+fn with_face_light(vox: Voxel, chunk_id: i32, face: u32) -> Voxel {
+    var out = vox;
+    let slot = face_slot(chunk_id, vox.faces, face);
+    if slot < 0 {
+        return out;
+    }
+    let light = face_lights[slot];
+    out.specular = vec3<f32>(decompress_uvec4(light.specular).xyz) / 255.0;
+    out.diffuse = vec3<f32>(vec3(light.diffuse_rg >> 16u, light.diffuse_rg & 0xFFFFu, light.diffuse_b >> 16u)) / 65535.0 * DIFFUSE_RANGE;
     return out;
 }
 struct Chunk {
     accumulated_light_samples: u32,
+    face_offset: u32, // the start of this chunk's block in face_lights
+    face_count: u32, // the size of the block
     pos: vec4<f32>, // the chunk's position in the scene (x, y, z) and if chunk contains data (w = 0.0 if chunk is empty)
     voxels: array<CompressedVoxel, 512>,// don't want to hardcode the size like this ;_;
 }
 struct Material {
-    emissive: u32,
-    opacity: f32,
-    refraction_index: f32,
-    specular: f32,
-    shininess: f32,
+    base_color: vec3<f32>, // multiplies the voxel albedo
+    metallic: f32, // metals tint their reflections and have no diffuse light
+    emission: vec3<f32>, // emitted color, multiplied by the voxel albedo
+    emission_strength: f32, // 0 if the material doesn't emit light
+    roughness: f32, // 0 is a perfect mirror
+    transmission: f32, // how much light passes through, 0 is opaque
+    ior: f32, // index of refraction
+    specular: f32, // reflectance of dielectrics, 0.5 is 4% when looking straight at the surface
+    density: f32, // above 0 for participating media, which scatter light along rays instead of stopping them
+    anisotropy: f32, // Henyey-Greenstein anisotropy of the medium
+    absorption: f32, // the part of the stopped light that is absorbed instead of scattered
+}
+struct Fog {
+    color: vec3<f32>, // the part of the light scattered by the fog
+    density: f32, // per scene unit below height, 0 if there is no fog
+    height: f32,
+    falloff: f32, // how fast the density decreases above height
+    anisotropy: f32, // Henyey-Greenstein anisotropy
+    samples: u32, // points along every primary ray where the fog is lit
 }
 struct Scene {
     size: vec4<f32>,
@@ -77,12 +146,39 @@ struct Scene {
     sun_strength: vec4<f32>,
     ambient_light: vec4<f32>,
     time: u32,
+    seed: u32, // base seed for random numbers
+    frame: u32, // incremented every frame
+    sampling: u32, // how diffuse and shadow directions are picked, see SAMPLING_R2
+    specular_samples: u32, // specular rays per voxel per frame
+    ambient_occlusion: u32, // 1 if the ambient occlusion of the faces darkens the diffuse light
+    fog: Fog,
     chunk_map: array<Chunk, 512>, // change this!!!
-    materials: array<Material, 4>, // how make dynamically sized?
+    materials: array<Material, 16>, // NUM_MATERIALS in scene.rs
 }
 @group(2) @binding(0)
 var<storage, read_write> scene: Scene;
 
+// the dynamic objects, see scene/objects.rs
+struct Instance {
+    world_to_object: mat4x4<f32>, // from scene space to the voxels of the object
+    size: vec3<u32>, // in voxels
+    voxel_offset: u32, // where the voxels of the object start in object_voxels
+}
+struct BvhNode {
+    min: vec3<f32>, // the box around the instances below, in scene space
+    count: u32, // the number of instances of a leaf, BVH_INNER for inner nodes
+    max: vec3<f32>,
+    index: u32, // the first instance of a leaf, or the second child of an inner node. The first child comes right after the node
+}
+// the instances in the order of the BVH leaves, rebuilt every frame
+@group(2) @binding(3)
+var<storage, read> instances: array<Instance>;
+@group(2) @binding(4)
+var<storage, read> bvh: array<BvhNode>;
+// the voxels of every object one after the other, x first, then y, then z. The faces and occlusion are unused
+@group(2) @binding(5)
+var<storage, read> object_voxels: array<CompressedVoxel>;
+
 // whether or not a position is within the scene
 fn in_scene_bounds(pos: vec3<i32>) -> bool {
     let fpos = vec3<f32>(pos);
@@ -98,6 +194,20 @@ fn compressed_voxel_at(chunk_id: i32, pos_in_chunk: vec3<i32>) -> CompressedVoxe
     return (*chunk).voxels[idx]; 
 }
 
+// whether a voxel blocks ambient light, in voxel space so neighbours in other chunks can be looked up
+fn is_occluder(voxel_pos: vec3<i32>) -> bool {
+    let scene_pos = voxel_pos / CHUNK_SIZE;
+    if any(voxel_pos < vec3(0)) || !in_scene_bounds(scene_pos) {
+        return false;
+    }
+    let chunk_id = get_scene_index(scene_pos);
+    if scene.chunk_map[chunk_id].pos.w == 0.0 {
+        return false;
+    }
+    let material = compressed_voxel_at(chunk_id, voxel_pos % CHUNK_SIZE).normal >> 24u;
+    return material != 255u && scene.materials[material].density <= 0.0;
+}
+
 // the index into the scene array that corresponds to a 3d position
 fn get_scene_index(pos: vec3<i32>) -> i32 {
     let isize = vec3<i32>(scene.size.xyz);
@@ -153,6 +263,7 @@ struct StepResult {
     color_mul: f32,
     // TODO: refraction
     debug: u32, // to be removed, useful for drawing debug info
+    object: bool, // whether a dynamic object was hit instead of the world
 }
 
 fn step_scene(ray: Ray, ignore_first: bool) -> StepResult {
@@ -160,19 +271,23 @@ fn step_scene(ray: Ray, ignore_first: bool) -> StepResult {
 
     var result: StepResult;
     result.hit = false;
+    result.object = false;
     result.color_add = vec3(0.0);
     result.color_mul = 1.0;
+    // the world is only traced up to the closest object
+    let object_hit = trace_objects(ray);
     var last_side_dist = vec3(0.0);
     var dda: DDA = init_DDA(ray);
     var normal = box_normal(ray.position, vec3(0.0), scene.size.xyz);
-    while in_scene_bounds(dda.pos) {
+    while in_scene_bounds(dda.pos) && min(min(last_side_dist.x, last_side_dist.y), last_side_dist.z) <= object_hit.t {
         let chunk_id = get_scene_index(dda.pos);
         let chunk = scene.chunk_map[chunk_id];
         if chunk.pos.w != 0.0 {  // the chunk has non-empty voxels
             var chunk_ray: Ray = dda.ray; // ray to use for traversing in the chunk
-            let updated_ray_pos = dda.ray.position + dda.ray.direction * (min(min(last_side_dist.x, last_side_dist.y), last_side_dist.z) - EPSILON); // move to the chunk bounds
+            let chunk_t = min(min(last_side_dist.x, last_side_dist.y), last_side_dist.z) - EPSILON;
+            let updated_ray_pos = dda.ray.position + dda.ray.direction * chunk_t; // move to the chunk bounds
             chunk_ray.position = clamp((updated_ray_pos - vec3<f32>(dda.pos)) * vec3(f32(CHUNK_SIZE)), vec3(EPSILON), vec3(f32(CHUNK_SIZE)) - EPSILON); // set position relative to chunk bounds
-            result = step_chunk(chunk_ray, chunk_id, ignore_first, result);
+            result = step_chunk(chunk_ray, chunk_id, ignore_first, (object_hit.t - chunk_t) * f32(CHUNK_SIZE), result);
             if result.hit {
                 result.new_pos = vec3<f32>(dda.pos) + result.new_pos / f32(CHUNK_SIZE); // hit position in scene space
                 return result;
@@ -182,39 +297,129 @@ fn step_scene(ray: Ray, ignore_first: bool) -> StepResult {
         normal = step_DDA(&dda);
         ignore_first = false;
     }
+    if object_hit.hit {
+        result.hit = true;
+        result.object = true;
+        result.new_pos = ray_at(ray, object_hit.t - EPSILON);
+        result.normal = object_hit.normal;
+        result.voxel = object_hit.voxel;
+    }
     return result;
 }
 
+struct ObjectHit {
+    hit: bool, // if this is false, t is the largest distance and the rest of the data is invalid
+    t: f32, // how far along the ray the hit is, in units of the ray direction
+    normal: vec3<f32>, // the face normal of the hit voxel, in scene space
+    voxel: Voxel,
+}
+
+// the closest voxel of any instance along a ray, found by walking the BVH
+fn trace_objects(ray: Ray) -> ObjectHit {
+    var closest: ObjectHit;
+    closest.hit = false;
+    closest.t = 1e30; // farther than anything in the scene
+    var stack: array<u32, 32>; // BVH_STACK_SIZE
+    var stack_size = 1;
+    stack[0] = 0u;
+    while stack_size > 0 {
+        stack_size--;
+        let node_id = stack[stack_size];
+        let node = bvh[node_id];
+        let span = intersect_box(ray, node.min, node.max);
+        if span.x > span.y || span.y < 0.0 || span.x > closest.t { // missed, or behind something closer
+            continue;
+        }
+        if node.count != BVH_INNER {
+            for (var i: u32 = node.index; i < node.index + node.count; i++) {
+                closest = trace_object(ray, i, closest);
+            }
+        } else if stack_size + 2 <= BVH_STACK_SIZE {
+            stack[stack_size] = node.index;
+            stack[stack_size + 1] = node_id + 1u;
+            stack_size += 2;
+        }
+    }
+    return closest;
+}
+
+// the closest voxel of an instance along a ray, if it's closer than the closest hit so far
+fn trace_object(ray: Ray, instance_id: u32, closest: ObjectHit) -> ObjectHit {
+    let instance = instances[instance_id];
+    let size = vec3<f32>(instance.size);
+    var object_ray: Ray;
+    object_ray.position = (instance.world_to_object * vec4(ray.position, 1.0)).xyz;
+    object_ray.direction = (instance.world_to_object * vec4(ray.direction, 0.0)).xyz; // not normalized, so distances along it are the same as along the ray
+    object_ray.inv_direction = 1.0 / object_ray.direction;
+    let span = intersect_box(object_ray, vec3(0.0), size);
+    if span.x > span.y || span.y < 0.0 || span.x > closest.t {
+        return closest;
+    }
+    // DDA through the object from where the ray enters it
+    let start = max(span.x, 0.0);
+    object_ray.position = clamp(ray_at(object_ray, start), vec3(EPSILON), size - EPSILON);
+    var dda: DDA = init_DDA(object_ray);
+    var normal = box_normal(object_ray.position, vec3(0.0), size);
+    var t = start;
+    while all(dda.pos >= vec3(0)) && all(vec3<u32>(dda.pos) < instance.size) && t < closest.t {
+        let idx = u32(dda.pos.x) + instance.size.x * (u32(dda.pos.y) + instance.size.y * u32(dda.pos.z));
+        let compressed = object_voxels[instance.voxel_offset + idx];
+        if (compressed.normal >> 24u) != 255u {
+            // normals are turned with the inverse transpose of the transform, which is the transpose of world_to_object
+            let to_world = mat3x3(instance.world_to_object[0].xyz, instance.world_to_object[1].xyz, instance.world_to_object[2].xyz);
+            var result: ObjectHit;
+            result.hit = true;
+            result.t = t;
+            result.normal = normalize(normal * to_world);
+            result.voxel = decompress_voxel(compressed);
+            result.voxel.normal = normalize(result.voxel.normal * to_world);
+            return result;
+        }
+        t = start + min(min(dda.side_dist.x, dda.side_dist.y), dda.side_dist.z); // where the ray enters the next voxel
+        normal = step_DDA(&dda);
+    }
+    return closest;
+}
+
 var<private> last_vox_id: u32 = 255u; // the last hit voxel's albedo and material, used for transparency
 var<private> last_vox_refract: f32 = 1.0; // last hit voxel's refraction index, used for TODO: refraction
 
-fn step_chunk(chunk_ray: Ray, chunk_id: i32, ignore_first: bool, partial_result: StepResult) -> StepResult {
+// max_t is how far along the chunk ray voxels can be hit before something closer than them was already found
+fn step_chunk(chunk_ray: Ray, chunk_id: i32, ignore_first: bool, max_t: f32, partial_result: StepResult) -> StepResult {
     var ignore_first: bool = ignore_first;
     var result: StepResult = partial_result;
     result.hit = false;
     var last_side_dist = vec3(0.0);
     var dda: DDA = init_DDA(chunk_ray);
     var normal = box_normal(chunk_ray.position, vec3(0.0), vec3(f32(CHUNK_SIZE)));
-    while in_chunk_bounds(dda.pos) {
+    while in_chunk_bounds(dda.pos) && min(min(last_side_dist.x, last_side_dist.y), last_side_dist.z) <= max_t {
         let compressed = compressed_voxel_at(chunk_id, dda.pos);
         let vox_id = (compressed.albedo & 0xFFFFFF00u) | (compressed.normal >> 24u);
         if (vox_id & 0xFFu) != 255u && !ignore_first { // would be a constant for MATERIAL_EMPTY instead of 255
             let vox = decompress_voxel(compressed); 
             let material = scene.materials[vox.material];
-            if material.opacity >= 1.0 {
+            if material.density > 0.0 { // participating media scatter and absorb light along the ray instead of stopping it
+                let ray_length = min(min(dda.side_dist.x, dda.side_dist.y), dda.side_dist.z) - min(min(last_side_dist.x, last_side_dist.y), last_side_dist.z); // length of the ray in this voxel
+                let transmittance = exp(-material.density * ray_length);
+                let in_scattered = surface_color(vox) * (1.0 - material.absorption) * with_face_light(vox, chunk_id, 0u).diffuse + emitted_light(vox); // media are lit as a whole, in the slot of the first face
+                result.color_add += result.color_mul * (1.0 - transmittance) * in_scattered;
+                result.color_mul *= transmittance;
+                last_vox_id = vox_id;
+            }
+            else if material.transmission <= 0.0 {
                 result.hit = true;
                 result.new_pos = vec3<f32>(dda.pos) + dda.ray.direction * (min(min(last_side_dist.x, last_side_dist.y), last_side_dist.z) - EPSILON);
                 result.normal = normal;
-                result.voxel = vox;
+                result.voxel = with_face_light(vox, chunk_id, face_index(normal));
                 result.debug = compressed.albedo & 0xFFu;//vec2(get_chunk_index(dda.pos), chunk_id);
                 return result;
             }
             else if last_vox_id != vox_id {
-                result.color_add += result.color_mul * material.opacity * vox.albedo * scene.sun_strength.xyz;
-                result.color_mul *= 1.0 - material.opacity;
+                result.color_add += result.color_mul * (1.0 - material.transmission) * surface_color(vox) * scene.sun_strength.xyz;
+                result.color_mul *= material.transmission;
                 //TODO: refraction
                 last_vox_id = vox_id;
-                last_vox_refract = material.refraction_index;
+                last_vox_refract = material.ior;
             }
         }
         else if last_vox_id != 255u {
@@ -232,6 +437,13 @@ fn step_chunk(chunk_ray: Ray, chunk_id: i32, ignore_first: bool, partial_result:
 var<private> EPSILON: f32 = 0.0001; // I have to do this instead of constants at the moment, since Naga doesn't have constants yet.
 //var<private> CHUNK_SIZE: vec3<i32> = vec3(8); // THIS CONST EXPR ISN'T IMPLEMENTED
 var<private> CHUNK_SIZE: i32 = 8;
+var<private> ORTHOGRAPHIC: u32 = 1u; // camera.projection.x for orthographic cameras
+var<private> SAMPLING_R2: u32 = 1u; // scene.sampling for the R2 low discrepancy sequence
+var<private> DIFFUSE_RANGE: f32 = 4.0; // the brightest diffuse light that can be stored, so lights can be brighter than white
+var<private> AO_RADIUS: i32 = 3; // how many voxels in front of a face can occlude it
+var<private> SPECULAR_HISTORY: f32 = 15.0; // how many frames of specular light are kept. Reflections move with the camera, so unlike diffuse light this has to be short
+var<private> BVH_INNER: u32 = 0xFFFFFFFFu; // BvhNode::count of nodes that have children instead of instances
+var<private> BVH_STACK_SIZE: i32 = 32; // the size of the stack of nodes still to visit in trace_objects
 
 
 fn mandelbrot(pos: vec2<f32>) -> vec3<f32> {
@@ -268,6 +480,98 @@ fn box_normal(intersect_pos: vec3<f32>, box_min: vec3<f32>, box_max: vec3<f32>)
     return normalize(trunc(p / d * bias));
 }
 
+// the albedo of a voxel multiplied by its material's base color
+fn surface_color(vox: Voxel) -> vec3<f32> {
+    return vox.albedo * scene.materials[vox.material].base_color;
+}
+// the light emitted by a voxel, which can be brighter than white
+fn emitted_light(vox: Voxel) -> vec3<f32> {
+    let material = scene.materials[vox.material];
+    return surface_color(vox) * material.emission * material.emission_strength;
+}
+
+// the Henyey-Greenstein phase function relative to scattering the same amount in every direction,
+// where cos_theta is between the direction the light comes from and the direction it leaves in
+fn henyey_greenstein(cos_theta: f32, g: f32) -> f32 {
+    let g2 = g * g;
+    return (1.0 - g2) / pow(max(1.0 + g2 - 2.0 * g * cos_theta, EPSILON), 1.5);
+}
+
+fn fog_density(height: f32) -> f32 {
+    return scene.fog.density * exp(-scene.fog.falloff * max(height - scene.fog.height, 0.0));
+}
+
+struct FogResult {
+    transmittance: f32, // how much of the light behind the fog gets through
+    light: vec3<f32>, // light scattered toward the ray origin
+}
+// march through the fog along the first ray_length of a ray, casting shadow rays toward the sun at every sample for light shafts
+fn march_fog(ray: Ray, ray_length: f32, rng: ptr<function, u32>) -> FogResult {
+    var result: FogResult;
+    result.transmittance = 1.0;
+    result.light = vec3(0.0);
+    let samples = max(scene.fog.samples, 1u);
+    let step = ray_length / f32(samples);
+    let phase = henyey_greenstein(dot(ray.direction, scene.sun_direction.xyz), scene.fog.anisotropy);
+    let jitter = rand(rng); // offset the samples so the steps don't show as bands
+    for (var i: u32 = 0u; i < samples; i++) {
+        let pos = ray_at(ray, (f32(i) + jitter) * step);
+        let transmittance = exp(-fog_density(pos.y) * step);
+        var sun_ray: Ray;
+        sun_ray.position = pos;
+        sun_ray.direction = scene.sun_direction.xyz + EPSILON;
+        sun_ray.inv_direction = 1.0 / sun_ray.direction;
+        var in_scattered = scene.ambient_light.xyz;
+        let info = step_scene(sun_ray, false);
+        if !info.hit {
+            in_scattered += (scene.sun_strength.xyz * info.color_mul + info.color_add) * phase;
+        }
+        result.light += result.transmittance * (1.0 - transmittance) * scene.fog.color * in_scattered;
+        result.transmittance *= transmittance;
+    }
+    return result;
+}
+
+// how much of the space in front of each face of a voxel is empty, from 0 for an enclosed face to 1 for an open one.
+// Looks at a pyramid of voxels in front of every face, where closer voxels count more
+fn ambient_occlusion(voxel_pos: vec3<i32>) -> u32 {
+    var packed = 0u;
+    for (var face: i32 = 0; face < 6; face++) {
+        let axis = face / 2;
+        let normal = vec3(select(0, 1, axis == 0), select(0, 1, axis == 1), select(0, 1, axis == 2)) * (1 - 2 * (face % 2));
+        let tangent = abs(normal.yzx);
+        let bitangent = abs(normal.zxy);
+        var occluded = 0.0;
+        var total = 0.0;
+        for (var depth: i32 = 1; depth <= AO_RADIUS; depth++) {
+            let weight = 1.0 / f32(depth);
+            for (var a: i32 = 1 - depth; a < depth; a++) {
+                for (var b: i32 = 1 - depth; b < depth; b++) {
+                    if is_occluder(voxel_pos + normal * depth + tangent * a + bitangent * b) {
+                        occluded += weight;
+                    }
+                    total += weight;
+                }
+            }
+        }
+        packed |= u32(round((1.0 - occluded / total) * 31.0)) << (5u * u32(face));
+    }
+    return packed;
+}
+
+// the ambient occlusion of the face of a voxel with the given normal, or 1 if it's turned off
+fn face_occlusion(occlusion: u32, face_normal: vec3<f32>) -> f32 {
+    if scene.ambient_occlusion == 0u {
+        return 1.0;
+    }
+    return f32((occlusion >> (5u * face_index(face_normal))) & 31u) / 31.0;
+}
+
+// the diffuse and ambient light on the face of a voxel, darkened in corners right away instead of once enough rays found them
+fn diffuse_light(vox: Voxel, face_normal: vec3<f32>) -> vec3<f32> {
+    return (vox.diffuse + scene.ambient_light.xyz) * face_occlusion(vox.occlusion, face_normal);
+}
+
 fn skybox_color(direction: vec3<f32>) -> vec3<f32> {
     return textureSampleLevel(skybox_t, skybox_s, direction, 0.0).xyz * scene.sun_strength.xyz;
 }
@@ -275,10 +579,14 @@ fn voxel_color(info: StepResult) -> vec3<f32> {
     let vox = info.voxel;
     let material = scene.materials[vox.material];
     var solid_color: vec3<f32>;
-    if material.emissive != 0u { // material is emissive
-        solid_color = vox.albedo;
+    if material.emission_strength > 0.0 { // material is emissive
+        solid_color = emitted_light(vox);
     } else {
-        solid_color = vox.albedo * vox.diffuse + vox.specular;
+        var light = vox.diffuse; // dynamic objects get all of their light from object_light
+        if !info.object {
+            light = diffuse_light(vox, info.normal);
+        }
+        solid_color = surface_color(vox) * light * (1.0 - material.metallic) + vox.specular;
     }
     return solid_color * info.color_mul + info.color_add; // total lighting 
     // return solid_color * sin(f32(scene.time / 10)) * info.color_mul + info.color_add; // visualize time
@@ -288,23 +596,93 @@ fn voxel_color(info: StepResult) -> vec3<f32> {
     // return vox.albedo; // albedo
 }
 
+// the light on a voxel of a dynamic object, which isn't in the lighting cache: the ambient light, the sky straight
+// out from the face and the sun, with hard shadows so it doesn't have to be averaged over frames
+fn object_light(info: StepResult) -> vec3<f32> {
+    var light = scene.ambient_light.xyz;
+    var sky_ray: Ray;
+    sky_ray.position = info.new_pos;
+    sky_ray.direction = info.normal + EPSILON;
+    sky_ray.inv_direction = 1.0 / sky_ray.direction;
+    let sky = step_scene(sky_ray, false);
+    if !sky.hit {
+        light += skybox_color(info.normal) * sky.color_mul + sky.color_add;
+    }
+    let facing = dot(info.normal, scene.sun_direction.xyz);
+    if facing <= 0.0 {
+        return light;
+    }
+    var sun_ray: Ray;
+    sun_ray.position = info.new_pos;
+    sun_ray.direction = scene.sun_direction.xyz + EPSILON;
+    sun_ray.inv_direction = 1.0 / sun_ray.direction;
+    let shadow = step_scene(sun_ray, false);
+    if !shadow.hit {
+        light += (scene.sun_strength.xyz * shadow.color_mul + shadow.color_add) * facing;
+    }
+    return light;
+}
+
+// the ray through a pixel, in screen space
+fn camera_ray(screen_pos: vec2<f32>) -> Ray {
+    var ray: Ray;
+    if camera.projection.x == ORTHOGRAPHIC {
+        // every ray goes in the same direction, starting from the pixel's position on the near plane
+        let view_pos = camera.inv_proj * vec4(screen_pos, 0.0, 1.0);
+        ray.position = (camera.inv_view * vec4(view_pos.xyz / view_pos.w, 1.0)).xyz;
+        ray.direction = camera.direction.xyz + EPSILON;
+    } else {
+        var inv_view_centered: mat4x4<f32> = camera.inv_view; // the camera's inverse view matrix but without the translation
+        inv_view_centered[3] = vec4(0.0, 0.0, 0.0, 1.0);
+        ray.position = camera.position.xyz;
+        ray.direction = normalize((inv_view_centered * camera.inv_proj * vec4(screen_pos, 0.0, 1.0)).xyz) + EPSILON;
+    }
+    ray.inv_direction = 1.0 / ray.direction;
+    return ray;
+}
+
+// move a pinhole camera ray to a random point on the lens, keeping the point where it crosses the focus plane
+fn thin_lens_ray(pinhole: Ray, rng: ptr<function, u32>) -> Ray {
+    // the focus plane is perpendicular to the view direction
+    let focus_point = ray_at(pinhole, camera.lens.y / dot(pinhole.direction, camera.direction.xyz));
+    // uniformly distributed point on a disk
+    let angle = 2.0 * 3.1415926 * rand(rng);
+    let radius = camera.lens.x * sqrt(rand(rng));
+    let right = camera.inv_view[0].xyz;
+    let up = camera.inv_view[1].xyz;
+    var ray: Ray;
+    ray.position = pinhole.position + (right * cos(angle) + up * sin(angle)) * radius;
+    ray.direction = normalize(focus_point - ray.position) + EPSILON;
+    ray.inv_direction = 1.0 / ray.direction;
+    return ray;
+}
+
+// the direction from the camera to a point in the scene
+fn view_direction(pos: vec3<f32>) -> vec3<f32> {
+    if camera.projection.x == ORTHOGRAPHIC {
+        return camera.direction.xyz;
+    }
+    return normalize(pos - camera.position.xyz);
+}
+
 @compute @workgroup_size(16, 16, 1) // Does the raytracing from the camera to the closest voxel, drawing the color to the final texture.
 fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
     var out_color: vec3<f32>;    
 
     let texture_pos = vec2<i32>(global_id.xy); // cast to i32 so we can use in textureStore
     let texture_dim = textureDimensions(screen);
+    if any(global_id.xy >= texture_dim) { // outside the screen, but in a workgroup that's partially on screen
+        return;
+    }
     let screen_pos = (vec2<f32>(texture_pos) / vec2<f32>(texture_dim)) * 2.0 - 1.0; // pixel position in screen space
+    let pixel_idx = global_id.x + global_id.y * texture_dim.x;
     
-    var ray_pos: vec3<f32> = camera.position.xyz;
-    var inv_view_centered: mat4x4<f32> = camera.inv_view; // the camera's inverse view matrix but without the translation
-    inv_view_centered[3] = vec4(0.0, 0.0, 0.0, 1.0);
-    let ray_dir = normalize((inv_view_centered * camera.inv_proj * vec4(screen_pos, 0.0, 1.0)).xyz) + EPSILON;
-
-    var ray: Ray;
-    ray.position = ray_pos;
-    ray.direction = ray_dir;
-    ray.inv_direction = 1.0 / ray_dir;
+    var ray: Ray = camera_ray(screen_pos);
+    var rng: u32 = hashed_seed(vec3(global_id.xy, 0u), camera.frame.y);
+    let depth_of_field = camera.lens.x > 0.0;
+    if depth_of_field {
+        ray = thin_lens_ray(ray, &rng);
+    }
 
     let scene_intersection = intersect_box(ray, vec3(0.0), scene.size.xyz);
     if scene_intersection.x > scene_intersection.y || scene_intersection.y < 0.0 { // missed map if near > far or far < 0
@@ -316,107 +694,148 @@ fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
         }
         var final_normal = box_normal(ray.position, vec3(0.0), scene.size.xyz);
         
-        let final_info = step_scene(ray, false);
+        var final_info = step_scene(ray, false);
         if final_info.hit {
             //let dist = distance(ray_pos, final_info.new_pos) / (scene.size.x * 4.0);
             //out_color = vec3(1.0-dist);
+            if final_info.object {
+                final_info.voxel.diffuse = object_light(final_info);
+            }
             out_color = voxel_color(final_info);
         } else {
             out_color = skybox_color(ray.direction) * final_info.color_mul + final_info.color_add;
         }
+        if scene.fog.density > 0.0 {
+            // the fog between the camera and the hit voxel, or the edge of the scene
+            var fog_distance = scene_intersection.y - max(scene_intersection.x, 0.0);
+            if final_info.hit {
+                fog_distance = distance(ray.position, final_info.new_pos);
+            }
+            let fog = march_fog(ray, fog_distance, &rng);
+            out_color = out_color * fog.transmittance + fog.light;
+        }
     }
     //out_color = mandelbrot((screen_pos - vec2(0.25, 0.0)) * vec2(1.0, 0.75));
     //out_color = vec3(normalize(scene_intersection.xy), 0.0);
     //out_color = vec3(vec2<f32>(texture_pos)/vec2<f32>(texture_dim), 0.0);
     //out_color = vec3(screen_pos, 0.0);
+    if depth_of_field || scene.fog.density > 0.0 { // average over the lens and fog samples of the frames since the camera or the scene last changed
+        let accumulated_frames = f32(camera.frame.x);
+        out_color = (accumulation[pixel_idx].xyz * accumulated_frames + out_color) / (accumulated_frames + 1.0);
+        accumulation[pixel_idx] = vec4(out_color, 1.0);
+    }
     textureStore(screen, texture_pos, vec4<f32>(out_color, 1.0));
 }
 
 
-// Performs lighting calculations for every voxel in the scene, storing the output in the voxels themselves
+// Performs lighting calculations for every voxel in the chunks of lighting_chunks, storing the output in the voxels themselves
 @compute @workgroup_size(8, 8, 8)
 fn lighting_main(@builtin(workgroup_id) wg_id: vec3<u32>, @builtin(local_invocation_id) invoc_id: vec3<u32>) {
 
     let num_diffuse_samples = 1;
 
-    let scene_pos = vec3<i32>(wg_id);
+    let scene_pos = vec3<i32>(scene.chunk_map[lighting_chunks[wg_id.x]].pos.xyz);
     let pos_in_chunk = vec3<i32>(invoc_id);
     let scene_idx = get_scene_index(scene_pos);
     let chunk_idx = get_chunk_index(pos_in_chunk);
     if !in_chunk_bounds(pos_in_chunk) || !in_scene_bounds(scene_pos) || scene.chunk_map[scene_idx].pos.w == 0.0 { // don't bother with lighting for empty or oob chunks
         return;
     }
+    let voxel_pos = vec3<u32>(scene_pos * CHUNK_SIZE + pos_in_chunk);
+    let voxel_seed = hashed_seed(voxel_pos, scene.frame);
+    let voxel_offsets = vec4(hashed_seed(voxel_pos, SEQUENCE_SALT), hashed_seed(voxel_pos, SEQUENCE_SALT + 1u), hashed_seed(voxel_pos, SEQUENCE_SALT + 2u), hashed_seed(voxel_pos, SEQUENCE_SALT + 3u));
     let accumulated_samples = f32(min(scene.chunk_map[scene_idx].accumulated_light_samples, 1000u));
     if accumulated_samples == 0.0 {
         first_sample = true;
     }
     let compressed = compressed_voxel_at(scene_idx, pos_in_chunk);
-    let this_voxel = decompress_voxel(compressed);
-    let this_material = scene.materials[this_voxel.material];
-    // start the ray at the center of the voxel
+    let voxel = decompress_voxel(compressed);
+    let this_material = scene.materials[voxel.material];
+    let is_volume = this_material.density > 0.0;
     let inv_chunk_size = vec3(1.0/f32(CHUNK_SIZE)); 
     let half_inv_chunk_size = inv_chunk_size / 2.0;
-    var ray_pos: vec3<f32> = vec3<f32>(pos_in_chunk) * inv_chunk_size // the number of eights of a chunk away from the chunk origin corner
+    let voxel_center = vec3<f32>(pos_in_chunk) * inv_chunk_size // the number of eights of a chunk away from the chunk origin corner
         + vec3<f32>(scene_pos) // the chunk origin corner
-        + half_inv_chunk_size  // to the middle of the voxel
-        + (half_inv_chunk_size - vec3(EPSILON)) * this_voxel.normal; // bias slightly on the normal of the vector
-    
-    // TODO: should be moved into a struct probably
-    var spec_light: vec3<f32> = vec3(0.0);
-    var diff_light: vec3<f32> = vec3(0.0);
+        + half_inv_chunk_size; // to the middle of the voxel
+
+    // light every exposed face from its own normal, so light doesn't leak through thin walls. Media are lit once, from the center
+    for (var face: u32 = 0u; face < 6u; face++) {
+        let slot = face_slot(scene_idx, compressed.faces, face);
+        if slot < 0 {
+            continue;
+        }
+        var this_voxel = with_face_light(voxel, scene_idx, face);
+        this_voxel.normal = select(face_normal(face), vec3(0.0), is_volume);
+        let seed = hash(voxel_seed + face);
+        // every face walks its own shift of the sequences, so the faces of a voxel don't sample the same directions
+        let face_salt = hash(SEQUENCE_SALT + face);
+        sequence_offsets = voxel_offsets ^ vec4(face_salt);
+        // start the ray at the center of the face
+        let ray_pos = voxel_center + (half_inv_chunk_size - vec3(EPSILON)) * this_voxel.normal;
+
+        // TODO: should be moved into a struct probably
+        var spec_light: vec3<f32> = vec3(0.0);
+        var diff_light: vec3<f32> = vec3(0.0);
     
-    let view_dir = normalize(ray_pos - camera.position.xyz);
-    // specular rays
-    if this_material.specular > 0.0 && dot(view_dir, this_voxel.normal) < 0.0 { // view ray is looking at the voxel face from the front
-        var sphere_points = array<vec3<f32>,15>( // points on unit sphere, taken from DoonEngine. Must be var since there are no consts and let-bindings can't be indexed by non consts
-            vec3(0.000000, 1.000000, 0.000000), 
-            vec3(-0.379803, 0.857143, 0.347931), 
-            vec3(0.061185, 0.714286, -0.697174), 
-            vec3(0.499316, 0.571429, 0.651270), 
-            vec3(-0.889696, 0.428571, -0.157375), 
-            vec3(0.808584, 0.285714, -0.514354), 
-            vec3(-0.256942, 0.142857, 0.955810), 
-            vec3(-0.460906, 0.000000, -0.887449), 
-            vec3(0.929687, -0.142857, 0.339521), 
-            vec3(-0.885815, -0.285714, 0.365650), 
-            vec3(0.382949, -0.428571, -0.818338), 
-            vec3(0.245607, -0.571429, 0.783037), 
-            vec3(-0.605521, -0.714286, -0.350913), 
-            vec3(0.503065, -0.857143, -0.110596), 
-            vec3(-0.000000, -1.000000, 0.000000)
-        );
-
-        let reflected = reflect(view_dir, this_voxel.normal);
-        for (var i: i32 = 0; i < 15; i++) {
-            let specular_dir = normalize(reflected * this_material.shininess + sphere_points[i]) + EPSILON;
-            var spec_ray: Ray;
-            spec_ray.direction = specular_dir;
-            spec_ray.inv_direction = 1.0 / specular_dir;
-            spec_ray.position = ray_pos;
-            spec_light = specular_ray(scene_pos, spec_ray, this_voxel, spec_light);
+        let view_dir = view_direction(ray_pos);
+        // specular rays
+        // rough dielectrics reflect so little that their specular light is skipped
+        let has_specular = !is_volume && (this_material.metallic > 0.0 || this_material.roughness < 1.0);
+        if has_specular && dot(view_dir, this_voxel.normal) < 0.0 { // view ray is looking at the voxel face from the front
+            let alpha = this_material.roughness * this_material.roughness;
+            let f0 = mix(vec3(0.08 * this_material.specular), surface_color(this_voxel), this_material.metallic); // reflectance when looking straight at the surface
+            let specular_offset = vec2(hashed_seed(voxel_pos, SEQUENCE_SALT + 4u), hashed_seed(voxel_pos, SEQUENCE_SALT + 5u)) ^ vec2(face_salt);
+            var spec_rng = hash(seed + 2u * u32(num_diffuse_samples)); // after the seeds of the diffuse rays
+            let samples = max(scene.specular_samples, 1u);
+            var new_spec = vec3(0.0);
+            for (var i: u32 = 0u; i < samples; i++) {
+                let u = sample_square(&spec_rng, specular_offset, scene.frame * samples + i);
+                let half_vector = sample_ggx(this_voxel.normal, alpha, u);
+                let specular_dir = reflect(view_dir, half_vector);
+                if dot(specular_dir, this_voxel.normal) <= 0.0 { // the sample went into the surface, so its light is absorbed
+                    continue;
+                }
+                var spec_ray: Ray;
+                spec_ray.direction = specular_dir + EPSILON;
+                spec_ray.inv_direction = 1.0 / spec_ray.direction;
+                spec_ray.position = ray_pos;
+                let weight = ggx_weight(this_voxel.normal, -view_dir, specular_dir, half_vector, alpha, f0);
+                new_spec = specular_ray(scene_pos, spec_ray, weight, new_spec);
+            }
+            new_spec /= f32(samples);
+            let history = min(accumulated_samples, SPECULAR_HISTORY);
+            spec_light = (new_spec + this_voxel.specular * history) / (history + 1.0);
         }
-        spec_light /= 15.0;
-    }
-    // diffuse + shadow rays
-    if this_material.specular < 1.0 {
-        for (var i: i32 = 0; i < num_diffuse_samples; i++) {
-            diff_light += scene.ambient_light.xyz;
-            diff_light = diffuse_ray(ray_pos, this_voxel, scene.time * (u32(i) + 1u), diff_light);
-            diff_light = shadow_ray(ray_pos, scene.time * (u32(i) + 2u), diff_light);
+        // diffuse + shadow rays
+        if !is_volume && this_material.metallic < 1.0 {
+            for (var i: i32 = 0; i < num_diffuse_samples; i++) {
+                diff_light = diffuse_ray(ray_pos, this_voxel, hash(seed + 2u * u32(i)), diff_light);
+                diff_light = shadow_ray(ray_pos, hash(seed + 2u * u32(i) + 1u), diff_light);
+            }
+            diff_light = (diff_light + this_voxel.diffuse * accumulated_samples) / (accumulated_samples + f32(num_diffuse_samples));
+        }
+        // light scattered toward the camera by media. It depends on the view direction, so like specular light only a few frames are kept
+        if is_volume {
+            let sun_light = shadow_ray(ray_pos, hash(seed + 1u), vec3(0.0));
+            let in_scattered = scene.ambient_light.xyz + sun_light * henyey_greenstein(dot(view_dir, scene.sun_direction.xyz), this_material.anisotropy);
+            let history = min(accumulated_samples, SPECULAR_HISTORY);
+            diff_light = (in_scattered + this_voxel.diffuse * history) / (history + 1.0);
         }
-        diff_light = (diff_light + this_voxel.diffuse * accumulated_samples) / (accumulated_samples + f32(num_diffuse_samples));
+        // as this will be assumed to be in the range 0-1 (0-DIFFUSE_RANGE for diffuse) when compressing and decompressing:
+        spec_light = clamp(spec_light, vec3(0.0), vec3(1.0));
+        diff_light = clamp(diff_light, vec3(0.0), vec3(DIFFUSE_RANGE));
+
+        let store_diffuse = vec3<u32>(round(diff_light / DIFFUSE_RANGE * 65535.0)); // stored as 16-bit x, y, z
+        let store_specular = vec3<u32>(round(spec_light * 255.0));
+        face_lights[slot].specular = compress_uvec4(vec4(store_specular, 0u));
+        face_lights[slot].diffuse_rg = (store_diffuse.x << 16u) | store_diffuse.y;
+        face_lights[slot].diffuse_b = store_diffuse.z << 16u;
     }
-    // as this will be assumed to be in the range 0-1 when compressing and decompressing:
-    spec_light = clamp(spec_light, vec3(0.0), vec3(1.0));
-    diff_light = clamp(diff_light, vec3(0.0), vec3(1.0));
 
-    let store_diffuse = vec3<u32>(round(diff_light * 65535.0)); // stored as 16-bit x, y, z
-    let diffuse_low_bytes = store_diffuse & vec3(0xFFu);
-    let diffuse_high_bytes = (store_diffuse >> vec3(8u)) & vec3(0xFFu);
-    scene.chunk_map[scene_idx].voxels[chunk_idx].albedo = compress_uvec4(vec4(vec3<u32>(round(this_voxel.albedo * 255.0)), u32(round(spec_light.x * 255.0))));
-    // scene.chunk_map[scene_idx].voxels[chunk_idx].albedo = compress_uvec4(vec4(vec3(255u,0u,0u), u32(round(spec_light.x * 255.0))));
-    scene.chunk_map[scene_idx].voxels[chunk_idx].spec_light = compress_uvec4(vec4(vec2<u32>(round(spec_light.yz * 255.0)), diffuse_high_bytes.x, diffuse_low_bytes.x));
-    scene.chunk_map[scene_idx].voxels[chunk_idx].diff_light = compress_uvec4(vec4(diffuse_high_bytes.y, diffuse_low_bytes.y, diffuse_high_bytes.z, diffuse_low_bytes.z));
+    // the ambient occlusion only changes with the voxels around, so it's found once when the lighting starts over
+    if first_sample {
+        scene.chunk_map[scene_idx].voxels[chunk_idx].occlusion = ambient_occlusion(vec3<i32>(voxel_pos));
+    }
 
     // accumulate light samples
     if chunk_idx == 0 {
@@ -425,15 +844,17 @@ fn lighting_main(@builtin(workgroup_id) wg_id: vec3<u32>, @builtin(local_invocat
 }
 
 var<workgroup> first_sample: bool = false;
+var<private> sequence_offsets: vec4<u32>; // per-face shift of the R2 sequence, xy for diffuse rays and zw for shadow rays
+var<private> SEQUENCE_SALT: u32 = 0x9E3779B9u; // used instead of a frame number to get seeds that are the same every frame
 
-// cast a specular ray from vox at scene_pos, accumulating color in spec_light, which is returned
-fn specular_ray(scene_pos: vec3<i32>, ray: Ray, vox: Voxel, spec_light: vec3<f32>) -> vec3<f32> {
+// cast a specular ray from scene_pos, accumulating color times the BRDF weight in spec_light, which is returned
+fn specular_ray(scene_pos: vec3<i32>, ray: Ray, weight: vec3<f32>, spec_light: vec3<f32>) -> vec3<f32> {
     let spec_bounce_limit = 2; // should maybe come from CPU
     
     var spec_light: vec3<f32> = spec_light;
 
     var last_pos: vec3<f32> = ray.position;
-    var multiplier: vec3<f32> = vox.albedo; // accumulate color over the bounces
+    var multiplier: vec3<f32> = weight; // accumulate color over the bounces
     var mut_ray: Ray = ray;
     for(var i: i32; i < spec_bounce_limit; i++) {
         let info = step_scene(mut_ray, true); 
@@ -444,17 +865,17 @@ fn specular_ray(scene_pos: vec3<i32>, ray: Ray, vox: Voxel, spec_light: vec3<f32
             }
             var hit_voxel: Voxel = info.voxel;
             let hit_material = scene.materials[hit_voxel.material];
-            hit_voxel.diffuse *= 1.0 - hit_material.specular;
-            if hit_material.emissive != 0u {
-                return spec_light + (hit_voxel.albedo * info.color_mul + info.color_add) * multiplier * vox.albedo;
+            hit_voxel.diffuse = diffuse_light(hit_voxel, info.normal) * (1.0 - hit_material.metallic);
+            if hit_material.emission_strength > 0.0 {
+                return spec_light + (emitted_light(hit_voxel) * info.color_mul + info.color_add) * multiplier;
             } else {
-                let hit_color = hit_voxel.diffuse * hit_voxel.albedo;
+                let hit_color = hit_voxel.diffuse * surface_color(hit_voxel);
                 spec_light += (hit_color * info.color_mul + info.color_add) * multiplier;
-                if hit_material.specular == 0.0 { // no more reflections needed
+                if hit_material.metallic == 0.0 { // no more reflections needed
                     return spec_light;
                 }
                 // bounce the ray
-                multiplier *= hit_voxel.albedo * info.color_mul * hit_material.specular;
+                multiplier *= surface_color(hit_voxel) * info.color_mul * hit_material.metallic;
                 last_pos = info.new_pos;
                 mut_ray.position = info.new_pos;
                 mut_ray.direction = reflect(mut_ray.direction, hit_voxel.normal);
@@ -462,7 +883,7 @@ fn specular_ray(scene_pos: vec3<i32>, ray: Ray, vox: Voxel, spec_light: vec3<f32
             }
         }
         else if dot(mut_ray.direction, scene.sun_direction.xyz) > 0.99 { // specular highlight
-            return spec_light + (scene.sun_strength.xyz * info.color_mul + info.color_add);
+            return spec_light + (scene.sun_strength.xyz * info.color_mul + info.color_add) * multiplier;
         }
         else { // reflect sky color
             return spec_light + (skybox_color(mut_ray.direction) * info.color_mul + info.color_add) * multiplier;
@@ -485,16 +906,18 @@ fn diffuse_ray(ray_pos: vec3<f32>, vox: Voxel, rng: u32, diff_light: vec3<f32>)
     var mut_ray: Ray;
     var info: StepResult;
     mut_ray.position = ray_pos;
+    let sequence_index = scene.frame * u32(diffuse_bounce_limit); // every bounce takes its own point of the sequence
     for (var i: i32 = 0; i < diffuse_bounce_limit; i++) {
         if i > 0 { // sometimes reflect ray on later bounces
-            if rand(&rng) < hit_mat.specular {
-                mut_ray.direction = normalize(reflect(last_dir, hit_normal) * hit_mat.shininess + rand_unit_sphere(&rng)); 
+            if rand(&rng) < hit_mat.metallic {
+                let u = sample_square(&rng, sequence_offsets.xy, sequence_index + u32(i));
+                mut_ray.direction = reflect(last_dir, sample_ggx(hit_normal, hit_mat.roughness * hit_mat.roughness, u));
             }
         } else if first_sample {
             mut_ray.direction = normalize(hit_normal) + EPSILON;
         } else {
             // randomize after first sample, spherically
-            mut_ray.direction = normalize(hit_normal + rand_unit_sphere(&rng)) + EPSILON;
+            mut_ray.direction = normalize(hit_normal + sample_unit_sphere(&rng, sequence_offsets.xy, sequence_index)) + EPSILON;
         }
         mut_ray.inv_direction = 1.0 / mut_ray.direction;
         info = step_scene(mut_ray, true);
@@ -506,11 +929,11 @@ fn diffuse_ray(ray_pos: vec3<f32>, vox: Voxel, rng: u32, diff_light: vec3<f32>)
             if dot(dist, dist) <= 1.0 { // hit adjacent voxel, meaning it's occluded
                 return diff_light;
             } 
-            if hit_mat.emissive != 0u {
-                return diff_light + new_color * (info.voxel.albedo * info.color_mul + info.color_add);
+            if hit_mat.emission_strength > 0.0 {
+                return diff_light + new_color * (emitted_light(info.voxel) * info.color_mul + info.color_add);
             }
             else {
-                new_color *= info.voxel.albedo * info.color_mul + info.color_add;
+                new_color *= surface_color(info.voxel) * info.color_mul + info.color_add;
             }
         }
         else {
@@ -529,7 +952,7 @@ fn shadow_ray(ray_pos: vec3<f32>, rng: u32, diff_light: vec3<f32>) -> vec3<f32>
     if first_sample {
         sun_ray.direction = scene.sun_direction.xyz + EPSILON;
     } else {
-        sun_ray.direction = normalize(scene.sun_direction.xyz * 10.0 + rand_unit_sphere(&rng));
+        sun_ray.direction = normalize(scene.sun_direction.xyz * 10.0 + sample_unit_sphere(&rng, sequence_offsets.zw, scene.frame));
     }
     sun_ray.inv_direction = 1.0 / sun_ray.direction;    
 
@@ -561,6 +984,69 @@ fn rand_normal_dist(seed: ptr<function, u32>) -> f32 {
     return rho * cos(theta);
 }
 
+// a point on the unit sphere, either random or from the index-th point of the R2 sequence shifted by sequence_offset
+fn sample_unit_sphere(seed: ptr<function, u32>, sequence_offset: vec2<u32>, index: u32) -> vec3<f32> {
+    if scene.sampling != SAMPLING_R2 {
+        return rand_unit_sphere(seed);
+    }
+    let u = sample_square(seed, sequence_offset, index);
+    let z = 1.0 - 2.0 * u.x;
+    let r = sqrt(max(0.0, 1.0 - z * z));
+    let phi = 2.0 * 3.1415926 * u.y;
+    return vec3(r * cos(phi), r * sin(phi), z);
+}
+
+// a point in the unit square, either random or the index-th point of the R2 sequence shifted by sequence_offset
+fn sample_square(seed: ptr<function, u32>, sequence_offset: vec2<u32>, index: u32) -> vec2<f32> {
+    if scene.sampling != SAMPLING_R2 {
+        let x = rand(seed);
+        let y = rand(seed);
+        return vec2(x, y);
+    }
+    // wrapping around in fixed point keeps the sequence exact for any index
+    let r2_alpha = vec2(3242174889u, 2447445414u); // 1/g and 1/g^2 of the R2 sequence in 0.32 fixed point
+    return vec2<f32>(sequence_offset + index * r2_alpha) / 4294967296.0; // 2^32
+}
+
+// a half vector from the GGX distribution around normal with roughness alpha, from a point u in the unit square
+fn sample_ggx(normal: vec3<f32>, alpha: f32, u: vec2<f32>) -> vec3<f32> {
+    let alpha2 = max(alpha * alpha, EPSILON); // a perfect mirror would divide by zero
+    let cos_theta = sqrt((1.0 - u.x) / (1.0 + (alpha2 - 1.0) * u.x));
+    let sin_theta = sqrt(max(0.0, 1.0 - cos_theta * cos_theta));
+    let phi = 2.0 * 3.1415926 * u.y;
+    let up = select(vec3(1.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0), abs(normal.x) > 0.9);
+    let tangent = normalize(cross(up, normal));
+    let bitangent = cross(normal, tangent);
+    return normalize(tangent * cos(phi) * sin_theta + bitangent * sin(phi) * sin_theta + normal * cos_theta);
+}
+
+// the Cook-Torrance BRDF times the cosine term divided by the probability of sampling to_light with sample_ggx,
+// which is what the light from that direction has to be multiplied with
+fn ggx_weight(normal: vec3<f32>, to_view: vec3<f32>, to_light: vec3<f32>, half_vector: vec3<f32>, alpha: f32, f0: vec3<f32>) -> vec3<f32> {
+    let n_dot_v = max(dot(normal, to_view), EPSILON);
+    let n_dot_l = max(dot(normal, to_light), EPSILON);
+    let n_dot_h = max(dot(normal, half_vector), EPSILON);
+    let v_dot_h = max(dot(to_view, half_vector), 0.0);
+    let fresnel = f0 + (1.0 - f0) * pow(1.0 - v_dot_h, 5.0); // Schlick
+    let geometry = smith_g1(n_dot_v, alpha) * smith_g1(n_dot_l, alpha);
+    return fresnel * geometry * v_dot_h / (n_dot_v * n_dot_h);
+}
+
+fn smith_g1(n_dot_x: f32, alpha: f32) -> f32 {
+    let alpha2 = alpha * alpha;
+    return 2.0 * n_dot_x / (n_dot_x + sqrt(alpha2 + (1.0 - alpha2) * n_dot_x * n_dot_x));
+}
+
+fn hash(value: u32) -> u32 {
+    var state = value;
+    return next_random_number(&state);
+}
+
+// a seed that is different for every position and frame, but the same between runs with the same scene.seed
+fn hashed_seed(pos: vec3<u32>, frame: u32) -> u32 {
+    return hash(pos.x ^ hash(pos.y ^ hash(pos.z ^ hash(frame ^ hash(scene.seed)))));
+}
+
 fn next_random_number(seed: ptr<function,u32>) -> u32 {
     *seed = *seed * 747796405u + 2891336453u;
     var result: u32 = ((*seed >> ((*seed >> 28u) + 4u)) ^ *seed) * 277803737u;
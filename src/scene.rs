@@ -1,10 +1,28 @@
-use glam::{Vec3, UVec3, ivec3, uvec3, vec3, Vec4, Vec4Swizzles, UVec4};
+use std::ops::Range;
+use glam::{Vec3, UVec3, IVec3, ivec3, uvec3, vec3, Vec4, Vec4Swizzles};
+
+pub mod animation;
+pub mod brush;
+pub mod faces;
+pub mod noise;
+pub mod objects;
+pub mod prefab;
+pub mod schedule;
+pub mod sdf;
+pub mod simulation;
+pub mod terrain;
+
+use brush::Brush;
+use faces::{FaceAllocator, FaceLayout};
 
 pub const SCENE_SIZE: usize = 8; // scene is 8x8x8 chunks
 pub const CHUNK_SIZE: usize = 8; // chunks are 8x8x8 voxels
-const NUM_MATERIALS: usize = 5;
+const NUM_MATERIALS: usize = 16; // mirrored in raytracing.wgsl
 const MATERIAL_EMPTY: u32 = 255;
 
+pub type MaterialId = u32; // index into the materials of a scene
+const MATERIAL_PADDING: usize = 4; // bytes after every material in the scene buffer
+
 #[repr(C)]
 #[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct TempScene {
@@ -14,7 +32,7 @@ pub struct TempScene {
 }
 #[allow(dead_code)]
 impl TempScene {
-    pub fn into_buffer(&self) -> &[u8] {
+    pub fn as_buffer(&self) -> &[u8] {
         bytemuck::bytes_of(self)
     }
 }
@@ -52,141 +70,394 @@ impl Default for TempScene {
 
 
 pub struct Scene {
-    size: Vec4, // number of chunks per dimension in this scene. Should be SCENE_SIZE.
-    sun_direction: Vec4,
-    sun_strength: Vec4,
-    ambient_light: Vec4,
-    time: u32,
-    chunks: Vec<Chunk>,
-    materials: [Material;NUM_MATERIALS],
+    pub(crate) size: Vec4, // number of chunks per dimension in this scene. Should be SCENE_SIZE.
+    pub(crate) sun_direction: Vec4,
+    pub(crate) sun_strength: Vec4,
+    pub(crate) ambient_light: Vec4,
+    pub(crate) time: u32,
+    pub(crate) seed: u32, // base seed for the lighting, the same seed and frame give the same noise
+    pub(crate) frame: u32, // number of frames lit so far
+    pub(crate) sampling: SamplingMode,
+    pub(crate) specular_samples: u32, // specular rays per voxel per frame
+    pub(crate) ambient_occlusion: bool, // whether the diffuse light is darkened by nearby voxels
+    pub(crate) fog: Fog,
+    pub(crate) chunks: Vec<Chunk>,
+    pub(crate) face_allocator: FaceAllocator, // blocks of the face light buffer, see faces.rs
+    pub(crate) stale_faces: Vec<bool>, // chunks whose exposed faces have to be found again, per chunk
+    pub(crate) dirty_chunks: Vec<bool>, // chunks that changed since they were last written to the GPU, per chunk
+    pub(crate) recolored_chunks: Vec<bool>, // chunks that only changed colors and keep their lighting, per chunk
+    pub(crate) dirty_settings: bool, // whether the header or the materials changed since they were last written to the GPU
+    pub(crate) invalidation_radius: u32, // how many chunks around a change lose their lighting as well
+    pub(crate) materials: [Material;NUM_MATERIALS],
+    pub(crate) objects: Vec<objects::VoxelObject>, // the dynamic objects, see objects.rs
+    pub(crate) instances: Vec<Option<objects::Instance>>, // None for removed instances
+    pub(crate) dirty_objects: bool, // whether objects were added since they were last written to the GPU
+    pub(crate) animations: Vec<Option<animation::Animation>>, // None for removed animations, see animation.rs
+    pub(crate) animated: u32, // the time the animations were last played at
+    pub(crate) lit_emission: [f32;NUM_MATERIALS], // the emission strength the lighting was gathered with, per material
 }
 
 impl Scene {
-    pub fn into_buffer(&self) -> Vec<u8> {
-        // bytemuck::bytes_of(self)
+    // the scene buffer. The faces have to be up to date, see update_faces
+    pub fn to_buffer(&self) -> Vec<u8> {
+        debug_assert!(!self.stale_faces.contains(&true), "update_faces has to be called after changing voxels");
+        let chunks = bytemuck::cast_slice(&self.chunks);
+        [&self.header_buffer(), chunks, &self.material_buffer()].concat()
+    }
+    // everything in front of the chunks: size, sun, ambient light, frame data and fog
+    fn header_buffer(&self) -> Vec<u8> {
         let size = bytemuck::bytes_of(&self.size);
         let sun_pos = bytemuck::bytes_of(&self.sun_direction);
         let sun_str = bytemuck::bytes_of(&self.sun_strength);
         let ambient_str = bytemuck::bytes_of(&self.ambient_light);
-        let time = bytemuck::bytes_of(&self.time);
-        let chunks = bytemuck::cast_slice(&self.chunks);
-        let materials = bytemuck::cast_slice(&self.materials);
-        let padding = bytemuck::bytes_of(&[0u32,0,0]);
-        [size, sun_pos, sun_str, ambient_str, time, padding, chunks, materials].concat() // woo, padding :)))
+        let frame_data = self.frame_data();
+        let frame_data = bytemuck::bytes_of(&frame_data);
+        let fog = bytemuck::bytes_of(&self.fog);
+        [size, sun_pos, sun_str, ambient_str, frame_data, fog].concat()
+    }
+    fn material_buffer(&self) -> Vec<u8> {
+        // the shader rounds the size of every material up to a multiple of 16 bytes
+        self.materials.iter()
+            .flat_map(|material| [bytemuck::bytes_of(material), &[0; MATERIAL_PADDING]].concat())
+            .collect()
+    }
+    // write everything that changed since the last call to a buffer made with to_buffer. The faces have to be up to date
+    // returns whether anything changed
+    pub fn write_changes(&mut self, queue: &wgpu::Queue, buffer: &wgpu::Buffer) -> bool {
+        let writes = self.take_writes();
+        for (offset, data) in &writes {
+            queue.write_buffer(buffer, *offset, data);
+        }
+        !writes.is_empty()
+    }
+    // the parts of the scene buffer that changed since the last call, as offsets and their new bytes
+    pub fn take_writes(&mut self) -> Vec<(wgpu::BufferAddress, Vec<u8>)> {
+        debug_assert!(!self.stale_faces.contains(&true), "update_faces has to be called after changing voxels");
+        let mut writes = Vec::new();
+        if std::mem::take(&mut self.dirty_settings) {
+            writes.push((0, self.header_buffer()));
+            writes.push((self.materials_offset(), self.material_buffer()));
+        }
+        let dirty = self.take_dirty_chunks();
+        for chunks in &dirty {
+            let offset = Self::CHUNKS_OFFSET + (chunks.start * std::mem::size_of::<Chunk>()) as wgpu::BufferAddress;
+            writes.push((offset, bytemuck::cast_slice(&self.chunks[chunks.clone()]).to_vec()));
+        }
+        // only the voxels of recolored chunks, so the GPU keeps counting the light samples in front of them
+        let voxels_offset = std::mem::offset_of!(Chunk, voxels);
+        for (idx, recolored) in self.recolored_chunks.iter_mut().enumerate() {
+            if std::mem::take(recolored) && !dirty.iter().any(|chunks| chunks.contains(&idx)) {
+                let offset = Self::CHUNKS_OFFSET + (idx * std::mem::size_of::<Chunk>() + voxels_offset) as wgpu::BufferAddress;
+                writes.push((offset, bytemuck::cast_slice(&self.chunks[idx].voxels).to_vec()));
+            }
+        }
+        writes
+    }
+    // the chunks that changed since the last call, as runs of neighbouring chunk indices
+    pub fn take_dirty_chunks(&mut self) -> Vec<Range<usize>> {
+        let mut runs: Vec<Range<usize>> = Vec::new();
+        for (idx, dirty) in self.dirty_chunks.iter_mut().enumerate() {
+            if !std::mem::take(dirty) {
+                continue;
+            }
+            match runs.last_mut() {
+                Some(run) if run.end == idx => run.end += 1,
+                _ => runs.push(idx..idx + 1),
+            }
+        }
+        runs
+    }
+    fn materials_offset(&self) -> wgpu::BufferAddress {
+        Self::CHUNKS_OFFSET + std::mem::size_of_val(self.chunks.as_slice()) as wgpu::BufferAddress
     }
     pub fn new() -> Self {
-        let mut materials = std::array::from_fn(|_| Material::default());
-        materials[0] = Material {
-            emissive: false as u32,
-            opacity: 1.0,
-            specular: 0.0,
-            ..Default::default()
-        };
-        materials[1] = Material {
-            emissive: false as u32,
-            specular: 0.8,
-            opacity: 1.0,
-            shininess: 3.0,
-            ..Default::default()
-        };
-        materials[2] = Material {
-            emissive: false as u32,
-            specular: 0.0,
-            opacity: 0.5,
-            refraction_index: 1.52,
-            ..Default::default()
-        };
-        materials[3] = Material {
-            emissive: true as u32,
-            specular: 0.0,
-            opacity: 1.0,
-            ..Default::default()
-        };
-        materials[4] = Material {
-            emissive: false as u32,
-            specular: 1.0,
-            opacity: 1.0,
-            shininess: 10.0,
-            ..Default::default()
-        };
-        let chunks =  (0..SCENE_SIZE*SCENE_SIZE*SCENE_SIZE).map(|i| Chunk::empty(i)).collect::<Vec<_>>();
+        // the rest are diffuse, free for scenes to set up
+        let mut materials = [Material::default(); NUM_MATERIALS];
+        materials[..5].copy_from_slice(&[
+            Material::legacy(false, 1.0, 0.0, 0.0, 0.0), // diffuse
+            Material::legacy(false, 1.0, 0.0, 0.8, 3.0), // glossy
+            Material::legacy(false, 0.5, 1.52, 0.0, 0.0), // glass
+            Material::legacy(true, 1.0, 0.0, 0.0, 0.0), // emissive
+            Material::legacy(false, 1.0, 0.0, 1.0, 10.0), // mirror
+        ]);
+        let chunks =  (0..SCENE_SIZE*SCENE_SIZE*SCENE_SIZE).map(Chunk::empty).collect::<Vec<_>>();
         Self {
             size: Vec4::from_array([SCENE_SIZE as f32;4]),
             sun_direction: Vec4::new(-0.408248, 0.816497, -0.408248, 0.0), // vec3(-0.5,1.0,-0.5).normalize().extend(0.0);
             sun_strength: Vec4::new(0.6, 0.6, 0.6, 0.0),
             ambient_light: Vec4::new(0.01, 0.01, 0.01, 0.0),
             time: 0,
+            seed: 0,
+            frame: 0,
+            sampling: SamplingMode::Random,
+            specular_samples: 4,
+            ambient_occlusion: true,
+            fog: Fog::default(),
+            stale_faces: vec![true; chunks.len()],
+            dirty_chunks: vec![false; chunks.len()],
+            recolored_chunks: vec![false; chunks.len()],
+            dirty_settings: false,
+            invalidation_radius: 1,
             chunks,
-            materials
+            face_allocator: FaceAllocator::default(),
+            lit_emission: materials.map(|material| material.emission_strength),
+            materials,
+            objects: Vec::new(),
+            instances: Vec::new(),
+            dirty_objects: false,
+            animations: Vec::new(),
+            animated: 0,
         }
     }
+    // the scene the viewer starts with
+    pub fn demo() -> Self {
+        let mut scene = Self::new();
+        scene.spawn_ground_plane();
+        scene.spawn_far_walls();
+        scene.chunk_at(uvec3(0, 0, 0)).fill_sphere(0, uvec3(180, 180, 180));
+        scene.chunk_at(uvec3(1, 0, 1)).fill_sphere(1, uvec3(180, 180, 180));
+        scene.chunk_at(uvec3(2, 1, 1)).fill_borders(2, uvec3(255, 255, 84));
+        scene.chunk_at(uvec3(2, 2, 2)).fill_sphere(2, uvec3(210, 115, 80));
+        scene.chunk_at(uvec3(5, 1, 3)).fill_sphere(3, uvec3(0, 190, 0));
+        scene.chunk_at(uvec3(6, 0, 6)).fill_sphere(1, uvec3(0, 250, 40));
+        scene.chunk_at(uvec3(4, 0, 6)).fill_sphere(1, uvec3(240, 0, 40));
+        scene.chunk_at(uvec3(1, 0, 6)).fill_sphere(1, uvec3(240, 40, 0));
+        scene.chunk_at(uvec3(6, 0, 1)).fill_sphere(1, uvec3(0, 40, 250));
+        scene.chunk_at(uvec3(5, 0, 4)).fill_sphere(2, uvec3(10, 40, 50));
+        scene.chunk_at(uvec3(4, 0, 3)).fill_borders(1, uvec3(110, 140, 150));
+        scene
+    }
+    // the chunk at a position in scene space, to be changed. Its faces and the faces next to it are laid out again by update_faces,
+    // and the lighting around it starts over
     pub fn chunk_at(&mut self, pos: UVec3) -> &mut Chunk {
-        let idx = flatten_index(pos, self.size.xyz().as_uvec3());
+        self.invalidate_lighting(LightingRegion::chunk(pos));
+        let size = self.size.xyz().as_uvec3();
+        for face in 0..faces::FACE_COUNT {
+            let neighbour = pos.as_ivec3() + faces::face_normal(face);
+            if neighbour.cmpge(IVec3::ZERO).all() && neighbour.cmplt(size.as_ivec3()).all() {
+                self.stale_faces[flatten_index(neighbour.as_uvec3(), size)] = true;
+            }
+        }
+        let idx = flatten_index(pos, size);
+        self.stale_faces[idx] = true;
         &mut self.chunks[idx]
     }
+    // find the exposed faces of changed chunks and allocate their lighting. Returns true if the face light buffer has to grow
+    pub fn update_faces(&mut self) -> bool {
+        // laying out the faces again throws their light away
+        for (dirty, stale) in self.dirty_chunks.iter_mut().zip(&self.stale_faces) {
+            *dirty |= *stale;
+        }
+        FaceLayout {
+            chunks: &mut self.chunks,
+            stale: &mut self.stale_faces,
+            allocator: &mut self.face_allocator,
+            materials: &self.materials,
+            size: self.size.xyz().as_uvec3(),
+        }.update()
+    }
+    // the number of faces the face light buffer has room for
+    pub fn face_capacity(&self) -> u32 {
+        self.face_allocator.capacity()
+    }
+    // throw away the light gathered in a region, so it is lit from scratch. Chunks within invalidation_radius of the region
+    // lose their light too, since the light bounces between them. The setters of the scene do this on their own,
+    // this is for changes they don't know about
+    pub fn invalidate_lighting(&mut self, region: LightingRegion) {
+        let size = self.size.xyz().as_uvec3();
+        let (min, max) = match region {
+            LightingRegion::All => (UVec3::ZERO, size - 1),
+            LightingRegion::Chunks { min, max } => (
+                (min.as_ivec3() - self.invalidation_radius as i32).max(IVec3::ZERO).as_uvec3(),
+                (max + self.invalidation_radius).min(size - 1),
+            ),
+        };
+        for z in min.z..=max.z {
+            for y in min.y..=max.y {
+                for x in min.x..=max.x {
+                    let idx = flatten_index(uvec3(x, y, z), size);
+                    self.chunks[idx].accumulated_light_samples = 0;
+                    self.dirty_chunks[idx] = true;
+                }
+            }
+        }
+    }
+    pub fn invalidation_radius(&self) -> u32 {
+        self.invalidation_radius
+    }
+    // how many chunks around a change lose their lighting as well
+    pub fn set_invalidation_radius(&mut self, radius: u32) {
+        self.invalidation_radius = radius;
+    }
+    // whether the lighting of a chunk has been thrown away and not been written to the GPU yet
+    pub fn is_chunk_dirty(&self, pos: UVec3) -> bool {
+        self.dirty_chunks[flatten_index(pos, self.size.xyz().as_uvec3())]
+    }
+    // advance time and play the animations
     pub fn update(&mut self, dt: instant::Duration) {
         self.time += dt.as_millis() as u32;
+        self.frame = self.frame.wrapping_add(1);
+        self.animate();
     }
     pub fn time(&self) -> u32 {
         self.time
     }
-    pub fn spawn_ground_plane(&mut self) {
-        for chunk_x in 0..self.size.x as u32 {
-            for chunk_z in 0..self.size.z as u32 {
-                let chunk = self.chunk_at(uvec3(chunk_x, 0, chunk_z));
-                for x in 0..CHUNK_SIZE as u32 {
-                    for z in 0..CHUNK_SIZE as u32 {
-                        chunk.modify_voxel_at(uvec3(x, 0, z), |vox| {
-                            vox.albedo = uvec3(128, 128, 110);
-                            vox.material = 0;
-                            vox.normal = Vec3::Y;
-                        });
-                    }
-                }
-            }
+    // the part of the scene header that can change every frame: time, seed, frame, sampling mode, sample counts and toggles
+    pub fn frame_data(&self) -> [u32; 8] {
+        [self.time, self.seed, self.frame, self.sampling as u32, self.specular_samples, self.ambient_occlusion as u32, 0, 0]
+    }
+    // where the frame data starts in the scene buffer
+    pub const FRAME_DATA_OFFSET: wgpu::BufferAddress = 64;
+    // where the fog starts in the scene buffer, right after the frame data
+    pub const FOG_OFFSET: wgpu::BufferAddress = 96;
+    // where the chunks start in the scene buffer, right after the fog
+    pub const CHUNKS_OFFSET: wgpu::BufferAddress = 128;
+    pub fn set_seed(&mut self, seed: u32) {
+        self.seed = seed;
+    }
+    pub fn sampling(&self) -> SamplingMode {
+        self.sampling
+    }
+    pub fn set_sampling(&mut self, sampling: SamplingMode) {
+        self.sampling = sampling;
+    }
+    pub fn material(&self, index: u32) -> &Material {
+        &self.materials[index as usize]
+    }
+    pub fn set_material(&mut self, index: u32, material: Material) {
+        self.materials[index as usize] = material;
+        self.lit_emission[index as usize] = material.emission_strength;
+        self.stale_faces.fill(true); // transparent and volumetric voxels don't hide faces
+        self.dirty_settings = true;
+        self.invalidate_lighting(LightingRegion::All);
+    }
+    pub fn ambient_occlusion(&self) -> bool {
+        self.ambient_occlusion
+    }
+    pub fn set_ambient_occlusion(&mut self, enabled: bool) {
+        self.ambient_occlusion = enabled;
+    }
+    pub fn fog(&self) -> &Fog {
+        &self.fog
+    }
+    pub fn set_fog(&mut self, fog: Fog) {
+        self.fog = fog;
+        self.dirty_settings = true;
+    }
+    pub fn set_specular_samples(&mut self, samples: u32) {
+        self.specular_samples = samples.max(1);
+    }
+    pub fn set_sun(&mut self, direction: Vec3, strength: Vec3) {
+        self.sun_direction = direction.normalize().extend(0.0);
+        self.sun_strength = strength.extend(0.0);
+        self.dirty_settings = true;
+        self.invalidate_lighting(LightingRegion::All);
+    }
+    pub fn set_ambient_light(&mut self, ambient_light: Vec3) {
+        self.ambient_light = ambient_light.extend(0.0);
+        self.dirty_settings = true;
+        self.invalidate_lighting(LightingRegion::All);
+    }
+    // set a single voxel, in voxel space (CHUNK_SIZE voxels per scene unit)
+    pub fn set_voxel(&mut self, pos: UVec3, voxel: Voxel) {
+        let chunk = pos / CHUNK_SIZE as u32;
+        let in_chunk = pos % CHUNK_SIZE as u32;
+        self.chunk_at(chunk).modify_voxel_at(in_chunk, |vox| *vox = voxel);
+    }
+    // the center of the scene, in scene space
+    pub fn center(&self) -> Vec3 {
+        self.size.xyz() / 2.0
+    }
+    // whether the voxel at a position in voxel space (CHUNK_SIZE voxels per scene unit) is filled
+    fn is_filled(&self, voxel: IVec3) -> bool {
+        let scene_voxels = self.size.xyz().as_ivec3() * CHUNK_SIZE as i32;
+        if voxel.cmplt(IVec3::ZERO).any() || voxel.cmpge(scene_voxels).any() {
+            return false;
         }
+        let chunk = (voxel / CHUNK_SIZE as i32).as_uvec3();
+        let in_chunk = (voxel % CHUNK_SIZE as i32).as_uvec3();
+        let chunk_idx = flatten_index(chunk, self.size.xyz().as_uvec3());
+        let voxel_idx = flatten_index(in_chunk, UVec3::ONE * CHUNK_SIZE as u32);
+        self.chunks[chunk_idx].voxels[voxel_idx].normal >> 24 != MATERIAL_EMPTY
     }
-    pub fn spawn_far_walls(&mut self) {
-        for cy in 0..2 {
-            for cx in 0..self.size.x as u32 {
-                let chunk = self.chunk_at(uvec3(cx, cy, self.size.z as u32 - 1));
-                for x in 0..CHUNK_SIZE as u32 {
-                    for y in 0..CHUNK_SIZE as u32 {
-                        chunk.modify_voxel_at(uvec3(x, y, CHUNK_SIZE as u32 - 1), |vox| {
-                            vox.albedo = uvec3(180, 180, 180);
-                            vox.material = 4;
-                            vox.normal = Vec3::NEG_Z;
-                        });
-                    }
-                }
-            }
-            for cz in 0..self.size.z as u32 {
-                let chunk = self.chunk_at(uvec3(self.size.x as u32 - 1, cy, cz));
-                for z in 0..CHUNK_SIZE as u32 {
-                    for y in 0..CHUNK_SIZE as u32 {
-                        chunk.modify_voxel_at(uvec3(CHUNK_SIZE as u32 -1, y, z), |vox| {
-                            vox.albedo = uvec3(180, 180, 180);
-                            vox.material = 4;
-                            vox.normal = Vec3::NEG_X;
-                        });
-                    }
-                }
+    // find the first filled voxel along a ray starting at origin (in scene space), used for picking
+    pub fn raycast(&self, origin: Vec3, direction: Vec3) -> Option<RayHit> {
+        let direction = direction.try_normalize()?;
+        // traverse in voxel space, so every step is one voxel
+        let origin = origin * CHUNK_SIZE as f32;
+        let scene_max = self.size.xyz() * CHUNK_SIZE as f32;
+        let inv_direction = direction.recip();
+        let t1 = (Vec3::ZERO - origin) * inv_direction;
+        let t2 = (scene_max - origin) * inv_direction;
+        let near = t1.min(t2).max_element().max(0.0);
+        let far = t1.max(t2).min_element();
+        if near > far {
+            return None;
+        }
+        let start = origin + direction * near;
+        let mut pos = start.floor().as_ivec3().clamp(IVec3::ZERO, scene_max.as_ivec3() - 1);
+        let step = direction.signum().as_ivec3();
+        let delta_dist = inv_direction.abs();
+        let mut side_dist = (direction.signum() * (pos.as_vec3() - start) + direction.signum() * 0.5 + 0.5) * delta_dist;
+        let mut normal = Vec3::ZERO;
+        let mut t = 0.0;
+        while t <= far - near {
+            if self.is_filled(pos) {
+                let distance = (near + t) / CHUNK_SIZE as f32;
+                return Some(RayHit {
+                    position: (start + direction * t) / CHUNK_SIZE as f32,
+                    voxel: pos.as_uvec3(),
+                    normal,
+                    distance,
+                });
             }
+            // step along the axis with the closest boundary
+            let axis = if side_dist.x < side_dist.y {
+                if side_dist.x < side_dist.z { 0 } else { 2 }
+            } else if side_dist.y < side_dist.z { 1 } else { 2 };
+            t = side_dist[axis];
+            side_dist[axis] += delta_dist[axis];
+            pos[axis] += step[axis];
+            normal = Vec3::ZERO;
+            normal[axis] = -direction.signum()[axis];
         }
+        None
+    }
+    pub fn spawn_ground_plane(&mut self) {
+        let size = self.size.xyz().as_uvec3() * CHUNK_SIZE as u32;
+        self.fill_box(UVec3::ZERO, uvec3(size.x, 1, size.z), &Brush::union(0, uvec3(128, 128, 110)).with_normal(Vec3::Y));
+    }
+    pub fn spawn_far_walls(&mut self) {
+        let size = self.size.xyz().as_uvec3() * CHUNK_SIZE as u32;
+        let height = 2 * CHUNK_SIZE as u32;
+        let wall = Brush::union(4, uvec3(180, 180, 180));
+        self.fill_box(uvec3(0, 0, size.z - 1), uvec3(size.x, height, size.z), &wall.with_normal(Vec3::NEG_Z));
+        self.fill_box(uvec3(size.x - 1, 0, 0), uvec3(size.x, height, size.z), &wall.with_normal(Vec3::NEG_X));
+    }
+}
+impl Default for Scene {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 #[repr(C)]
 #[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Chunk {
-    accumulated_light_samples: UVec4, // only the x component is used, the rest is padding
-    pos: Vec4, // position of this chunk in scene space and whether or not it has visible voxels (w component)
-    voxels: [CompressedVoxel;CHUNK_SIZE*CHUNK_SIZE*CHUNK_SIZE],
+    pub(crate) accumulated_light_samples: u32,
+    pub(crate) face_offset: u32, // the start of this chunk's block in the face light buffer
+    pub(crate) face_count: u32, // the size of the block
+    padding: u32,
+    pub(crate) pos: Vec4, // position of this chunk in scene space and whether or not it has visible voxels (w component)
+    pub(crate) voxels: [CompressedVoxel;CHUNK_SIZE*CHUNK_SIZE*CHUNK_SIZE],
 }
 impl Chunk {
     pub fn empty(i: usize) -> Self {
         Self {
-            accumulated_light_samples: UVec4::ZERO,
+            accumulated_light_samples: 0,
+            face_offset: 0,
+            face_count: 0,
+            padding: 0,
             pos: expand_index(i, UVec3::ONE * SCENE_SIZE as u32).extend(0.0),
             voxels: [Voxel::default().compress();CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE],
         }
@@ -244,20 +515,83 @@ impl Chunk {
 
 
 
+// The part of the scene whose lighting Scene::invalidate_lighting throws away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LightingRegion {
+    All,
+    Chunks { min: UVec3, max: UVec3 }, // a box of chunks in scene space, min and max included
+}
+
+impl LightingRegion {
+    pub fn chunk(pos: UVec3) -> Self {
+        Self::Chunks { min: pos, max: pos }
+    }
+    // the chunks touching a box of voxels in voxel space, min and max included
+    pub fn voxels(min: UVec3, max: UVec3) -> Self {
+        Self::Chunks { min: min / CHUNK_SIZE as u32, max: max / CHUNK_SIZE as u32 }
+    }
+}
+
+// How the lighting pass picks directions for diffuse and shadow rays
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SamplingMode {
+    Random = 0, // independent random directions every frame
+    R2 = 1, // the R2 low discrepancy sequence over frames, randomly shifted per voxel. Converges with less noise
+}
+
+impl SamplingMode {
+    pub fn next(self) -> Self {
+        match self {
+            Self::Random => Self::R2,
+            Self::R2 => Self::Random,
+        }
+    }
+}
+
+// The result of a successful Scene::raycast
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RayHit {
+    pub position: Vec3, // where the ray entered the voxel, in scene space
+    pub voxel: UVec3, // the hit voxel, in voxel space
+    pub normal: Vec3, // the normal of the face that was hit. Zero if the ray started inside the voxel
+    pub distance: f32, // distance from the ray origin, in scene space
+}
+
+#[derive(Clone, Copy)]
 pub struct Voxel {
-    normal: Vec3, // normal of this voxel
-    albedo: UVec3, // albedo of this voxel
-    material: u32, // index into material array
+    pub(crate) normal: Vec3, // normal of this voxel
+    pub(crate) albedo: UVec3, // albedo of this voxel
+    pub(crate) material: u32, // index into material array
 }
 
 impl Voxel {
+    pub fn new(material: u32, albedo: UVec3, normal: Vec3) -> Self {
+        Self {
+            normal,
+            albedo,
+            material,
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.material == MATERIAL_EMPTY
+    }
+    pub fn material(&self) -> u32 {
+        self.material
+    }
+    pub fn albedo(&self) -> UVec3 {
+        self.albedo
+    }
+    pub fn normal(&self) -> Vec3 {
+        self.normal
+    }
     pub fn compress(&self) -> CompressedVoxel {
         let normal = ((self.normal * 255.0) + 255.0).as_uvec3() / 2;
         CompressedVoxel {
             normal: ((self.material & 0xFF) << 24) | ((normal.x & 0xFF) << 16) | ((normal.y & 0xFF) << 8) | (normal.z & 0xFF), 
             albedo: ((self.albedo.x & 0xFF) << 24) | ((self.albedo.y & 0xFF) << 16) | ((self.albedo.z & 0xFF) << 8),
-            spec_light: 0, // these fields are only used on the GPU, don't matter here
-            diff_light: 0,
+            faces: 0, // found by Scene::update_faces
+            occlusion: UNOCCLUDED,
         }
     }
 }
@@ -273,23 +607,25 @@ impl Default for Voxel {
 
 
 #[repr(C)]
-#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct CompressedVoxel {
-    normal: u32, // material index (8 bits), normal.x, normal.y, normal.z (24 bits)
-    albedo: u32, // albedo.r (8), albedo.g (8), albedo.b (8), spec.x (8)
-    spec_light: u32, // spec.y(8), spec.z(8), diff.x(16)
-    diff_light: u32, // diff.y(16), diff.z(16)
+    pub(crate) normal: u32, // material index (8 bits), normal.x, normal.y, normal.z (24 bits)
+    pub(crate) albedo: u32, // albedo.r (8), albedo.g (8), albedo.b (8), unused (8)
+    pub(crate) faces: u32, // the slot of the first exposed face in the chunk's block (26), which faces are exposed (6)
+    pub(crate) occlusion: u32, // ambient occlusion of the +x, -x, +y, -y, +z and -z faces (5 bits each, from the lowest bits)
 }
+// the occlusion of a voxel with nothing around it, until the lighting pass finds the real one
+const UNOCCLUDED: u32 = (1 << 30) - 1;
 
 impl CompressedVoxel {
     pub fn decompress(&self) -> Voxel {
         let normal = (ivec3(
             ((self.normal >> 16) & 0xFF) as i32,
             ((self.normal >> 8) & 0xFF) as i32,
-            ((self.normal >> 0) & 0xFF) as i32
+            (self.normal & 0xFF) as i32
         ) * 2 - 255).as_vec3() * 1.0/255.0;
         let material = self.normal >> 24;
-        let albedo = uvec3(self.albedo >> 24, self.albedo >> 16, self.albedo >> 8);
+        let albedo = uvec3(self.albedo >> 24, self.albedo >> 16, self.albedo >> 8) & UVec3::splat(0xFF);
         Voxel {
             material,
             normal,
@@ -298,24 +634,120 @@ impl CompressedVoxel {
     }
 }
 
+// A physically based material, mirrored in raytracing.wgsl.
+// Diffuse light follows Lambert, specular light a Cook-Torrance BRDF with a GGX distribution.
 #[repr(C)]
-#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+#[derive(Debug, Clone, Copy, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Material {
-    emissive: u32, // may need to change for padding (bool is not zeroable???)
-    opacity: f32,
-    refraction_index: f32,
-    specular: f32,
-    shininess: f32,
-    // reflect type?
+    pub base_color: Vec3, // multiplies the albedo of voxels with this material
+    pub metallic: f32, // 0 for dielectrics, 1 for metals, which tint their reflections and have no diffuse light
+    pub emission: Vec3, // color of the emitted light, which is also multiplied by the voxel albedo
+    pub emission_strength: f32, // 0 for materials that don't emit light. Can be above 1 to light the scene brighter than white
+    pub roughness: f32, // 0 is a perfect mirror, 1 is completely rough
+    pub transmission: f32, // how much light passes through, 0 is opaque
+    pub ior: f32, // index of refraction
+    pub specular: f32, // reflectance of dielectrics, 0.5 is 4% when looking straight at the surface
+    pub density: f32, // 0 for surfaces. Above 0 the voxels are a participating medium like smoke, which light is scattered in
+    pub anisotropy: f32, // Henyey-Greenstein anisotropy of the medium, positive values scatter light forward
+    pub absorption: f32, // the part of the light stopped by the medium that is absorbed instead of scattered
+}
+impl Material {
+    // convert a material from the old emissive, opacity, refraction index, specular, shininess model
+    pub fn legacy(emissive: bool, opacity: f32, refraction_index: f32, specular: f32, shininess: f32) -> Self {
+        // the old specular lobes were about 1 / shininess radians wide, which is a Phong exponent of shininess^2
+        let phong_exponent = shininess * shininess;
+        let alpha = (2.0 / (phong_exponent + 2.0)).sqrt();
+        Self {
+            metallic: specular,
+            emission_strength: if emissive { 1.0 } else { 0.0 },
+            roughness: if specular > 0.0 { alpha.sqrt() } else { 1.0 },
+            transmission: 1.0 - opacity,
+            ior: if refraction_index > 0.0 { refraction_index } else { 1.0 },
+            ..Default::default()
+        }
+    }
+    // a medium that scatters light of its voxels' color, like colored smoke
+    pub fn volume(density: f32, anisotropy: f32) -> Self {
+        Self {
+            density,
+            anisotropy,
+            ..Default::default()
+        }
+    }
+    // clear water, mostly letting light through and reflecting a little
+    pub fn water() -> Self {
+        Self {
+            base_color: vec3(0.8, 0.9, 1.0),
+            roughness: 0.05,
+            transmission: 0.8,
+            ior: 1.33,
+            ..Default::default()
+        }
+    }
+    pub fn is_emissive(&self) -> bool {
+        self.emission_strength > 0.0
+    }
+    pub fn is_volume(&self) -> bool {
+        self.density > 0.0
+    }
+    // whether voxels of this material hide what is behind them
+    pub fn is_opaque(&self) -> bool {
+        !self.is_volume() && self.transmission <= 0.0
+    }
 }
 impl Default for Material {
     fn default() -> Self {
         Self {
-            emissive: 0,
-            opacity: 1.0,
-            refraction_index: 0.0,
-            specular: 0.0,
-            shininess: 0.0,
+            base_color: Vec3::ONE,
+            metallic: 0.0,
+            emission: Vec3::ONE,
+            emission_strength: 0.0,
+            roughness: 1.0,
+            transmission: 0.0,
+            ior: 1.5,
+            specular: 0.5,
+            density: 0.0,
+            anisotropy: 0.0,
+            absorption: 0.0,
+        }
+    }
+}
+
+// Fog that fills the scene up to a height and thins out exponentially above it, mirrored in raytracing.wgsl.
+// The primary rays are marched through it, casting shadow rays toward the sun for light shafts.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Fog {
+    pub color: Vec3, // the part of the light scattered by the fog, per channel
+    pub density: f32, // how much light the fog stops per scene unit below height. 0 disables the fog
+    pub height: f32, // in scene space
+    pub falloff: f32, // how fast the density decreases above height, per scene unit
+    pub anisotropy: f32, // Henyey-Greenstein anisotropy, positive values make the fog glow around the sun
+    pub samples: u32, // points along every primary ray where the fog is lit
+}
+impl Fog {
+    // a thin haze close to the ground, for the viewer
+    pub fn haze() -> Self {
+        Self {
+            density: 0.15,
+            height: 1.0,
+            falloff: 1.5,
+            ..Default::default()
+        }
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.density > 0.0
+    }
+}
+impl Default for Fog {
+    fn default() -> Self {
+        Self {
+            color: Vec3::ONE,
+            density: 0.0,
+            height: 1.0,
+            falloff: 1.0,
+            anisotropy: 0.6,
+            samples: 8,
         }
     }
 }
@@ -327,8 +759,8 @@ pub const fn flatten_index(position: UVec3, dimensions: UVec3) -> usize {
 
 pub const fn expand_index(idx: usize, dimensions: UVec3) -> Vec3 {
     let idx = idx as u32;
-    let x = idx % dimensions.x as u32;
-    let y = idx / dimensions.x as u32 % dimensions.y as u32;
-    let z = idx / (dimensions.x as u32 * dimensions.y as u32);
+    let x = idx % dimensions.x;
+    let y = idx / dimensions.x % dimensions.y;
+    let z = idx / (dimensions.x * dimensions.y);
     vec3(x as f32, y as f32, z as f32)
 }
\ No newline at end of file
@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use glam::{ivec3, uvec3, BVec3, UVec3, Vec3, Vec4Swizzles};
+use serde::{Deserialize, Serialize};
+
+use super::prefab::{Rotation90, VoxelModel};
+use super::{flatten_index, LightingRegion, Material, MaterialId, Scene, Voxel, CHUNK_SIZE};
+
+// Things that change with Scene::time: models that swap between frames like MagicaVoxel animations, material
+// parameters that follow curves, like pulsing or flickering lights, and colors that scroll over voxels, like flowing
+// water or lava. Scene::update plays them every frame. Only the chunks and materials that actually changed are
+// written to the GPU again. The lighting only starts over around new voxels and lights that changed noticeably, so
+// it still converges while things move.
+
+pub type AnimationId = usize; // index into the animations of a scene
+
+// How a curve gets from one key to the next
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Interpolation {
+    Step, // keeps the value of a key until the next one
+    #[default]
+    Linear,
+    Smooth, // eases in and out of every key
+}
+
+// A value over time, given by keys
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Curve {
+    keys: Vec<(f32, f32)>, // seconds and the value at that time, sorted by time
+    interpolation: Interpolation,
+    looping: bool, // whether the keys start over after the last one
+}
+
+// keys of Curve::flicker before it repeats
+const FLICKER_KEYS: i32 = 16;
+// how much an animated emission strength changes between keys before the lighting around it starts over
+const EMISSION_CHANGE: f32 = 0.1;
+
+impl Curve {
+    pub fn new(interpolation: Interpolation, mut keys: Vec<(f32, f32)>) -> Self {
+        keys.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Self { keys, interpolation, looping: false }
+    }
+    pub fn constant(value: f32) -> Self {
+        Self::new(Interpolation::Step, vec![(0.0, value)])
+    }
+    // the same curve starting over every duration seconds. The last key should have the value of the first
+    pub fn looping(self) -> Self {
+        Self { looping: true, ..self }
+    }
+    // smoothly from low up to high and back once every period seconds
+    pub fn pulse(low: f32, high: f32, period: f32) -> Self {
+        Self::new(Interpolation::Smooth, vec![(0.0, low), (period / 2.0, high), (period, low)]).looping()
+    }
+    // a random value between low and high every interval seconds, like a candle or a broken lamp
+    pub fn flicker(low: f32, high: f32, interval: f32, seed: u32) -> Self {
+        let random = |i: i32| super::noise::hash(ivec3(i % FLICKER_KEYS, 0, 0), seed) as f32 / u32::MAX as f32;
+        let keys = (0..=FLICKER_KEYS).map(|i| (i as f32 * interval, low + (high - low) * random(i))).collect();
+        Self::new(Interpolation::Linear, keys).looping()
+    }
+    pub fn keys(&self) -> &[(f32, f32)] {
+        &self.keys
+    }
+    // the time of the last key
+    pub fn duration(&self) -> f32 {
+        self.keys.last().map_or(0.0, |key| key.0)
+    }
+    // whether the curve passes a key after from and up to to, in seconds
+    pub fn crosses_key(&self, from: f32, to: f32) -> bool {
+        let duration = self.duration();
+        self.keys.iter().any(|&(key, _)| {
+            if self.looping && duration > 0.0 {
+                // the last time the key comes up before to
+                let last = key + ((to - key) / duration).floor() * duration;
+                from < last && last <= to
+            } else {
+                from < key && key <= to
+            }
+        })
+    }
+    // the value at a time in seconds. Before the first key and after the last one the curve stays flat, unless it loops.
+    // Curves without keys are 0
+    pub fn sample(&self, time: f32) -> f32 {
+        let (Some(first), Some(last)) = (self.keys.first(), self.keys.last()) else {
+            return 0.0;
+        };
+        let time = if self.looping && last.0 > 0.0 { time.rem_euclid(last.0) } else { time };
+        if time <= first.0 {
+            return first.1;
+        }
+        if time >= last.0 {
+            return last.1;
+        }
+        let i = self.keys.partition_point(|key| key.0 <= time) - 1;
+        let ((t1, v1), (t2, v2)) = (self.keys[i], self.keys[i + 1]);
+        let t = (time - t1) / (t2 - t1);
+        let t = match self.interpolation {
+            Interpolation::Step => 0.0,
+            Interpolation::Linear => t,
+            Interpolation::Smooth => t * t * (3.0 - 2.0 * t),
+        };
+        v1 + (v2 - v1) * t
+    }
+}
+
+// The parameters of a material that can be animated. The others change which faces are hidden, which is too much work
+// to do every frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MaterialParameter {
+    EmissionStrength,
+    Roughness,
+    Metallic,
+    Specular,
+}
+
+impl MaterialParameter {
+    fn value(self, material: &mut Material) -> &mut f32 {
+        match self {
+            Self::EmissionStrength => &mut material.emission_strength,
+            Self::Roughness => &mut material.roughness,
+            Self::Metallic => &mut material.metallic,
+            Self::Specular => &mut material.specular,
+        }
+    }
+}
+
+// A material parameter following a curve
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MaterialAnimation {
+    pub material: MaterialId,
+    pub parameter: MaterialParameter,
+    pub curve: Curve,
+}
+
+// A voxel model with frames that are shown one after the other, placed like Scene::stamp
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameAnimation {
+    pub frames: Vec<VoxelModel>,
+    pub frame_time: f32, // seconds every frame is shown
+    pub looping: bool, // whether the frames start over after the last one, which stays otherwise
+    pub position: UVec3,
+    pub rotation: Rotation90,
+    pub mirror: BVec3,
+    shown: Option<usize>, // the frame in the scene
+}
+
+impl FrameAnimation {
+    // a looping animation, not turned or mirrored
+    pub fn new(frames: Vec<VoxelModel>, frame_time: f32, position: UVec3) -> Self {
+        Self { frames, frame_time, looping: true, position, rotation: Rotation90::IDENTITY, mirror: BVec3::FALSE, shown: None }
+    }
+    // the frame shown at a time in seconds
+    pub fn frame_at(&self, time: f32) -> usize {
+        let frame = if self.frame_time > 0.0 { (time / self.frame_time).max(0.0) as usize } else { 0 };
+        if self.looping {
+            frame % self.frames.len().max(1)
+        } else {
+            frame.min(self.frames.len().saturating_sub(1))
+        }
+    }
+}
+
+// Colors blended in a cycle along the direction voxels scroll in, recoloring the voxels of a material in a box.
+// Noise bends the bands, so it can look like flowing water or lava instead of stripes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlbedoScroll {
+    pub min: UVec3, // the box of voxels in voxel space, max excluded
+    pub max: UVec3,
+    pub material: MaterialId, // only voxels of this material are recolored
+    pub colors: Vec<UVec3>,
+    pub wavelength: f32, // voxels for one cycle through the colors
+    pub velocity: Vec3, // voxels per second
+    pub turbulence: f32, // how far the noise bends the bands, in cycles. 0 for straight bands
+    pub seed: u32,
+}
+
+impl AlbedoScroll {
+    // the color of a voxel at a time in seconds
+    pub fn albedo_at(&self, pos: UVec3, time: f32) -> UVec3 {
+        let Some(&first) = self.colors.first() else {
+            return UVec3::splat(255);
+        };
+        let wavelength = self.wavelength.max(f32::EPSILON);
+        // the point of the pattern that moved to this voxel
+        let p = (pos.as_vec3() + 0.5 - self.velocity * time) / wavelength;
+        let phase = p.dot(self.velocity.normalize_or_zero()) + self.turbulence * super::noise::perlin(p, self.seed);
+        let cycle = phase.rem_euclid(1.0) * self.colors.len() as f32;
+        let i = (cycle as usize).min(self.colors.len() - 1);
+        let next = self.colors.get(i + 1).copied().unwrap_or(first);
+        self.colors[i].as_vec3().lerp(next.as_vec3(), cycle - i as f32).round().as_uvec3()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Animation {
+    Frames(FrameAnimation),
+    Material(MaterialAnimation),
+    Albedo(AlbedoScroll),
+}
+
+impl From<FrameAnimation> for Animation {
+    fn from(animation: FrameAnimation) -> Self {
+        Self::Frames(animation)
+    }
+}
+impl From<MaterialAnimation> for Animation {
+    fn from(animation: MaterialAnimation) -> Self {
+        Self::Material(animation)
+    }
+}
+impl From<AlbedoScroll> for Animation {
+    fn from(animation: AlbedoScroll) -> Self {
+        Self::Albedo(animation)
+    }
+}
+
+impl Scene {
+    // play an animation from the next update on
+    pub fn add_animation(&mut self, animation: impl Into<Animation>) -> AnimationId {
+        self.animations.push(Some(animation.into()));
+        self.animations.len() - 1
+    }
+    pub fn animation(&self, id: AnimationId) -> Option<&Animation> {
+        self.animations.get(id).and_then(Option::as_ref)
+    }
+    // stop an animation. What it changed stays the way it is, and its id isn't used again
+    pub fn remove_animation(&mut self, id: AnimationId) {
+        if let Some(animation) = self.animations.get_mut(id) {
+            *animation = None;
+        }
+    }
+    // bring every animation to the current time
+    pub fn animate(&mut self) {
+        let (from, time) = (self.animated as f32 / 1000.0, self.time as f32 / 1000.0);
+        self.animated = self.time;
+        let mut animations = std::mem::take(&mut self.animations);
+        let mut relit_materials = Vec::new();
+        for animation in animations.iter_mut().flatten() {
+            match animation {
+                Animation::Frames(frames) => self.show_frame(frames, time),
+                Animation::Material(animation) => {
+                    let Some(material) = self.materials.get_mut(animation.material as usize) else {
+                        continue;
+                    };
+                    let value = animation.parameter.value(material);
+                    let sample = animation.curve.sample(time);
+                    if *value == sample {
+                        continue;
+                    }
+                    *value = sample;
+                    self.dirty_settings = true;
+                    // the others only change how the light is shaded, but the emission is in the light of the voxels around.
+                    // Gathering it again every frame would keep the lighting noisy, so only at keys and bigger changes
+                    if animation.parameter != MaterialParameter::EmissionStrength {
+                        continue;
+                    }
+                    let lit = &mut self.lit_emission[animation.material as usize];
+                    if animation.curve.crosses_key(from, time) || (sample - *lit).abs() > EMISSION_CHANGE * sample.abs().max(lit.abs()) {
+                        *lit = sample;
+                        relit_materials.push(animation.material);
+                    }
+                }
+                Animation::Albedo(scroll) => self.scroll_albedo(scroll, time),
+            }
+        }
+        self.animations = animations;
+        if !relit_materials.is_empty() {
+            self.invalidate_materials(&relit_materials);
+        }
+    }
+
+    // swap the voxels of the frame in the scene for the ones of the frame at a time
+    fn show_frame(&mut self, animation: &mut FrameAnimation, time: f32) {
+        let frame = animation.frame_at(time);
+        if animation.frames.is_empty() || animation.shown == Some(frame) {
+            return;
+        }
+        let stamp = |model: &VoxelModel| self.stamp_edits(model, animation.position, animation.rotation, animation.mirror);
+        let mut edits: HashMap<UVec3, Voxel> = HashMap::new();
+        if let Some(shown) = animation.shown.and_then(|shown| animation.frames.get(shown)) {
+            edits.extend(stamp(shown).into_iter().map(|(pos, _)| (pos, Voxel::default())));
+        }
+        edits.extend(stamp(&animation.frames[frame]));
+        // voxels that are the same in both frames are left alone, so the chunks that don't change aren't touched
+        let edits = edits.into_iter().filter(|(pos, voxel)| self.get_voxel(*pos).compress() != voxel.compress()).collect();
+        self.write_voxels(edits);
+        animation.shown = Some(frame);
+    }
+    // recolor the voxels of a scroll to its colors at a time. Only the albedo changes, so the faces stay where they are
+    fn scroll_albedo(&mut self, scroll: &AlbedoScroll, time: f32) {
+        let size = self.size.xyz().as_uvec3();
+        let chunk_size = UVec3::splat(CHUNK_SIZE as u32);
+        let max = scroll.max.min(size * chunk_size);
+        let mut changed = Vec::new();
+        for z in scroll.min.z..max.z {
+            for y in scroll.min.y..max.y {
+                for x in scroll.min.x..max.x {
+                    let pos = uvec3(x, y, z);
+                    let chunk = flatten_index(pos / chunk_size, size);
+                    let voxel = &mut self.chunks[chunk].voxels[flatten_index(pos % chunk_size, chunk_size)];
+                    if voxel.normal >> 24 != scroll.material {
+                        continue;
+                    }
+                    let mut recolored = voxel.decompress();
+                    recolored.albedo = scroll.albedo_at(pos, time);
+                    let albedo = recolored.compress().albedo;
+                    if voxel.albedo != albedo {
+                        voxel.albedo = albedo;
+                        changed.push(pos / chunk_size);
+                    }
+                }
+            }
+        }
+        // the albedo is applied when shading, so the light gathered so far stays
+        for chunk in changed {
+            self.recolored_chunks[flatten_index(chunk, size)] = true;
+        }
+    }
+    // throw away the lighting around every chunk with voxels of some materials
+    fn invalidate_materials(&mut self, materials: &[MaterialId]) {
+        let chunks: Vec<UVec3> = self.chunks.iter()
+            .filter(|chunk| chunk.pos.w != 0.0 && chunk.voxels.iter().any(|voxel| materials.contains(&(voxel.normal >> 24))))
+            .map(|chunk| chunk.pos.xyz().as_uvec3())
+            .collect();
+        for chunk in chunks {
+            self.invalidate_lighting(LightingRegion::chunk(chunk));
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::HashSet;
+use glam::{UVec3, Vec3, Vec4Swizzles};
+
+use super::{flatten_index, Scene, Voxel, CHUNK_SIZE};
+
+// World-space editing of the scene. A brush fills, carves or trims a shape, no matter how many chunks it spans.
+// Everything is in voxel space (CHUNK_SIZE voxels per scene unit), and a voxel is inside a shape when its center is.
+// Filled voxels point out of the shape, and the voxels a brush uncovers point out of the hole it left.
+
+// How a brush combines its shape with the voxels already in the scene
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BrushMode {
+    #[default]
+    Union, // fill the shape
+    Subtract, // empty the shape
+    Intersect, // empty everything outside the shape
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Brush {
+    pub mode: BrushMode,
+    pub material: u32, // the material of filled voxels
+    pub albedo: UVec3,
+    pub normal: Option<Vec3>, // the normal of every voxel the brush touches, instead of the normals of the shape
+}
+
+impl Brush {
+    pub fn union(material: u32, albedo: UVec3) -> Self {
+        Self { mode: BrushMode::Union, material, albedo, normal: None }
+    }
+    pub fn subtract() -> Self {
+        Self { mode: BrushMode::Subtract, ..Self::union(0, UVec3::ZERO) }
+    }
+    pub fn intersect() -> Self {
+        Self { mode: BrushMode::Intersect, ..Self::union(0, UVec3::ZERO) }
+    }
+    // the same brush, giving every voxel it touches the same normal
+    pub fn with_normal(self, normal: Vec3) -> Self {
+        Self { normal: Some(normal.normalize()), ..self }
+    }
+}
+
+// The shapes a brush can have, in voxel space
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shape {
+    Box { min: UVec3, max: UVec3 }, // min included, max excluded
+    Sphere { center: Vec3, radius: f32 },
+    Cylinder { base: Vec3, top: Vec3, radius: f32 }, // capped with flat ends
+    Capsule { a: Vec3, b: Vec3, radius: f32 }, // capped with half spheres
+    Line { from: UVec3, to: UVec3 }, // the voxels between two voxels, both included, without gaps
+}
+
+// the step for finding normals from the distances around a point
+const GRADIENT_STEP: f32 = 0.01;
+
+impl Shape {
+    // the signed distance from a point to the surface, negative inside
+    pub fn distance(&self, p: Vec3) -> f32 {
+        match *self {
+            Shape::Box { min, max } => {
+                let (min, max) = (min.as_vec3(), max.as_vec3());
+                let q = (p - (min + max) / 2.0).abs() - (max - min) / 2.0;
+                q.max(Vec3::ZERO).length() + q.max_element().min(0.0)
+            }
+            Shape::Sphere { center, radius } => p.distance(center) - radius,
+            Shape::Cylinder { base, top, radius } => {
+                let axis = top - base;
+                let offset = p - base;
+                let axis_sq = axis.length_squared();
+                let along = offset.dot(axis);
+                // both distances scaled by axis_sq, to stay away from a division
+                let x = (offset * axis_sq - axis * along).length() - radius * axis_sq;
+                let y = (along - axis_sq * 0.5).abs() - axis_sq * 0.5;
+                let x_sq = x * x;
+                let y_sq = y * y * axis_sq;
+                let d = if x.max(y) < 0.0 {
+                    -x_sq.min(y_sq)
+                } else {
+                    (if x > 0.0 { x_sq } else { 0.0 }) + (if y > 0.0 { y_sq } else { 0.0 })
+                };
+                d.signum() * d.abs().sqrt() / axis_sq
+            }
+            Shape::Capsule { a, b, radius } => segment_distance(p, a, b) - radius,
+            Shape::Line { from, to } => segment_distance(p, from.as_vec3() + 0.5, to.as_vec3() + 0.5) - 0.5,
+        }
+    }
+    // whether the center of a voxel is inside
+    pub fn contains(&self, voxel: UVec3) -> bool {
+        match *self {
+            Shape::Line { from, to } => {
+                // step along the longest axis, rounding the others
+                let delta = to.as_vec3() - from.as_vec3();
+                let abs = delta.abs();
+                let axis = if abs.x >= abs.y && abs.x >= abs.z { 0 } else if abs.y >= abs.z { 1 } else { 2 };
+                let steps = delta[axis].abs();
+                let step = (voxel.as_vec3()[axis] - from.as_vec3()[axis]) * delta[axis].signum();
+                if steps == 0.0 {
+                    return voxel == from;
+                }
+                if !(0.0..=steps).contains(&step) {
+                    return false;
+                }
+                (from.as_vec3() + delta * step / steps).round() == voxel.as_vec3()
+            }
+            _ => self.distance(voxel.as_vec3() + 0.5) < 0.0,
+        }
+    }
+    // the direction the surface faces at a point, out of the shape
+    pub fn normal(&self, p: Vec3) -> Vec3 {
+        let gradient = Vec3::new(
+            self.distance(p + Vec3::X * GRADIENT_STEP) - self.distance(p - Vec3::X * GRADIENT_STEP),
+            self.distance(p + Vec3::Y * GRADIENT_STEP) - self.distance(p - Vec3::Y * GRADIENT_STEP),
+            self.distance(p + Vec3::Z * GRADIENT_STEP) - self.distance(p - Vec3::Z * GRADIENT_STEP),
+        );
+        gradient.try_normalize().unwrap_or(Vec3::Y) // the middle of lines has no direction
+    }
+    // the voxels that can be inside, min included and max excluded
+    pub fn bounds(&self) -> (Vec3, Vec3) {
+        match *self {
+            Shape::Box { min, max } => (min.as_vec3(), max.as_vec3()),
+            Shape::Sphere { center, radius } => (center - radius, center + radius),
+            Shape::Cylinder { base, top, radius } | Shape::Capsule { a: base, b: top, radius } => {
+                (base.min(top) - radius, base.max(top) + radius)
+            }
+            Shape::Line { from, to } => (from.min(to).as_vec3(), from.max(to).as_vec3() + 1.0),
+        }
+    }
+}
+
+// the distance from a point to the segment from a to b
+fn segment_distance(p: Vec3, a: Vec3, b: Vec3) -> f32 {
+    let axis = b - a;
+    let along = if axis == Vec3::ZERO { 0.0 } else { ((p - a).dot(axis) / axis.length_squared()).clamp(0.0, 1.0) };
+    p.distance(a + axis * along)
+}
+
+// the directions to the six neighbours of a voxel
+const NEIGHBOURS: [[i32; 3]; 6] = [[1, 0, 0], [-1, 0, 0], [0, 1, 0], [0, -1, 0], [0, 0, 1], [0, 0, -1]];
+
+impl Scene {
+    // the voxel at a position in voxel space. Everything outside the scene is empty
+    pub fn get_voxel(&self, pos: UVec3) -> Voxel {
+        if !self.contains_voxel(pos) {
+            return Voxel::default();
+        }
+        let chunk = flatten_index(pos / CHUNK_SIZE as u32, self.size.xyz().as_uvec3());
+        self.chunks[chunk].voxels[flatten_index(pos % CHUNK_SIZE as u32, UVec3::splat(CHUNK_SIZE as u32))].decompress()
+    }
+    // whether a position in voxel space is inside the scene
+    pub fn contains_voxel(&self, pos: UVec3) -> bool {
+        pos.cmplt(self.size.xyz().as_uvec3() * CHUNK_SIZE as u32).all()
+    }
+    // apply a brush to the voxels of a shape. Returns the chunks that changed, in scene space, which have their
+    // lighting invalidated and are written to the GPU by the next Scene::write_changes
+    pub fn paint(&mut self, shape: &Shape, brush: &Brush) -> Vec<UVec3> {
+        let scene_max = self.size.xyz().as_uvec3() * CHUNK_SIZE as u32;
+        let (min, max) = match brush.mode {
+            BrushMode::Intersect => (UVec3::ZERO, scene_max), // everything outside the shape is trimmed
+            _ => {
+                let (min, max) = shape.bounds();
+                (min.floor().max(Vec3::ZERO).as_uvec3(), max.ceil().max(Vec3::ZERO).as_uvec3().min(scene_max))
+            }
+        };
+        let mut edits = Vec::new();
+        let mut removed = HashSet::new();
+        for z in min.z..max.z {
+            for y in min.y..max.y {
+                for x in min.x..max.x {
+                    let pos = UVec3::new(x, y, z);
+                    let inside = shape.contains(pos);
+                    match brush.mode {
+                        BrushMode::Union if inside => {
+                            let normal = brush.normal.unwrap_or_else(|| shape.normal(pos.as_vec3() + 0.5));
+                            edits.push((pos, Voxel::new(brush.material, brush.albedo, normal)));
+                        }
+                        BrushMode::Subtract if inside && !self.get_voxel(pos).is_empty() => {
+                            removed.insert(pos);
+                        }
+                        BrushMode::Intersect if !inside && !self.get_voxel(pos).is_empty() => {
+                            removed.insert(pos);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        // the voxels next to the removed ones are now on the surface, facing out of the hole
+        let flip = if brush.mode == BrushMode::Subtract { -1.0 } else { 1.0 };
+        for &pos in &removed {
+            for offset in NEIGHBOURS {
+                let neighbour = (pos.as_ivec3() + glam::IVec3::from(offset)).as_uvec3();
+                if removed.contains(&neighbour) || !self.contains_voxel(neighbour) {
+                    continue; // negative positions wrap around to huge ones, which are outside too
+                }
+                let mut voxel = self.get_voxel(neighbour);
+                if voxel.is_empty() {
+                    continue;
+                }
+                voxel.normal = brush.normal.unwrap_or_else(|| shape.normal(neighbour.as_vec3() + 0.5) * flip);
+                edits.push((neighbour, voxel));
+            }
+        }
+        edits.extend(removed.into_iter().map(|pos| (pos, Voxel::default())));
+        self.write_voxels(edits)
+    }
+    // fill a box, min included and max excluded
+    pub fn fill_box(&mut self, min: UVec3, max: UVec3, brush: &Brush) -> Vec<UVec3> {
+        self.paint(&Shape::Box { min, max }, brush)
+    }
+    pub fn fill_sphere(&mut self, center: Vec3, radius: f32, brush: &Brush) -> Vec<UVec3> {
+        self.paint(&Shape::Sphere { center, radius }, brush)
+    }
+    // fill a cylinder with flat ends at base and top
+    pub fn fill_cylinder(&mut self, base: Vec3, top: Vec3, radius: f32, brush: &Brush) -> Vec<UVec3> {
+        self.paint(&Shape::Cylinder { base, top, radius }, brush)
+    }
+    // fill a cylinder from a to b with round ends
+    pub fn fill_capsule(&mut self, a: Vec3, b: Vec3, radius: f32, brush: &Brush) -> Vec<UVec3> {
+        self.paint(&Shape::Capsule { a, b, radius }, brush)
+    }
+    // a line of voxels from one voxel to another
+    pub fn line(&mut self, from: UVec3, to: UVec3, brush: &Brush) -> Vec<UVec3> {
+        self.paint(&Shape::Line { from, to }, brush)
+    }
+    // set many voxels, going through every chunk once. Returns the chunks that were written to
+    pub(crate) fn write_voxels(&mut self, mut edits: Vec<(UVec3, Voxel)>) -> Vec<UVec3> {
+        let size = self.size.xyz().as_uvec3();
+        let chunk_size = UVec3::splat(CHUNK_SIZE as u32);
+        edits.sort_by_key(|(pos, _)| flatten_index(*pos / chunk_size, size));
+        let mut touched = Vec::new();
+        for group in edits.chunk_by(|(a, _), (b, _)| *a / chunk_size == *b / chunk_size) {
+            let chunk_pos = group[0].0 / chunk_size;
+            let chunk = self.chunk_at(chunk_pos);
+            for (pos, voxel) in group {
+                chunk.voxels[flatten_index(*pos % chunk_size, chunk_size)] = voxel.compress();
+            }
+            chunk.update_visibility();
+            touched.push(chunk_pos);
+        }
+        touched
+    }
+}
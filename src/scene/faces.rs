@@ -0,0 +1,158 @@
+use std::ops::Range;
+use glam::{IVec3, UVec3};
+
+use super::{flatten_index, Chunk, Material, CHUNK_SIZE, MATERIAL_EMPTY};
+
+// The lighting cache is stored per voxel face rather than per voxel, so both sides of a thin wall get their own light.
+// Only faces that can be seen get a slot: faces next to empty, transparent or volumetric voxels and faces on the edge of the scene.
+// Every chunk owns one block of slots in the face light buffer, holding the faces of its voxels in order, and
+// CompressedVoxel::faces says which faces of a voxel are exposed and where in the block the first one is.
+
+pub const FACE_COUNT: u32 = 6; // +x, -x, +y, -y, +z, -z
+const FACE_MASK_BITS: u32 = 6;
+
+// The light of one voxel face, mirrored in raytracing.wgsl
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct FaceLight {
+    pub(crate) specular: u32, // r(8), g(8), b(8), unused(8)
+    pub(crate) diffuse_rg: u32, // r(16), g(16)
+    pub(crate) diffuse_b: u32, // b(16), unused(16)
+}
+
+// the outward normal of a face
+pub fn face_normal(face: u32) -> IVec3 {
+    let mut normal = IVec3::ZERO;
+    normal[(face / 2) as usize] = 1 - 2 * (face % 2) as i32;
+    normal
+}
+
+// pack the exposed faces of a voxel and the position of its first face in the chunk's block
+pub(crate) fn pack_faces(first_slot: u32, mask: u32) -> u32 {
+    (first_slot << FACE_MASK_BITS) | mask
+}
+
+// Hands out blocks of the face light buffer, first fit from a list of free ranges.
+// The buffer only grows when none of the free ranges is big enough.
+#[derive(Debug, Clone, Default)]
+pub struct FaceAllocator {
+    free: Vec<Range<u32>>, // sorted by start, and never touching each other
+    capacity: u32, // the number of slots the buffer needs
+}
+
+impl FaceAllocator {
+    // the start of a new block of size slots
+    pub fn allocate(&mut self, size: u32) -> u32 {
+        if let Some(i) = self.free.iter().position(|range| range.len() as u32 >= size) {
+            let start = self.free[i].start;
+            self.free[i].start += size;
+            if self.free[i].is_empty() {
+                self.free.remove(i);
+            }
+            return start;
+        }
+        // grow the buffer, reusing a free range at its end
+        let start = match self.free.last() {
+            Some(last) if last.end == self.capacity => self.free.pop().unwrap().start,
+            _ => self.capacity,
+        };
+        self.capacity = start + size;
+        start
+    }
+    pub fn free(&mut self, block: Range<u32>) {
+        if block.is_empty() {
+            return;
+        }
+        let i = self.free.partition_point(|range| range.start < block.start);
+        self.free.insert(i, block);
+        // merge with the neighbouring ranges
+        if i + 1 < self.free.len() && self.free[i].end == self.free[i + 1].start {
+            self.free[i].end = self.free.remove(i + 1).end;
+        }
+        if i > 0 && self.free[i - 1].end == self.free[i].start {
+            self.free[i - 1].end = self.free.remove(i).end;
+        }
+    }
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+}
+
+// The scene data the face layout depends on
+pub(crate) struct FaceLayout<'a> {
+    pub chunks: &'a mut [Chunk],
+    pub stale: &'a mut [bool], // chunks whose voxels or neighbours changed since their faces were laid out
+    pub allocator: &'a mut FaceAllocator,
+    pub materials: &'a [Material],
+    pub size: UVec3, // in chunks
+}
+
+impl FaceLayout<'_> {
+    // find the exposed faces of the voxels in stale chunks and give those chunks a block of the right size.
+    // Returns true if the face light buffer has to grow
+    pub fn update(&mut self) -> bool {
+        let capacity = self.allocator.capacity();
+        for chunk_idx in 0..self.chunks.len() {
+            if !self.stale[chunk_idx] {
+                continue;
+            }
+            self.stale[chunk_idx] = false;
+            let masks: Vec<u32> = (0..self.chunks[chunk_idx].voxels.len())
+                .map(|voxel_idx| self.exposed_faces(chunk_idx, voxel_idx))
+                .collect();
+            let face_count = masks.iter().map(|mask| mask.count_ones()).sum();
+            let chunk = &mut self.chunks[chunk_idx];
+            let old_block = chunk.face_offset..chunk.face_offset + chunk.face_count;
+            let mut first_slot = 0;
+            for (voxel, mask) in chunk.voxels.iter_mut().zip(masks) {
+                voxel.faces = pack_faces(first_slot, mask);
+                first_slot += mask.count_ones();
+            }
+            if face_count != chunk.face_count {
+                self.allocator.free(old_block);
+                chunk.face_offset = if face_count > 0 { self.allocator.allocate(face_count) } else { 0 };
+                chunk.face_count = face_count;
+            }
+            chunk.accumulated_light_samples = 0; // the light of the old faces doesn't belong to the new ones
+        }
+        self.allocator.capacity() > capacity
+    }
+
+    // the faces of a voxel that need lighting, as a bit mask in the order of face_normal
+    fn exposed_faces(&self, chunk_idx: usize, voxel_idx: usize) -> u32 {
+        let voxel = &self.chunks[chunk_idx].voxels[voxel_idx];
+        let material = voxel.normal >> 24;
+        if material == MATERIAL_EMPTY {
+            return 0;
+        }
+        let material = &self.materials[(material as usize).min(self.materials.len() - 1)];
+        if material.is_volume() {
+            return 1; // media are lit as a whole, in the slot of the first face
+        }
+        if material.transmission > 0.0 {
+            return 0; // transparent voxels only tint what is behind them
+        }
+        let chunk_pos = self.chunks[chunk_idx].pos.truncate().as_ivec3();
+        let in_chunk = super::expand_index(voxel_idx, UVec3::splat(CHUNK_SIZE as u32)).as_ivec3();
+        let pos = chunk_pos * CHUNK_SIZE as i32 + in_chunk;
+        (0..FACE_COUNT)
+            .filter(|&face| !self.is_opaque(pos + face_normal(face)))
+            .fold(0, |mask, face| mask | 1 << face)
+    }
+
+    // whether a voxel hides the faces next to it, in voxel space. Everything outside the scene is empty
+    fn is_opaque(&self, pos: IVec3) -> bool {
+        let scene_voxels = self.size.as_ivec3() * CHUNK_SIZE as i32;
+        if pos.cmplt(IVec3::ZERO).any() || pos.cmpge(scene_voxels).any() {
+            return false;
+        }
+        let chunk = (pos / CHUNK_SIZE as i32).as_uvec3();
+        let in_chunk = (pos % CHUNK_SIZE as i32).as_uvec3();
+        let voxel = &self.chunks[flatten_index(chunk, self.size)].voxels[flatten_index(in_chunk, UVec3::splat(CHUNK_SIZE as u32))];
+        let material = voxel.normal >> 24;
+        if material == MATERIAL_EMPTY {
+            return false;
+        }
+        self.materials[(material as usize).min(self.materials.len() - 1)].is_opaque()
+    }
+}
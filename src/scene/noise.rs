@@ -0,0 +1,90 @@
+use glam::{IVec3, Vec3};
+
+// Seeded gradient (Perlin) noise and the fractal sums built on it, for procedural content like terrain::Terrain.
+// Only integer hashing and plain float math, so the same seed gives the same noise every time.
+
+// a well mixed hash of a lattice point and a seed (from the lowbias32 family of integer hashes)
+pub(crate) fn hash(point: IVec3, seed: u32) -> u32 {
+    let mut h = seed ^ 0x9E37_79B9;
+    for coordinate in point.to_array() {
+        h ^= coordinate as u32;
+        h ^= h >> 16;
+        h = h.wrapping_mul(0x7FEB_352D);
+        h ^= h >> 15;
+        h = h.wrapping_mul(0x846C_A68B);
+        h ^= h >> 16;
+    }
+    h
+}
+
+// the gradients at the lattice points, the 12 directions to the edges of a cube
+const GRADIENTS: [Vec3; 12] = [
+    Vec3::new(1.0, 1.0, 0.0), Vec3::new(-1.0, 1.0, 0.0), Vec3::new(1.0, -1.0, 0.0), Vec3::new(-1.0, -1.0, 0.0),
+    Vec3::new(1.0, 0.0, 1.0), Vec3::new(-1.0, 0.0, 1.0), Vec3::new(1.0, 0.0, -1.0), Vec3::new(-1.0, 0.0, -1.0),
+    Vec3::new(0.0, 1.0, 1.0), Vec3::new(0.0, -1.0, 1.0), Vec3::new(0.0, 1.0, -1.0), Vec3::new(0.0, -1.0, -1.0),
+];
+
+// gradient noise in about -1..1, which is 0 at every integer point
+pub fn perlin(p: Vec3, seed: u32) -> f32 {
+    let cell = p.floor();
+    let local = p - cell;
+    let cell = cell.as_ivec3();
+    // quintic fade, so the noise is smooth across cells
+    let fade = local * local * local * (local * (local * 6.0 - 15.0) + 10.0);
+    let mut sum = 0.0;
+    for corner in 0..8 {
+        let offset = IVec3::new(corner & 1, (corner >> 1) & 1, corner >> 2);
+        let gradient = GRADIENTS[(hash(cell + offset, seed) % 12) as usize];
+        let weight = Vec3::select(offset.cmpeq(IVec3::ZERO), 1.0 - fade, fade);
+        sum += weight.x * weight.y * weight.z * gradient.dot(local - offset.as_vec3());
+    }
+    sum
+}
+
+// The octaves of a fractal sum of noise
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fractal {
+    pub frequency: f32, // of the first octave, in waves per unit
+    pub octaves: u32,
+    pub lacunarity: f32, // how much faster every octave changes than the one before
+    pub gain: f32, // how much weaker every octave is than the one before
+}
+
+impl Default for Fractal {
+    fn default() -> Self {
+        Self {
+            frequency: 1.0,
+            octaves: 5,
+            lacunarity: 2.0,
+            gain: 0.5,
+        }
+    }
+}
+
+impl Fractal {
+    // fractional Brownian motion: octaves of noise added up, in about -1..1
+    pub fn fbm(&self, p: Vec3, seed: u32) -> f32 {
+        self.sum(p, seed, |noise| noise)
+    }
+    // octaves of noise folded into sharp crests, for mountain ridges. In about 0..1, the ridges are near 1
+    pub fn ridged(&self, p: Vec3, seed: u32) -> f32 {
+        self.sum(p, seed, |noise| {
+            let ridge = 1.0 - noise.abs();
+            ridge * ridge
+        })
+    }
+    // the octaves of shape(noise), divided by the sum of the amplitudes. Every octave has its own seed
+    fn sum(&self, p: Vec3, seed: u32, shape: impl Fn(f32) -> f32) -> f32 {
+        let mut frequency = self.frequency;
+        let mut amplitude = 1.0;
+        let mut sum = 0.0;
+        let mut total = 0.0;
+        for octave in 0..self.octaves {
+            sum += shape(perlin(p * frequency, seed.wrapping_add(octave))) * amplitude;
+            total += amplitude;
+            frequency *= self.lacunarity;
+            amplitude *= self.gain;
+        }
+        if total > 0.0 { sum / total } else { 0.0 }
+    }
+}
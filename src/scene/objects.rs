@@ -0,0 +1,223 @@
+use glam::{uvec3, Affine3A, Mat4, UVec3, Vec3, Vec4Swizzles};
+
+use super::{CompressedVoxel, LightingRegion, Scene, Voxel, CHUNK_SIZE};
+use super::prefab::VoxelModel;
+
+// Dynamic objects like vehicles, doors and characters, which move and turn smoothly instead of being baked into the
+// chunks. A VoxelObject is a small grid of voxels, and every instance of it has its own affine transform. They live
+// in their own buffers: the voxels of all objects, and the instances sorted into a BVH that is built again every
+// frame. raytracing.wgsl walks the BVH and traces every instance it reaches in object space with the same DDA as
+// the chunks, so objects are seen, cast shadows and block light like the world does.
+// Objects aren't in the lighting cache, so they are lit where they are seen by the ambient light, the sky straight
+// out from each face and the sun, each blocked by whatever is in the way.
+// Known limitations: objects don't get bounced light or light from emissive voxels. And moving one only starts the
+// lighting over around where it was and where it is, so a shadow it casts farther away fades with the lighting
+// history instead of following it at once.
+
+pub type ObjectId = usize; // index into the objects of a scene
+pub type InstanceId = usize; // index into the instances of a scene
+
+// instances per leaf of the BVH
+const LEAF_SIZE: usize = 2;
+// BvhNode::count of nodes that have children instead of instances
+pub(crate) const BVH_INNER: u32 = u32::MAX;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoxelObject {
+    size: UVec3,
+    voxels: Vec<CompressedVoxel>, // x first, then y, then z
+}
+
+impl VoxelObject {
+    // an object with every voxel empty
+    pub fn new(size: UVec3) -> Self {
+        Self { size, voxels: vec![Voxel::default().compress(); (size.x * size.y * size.z) as usize] }
+    }
+    pub fn from_model(model: &VoxelModel) -> Self {
+        let mut object = Self::new(model.size());
+        for (pos, voxel) in model.voxels() {
+            let look = model.palette()[voxel.palette as usize];
+            object.set_voxel(pos, Voxel::new(look.material, look.albedo, voxel.normal));
+        }
+        object
+    }
+    pub fn size(&self) -> UVec3 {
+        self.size
+    }
+    pub fn get_voxel(&self, pos: UVec3) -> Voxel {
+        if pos.cmpge(self.size).any() {
+            return Voxel::default();
+        }
+        self.voxels[super::flatten_index(pos, self.size)].decompress()
+    }
+    // set a voxel. Voxels outside the object are ignored
+    pub fn set_voxel(&mut self, pos: UVec3, voxel: Voxel) {
+        if pos.cmplt(self.size).all() {
+            self.voxels[super::flatten_index(pos, self.size)] = voxel.compress();
+        }
+    }
+}
+
+// A placed copy of an object
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Instance {
+    pub object: ObjectId,
+    pub transform: Affine3A, // from the voxels of the object to voxel space, so the identity puts its voxels on the grid
+}
+
+impl Instance {
+    // the box around the transformed object in scene space, as min and max
+    fn bounds(&self, size: UVec3) -> (Vec3, Vec3) {
+        enclose((0..8).map(|i| self.transform.transform_point3((uvec3(i & 1, (i >> 1) & 1, i >> 2) * size).as_vec3()) / CHUNK_SIZE as f32))
+    }
+}
+
+// the smallest box around some points, as min and max
+fn enclose(points: impl Iterator<Item = Vec3>) -> (Vec3, Vec3) {
+    points.fold((Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)), |(min, max), point| (min.min(point), max.max(point)))
+}
+
+// An instance as raytracing.wgsl sees it
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct GpuInstance {
+    pub(crate) world_to_object: Mat4, // from scene space to the voxels of the object
+    pub(crate) size: UVec3,
+    pub(crate) voxel_offset: u32, // where the voxels of the object start in the object voxel buffer
+}
+
+// A node of the BVH over the instances, mirrored in raytracing.wgsl. Nodes are stored depth first, so the first child
+// of an inner node comes right after it
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct BvhNode {
+    pub(crate) min: Vec3, // the box around the instances below, in scene space
+    pub(crate) count: u32, // the number of instances of a leaf, BVH_INNER for inner nodes
+    pub(crate) max: Vec3,
+    pub(crate) index: u32, // the first instance of a leaf, or the second child of an inner node
+}
+
+// The instances of a scene sorted into a BVH, in the form the GPU takes them
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ObjectBvh {
+    pub nodes: Vec<BvhNode>, // never empty, the root of a scene without instances is a leaf without instances
+    pub instances: Vec<GpuInstance>, // in the order of the leaves
+}
+
+impl ObjectBvh {
+    // sort instances into a BVH by splitting them in half along the longest axis of their centers, over and over
+    fn build(instances: &[(GpuInstance, Vec3, Vec3)]) -> Self {
+        let mut items: Vec<&(GpuInstance, Vec3, Vec3)> = instances.iter().collect();
+        let mut bvh = Self::default();
+        bvh.add_node(&mut items);
+        bvh
+    }
+    fn add_node(&mut self, items: &mut [&(GpuInstance, Vec3, Vec3)]) {
+        let (min, max) = if items.is_empty() {
+            (Vec3::ZERO, Vec3::ZERO)
+        } else {
+            enclose(items.iter().flat_map(|(_, min, max)| [*min, *max]))
+        };
+        let node = self.nodes.len();
+        self.nodes.push(BvhNode { min, count: BVH_INNER, max, index: 0 });
+        if items.len() <= LEAF_SIZE {
+            self.nodes[node].count = items.len() as u32;
+            self.nodes[node].index = self.instances.len() as u32;
+            self.instances.extend(items.iter().map(|(instance, _, _)| *instance));
+            return;
+        }
+        let center = |item: &&(GpuInstance, Vec3, Vec3)| item.1 + item.2;
+        let (low, high) = enclose(items.iter().map(center));
+        let extent = high - low;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z { 0 } else if extent.y >= extent.z { 1 } else { 2 };
+        let half = items.len() / 2;
+        items.select_nth_unstable_by(half, |a, b| center(a)[axis].total_cmp(&center(b)[axis]));
+        let (first, second) = items.split_at_mut(half);
+        self.add_node(first);
+        self.nodes[node].index = self.nodes.len() as u32;
+        self.add_node(second);
+    }
+}
+
+impl Scene {
+    // add an object that instances can be made of
+    pub fn add_object(&mut self, object: VoxelObject) -> ObjectId {
+        self.objects.push(object);
+        self.dirty_objects = true;
+        self.objects.len() - 1
+    }
+    pub fn object(&self, id: ObjectId) -> &VoxelObject {
+        &self.objects[id]
+    }
+    // place an object, see Instance::transform
+    pub fn add_instance(&mut self, object: ObjectId, transform: Affine3A) -> InstanceId {
+        assert!(object < self.objects.len(), "There is no object {}", object);
+        let instance = Instance { object, transform };
+        self.invalidate_around(&instance);
+        self.instances.push(Some(instance));
+        self.instances.len() - 1
+    }
+    pub fn instance(&self, id: InstanceId) -> Option<&Instance> {
+        self.instances.get(id).and_then(Option::as_ref)
+    }
+    // move an instance. The lighting where it was and where it is now starts over
+    pub fn set_instance_transform(&mut self, id: InstanceId, transform: Affine3A) {
+        let Some(Some(instance)) = self.instances.get(id).copied() else {
+            return;
+        };
+        self.invalidate_around(&instance);
+        let moved = Instance { transform, ..instance };
+        self.invalidate_around(&moved);
+        self.instances[id] = Some(moved);
+    }
+    // remove an instance. Its id isn't used again
+    pub fn remove_instance(&mut self, id: InstanceId) {
+        if let Some(instance) = self.instances.get_mut(id).and_then(Option::take) {
+            self.invalidate_around(&instance);
+        }
+    }
+    // throw away the lighting around an instance. Not all the way down its shadow, which would relight whole columns of
+    // chunks every time something moves
+    fn invalidate_around(&mut self, instance: &Instance) {
+        let (min, max) = instance.bounds(self.objects[instance.object].size);
+        let size = self.size.xyz();
+        if max.cmplt(Vec3::ZERO).any() || min.cmpge(size).any() {
+            return;
+        }
+        let chunk = |pos: Vec3| pos.clamp(Vec3::ZERO, size - 1.0).as_uvec3();
+        self.invalidate_lighting(LightingRegion::Chunks { min: chunk(min), max: chunk(max) });
+    }
+
+    // the voxels of every object one after the other, for the object voxel buffer
+    pub fn object_voxels(&self) -> Vec<CompressedVoxel> {
+        self.objects.iter().flat_map(|object| object.voxels.iter().copied()).collect()
+    }
+    // whether objects were added since the last call, so the object voxel buffer has to be written again
+    pub fn take_dirty_objects(&mut self) -> bool {
+        std::mem::take(&mut self.dirty_objects)
+    }
+    // the instances sorted into a BVH, built from scratch since instances can move every frame
+    pub fn object_bvh(&self) -> ObjectBvh {
+        let offsets: Vec<u32> = self.objects.iter()
+            .scan(0, |offset, object| {
+                let start = *offset;
+                *offset += object.voxels.len() as u32;
+                Some(start)
+            })
+            .collect();
+        let to_voxels = Mat4::from_scale(Vec3::splat(CHUNK_SIZE as f32));
+        let instances: Vec<_> = self.instances.iter().flatten()
+            .map(|instance| {
+                let size = self.objects[instance.object].size;
+                let gpu = GpuInstance {
+                    world_to_object: Mat4::from(instance.transform.inverse()) * to_voxels,
+                    size,
+                    voxel_offset: offsets[instance.object],
+                };
+                let (min, max) = instance.bounds(size);
+                (gpu, min, max)
+            })
+            .collect();
+        ObjectBvh::build(&instances)
+    }
+}
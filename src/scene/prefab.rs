@@ -0,0 +1,299 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use anyhow::*;
+use glam::{uvec3, BVec3, IVec3, UVec3, Vec3, Vec4Swizzles};
+use serde::{Deserialize, Serialize};
+
+use super::{MaterialId, Scene, CHUNK_SIZE};
+use crate::model::vox::VoxModel;
+
+// Props that are placed many times, like trees, lamps and rocks. A VoxelModel is a small grid of voxels with its own
+// palette of looks, and Scene::stamp bakes a copy into the chunks, turned by quarter turns and mirrored.
+// Models are saved as .ron or .json, and MagicaVoxel .vox files can be loaded as well.
+
+// The look of voxels in a model
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PaletteEntry {
+    pub material: MaterialId,
+    pub albedo: UVec3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ModelVoxel {
+    pub palette: u8, // index into VoxelModel::palette
+    pub normal: Vec3,
+}
+
+// Models that are mostly filled store every voxel, the rest only the filled ones
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum Storage {
+    Dense(Vec<Option<ModelVoxel>>), // x first, then y, then z
+    Sparse(Vec<(UVec3, ModelVoxel)>), // sorted by z, then y, then x
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VoxelModel {
+    size: UVec3,
+    palette: Vec<PaletteEntry>, // at most 256 entries
+    voxels: Storage,
+}
+
+// models with less of their voxels filled than this are stored sparse by from_scene and from_vox
+const SPARSE_FILL: f32 = 0.25;
+
+impl VoxelModel {
+    // an empty model with every voxel stored
+    pub fn dense(size: UVec3) -> Self {
+        Self { size, palette: Vec::new(), voxels: Storage::Dense(vec![None; (size.x * size.y * size.z) as usize]) }
+    }
+    // an empty model with only the filled voxels stored
+    pub fn sparse(size: UVec3) -> Self {
+        Self { size, palette: Vec::new(), voxels: Storage::Sparse(Vec::new()) }
+    }
+    // an empty model stored the way that suits filled out of all voxels
+    fn with_fill(size: UVec3, filled: usize) -> Self {
+        if (filled as f32) < SPARSE_FILL * (size.x * size.y * size.z) as f32 { Self::sparse(size) } else { Self::dense(size) }
+    }
+
+    // the voxels from min up to max of a scene, max excluded
+    pub fn from_scene(scene: &Scene, min: UVec3, max: UVec3) -> Result<Self> {
+        let max = max.max(min);
+        let filled: Vec<(UVec3, super::Voxel)> = (min.z..max.z)
+            .flat_map(|z| (min.y..max.y).flat_map(move |y| (min.x..max.x).map(move |x| uvec3(x, y, z))))
+            .map(|pos| (pos, scene.get_voxel(pos)))
+            .filter(|(_, voxel)| !voxel.is_empty())
+            .collect();
+        let mut model = Self::with_fill(max - min, filled.len());
+        for (pos, voxel) in filled {
+            let palette = model.add_color(voxel.material(), voxel.albedo())?;
+            model.set(pos - min, Some(ModelVoxel { palette, normal: voxel.normal().normalize_or_zero() }));
+        }
+        Ok(model)
+    }
+    // a MagicaVoxel model, with the scene material of each of its materials. Missing ones get material 0
+    pub fn from_vox(vox: &VoxModel, materials: &[MaterialId]) -> Result<Self> {
+        let mut model = Self::with_fill(vox.size, vox.voxels.len());
+        let filled: HashSet<IVec3> = vox.voxels.iter().map(|(pos, _)| pos.as_ivec3()).collect();
+        for &(pos, index) in &vox.voxels {
+            let entry = vox.palette[index as usize];
+            let palette = model.add_color(materials.get(entry.material).copied().unwrap_or(0), entry.albedo)?;
+            let normal = crate::model::exposed_normal(&filled, pos.as_ivec3());
+            model.set(pos, Some(ModelVoxel { palette, normal }));
+        }
+        Ok(model)
+    }
+
+    pub fn size(&self) -> UVec3 {
+        self.size
+    }
+    pub fn palette(&self) -> &[PaletteEntry] {
+        &self.palette
+    }
+    pub fn is_sparse(&self) -> bool {
+        matches!(self.voxels, Storage::Sparse(_))
+    }
+    // the palette index of a look, added if it's new
+    pub fn add_color(&mut self, material: MaterialId, albedo: UVec3) -> Result<u8> {
+        let entry = PaletteEntry { material, albedo };
+        if let Some(index) = self.palette.iter().position(|known| *known == entry) {
+            return Ok(index as u8);
+        }
+        ensure!(self.palette.len() < 256, "A voxel model can't have more than 256 colors");
+        self.palette.push(entry);
+        Ok(self.palette.len() as u8 - 1)
+    }
+    pub fn get(&self, pos: UVec3) -> Option<ModelVoxel> {
+        if pos.cmpge(self.size).any() {
+            return None;
+        }
+        match &self.voxels {
+            Storage::Dense(voxels) => voxels[self.index(pos)],
+            Storage::Sparse(voxels) => voxels.binary_search_by_key(&sort_key(pos), |(pos, _)| sort_key(*pos)).ok().map(|i| voxels[i].1),
+        }
+    }
+    // set or clear a voxel. Voxels outside the model are ignored
+    pub fn set(&mut self, pos: UVec3, voxel: Option<ModelVoxel>) {
+        if pos.cmpge(self.size).any() {
+            return;
+        }
+        let index = self.index(pos);
+        match &mut self.voxels {
+            Storage::Dense(voxels) => voxels[index] = voxel,
+            Storage::Sparse(voxels) => {
+                let search = voxels.binary_search_by_key(&sort_key(pos), |(pos, _)| sort_key(*pos));
+                let i = search.unwrap_or_else(|i| i);
+                match (search.is_ok(), voxel) {
+                    (true, Some(voxel)) => voxels[i].1 = voxel,
+                    (true, None) => {
+                        voxels.remove(i);
+                    }
+                    (false, Some(voxel)) => voxels.insert(i, (pos, voxel)),
+                    (false, None) => {}
+                }
+            }
+        }
+    }
+    fn index(&self, pos: UVec3) -> usize {
+        (pos.x + self.size.x * (pos.y + self.size.y * pos.z)) as usize
+    }
+    // the filled voxels, in order of z, then y, then x
+    pub fn voxels(&self) -> Vec<(UVec3, ModelVoxel)> {
+        match &self.voxels {
+            Storage::Dense(voxels) => (0..self.size.z)
+                .flat_map(|z| (0..self.size.y).flat_map(move |y| (0..self.size.x).map(move |x| uvec3(x, y, z))))
+                .zip(voxels)
+                .filter_map(|(pos, voxel)| voxel.map(|voxel| (pos, voxel)))
+                .collect(),
+            Storage::Sparse(voxels) => voxels.clone(),
+        }
+    }
+
+    // load a model from a .ron, .json or .vox file. Materials of .vox files become the scene materials in materials
+    pub fn load(path: &Path, materials: &[MaterialId]) -> Result<Self> {
+        let read = || std::fs::read_to_string(path).with_context(|| format!("Could not read voxel model '{}'", path.display()));
+        let model: Self = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::from_str(&read()?)?,
+            Some("ron") => ron::from_str(&read()?)?,
+            Some("vox") => Self::from_vox(&VoxModel::load(path)?, materials)?,
+            _ => bail!("Unknown voxel model format '{}', expected .ron, .json or .vox", path.display()),
+        };
+        model.validate().with_context(|| format!("Invalid voxel model '{}'", path.display()))?;
+        Ok(model)
+    }
+    // the frames of an animation: every model of a .vox file, or a single model from any other file
+    pub fn load_frames(path: &Path, materials: &[MaterialId]) -> Result<Vec<Self>> {
+        if path.extension().and_then(|e| e.to_str()) != Some("vox") {
+            return Ok(vec![Self::load(path, materials)?]);
+        }
+        VoxModel::load_frames(path)?.iter().map(|vox| Self::from_vox(vox, materials)).collect()
+    }
+    // save a model to a .ron or .json file
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::to_string(self)?,
+            Some("ron") => ron::to_string(self)?,
+            _ => bail!("Unknown voxel model format '{}', expected .ron or .json", path.display()),
+        };
+        std::fs::write(path, text).with_context(|| format!("Could not write voxel model '{}'", path.display()))
+    }
+    // check what a loaded file can get wrong
+    fn validate(&self) -> Result<()> {
+        ensure!(self.palette.len() <= 256, "More than 256 colors");
+        match &self.voxels {
+            Storage::Dense(voxels) => {
+                ensure!(voxels.len() == (self.size.x * self.size.y * self.size.z) as usize, "The voxels don't match the size");
+            }
+            Storage::Sparse(voxels) => {
+                ensure!(voxels.windows(2).all(|pair| sort_key(pair[0].0) < sort_key(pair[1].0)), "The voxels aren't sorted");
+            }
+        }
+        let voxels = self.voxels();
+        ensure!(voxels.iter().all(|(pos, _)| pos.cmplt(self.size).all()), "A voxel is outside the size");
+        ensure!(voxels.iter().all(|(_, voxel)| (voxel.palette as usize) < self.palette.len()), "A palette index is out of range");
+        Ok(())
+    }
+}
+
+// the order of the voxels of sparse models
+fn sort_key(pos: UVec3) -> (u32, u32, u32) {
+    (pos.z, pos.y, pos.x)
+}
+
+// A rotation by quarter turns around the x, y and z axes, applied in that order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rotation90 {
+    pub x: u8,
+    pub y: u8,
+    pub z: u8,
+}
+
+impl Rotation90 {
+    pub const IDENTITY: Self = Self { x: 0, y: 0, z: 0 };
+
+    // quarter turns around the y axis only, turning x towards -z like yaw
+    pub fn y(turns: u8) -> Self {
+        Self { y: turns, ..Self::IDENTITY }
+    }
+    // rotate a vector, staying exact for integer vectors
+    pub fn apply(&self, v: Vec3) -> Vec3 {
+        let mut v = v;
+        for _ in 0..self.x % 4 {
+            v = Vec3::new(v.x, -v.z, v.y);
+        }
+        for _ in 0..self.y % 4 {
+            v = Vec3::new(v.z, v.y, -v.x);
+        }
+        for _ in 0..self.z % 4 {
+            v = Vec3::new(-v.y, v.x, v.z);
+        }
+        v
+    }
+}
+
+impl Scene {
+    // bake a copy of a model into the scene, with its lowest corner at position after mirroring it along the axes
+    // in mirror and then rotating it. Empty voxels of the model leave the scene alone, and the parts outside the
+    // scene are cut off. Returns the chunks that changed, like Scene::paint
+    pub fn stamp(&mut self, model: &VoxelModel, position: UVec3, rotation: Rotation90, mirror: BVec3) -> Vec<UVec3> {
+        let edits = self.stamp_edits(model, position, rotation, mirror);
+        self.write_voxels(edits)
+    }
+    // the voxels Scene::stamp writes
+    pub(crate) fn stamp_edits(&self, model: &VoxelModel, position: UVec3, rotation: Rotation90, mirror: BVec3) -> Vec<(UVec3, super::Voxel)> {
+        let scene_size = self.size.xyz().as_uvec3() * CHUNK_SIZE as u32;
+        let flip = Vec3::select(mirror, Vec3::NEG_ONE, Vec3::ONE);
+        let transform = |v: Vec3| rotation.apply(v * flip);
+        let size = model.size.as_vec3();
+        let rotated_size = transform(size).abs();
+        model.voxels()
+            .into_iter()
+            .filter_map(|(pos, voxel)| {
+                // turn the voxel center around the center of the model
+                let center = transform(pos.as_vec3() + 0.5 - size / 2.0) + rotated_size / 2.0;
+                let pos = position + center.floor().as_uvec3();
+                let entry = model.palette.get(voxel.palette as usize)?;
+                pos.cmplt(scene_size).all().then(|| (pos, super::Voxel::new(entry.material, entry.albedo, transform(voxel.normal))))
+            })
+            .collect()
+    }
+}
+
+// Voxel models by name, for placing props with Scene::stamp
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModelLibrary {
+    models: HashMap<String, VoxelModel>,
+}
+
+impl ModelLibrary {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    // every .ron, .json and .vox file in a directory, named by its file name without the extension.
+    // Materials of .vox files become the scene materials in materials
+    pub fn load(directory: &Path, materials: &[MaterialId]) -> Result<Self> {
+        let mut library = Self::new();
+        let entries = std::fs::read_dir(directory).with_context(|| format!("Could not read '{}'", directory.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            let supported = path.extension().and_then(|e| e.to_str()).is_some_and(|e| matches!(e, "ron" | "json" | "vox"));
+            let Some(name) = path.file_stem().and_then(|name| name.to_str()).filter(|_| supported) else {
+                continue;
+            };
+            ensure!(!library.models.contains_key(name), "There are two voxel models called '{}'", name);
+            library.insert(name, VoxelModel::load(&path, materials)?);
+        }
+        Ok(library)
+    }
+    pub fn insert(&mut self, name: &str, model: VoxelModel) {
+        self.models.insert(name.to_string(), model);
+    }
+    pub fn get(&self, name: &str) -> Option<&VoxelModel> {
+        self.models.get(name)
+    }
+    // the names of the models, sorted
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.models.keys().map(String::as_str).collect();
+        names.sort();
+        names
+    }
+}
@@ -0,0 +1,82 @@
+use glam::{Vec3, Vec4Swizzles};
+
+use crate::camera::frustum::Frustum;
+use super::{Scene, CHUNK_SIZE};
+
+// Picks the chunks the lighting pass updates each frame, so big scenes don't have to light every chunk every frame.
+// Chunks on screen come first, then chunks near the camera, then the rest, which only get a turn every few frames.
+// Within each group closer chunks go first, but chunks that were skipped move up the longer they wait.
+// Chunks that waited for more than max_wait frames go before everything else, so nothing starves when the budget is small.
+
+const CHUNK_VOXELS: u32 = (CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE) as u32;
+
+#[derive(Debug, Clone)]
+pub struct LightingSchedule {
+    pub budget: u32, // the most voxels to light per frame. At least one chunk is always lit
+    pub near_distance: f32, // chunks closer than this are lit like visible ones, in scene units
+    pub far_interval: u32, // chunks that are neither visible nor near get a turn once every this many frames
+    pub max_wait: u32, // chunks that weren't lit for this many frames are lit first
+    last_lit: Vec<u32>, // the frame every chunk was last lit in
+}
+
+impl Default for LightingSchedule {
+    fn default() -> Self {
+        Self {
+            budget: 64 * CHUNK_VOXELS,
+            near_distance: 3.0,
+            far_interval: 4,
+            max_wait: 60,
+            last_lit: Vec::new(),
+        }
+    }
+}
+
+// how urgently a chunk needs light, in the order the groups are lit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Priority {
+    Overdue,
+    Visible,
+    Near,
+    Far,
+}
+
+impl LightingSchedule {
+    // a schedule that lights at most budget voxels per frame
+    pub fn with_budget(budget: u32) -> Self {
+        Self { budget, ..Self::default() }
+    }
+    // the indices of the chunks to light this frame, most urgent first. Empty chunks are never lit
+    pub fn plan(&mut self, scene: &Scene, frustum: &Frustum, eye: Vec3) -> Vec<u32> {
+        let frame = scene.frame;
+        self.last_lit.resize(scene.chunks.len(), 0);
+        let far_turn = frame.is_multiple_of(self.far_interval.max(1));
+        let mut candidates: Vec<(Priority, f32, u32)> = scene.chunks.iter().enumerate()
+            .filter(|(_, chunk)| chunk.pos.w != 0.0)
+            .filter_map(|(idx, chunk)| {
+                let min = chunk.pos.xyz();
+                let distance = eye.distance(min + 0.5);
+                let waited = frame.wrapping_sub(self.last_lit[idx]);
+                let priority = if waited > self.max_wait {
+                    Priority::Overdue
+                } else if frustum.intersects_box(min, min + 1.0) {
+                    Priority::Visible
+                } else if distance < self.near_distance {
+                    Priority::Near
+                } else if far_turn {
+                    Priority::Far
+                } else {
+                    return None;
+                };
+                // a chunk that waited n frames counts as n+1 times closer
+                Some((priority, distance / (1 + waited) as f32, idx as u32))
+            })
+            .collect();
+        candidates.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)));
+        let chunk_count = (self.budget / CHUNK_VOXELS).max(1) as usize;
+        let chunks: Vec<u32> = candidates.into_iter().take(chunk_count).map(|(_, _, idx)| idx).collect();
+        for &idx in &chunks {
+            self.last_lit[idx as usize] = frame;
+        }
+        chunks
+    }
+}
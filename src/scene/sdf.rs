@@ -0,0 +1,257 @@
+use glam::{Affine3A, Quat, UVec3, Vec2, Vec3, Vec3Swizzles, Vec4Swizzles};
+use rayon::prelude::*;
+
+use super::{MaterialId, Scene, Voxel, CHUNK_SIZE};
+
+// Signed distance fields, for describing procedural props and voxelizing them into a scene.
+// A field gives the distance from a point to the surface of a shape, negative inside. The primitives are centered
+// on the origin and are moved around with the transforms, all in voxel space (CHUNK_SIZE voxels per scene unit).
+// Any Fn(Vec3) -> f32 is a field as well.
+
+pub trait Sdf {
+    fn distance(&self, p: Vec3) -> f32;
+
+    // the direction of steepest increase of the distance, which is the normal on the surface
+    fn gradient(&self, p: Vec3) -> Vec3 {
+        gradient(|p| self.distance(p), p)
+    }
+    fn translate(self, offset: Vec3) -> Transform<Self> where Self: Sized {
+        Transform::new(self, Affine3A::from_translation(offset))
+    }
+    fn rotate(self, rotation: Quat) -> Transform<Self> where Self: Sized {
+        Transform::new(self, Affine3A::from_quat(rotation))
+    }
+    // scaling the same along every axis keeps the distances right
+    fn scale(self, factor: f32) -> Scale<Self> where Self: Sized {
+        Scale { inner: self, factor }
+    }
+    fn union<B: Sdf>(self, other: B) -> Union<Self, B> where Self: Sized {
+        Union { a: self, b: other }
+    }
+    // a union that blends the shapes together within radius of where they meet
+    fn smooth_union<B: Sdf>(self, other: B, radius: f32) -> SmoothUnion<Self, B> where Self: Sized {
+        SmoothUnion { a: self, b: other, radius }
+    }
+    // this shape with other cut out of it
+    fn subtract<B: Sdf>(self, other: B) -> Subtraction<Self, B> where Self: Sized {
+        Subtraction { a: self, b: other }
+    }
+    // copies of this shape every period along each axis, centered on the origin. Axes with a period of 0 aren't repeated.
+    // The shape should fit in one period
+    fn repeat(self, period: Vec3) -> Repeat<Self> where Self: Sized {
+        Repeat { inner: self, period }
+    }
+    // the field with a material and albedo everywhere, ready for Scene::voxelize
+    fn with_material(self, material: MaterialId, albedo: UVec3) -> Painted<Self> where Self: Sized {
+        Painted { inner: self, material, albedo }
+    }
+}
+
+impl<F: Fn(Vec3) -> f32> Sdf for F {
+    fn distance(&self, p: Vec3) -> f32 {
+        self(p)
+    }
+}
+
+// the step for finding gradients from the distances around a point, in voxels
+const GRADIENT_STEP: f32 = 0.001;
+
+// the gradient of a field from the distances at the corners of a tetrahedron around p
+fn gradient(distance: impl Fn(Vec3) -> f32, p: Vec3) -> Vec3 {
+    const CORNERS: [Vec3; 4] = [Vec3::new(1.0, -1.0, -1.0), Vec3::new(-1.0, -1.0, 1.0), Vec3::new(-1.0, 1.0, -1.0), Vec3::new(1.0, 1.0, 1.0)];
+    CORNERS.iter()
+        .map(|&corner| corner * distance(p + corner * GRADIENT_STEP))
+        .sum::<Vec3>()
+        .normalize_or_zero()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sphere {
+    pub radius: f32,
+}
+
+impl Sdf for Sphere {
+    fn distance(&self, p: Vec3) -> f32 {
+        p.length() - self.radius
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cuboid {
+    pub half_size: Vec3,
+}
+
+impl Sdf for Cuboid {
+    fn distance(&self, p: Vec3) -> f32 {
+        let q = p.abs() - self.half_size;
+        q.max(Vec3::ZERO).length() + q.max_element().min(0.0)
+    }
+}
+
+// a box with its edges rounded off, radius is included in half_size
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RoundedBox {
+    pub half_size: Vec3,
+    pub radius: f32,
+}
+
+impl Sdf for RoundedBox {
+    fn distance(&self, p: Vec3) -> f32 {
+        Cuboid { half_size: self.half_size - self.radius }.distance(p) - self.radius
+    }
+}
+
+// a ring around the y axis
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Torus {
+    pub major_radius: f32, // from the center to the middle of the tube
+    pub minor_radius: f32, // of the tube
+}
+
+impl Sdf for Torus {
+    fn distance(&self, p: Vec3) -> f32 {
+        Vec2::new(p.xz().length() - self.major_radius, p.y).length() - self.minor_radius
+    }
+}
+
+// everything below a plane through the origin, facing normal
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Plane {
+    pub normal: Vec3, // normalized
+}
+
+impl Sdf for Plane {
+    fn distance(&self, p: Vec3) -> f32 {
+        p.dot(self.normal)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform<S> {
+    inner: S,
+    inverse: Affine3A, // from outside the transform to inside it
+}
+
+impl<S> Transform<S> {
+    // the transform has to be rigid, scales go through Sdf::scale
+    pub fn new(inner: S, transform: Affine3A) -> Self {
+        Self { inner, inverse: transform.inverse() }
+    }
+}
+
+impl<S: Sdf> Sdf for Transform<S> {
+    fn distance(&self, p: Vec3) -> f32 {
+        self.inner.distance(self.inverse.transform_point3(p))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Scale<S> {
+    inner: S,
+    factor: f32,
+}
+
+impl<S: Sdf> Sdf for Scale<S> {
+    fn distance(&self, p: Vec3) -> f32 {
+        self.inner.distance(p / self.factor) * self.factor
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Union<A, B> {
+    a: A,
+    b: B,
+}
+
+impl<A: Sdf, B: Sdf> Sdf for Union<A, B> {
+    fn distance(&self, p: Vec3) -> f32 {
+        self.a.distance(p).min(self.b.distance(p))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SmoothUnion<A, B> {
+    a: A,
+    b: B,
+    radius: f32,
+}
+
+impl<A: Sdf, B: Sdf> Sdf for SmoothUnion<A, B> {
+    fn distance(&self, p: Vec3) -> f32 {
+        let (a, b) = (self.a.distance(p), self.b.distance(p));
+        if self.radius <= 0.0 {
+            return a.min(b);
+        }
+        // polynomial smooth minimum
+        let h = (0.5 + 0.5 * (b - a) / self.radius).clamp(0.0, 1.0);
+        b + (a - b) * h - self.radius * h * (1.0 - h)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Subtraction<A, B> {
+    a: A,
+    b: B,
+}
+
+impl<A: Sdf, B: Sdf> Sdf for Subtraction<A, B> {
+    fn distance(&self, p: Vec3) -> f32 {
+        self.a.distance(p).max(-self.b.distance(p))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Repeat<S> {
+    inner: S,
+    period: Vec3,
+}
+
+impl<S: Sdf> Sdf for Repeat<S> {
+    fn distance(&self, p: Vec3) -> f32 {
+        let cell = Vec3::select(self.period.cmpgt(Vec3::ZERO), (p / self.period).round() * self.period, Vec3::ZERO);
+        self.inner.distance(p - cell)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Painted<S> {
+    inner: S,
+    material: MaterialId,
+    albedo: UVec3,
+}
+
+impl<S: Sdf> Painted<S> {
+    // the distance, material and albedo at a point, the way Scene::voxelize wants them
+    pub fn sample(&self, p: Vec3) -> (f32, MaterialId, UVec3) {
+        (self.inner.distance(p), self.material, self.albedo)
+    }
+}
+
+impl Scene {
+    // fill the voxels with their centers inside a field, in a box in voxel space. The field gives the distance,
+    // material and albedo at a point, and the normals come from the gradient of the distance.
+    // Voxels outside the field are left alone. Returns the chunks that changed, like Scene::paint
+    pub fn voxelize<F>(&mut self, min: Vec3, max: Vec3, field: F) -> Vec<UVec3>
+    where F: Fn(Vec3) -> (f32, MaterialId, UVec3) + Sync {
+        let scene_max = self.size.xyz().as_uvec3() * CHUNK_SIZE as u32;
+        let min = min.floor().max(Vec3::ZERO).as_uvec3();
+        let max = max.ceil().max(Vec3::ZERO).as_uvec3().min(scene_max);
+        let edits: Vec<(UVec3, Voxel)> = (min.z..max.z.max(min.z))
+            .into_par_iter()
+            .flat_map_iter(|z| {
+                let field = &field;
+                (min.y..max.y).flat_map(move |y| (min.x..max.x).map(move |x| UVec3::new(x, y, z)))
+                    .filter_map(move |pos| {
+                        let center = pos.as_vec3() + 0.5;
+                        let (distance, material, albedo) = field(center);
+                        if distance >= 0.0 {
+                            return None;
+                        }
+                        let normal = gradient(|p| field(p).0, center);
+                        Some((pos, Voxel::new(material, albedo, normal)))
+                    })
+            })
+            .collect();
+        self.write_voxels(edits)
+    }
+}
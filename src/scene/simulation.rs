@@ -0,0 +1,260 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use glam::{ivec3, IVec3, UVec3, Vec4Swizzles};
+use instant::Duration;
+
+use super::prefab::PaletteEntry;
+use super::{flatten_index, MaterialId, Scene, Voxel, CHUNK_SIZE};
+
+// Falling sand: cellular automata on the voxels of a scene, for gameplay where the world moves. Every tick the voxels
+// of materials with a rule act on the voxels around them. Powders fall and pile up, liquids fall and flow sideways
+// towards drops until they are level, and fire lights flammable voxels before it burns out into what it leaves behind.
+// Ticks run on the CPU, bottom up, and only in the chunks where something happened in the last tick, so a settled scene
+// costs nothing. Edits from outside have to wake the chunks they touched. The randomness only depends on the seed, the
+// tick and the position, so the same scene and seed always play out the same way.
+// Liquids never move sideways on the level they are on unless there is a drop to move into, which is what lets them
+// settle. Reach only limits how far they look for one over dry ground, across their own surface they look as far as
+// it goes, so pools end up level to within a voxel however big they are.
+
+// What the voxels of a material do on their own
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rule {
+    Powder, // falls, and slides down to the side when blocked, so it piles up. Sinks in liquids
+    Liquid { reach: u32 }, // falls, and flows sideways to the closest drop, up to reach voxels away over dry ground
+    // goes out with a chance of burnout every tick, leaving a voxel or nothing. Goes out at once next to a liquid
+    Fire { burnout: f32, leaves: Option<PaletteEntry> },
+    // turns into fire with a chance of ignition every tick for every burning neighbour
+    Flammable { ignition: f32, fire: PaletteEntry },
+}
+
+// the neighbours on the same level, the order the sideways moves are tried in
+const SIDES: [IVec3; 4] = [IVec3::X, IVec3::Z, IVec3::NEG_X, IVec3::NEG_Z];
+const NEIGHBOURS: [IVec3; 6] = [IVec3::X, IVec3::NEG_X, IVec3::Y, IVec3::NEG_Y, IVec3::Z, IVec3::NEG_Z];
+
+#[derive(Debug, Clone)]
+pub struct Simulation {
+    pub seed: u32,
+    pub tick_time: Duration, // scene time per tick, see update
+    rules: HashMap<MaterialId, Rule>,
+    tick: u32, // ticks run so far
+    awake: Vec<bool>, // the chunks the next tick runs in, per chunk. Empty before the first tick, which runs everywhere
+    elapsed: Duration, // time update hasn't run ticks for yet
+}
+
+impl Default for Simulation {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl Simulation {
+    // a simulation without rules, 20 ticks per second
+    pub fn new(seed: u32) -> Self {
+        Self { seed, tick_time: Duration::from_millis(50), rules: HashMap::new(), tick: 0, awake: Vec::new(), elapsed: Duration::ZERO }
+    }
+    pub fn set_rule(&mut self, material: MaterialId, rule: Rule) {
+        self.rules.insert(material, rule);
+        self.wake_all();
+    }
+    pub fn rule(&self, material: MaterialId) -> Option<&Rule> {
+        self.rules.get(&material)
+    }
+    pub fn tick(&self) -> u32 {
+        self.tick
+    }
+    // run the next tick in these chunks, after they were edited from outside, like with the chunks Scene::paint returns
+    pub fn wake(&mut self, scene: &Scene, chunks: &[UVec3]) {
+        if self.awake.is_empty() {
+            return; // the first tick runs everywhere anyway
+        }
+        let size = scene.size.xyz().as_uvec3();
+        for &chunk in chunks {
+            self.awake[flatten_index(chunk, size)] = true;
+        }
+    }
+    pub fn wake_all(&mut self) {
+        self.awake.clear();
+    }
+    // whether the next tick has nothing to do
+    pub fn is_settled(&self) -> bool {
+        !self.awake.is_empty() && !self.awake.contains(&true)
+    }
+    // run as many ticks as fit in the time since the last call, so the simulation doesn't depend on the frame rate.
+    // Returns the chunks that changed
+    pub fn update(&mut self, scene: &mut Scene, dt: Duration) -> Vec<UVec3> {
+        self.elapsed += dt;
+        let mut changed = Vec::new();
+        while !self.tick_time.is_zero() && self.elapsed >= self.tick_time {
+            self.elapsed -= self.tick_time;
+            changed.extend(self.step(scene));
+        }
+        changed.sort_by_key(|pos| (pos.z, pos.y, pos.x));
+        changed.dedup();
+        changed
+    }
+    // run one tick. Returns the chunks that changed, like Scene::paint
+    pub fn step(&mut self, scene: &mut Scene) -> Vec<UVec3> {
+        let size = scene.size.xyz().as_uvec3();
+        if self.awake.len() != scene.chunks.len() {
+            self.awake = vec![true; scene.chunks.len()];
+        }
+        let seed = self.seed ^ self.tick.wrapping_mul(0x9E37_79B9);
+        self.tick = self.tick.wrapping_add(1);
+        // bottom up, so everything falls one voxel per tick no matter where the chunks start
+        let mut chunks: Vec<UVec3> = scene.chunks.iter().zip(&self.awake)
+            .filter(|(chunk, awake)| **awake && chunk.pos.w != 0.0)
+            .map(|(chunk, _)| chunk.pos.xyz().as_uvec3())
+            .collect();
+        chunks.sort_by_key(|pos| (pos.y, pos.z, pos.x));
+        self.awake.fill(false);
+        if self.rules.is_empty() {
+            return Vec::new();
+        }
+
+        let mut grid = Grid { scene: &*scene, edits: HashMap::new(), done: HashSet::new() };
+        let mut burning = Vec::new();
+        for chunk in chunks {
+            let min = (chunk * CHUNK_SIZE as u32).as_ivec3();
+            for y in 0..CHUNK_SIZE as i32 {
+                for z in 0..CHUNK_SIZE as i32 {
+                    for x in 0..CHUNK_SIZE as i32 {
+                        let pos = min + ivec3(x, y, z);
+                        if self.act(&mut grid, pos, seed) {
+                            burning.push(pos);
+                        }
+                    }
+                }
+            }
+        }
+        let edits: Vec<(UVec3, Voxel)> = grid.edits.into_iter()
+            .map(|(pos, voxel)| (pos.as_uvec3(), voxel))
+            .filter(|(pos, voxel)| scene.get_voxel(*pos).compress() != voxel.compress())
+            .collect();
+        // the chunks around a change wake up, and fire keeps its chunk awake until it goes out
+        let chunk_of = |pos: IVec3| (pos.clamp(IVec3::ZERO, (size * CHUNK_SIZE as u32).as_ivec3() - 1) / CHUNK_SIZE as i32).as_uvec3();
+        for (pos, _) in &edits {
+            for offset in (0..27).map(|i| ivec3(i % 3, i / 3 % 3, i / 9) - 1) {
+                self.awake[flatten_index(chunk_of(pos.as_ivec3() + offset), size)] = true;
+            }
+        }
+        for pos in burning {
+            self.awake[flatten_index(chunk_of(pos), size)] = true;
+        }
+        scene.write_voxels(edits)
+    }
+
+    // let the voxel at a position follow its rule. Returns whether it is still burning
+    fn act(&self, grid: &mut Grid, pos: IVec3, seed: u32) -> bool {
+        if grid.done.contains(&pos) {
+            return false;
+        }
+        let Some(voxel) = grid.get(pos).filter(|voxel| !voxel.is_empty()) else {
+            return false;
+        };
+        // a number in 0..1 for every position, tick and use
+        let random = |salt: u32| super::noise::hash(pos, seed ^ salt.wrapping_mul(0x85EB_CA6B)) as f32 / u32::MAX as f32;
+        let first_side = (random(0) * 4.0) as usize;
+        let sides: [IVec3; 4] = std::array::from_fn(|i| SIDES[(first_side + i) % 4]);
+        let is_liquid = |voxel: &Voxel| matches!(self.rules.get(&voxel.material), Some(Rule::Liquid { .. }));
+        match self.rules.get(&voxel.material) {
+            Some(Rule::Powder) => {
+                let sinks_into = |grid: &Grid, pos| grid.get(pos).is_some_and(|voxel| voxel.is_empty() || is_liquid(&voxel));
+                if sinks_into(grid, pos - IVec3::Y) {
+                    grid.swap(pos, pos - IVec3::Y);
+                    return false;
+                }
+                // diagonally only if it doesn't squeeze through the edge between two voxels
+                for side in sides {
+                    if sinks_into(grid, pos + side) && sinks_into(grid, pos + side - IVec3::Y) {
+                        grid.swap(pos, pos + side - IVec3::Y);
+                        return false;
+                    }
+                }
+                false
+            }
+            Some(&Rule::Liquid { reach }) => {
+                let is_empty = |grid: &Grid, pos| grid.get(pos).is_some_and(|voxel| voxel.is_empty());
+                if is_empty(grid, pos - IVec3::Y) {
+                    grid.swap(pos, pos - IVec3::Y);
+                    return false;
+                }
+                // the closest drop through the empty voxels on this level. Crossing the surface of a liquid one voxel
+                // lower is free, so a voxel on top of a pool finds the lower parts of it however far away they are and
+                // the surface ends up level. Only crossing anything else counts against reach
+                let mut visited = HashSet::new();
+                let mut queue = VecDeque::from([(pos, 0)]);
+                while let Some((cell, distance)) = queue.pop_front() {
+                    if !visited.insert(cell) {
+                        continue;
+                    }
+                    for side in sides {
+                        let next = cell + side;
+                        if visited.contains(&next) || !is_empty(grid, next) {
+                            continue;
+                        }
+                        match grid.get(next - IVec3::Y) {
+                            Some(below) if below.is_empty() => {
+                                grid.swap(pos, next - IVec3::Y);
+                                return false;
+                            }
+                            Some(below) if is_liquid(&below) => queue.push_front((next, distance)),
+                            _ if distance < reach => queue.push_back((next, distance + 1)),
+                            _ => {}
+                        }
+                    }
+                }
+                false
+            }
+            Some(&Rule::Fire { burnout, leaves }) => {
+                let out = leaves.map_or(Voxel::default(), |leaves| Voxel::new(leaves.material, leaves.albedo, voxel.normal));
+                if NEIGHBOURS.iter().any(|&offset| grid.get(pos + offset).is_some_and(|voxel| is_liquid(&voxel))) {
+                    grid.set(pos, out);
+                    return false;
+                }
+                for (i, &offset) in NEIGHBOURS.iter().enumerate() {
+                    let Some(neighbour) = grid.get(pos + offset).filter(|voxel| !grid.done.contains(&(pos + offset)) && !voxel.is_empty()) else {
+                        continue;
+                    };
+                    if let Some(&Rule::Flammable { ignition, fire }) = self.rules.get(&neighbour.material) {
+                        if random(i as u32 + 1) < ignition {
+                            grid.set(pos + offset, Voxel::new(fire.material, fire.albedo, neighbour.normal));
+                        }
+                    }
+                }
+                if random(7) < burnout {
+                    grid.set(pos, out);
+                    return false;
+                }
+                true
+            }
+            Some(Rule::Flammable { .. }) | None => false,
+        }
+    }
+}
+
+// The voxels of a scene with the edits of a tick on top
+struct Grid<'a> {
+    scene: &'a Scene,
+    edits: HashMap<IVec3, Voxel>,
+    done: HashSet<IVec3>, // voxels that moved or changed this tick, which don't act again until the next one
+}
+
+impl Grid<'_> {
+    // None outside the scene
+    fn get(&self, pos: IVec3) -> Option<Voxel> {
+        if pos.cmplt(IVec3::ZERO).any() || !self.scene.contains_voxel(pos.as_uvec3()) {
+            return None;
+        }
+        Some(self.edits.get(&pos).copied().unwrap_or_else(|| self.scene.get_voxel(pos.as_uvec3())))
+    }
+    fn set(&mut self, pos: IVec3, voxel: Voxel) {
+        self.edits.insert(pos, voxel);
+        self.done.insert(pos);
+    }
+    fn swap(&mut self, a: IVec3, b: IVec3) {
+        let (Some(first), Some(second)) = (self.get(a), self.get(b)) else {
+            return;
+        };
+        self.set(a, second);
+        self.set(b, first);
+    }
+}
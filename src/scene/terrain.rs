@@ -0,0 +1,156 @@
+use glam::{uvec3, vec3, UVec3, Vec3, Vec4Swizzles};
+
+use super::noise::Fractal;
+use super::{MaterialId, Scene, Voxel, CHUNK_SIZE};
+
+// Procedural terrain: a heightmap of fractal noise, blended with ridged noise for mountains, with caves carved out of it
+// by 3D noise and water filled in up to a level. The surface gets its look from biome rules on height and slope.
+// Everything is in voxel space and the same settings and seed always give the same terrain.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Terrain {
+    pub seed: u32,
+    pub base_height: f32, // the average height of the ground
+    pub amplitude: f32, // how far the hills reach above and below base_height
+    pub hills: Fractal, // frequencies are in waves per voxel
+    pub ridges: f32, // 0..1, how much of the height comes from ridged noise instead of rolling hills
+    pub caves: Option<Caves>,
+    pub water_level: f32, // voxels below this that are not ground are filled with water
+    pub water: (MaterialId, UVec3), // material and albedo of the water, see Material::water
+    pub soil_depth: u32, // how many voxels below the surface look like the surface
+    pub biomes: Vec<Biome>, // the first one that fits a surface voxel is used
+    pub bedrock: Biome, // used below the soil, and for the surface where no biome fits
+}
+
+// 3D noise that hollows out the ground where it is above a threshold
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Caves {
+    pub noise: Fractal,
+    pub threshold: f32, // in 0..1, higher values give fewer and thinner caves
+    pub min_depth: f32, // caves stay this many voxels below the surface
+}
+
+// What the surface looks like in a range of heights and slopes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Biome {
+    pub min_height: f32,
+    pub max_height: f32,
+    pub max_slope: f32, // height change per voxel
+    pub material: MaterialId,
+    pub albedo: UVec3,
+}
+
+impl Biome {
+    pub fn fits(&self, height: f32, slope: f32) -> bool {
+        (self.min_height..self.max_height).contains(&height) && slope <= self.max_slope
+    }
+}
+
+// the step for finding the slope of the heightmap, in voxels
+const GRADIENT_STEP: f32 = 0.5;
+
+impl Default for Terrain {
+    // hills of grass between sandy shores and snowy peaks, in the 64 voxel high scene of Scene::new
+    fn default() -> Self {
+        let water_level = 14.0;
+        Self {
+            seed: 0,
+            base_height: 18.0,
+            amplitude: 16.0,
+            hills: Fractal { frequency: 1.0 / 48.0, ..Default::default() },
+            ridges: 0.3,
+            caves: Some(Caves {
+                noise: Fractal { frequency: 1.0 / 16.0, octaves: 2, ..Default::default() },
+                threshold: 0.35,
+                min_depth: 4.0,
+            }),
+            water_level,
+            water: (2, uvec3(40, 90, 160)),
+            soil_depth: 3,
+            biomes: vec![
+                Biome { min_height: f32::MIN, max_height: water_level + 2.0, max_slope: 1.0, material: 0, albedo: uvec3(214, 196, 140) }, // sand
+                Biome { min_height: 30.0, max_height: f32::MAX, max_slope: 1.5, material: 0, albedo: uvec3(240, 244, 250) }, // snow
+                Biome { min_height: f32::MIN, max_height: f32::MAX, max_slope: 0.8, material: 0, albedo: uvec3(80, 140, 50) }, // grass
+            ],
+            bedrock: Biome { min_height: f32::MIN, max_height: f32::MAX, max_slope: f32::MAX, material: 0, albedo: uvec3(110, 105, 100) },
+        }
+    }
+}
+
+impl Terrain {
+    // the height of the ground at a column, in voxels
+    pub fn height(&self, x: f32, z: f32) -> f32 {
+        let p = vec3(x, 0.0, z);
+        let hills = self.hills.fbm(p, self.seed);
+        let ridges = self.hills.ridged(p, self.seed.wrapping_add(0x5EED)) * 2.0 - 1.0;
+        self.base_height + self.amplitude * (hills * (1.0 - self.ridges) + ridges * self.ridges)
+    }
+    // the slope of the heightmap, (d height / dx, d height / dz)
+    pub fn gradient(&self, x: f32, z: f32) -> (f32, f32) {
+        let dx = self.height(x + GRADIENT_STEP, z) - self.height(x - GRADIENT_STEP, z);
+        let dz = self.height(x, z + GRADIENT_STEP) - self.height(x, z - GRADIENT_STEP);
+        (dx / (2.0 * GRADIENT_STEP), dz / (2.0 * GRADIENT_STEP))
+    }
+    // the biome for a surface voxel
+    pub fn biome(&self, height: f32, slope: f32) -> &Biome {
+        self.biomes.iter().find(|biome| biome.fits(height, slope)).unwrap_or(&self.bedrock)
+    }
+    // the cave noise at a point, caves are where it is above the threshold
+    fn cave_density(&self, caves: &Caves, p: Vec3) -> f32 {
+        caves.noise.fbm(p, self.seed.wrapping_add(0xCA7E)) * 0.5 + 0.5
+    }
+    fn is_cave(&self, p: Vec3, surface: f32) -> bool {
+        self.caves.as_ref().is_some_and(|caves| {
+            p.y < surface - caves.min_depth && self.cave_density(caves, p) > caves.threshold
+        })
+    }
+
+    // fill the scene with the terrain. Voxels above the ground and the water are left alone.
+    // Returns the chunks that changed, like Scene::paint
+    pub fn generate(&self, scene: &mut Scene) -> Vec<UVec3> {
+        let size = scene.size.xyz().as_uvec3() * CHUNK_SIZE as u32;
+        let mut edits = Vec::new();
+        for z in 0..size.z {
+            for x in 0..size.x {
+                let (column_x, column_z) = (x as f32 + 0.5, z as f32 + 0.5);
+                let height = self.height(column_x, column_z);
+                let (dx, dz) = self.gradient(column_x, column_z);
+                let slope = (dx * dx + dz * dz).sqrt();
+                let surface_normal = vec3(-dx, 1.0, -dz).normalize();
+                let surface = self.biome(height, slope);
+                let top = (height.max(self.water_level).ceil().max(0.0) as u32).min(size.y);
+                for y in 0..top {
+                    let center = vec3(column_x, y as f32 + 0.5, column_z);
+                    if center.y >= height || self.is_cave(center, height) {
+                        if center.y < self.water_level {
+                            edits.push((uvec3(x, y, z), Voxel::new(self.water.0, self.water.1, Vec3::Y)));
+                        }
+                        continue;
+                    }
+                    let depth = height - center.y;
+                    let biome = if depth < self.soil_depth as f32 { surface } else { &self.bedrock };
+                    edits.push((uvec3(x, y, z), Voxel::new(biome.material, biome.albedo, self.normal(center, height, surface_normal))));
+                }
+            }
+        }
+        scene.write_voxels(edits)
+    }
+    // the normal of a ground voxel: the slope of the heightmap, or pointing into the cave next to it
+    fn normal(&self, center: Vec3, height: f32, surface_normal: Vec3) -> Vec3 {
+        let Some(caves) = &self.caves else {
+            return surface_normal;
+        };
+        let next_to_cave = [Vec3::X, Vec3::NEG_X, Vec3::Y, Vec3::NEG_Y, Vec3::Z, Vec3::NEG_Z].iter()
+            .any(|&offset| self.is_cave(center + offset, height));
+        if !next_to_cave {
+            return surface_normal;
+        }
+        // the cave noise rises out of the ground into the cave
+        let gradient = vec3(
+            self.cave_density(caves, center + Vec3::X * GRADIENT_STEP) - self.cave_density(caves, center - Vec3::X * GRADIENT_STEP),
+            self.cave_density(caves, center + Vec3::Y * GRADIENT_STEP) - self.cave_density(caves, center - Vec3::Y * GRADIENT_STEP),
+            self.cave_density(caves, center + Vec3::Z * GRADIENT_STEP) - self.cave_density(caves, center - Vec3::Z * GRADIENT_STEP),
+        );
+        gradient.try_normalize().unwrap_or(surface_normal)
+    }
+}
@@ -129,7 +129,8 @@ impl Texture {
                 sample_count: 1,
                 dimension: wgpu::TextureDimension::D2,
                 format: srgb_format,
-                usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::TEXTURE_BINDING, // SEE WHAT'S NEEDED HERE
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::TEXTURE_BINDING
+                    | wgpu::TextureUsages::COPY_SRC, // copied to a buffer for screenshots
                 view_formats: &[]
             }
         );
@@ -150,6 +151,57 @@ impl Texture {
         );
         Self {texture, view, sampler}
     }
+    // copy the contents of an Rgba8 texture back to the CPU, the way it is shown on screen. Blocks until the GPU is done
+    // with it. Used for screenshots and recording, but doesn't need a window so it works for offscreen rendering too.
+    pub fn read_to_image(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<image::RgbaImage> {
+        let size = self.texture.size();
+        let unpadded_bytes_per_row = 4 * size.width;
+        // rows in the buffer have to be aligned to 256 bytes
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_bytes_per_row = unpadded_bytes_per_row.div_ceil(align) * align;
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Texture readback buffer"),
+            size: (padded_bytes_per_row * size.height) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Texture readback encoder"),
+        });
+        encoder.copy_texture_to_buffer(
+            self.texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_bytes_per_row),
+                    rows_per_image: Some(size.height),
+                },
+            },
+            wgpu::Extent3d { depth_or_array_layers: 1, ..size },
+        );
+        queue.submit([encoder.finish()]);
+
+        let slice = buffer.slice(..);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            sender.send(result).ok();
+        });
+        device.poll(wgpu::Maintain::Wait);
+        receiver.recv()?.context("Could not map readback buffer")?;
+
+        let mut pixels = Vec::with_capacity((unpadded_bytes_per_row * size.height) as usize);
+        {
+            let data = slice.get_mapped_range();
+            for row in data.chunks(padded_bytes_per_row as usize) {
+                pixels.extend_from_slice(&row[..unpadded_bytes_per_row as usize]);
+            }
+        }
+        buffer.unmap();
+        let image = image::RgbaImage::from_raw(size.width, size.height, pixels).context("Readback buffer has the wrong size")?;
+        // the texture is upside down, screen_shader.wgsl flips it when drawing
+        Ok(image::imageops::flip_vertical(&image))
+    }
     // create a cubemap texture, textureview and sampler to be used as a skybox
     pub async fn create_cubemap(device: &wgpu::Device, queue: &wgpu::Queue, box_path: &str) -> Self {
          // TODO: actually fix the skybox. it takes several seconds to load as it is.
@@ -163,7 +215,7 @@ impl Texture {
                         &path
                     )
                     .await
-                    .expect(&format!("Could not open resource: '{}'", path))
+                    .unwrap_or_else(|_| panic!("Could not open resource: '{}'", path))
                 ).expect("Could not load texture")
                 .to_rgba8()
             );
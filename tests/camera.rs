@@ -0,0 +1,135 @@
+use std::f32::consts::PI;
+use std::path::PathBuf;
+use glam::{vec3, Vec3};
+use instant::Duration;
+use voxel_raytracer_lib::camera::bindings::{Action, KeyBindings};
+use voxel_raytracer_lib::camera::path::{CameraPath, Keyframe, Playback};
+use voxel_raytracer_lib::camera::{CameraController, CameraMode, Projection, View};
+use winit::event::{ElementState, VirtualKeyCode};
+
+// Tests for camera paths and controls, which don't need a renderer
+
+fn keyframe(time: f32, position: Vec3, yaw: f32) -> Keyframe {
+    Keyframe { time, position, yaw, pitch: 0.0, fov: 1.0 }
+}
+
+fn bent_path() -> CameraPath {
+    CameraPath::new(vec![
+        keyframe(2.0, vec3(2.0, 1.0, 0.0), 0.0),
+        keyframe(0.0, vec3(0.0, 0.0, 0.0), 0.0),
+        keyframe(3.0, vec3(3.0, 1.0, 0.0), 0.0),
+        keyframe(1.0, vec3(1.0, 0.0, 0.0), 0.0),
+    ])
+}
+
+#[test]
+fn paths_pass_through_their_keyframes() {
+    let path = bent_path();
+    assert_eq!(path.keyframes().iter().map(|k| k.time).collect::<Vec<_>>(), [0.0, 1.0, 2.0, 3.0]);
+    assert_eq!(path.duration(), 3.0);
+    for keyframe in path.keyframes() {
+        assert!(path.sample(keyframe.time).unwrap().position.abs_diff_eq(keyframe.position, 1e-6));
+    }
+    // before the first and after the last keyframe the camera stays put
+    assert_eq!(path.sample(-1.0).unwrap().position, Vec3::ZERO);
+    assert_eq!(path.sample(5.0).unwrap().position, vec3(3.0, 1.0, 0.0));
+    assert!(CameraPath::default().sample(0.0).is_none());
+}
+
+#[test]
+fn paths_are_smooth_between_their_keyframes() {
+    let path = bent_path();
+    // Catmull-Rom halfway through the middle segment, with the keyframes on either side pulling it along
+    assert!(path.sample(1.5).unwrap().position.abs_diff_eq(vec3(1.5, 0.5, 0.0), 1e-6));
+    // evenly spaced keyframes on a line are followed at an even speed, away from the ends where the spline eases in and out
+    let line = CameraPath::new((0..4).map(|i| keyframe(i as f32, Vec3::X * 2.0 * i as f32, 0.0)).collect());
+    for time in [1.25, 1.5, 1.75] {
+        assert!(line.sample(time).unwrap().position.abs_diff_eq(Vec3::X * 2.0 * time, 1e-5), "{}", time);
+    }
+    let mut wide = line.clone();
+    wide.push(Keyframe { fov: 2.0, ..keyframe(4.0, Vec3::X * 8.0, 0.0) });
+    assert_eq!(wide.sample(3.5).unwrap().fov, 1.5);
+}
+
+#[test]
+fn angles_take_the_short_way_around() {
+    let path = CameraPath::new(vec![keyframe(0.0, Vec3::ZERO, 3.0), keyframe(1.0, Vec3::ZERO, -3.0)]);
+    // across +-pi rather than back through 0
+    let yaw = path.sample(0.5).unwrap().yaw;
+    assert!((yaw.abs() - PI).abs() < 1e-5, "{}", yaw);
+    assert!(path.sample(0.25).unwrap().yaw > 3.0);
+    let back = CameraPath::new(vec![keyframe(0.0, Vec3::ZERO, -3.0), keyframe(1.0, Vec3::ZERO, 3.0)]);
+    assert!(back.sample(0.25).unwrap().yaw < -3.0);
+    let near = CameraPath::new(vec![keyframe(0.0, Vec3::ZERO, -0.5), keyframe(1.0, Vec3::ZERO, 0.5)]);
+    assert!(near.sample(0.5).unwrap().yaw.abs() < 1e-6);
+}
+
+#[test]
+fn paths_round_trip_through_files() {
+    let directory = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("camera_paths");
+    std::fs::create_dir_all(&directory).unwrap();
+    let path = bent_path();
+    for file in ["path.ron", "path.json"] {
+        path.save(&directory.join(file)).unwrap();
+        assert_eq!(CameraPath::load(&directory.join(file)).unwrap(), path, "{}", file);
+    }
+    // keyframes written out of order are sorted when loading
+    std::fs::write(directory.join("unsorted.ron"), "(keyframes: [(time: 1.0, position: (1.0, 0.0, 0.0), yaw: 0.0, pitch: 0.0, fov: 1.0), (time: 0.0, position: (0.0, 0.0, 0.0), yaw: 0.0, pitch: 0.0, fov: 1.0)])").unwrap();
+    assert_eq!(CameraPath::load(&directory.join("unsorted.ron")).unwrap().keyframes()[0].time, 0.0);
+    assert!(path.save(&directory.join("path.txt")).is_err());
+    assert!(CameraPath::load(&directory.join("missing.ron")).is_err());
+}
+
+#[test]
+fn looping_playback_starts_over() {
+    let mut view = View::new(Vec3::ZERO, 0.0, 0.0);
+    let mut projection = Projection::new(1.0, 1.0, 0.1, 100.0);
+    let mut playback = Playback::new(bent_path(), true);
+    playback.update(&mut view, &mut projection, Duration::from_millis(3500));
+    assert!(view.position.abs_diff_eq(bent_path().sample(0.5).unwrap().position, 1e-5));
+    assert!(!playback.is_finished());
+    let mut once = Playback::new(bent_path(), false);
+    once.update(&mut view, &mut projection, Duration::from_millis(3500));
+    assert_eq!(view.position, vec3(3.0, 1.0, 0.0));
+    assert!(once.is_finished());
+}
+
+#[test]
+fn holding_zoom_changes_the_distance_at_a_steady_rate() {
+    let mut controller = CameraController::new(4.0, 1.0);
+    let mut view = View::new(vec3(0.0, 0.0, -10.0), 0.0, 0.0);
+    controller.orbit(&mut view, Vec3::ZERO);
+    assert!(controller.process_keyboard(VirtualKeyCode::Equals, ElementState::Pressed));
+    for _ in 0..60 {
+        controller.update_camera(&mut view, Duration::from_secs_f32(1.0 / 60.0));
+    }
+    let CameraMode::Orbit { distance, .. } = controller.mode else { panic!("not orbiting") };
+    // a second of zooming in gets closer, but doesn't jump onto the target
+    assert!(distance > 2.0 && distance < 8.0, "{}", distance);
+    assert!((view.position.length() - distance).abs() < 1e-3);
+    // flying moves forward at about the movement speed
+    let mut view = View::new(Vec3::ZERO, 0.0, 0.0);
+    controller.fly();
+    for _ in 0..60 {
+        controller.update_camera(&mut view, Duration::from_secs_f32(1.0 / 60.0));
+    }
+    assert!((view.position.length() - 4.0).abs() < 1e-3, "{}", view.position);
+}
+
+#[test]
+fn viewer_keys_are_bound_from_files_too() {
+    let defaults = KeyBindings::default();
+    assert_eq!(defaults.action(VirtualKeyCode::F12), Some(Action::Screenshot));
+    assert_eq!(defaults.action(VirtualKeyCode::K), Some(Action::RecordKeyframe));
+    let directory = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("key_bindings");
+    std::fs::create_dir_all(&directory).unwrap();
+    std::fs::write(directory.join("keys.ron"), "{ Snapshot: Screenshot, W: Forward }").unwrap();
+    let bindings = KeyBindings::load(&directory.join("keys.ron")).unwrap();
+    assert_eq!(bindings.action(VirtualKeyCode::Snapshot), Some(Action::Screenshot));
+    assert_eq!(bindings.action(VirtualKeyCode::F12), None);
+    // viewer actions are left to the viewer by the camera controller
+    let mut controller = CameraController::new(4.0, 1.0);
+    controller.bindings = bindings;
+    assert!(!controller.process_keyboard(VirtualKeyCode::Snapshot, ElementState::Pressed));
+    assert!(controller.process_keyboard(VirtualKeyCode::W, ElementState::Pressed));
+}
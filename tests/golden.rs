@@ -0,0 +1,254 @@
+use std::path::{Path, PathBuf};
+use glam::{uvec3, vec3, Affine3A, EulerRot, Quat, UVec3, Vec3};
+use voxel_raytracer_lib::camera::Camera;
+use voxel_raytracer_lib::cpu_trace::{self, Skybox, TraceSettings};
+use voxel_raytracer_lib::scene::brush::Brush;
+use voxel_raytracer_lib::scene::objects::VoxelObject;
+use voxel_raytracer_lib::scene::terrain::Terrain;
+use voxel_raytracer_lib::scene::{Fog, Material, SamplingMode, Scene, Voxel};
+
+// Golden image tests for the renderer.
+// Each test renders a canonical scene with the CPU reference tracer at a fixed camera, seed and number of
+// lighting frames, and compares it to a checked-in PNG in tests/golden.
+// Only the CPU reference is covered: the tests need no GPU, so they don't check that raytracing.wgsl still renders
+// what cpu_trace.rs does. Changes to the shader have to be mirrored in cpu_trace.rs by hand and compared in the viewer.
+// Run with GOLDEN_UPDATE=1 to (re)generate the images after an intentional change to the shading.
+// On failure the rendered image and a diff image are written to the cargo test tmp directory.
+
+const WIDTH: u32 = 128;
+const HEIGHT: u32 = 72;
+const LIGHTING_FRAMES: u32 = 8;
+// the largest root mean square error per channel (in 0..255) that still passes
+const MAX_RMSE: f32 = 1.5;
+// materials of Scene::new
+const DIFFUSE: u32 = 0;
+const GLOSSY: u32 = 1;
+const GLASS: u32 = 2;
+const EMISSIVE: u32 = 3;
+const MIRROR: u32 = 4;
+
+const WHITE: UVec3 = uvec3(200, 200, 200);
+const SKY: Vec3 = vec3(0.5, 0.7, 1.0);
+
+fn camera(position: Vec3, yaw_degrees: f32, pitch_degrees: f32) -> Camera {
+    Camera::new(
+        position,
+        yaw_degrees.to_radians(),
+        pitch_degrees.to_radians(),
+        WIDTH as f32 / HEIGHT as f32,
+        59.0f32.to_radians(),
+        0.1,
+        100.0,
+    )
+}
+
+// a 32 voxel box open towards -z, lit only by a patch in the ceiling
+fn cornell_box() -> Scene {
+    let mut scene = Scene::new();
+    scene.set_sun(Vec3::Y, Vec3::ZERO);
+    scene.fill_box(uvec3(0, 0, 0), uvec3(32, 1, 32), &Brush::union(DIFFUSE, WHITE).with_normal(Vec3::Y)); // floor
+    scene.fill_box(uvec3(0, 31, 0), uvec3(32, 32, 32), &Brush::union(DIFFUSE, WHITE).with_normal(-Vec3::Y)); // ceiling
+    scene.fill_box(uvec3(0, 1, 31), uvec3(32, 31, 32), &Brush::union(DIFFUSE, WHITE).with_normal(-Vec3::Z)); // back
+    scene.fill_box(uvec3(0, 1, 0), uvec3(1, 31, 31), &Brush::union(DIFFUSE, uvec3(200, 30, 30)).with_normal(Vec3::X)); // left
+    scene.fill_box(uvec3(31, 1, 0), uvec3(32, 31, 31), &Brush::union(DIFFUSE, uvec3(30, 200, 30)).with_normal(-Vec3::X)); // right
+    scene.fill_box(uvec3(12, 31, 12), uvec3(20, 32, 20), &Brush::union(EMISSIVE, uvec3(255, 255, 255)).with_normal(-Vec3::Y)); // light
+    scene.fill_box(uvec3(6, 1, 16), uvec3(14, 18, 24), &Brush::union(MIRROR, WHITE));
+    scene.fill_box(uvec3(18, 1, 8), uvec3(26, 9, 16), &Brush::union(DIFFUSE, WHITE));
+    scene
+}
+
+// a glass sphere in front of colored blocks, lit by the sun
+fn glass_sphere() -> Scene {
+    let mut scene = Scene::new();
+    scene.spawn_ground_plane();
+    scene.fill_sphere(vec3(24.0, 11.0, 24.0), 10.0, &Brush::union(GLASS, uvec3(230, 240, 255)));
+    scene.fill_box(uvec3(10, 1, 40), uvec3(18, 16, 44), &Brush::union(DIFFUSE, uvec3(200, 40, 40)));
+    scene.fill_box(uvec3(20, 1, 40), uvec3(28, 16, 44), &Brush::union(GLOSSY, uvec3(40, 200, 40)));
+    scene.fill_box(uvec3(30, 1, 40), uvec3(38, 16, 44), &Brush::union(DIFFUSE, uvec3(40, 40, 200)));
+    scene
+}
+
+// a closed room lit only by bright emissive spheres
+fn emissive_room() -> Scene {
+    let mut scene = Scene::new();
+    scene.set_material(EMISSIVE, Material {
+        emission_strength: 3.0,
+        ..*scene.material(EMISSIVE)
+    });
+    scene.set_sun(Vec3::Y, Vec3::ZERO);
+    scene.set_ambient_light(Vec3::ZERO);
+    scene.fill_box(uvec3(0, 0, 0), uvec3(32, 1, 32), &Brush::union(DIFFUSE, WHITE).with_normal(Vec3::Y));
+    scene.fill_box(uvec3(0, 31, 0), uvec3(32, 32, 32), &Brush::union(DIFFUSE, WHITE).with_normal(-Vec3::Y));
+    scene.fill_box(uvec3(0, 1, 0), uvec3(32, 31, 1), &Brush::union(DIFFUSE, WHITE).with_normal(Vec3::Z));
+    scene.fill_box(uvec3(0, 1, 31), uvec3(32, 31, 32), &Brush::union(DIFFUSE, WHITE).with_normal(-Vec3::Z));
+    scene.fill_box(uvec3(0, 1, 1), uvec3(1, 31, 31), &Brush::union(DIFFUSE, WHITE).with_normal(Vec3::X));
+    scene.fill_box(uvec3(31, 1, 1), uvec3(32, 31, 31), &Brush::union(DIFFUSE, WHITE).with_normal(-Vec3::X));
+    scene.fill_sphere(vec3(10.0, 6.0, 20.0), 4.0, &Brush::union(EMISSIVE, uvec3(255, 120, 40)));
+    scene.fill_sphere(vec3(22.0, 8.0, 22.0), 4.0, &Brush::union(EMISSIVE, uvec3(40, 120, 255)));
+    scene.fill_box(uvec3(14, 1, 14), uvec3(18, 12, 18), &Brush::union(GLOSSY, WHITE));
+    scene
+}
+
+// spheres of gold with increasing roughness next to a rough plastic one, lit by the sun
+fn pbr_materials() -> Scene {
+    let mut scene = Scene::new();
+    scene.spawn_ground_plane();
+    for (index, roughness) in [(GLOSSY, 0.1), (GLASS, 0.4), (EMISSIVE, 0.7)] {
+        scene.set_material(index, Material {
+            base_color: vec3(1.0, 0.78, 0.34),
+            metallic: 1.0,
+            roughness,
+            ..Default::default()
+        });
+    }
+    scene.set_material(MIRROR, Material {
+        roughness: 0.5,
+        ..Default::default()
+    });
+    let gold = uvec3(255, 255, 255);
+    scene.fill_sphere(vec3(12.0, 7.0, 24.0), 6.0, &Brush::union(GLOSSY, gold));
+    scene.fill_sphere(vec3(26.0, 7.0, 24.0), 6.0, &Brush::union(GLASS, gold));
+    scene.fill_sphere(vec3(40.0, 7.0, 24.0), 6.0, &Brush::union(EMISSIVE, gold));
+    scene.fill_sphere(vec3(54.0, 7.0, 24.0), 6.0, &Brush::union(MIRROR, uvec3(200, 40, 40)));
+    scene
+}
+
+// a slatted roof over height fog, with light shafts between the slats and a column of orange smoke
+fn foggy_hall() -> Scene {
+    let mut scene = Scene::new();
+    scene.spawn_ground_plane();
+    scene.set_sun(vec3(-0.5, 1.0, -0.3), Vec3::ONE);
+    scene.set_fog(Fog {
+        density: 0.4,
+        height: 2.0,
+        ..Default::default()
+    });
+    scene.set_material(GLASS, Material::volume(1.5, 0.3));
+    for x in (0..48).filter(|x| x % 8 >= 3) {
+        scene.fill_box(uvec3(x, 23, 0), uvec3(x + 1, 24, 48), &Brush::union(DIFFUSE, WHITE).with_normal(-Vec3::Y));
+    }
+    scene.fill_box(uvec3(20, 1, 28), uvec3(26, 22, 34), &Brush::union(GLASS, uvec3(255, 150, 60)));
+    scene
+}
+
+// a checkered crate turned around two axes and a stretched and tilted copy of it as a plank, both dynamic objects,
+// casting shadows on the ground and on the blocks next to them
+fn dynamic_objects() -> Scene {
+    let mut scene = Scene::new();
+    scene.spawn_ground_plane();
+    scene.set_sun(vec3(-0.4, 1.0, 0.3), Vec3::ONE);
+    scene.fill_box(uvec3(30, 1, 36), uvec3(38, 12, 44), &Brush::union(GLOSSY, uvec3(40, 200, 40)));
+    let mut object = VoxelObject::new(UVec3::splat(8));
+    let center = Vec3::splat(4.0);
+    for z in 0..8 {
+        for y in 0..8 {
+            for x in 0..8 {
+                let albedo = if (x / 2 + y / 2 + z / 2) % 2 == 0 { uvec3(220, 140, 60) } else { uvec3(120, 60, 30) };
+                let normal = (uvec3(x, y, z).as_vec3() + 0.5 - center).normalize();
+                object.set_voxel(uvec3(x, y, z), Voxel::new(DIFFUSE, albedo, normal));
+            }
+        }
+    }
+    let object = scene.add_object(object);
+    scene.add_instance(object, Affine3A::from_rotation_translation(Quat::from_euler(EulerRot::YXZ, 0.5, 0.4, 0.0), vec3(22.0, 6.0, 30.0)));
+    scene.add_instance(object, Affine3A::from_scale_rotation_translation(vec3(3.0, 0.25, 1.0), Quat::from_rotation_z(0.3), vec3(8.0, 12.0, 36.0)));
+    scene
+}
+
+// rolling hills with a lake, lit by a low sun
+fn terrain() -> Scene {
+    let mut scene = Scene::new();
+    scene.set_sun(vec3(-0.6, 0.5, 0.4), Vec3::ONE);
+    scene.set_material(GLASS, Material::water());
+    Terrain { seed: 7, ..Default::default() }.generate(&mut scene);
+    scene
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden").join(format!("{}.png", name))
+}
+
+fn check_golden(name: &str, scene: &Scene, camera: &Camera) {
+    let settings = TraceSettings {
+        width: WIDTH,
+        height: HEIGHT,
+        lighting_frames: LIGHTING_FRAMES,
+    };
+    let actual = cpu_trace::render(scene, camera, &Skybox::Color(SKY), &settings);
+    let path = golden_path(name);
+    if std::env::var_os("GOLDEN_UPDATE").is_some() {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        actual.save(&path).unwrap();
+        return;
+    }
+    let expected = image::open(&path)
+        .unwrap_or_else(|e| panic!("Could not open golden image '{}' ({}), run with GOLDEN_UPDATE=1 to create it", path.display(), e))
+        .to_rgba8();
+    let difference = cpu_trace::compare_images(&expected, &actual, 0).unwrap();
+    if difference.rmse > MAX_RMSE {
+        let output = Path::new(env!("CARGO_TARGET_TMPDIR")).join("golden");
+        std::fs::create_dir_all(&output).unwrap();
+        let actual_path = output.join(format!("{}_actual.png", name));
+        let diff_path = output.join(format!("{}_diff.png", name));
+        actual.save(&actual_path).unwrap();
+        difference.diff_image.save(&diff_path).unwrap();
+        panic!(
+            "'{}' differs from its golden image: {}\nwrote '{}' and '{}'",
+            name, difference, actual_path.display(), diff_path.display(),
+        );
+    }
+}
+
+#[test]
+fn demo_scene() {
+    check_golden("demo", &Scene::demo(), &camera(vec3(-4.0, 4.0, -4.0), 45.0, -25.0));
+}
+
+#[test]
+fn cornell_box_scene() {
+    check_golden("cornell_box", &cornell_box(), &camera(vec3(2.0, 2.0, -2.5), 90.0, 0.0));
+}
+
+#[test]
+fn cornell_box_without_ao_scene() {
+    let mut scene = cornell_box();
+    scene.set_ambient_occlusion(false);
+    check_golden("cornell_box_without_ao", &scene, &camera(vec3(2.0, 2.0, -2.5), 90.0, 0.0));
+}
+
+#[test]
+fn cornell_box_r2_scene() {
+    let mut scene = cornell_box();
+    scene.set_sampling(SamplingMode::R2);
+    check_golden("cornell_box_r2", &scene, &camera(vec3(2.0, 2.0, -2.5), 90.0, 0.0));
+}
+
+#[test]
+fn glass_sphere_scene() {
+    check_golden("glass_sphere", &glass_sphere(), &camera(vec3(3.0, 2.0, -1.0), 90.0, -12.0));
+}
+
+#[test]
+fn pbr_materials_scene() {
+    check_golden("pbr_materials", &pbr_materials(), &camera(vec3(4.1, 2.0, -1.0), 90.0, -15.0));
+}
+
+#[test]
+fn foggy_hall_scene() {
+    check_golden("foggy_hall", &foggy_hall(), &camera(vec3(3.0, 1.2, 7.5), -60.0, 5.0));
+}
+
+#[test]
+fn terrain_scene() {
+    check_golden("terrain", &terrain(), &camera(vec3(0.5, 6.0, 0.5), 45.0, -35.0));
+}
+
+#[test]
+fn emissive_room_scene() {
+    check_golden("emissive_room", &emissive_room(), &camera(vec3(0.5, 2.5, 0.5), 45.0, -20.0));
+}
+
+#[test]
+fn dynamic_objects_scene() {
+    check_golden("dynamic_objects", &dynamic_objects(), &camera(vec3(3.0, 2.0, -1.0), 90.0, -12.0));
+}
@@ -0,0 +1,584 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use anyhow::Result;
+use base64::Engine;
+use glam::{uvec3, vec3, UVec3, Vec3, Vec4};
+use image::{ImageBuffer, Luma, Rgba, RgbaImage};
+use voxel_raytracer_lib::model::heightmap::{Heightmap, HeightmapOptions};
+use voxel_raytracer_lib::model::mesh::VoxelMesh;
+use voxel_raytracer_lib::model::ply::PointCloud;
+use voxel_raytracer_lib::model::slices::{Band, ImageStack, TransferFunction};
+use voxel_raytracer_lib::model::vox::VoxModel;
+use voxel_raytracer_lib::model::{gltf, obj, Fill, Model, ModelMaterial, VoxelizeOptions};
+use voxel_raytracer_lib::scene::brush::Brush;
+use voxel_raytracer_lib::scene::prefab::VoxelModel;
+use voxel_raytracer_lib::scene::{Material, Scene, Voxel};
+
+// Tests for loading meshes, heightmaps and image stacks into a scene, and exporting regions of it
+
+fn no_libraries(name: &str) -> Result<Vec<ModelMaterial>> {
+    anyhow::bail!("unexpected material library {name}")
+}
+
+fn filled_count(scene: &Scene, min: UVec3, max: UVec3) -> usize {
+    let mut count = 0;
+    for z in min.z..max.z {
+        for y in min.y..max.y {
+            for x in min.x..max.x {
+                count += !scene.get_voxel(uvec3(x, y, z)).is_empty() as usize;
+            }
+        }
+    }
+    count
+}
+
+const CUBE: &str = "
+v -0.5 -0.5 -0.5
+v 0.5 -0.5 -0.5
+v 0.5 0.5 -0.5
+v -0.5 0.5 -0.5
+v -0.5 -0.5 0.5
+v 0.5 -0.5 0.5
+v 0.5 0.5 0.5
+v -0.5 0.5 0.5
+f 1 4 3 2
+f 5 6 7 8
+f 1 2 6 5
+f 4 8 7 3
+f 1 5 8 4
+f 2 3 7 6
+";
+
+#[test]
+fn cubes_voxelize_as_a_shell_or_solid() {
+    let model = obj::parse(CUBE, no_libraries).unwrap();
+    assert_eq!(model.triangles.len(), 12);
+    // the faces go through the voxel centers from 4.5 to 12.5, so 9 voxels along each edge
+    let options = VoxelizeOptions { scale: 8.0, offset: Vec3::splat(8.5), default_material: 1, ..Default::default() };
+    let (min, max) = (UVec3::splat(2), UVec3::splat(16));
+
+    let mut scene = Scene::new();
+    model.voxelize(&mut scene, &options);
+    assert_eq!(filled_count(&scene, min, max), 9 * 9 * 9 - 7 * 7 * 7);
+    assert!(scene.get_voxel(UVec3::splat(8)).is_empty());
+    let side = scene.get_voxel(uvec3(4, 8, 8));
+    assert_eq!(side.material(), 1);
+    assert!(side.normal().dot(Vec3::NEG_X) > 0.99, "the face normal points out of the cube");
+
+    let mut scene = Scene::new();
+    model.voxelize(&mut scene, &VoxelizeOptions { fill: Fill::Solid, ..options });
+    assert_eq!(filled_count(&scene, min, max), 9 * 9 * 9);
+    assert_eq!(scene.get_voxel(UVec3::splat(8)).material(), 1);
+}
+
+const QUAD: &str = "
+mtllib quad.mtl
+v 0 0 0 1 0 0
+v 0 0 8 1 0 0
+v 8 0 8 0 0 1
+v 8 0 0 0 0 1
+vt 0 0
+vt 0 1
+vt 1 1
+vt 1 0
+vn 0 1 0
+usemtl checker
+f 1/1/1 2/2/1 3/3/1 4/4/1
+";
+
+const QUAD_MATERIALS: &str = "
+newmtl checker
+Kd 1 1 1
+Ns 0
+map_Kd -s 1 1 1 checker.png
+";
+
+// a 2x1 texture, white on the left and magenta on the right
+fn checker() -> RgbaImage {
+    RgbaImage::from_fn(2, 1, |x, _| if x == 0 { Rgba([255, 255, 255, 255]) } else { Rgba([255, 0, 255, 255]) })
+}
+
+#[test]
+fn albedo_comes_from_textures_and_vertex_colors() {
+    let model = obj::parse(QUAD, |library| {
+        assert_eq!(library, "quad.mtl");
+        obj::parse_material_library(QUAD_MATERIALS, |texture| {
+            assert_eq!(texture, "checker.png");
+            Ok(checker())
+        })
+    })
+    .unwrap();
+    assert_eq!(model.materials.len(), 1);
+    assert_eq!(model.materials[0].roughness, 1.0);
+
+    let mut scene = Scene::new();
+    let options = VoxelizeOptions { offset: vec3(4.0, 4.5, 4.0), materials: vec![2], ..Default::default() };
+    model.voxelize(&mut scene, &options);
+    // red vertices on the left over the white texel, blue ones on the right over the magenta texel.
+    // The vertex colors blend a little towards the middle
+    let left = scene.get_voxel(uvec3(4, 4, 8));
+    let right = scene.get_voxel(uvec3(11, 4, 8));
+    let middle = scene.get_voxel(uvec3(7, 4, 8));
+    assert_eq!(left.material(), 2);
+    assert!(left.albedo().as_vec3().distance(vec3(255.0, 0.0, 0.0)) < 24.0, "{}", left.albedo());
+    assert!(right.albedo().as_vec3().distance(vec3(0.0, 0.0, 255.0)) < 24.0, "{}", right.albedo());
+    assert_eq!(middle.albedo().y, 0);
+    assert!(middle.albedo().x > 64 && middle.albedo().z > 64, "{}", middle.albedo());
+    assert!(left.normal().dot(Vec3::Y) > 0.99);
+}
+
+#[test]
+fn obj_files_load_their_materials_and_textures() {
+    let directory = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("obj_model");
+    std::fs::create_dir_all(&directory).unwrap();
+    std::fs::write(directory.join("quad.obj"), QUAD).unwrap();
+    std::fs::write(directory.join("quad.mtl"), QUAD_MATERIALS).unwrap();
+    checker().save(directory.join("checker.png")).unwrap();
+
+    let model = Model::load(&directory.join("quad.obj")).unwrap();
+    assert_eq!(model.triangles.len(), 2);
+    assert_eq!(model.materials[0].texture, Some(checker()));
+    assert!(Model::load(&directory.join("quad.mtl")).is_err());
+}
+
+// a triangle with normals and indices in one buffer, and one material using every supported extension
+fn triangle_gltf(buffer_uri: Option<String>, buffer_length: usize) -> String {
+    let uri = buffer_uri.map(|uri| format!(r#""uri": "{uri}","#)).unwrap_or_default();
+    format!(r#"{{
+        "asset": {{ "version": "2.0" }},
+        "scene": 0,
+        "scenes": [{{ "nodes": [0] }}],
+        "nodes": [{{ "translation": [10, 10.5, 14], "children": [1] }}, {{ "rotation": [1, 0, 0, 0], "mesh": 0 }}],
+        "meshes": [{{ "name": "triangle", "primitives": [{{ "attributes": {{ "POSITION": 0, "NORMAL": 1 }}, "indices": 2, "material": 0 }}] }}],
+        "buffers": [{{ {uri} "byteLength": {buffer_length} }}],
+        "bufferViews": [
+            {{ "buffer": 0, "byteOffset": 0, "byteLength": 72 }},
+            {{ "buffer": 0, "byteOffset": 72, "byteLength": 6 }}
+        ],
+        "accessors": [
+            {{ "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3" }},
+            {{ "bufferView": 0, "byteOffset": 36, "componentType": 5126, "count": 3, "type": "VEC3" }},
+            {{ "bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR" }}
+        ],
+        "materials": [{{
+            "name": "glowing glass",
+            "pbrMetallicRoughness": {{ "baseColorFactor": [1, 0.5, 0, 1], "metallicFactor": 0.25, "roughnessFactor": 0.5 }},
+            "emissiveFactor": [1, 0.5, 0],
+            "extensions": {{
+                "KHR_materials_emissive_strength": {{ "emissiveStrength": 4 }},
+                "KHR_materials_transmission": {{ "transmissionFactor": 0.6 }},
+                "KHR_materials_ior": {{ "ior": 1.4 }}
+            }}
+        }}]
+    }}"#)
+}
+
+fn triangle_buffer() -> Vec<u8> {
+    let positions = [0.0f32, 0.0, 0.0, 0.0, 0.0, 4.0, 4.0, 0.0, 0.0];
+    let normals = [0.0f32, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0];
+    let mut buffer: Vec<u8> = positions.iter().chain(&normals).flat_map(|value| value.to_le_bytes()).collect();
+    buffer.extend([0u16, 1, 2].iter().flat_map(|index| index.to_le_bytes()));
+    buffer
+}
+
+#[test]
+fn gltf_triangles_are_placed_by_their_nodes() {
+    let buffer = triangle_buffer();
+    let uri = format!("data:application/octet-stream;base64,{}", base64::engine::general_purpose::STANDARD.encode(&buffer));
+    let json = triangle_gltf(Some(uri), buffer.len());
+    let model = gltf::parse(json.as_bytes(), |uri| anyhow::bail!("unexpected file {uri}")).unwrap();
+    assert_eq!(model.triangles.len(), 1);
+    // turned upside down around x, then moved
+    let triangle = model.triangles[0];
+    assert!(triangle.positions[1].abs_diff_eq(vec3(10.0, 10.5, 10.0), 1e-5));
+    assert!(triangle.normals.unwrap()[0].abs_diff_eq(Vec3::NEG_Y, 1e-5));
+
+    let material = &model.materials[0];
+    assert_eq!(material.name, "glowing glass");
+    assert_eq!(material.base_color, Vec4::new(1.0, 0.5, 0.0, 1.0));
+    assert_eq!(material.emission, vec3(4.0, 2.0, 0.0));
+    let scene_material = material.material();
+    assert_eq!((scene_material.metallic, scene_material.roughness), (0.25, 0.5));
+    assert_eq!((scene_material.emission, scene_material.emission_strength), (vec3(1.0, 0.5, 0.0), 4.0));
+    assert_eq!((scene_material.transmission, scene_material.ior), (0.6, 1.4));
+
+    let mut scene = Scene::new();
+    model.voxelize(&mut scene, &VoxelizeOptions { materials: vec![3], ..Default::default() });
+    let voxel = scene.get_voxel(uvec3(11, 10, 11));
+    assert_eq!(voxel.material(), 3);
+    assert_eq!(voxel.albedo(), uvec3(255, 128, 0));
+    assert!(voxel.normal().dot(Vec3::NEG_Y) > 0.99);
+    assert!(scene.get_voxel(uvec3(11, 10, 15)).is_empty());
+}
+
+#[test]
+fn gltf_accessors_out_of_range_are_errors() {
+    let buffer = triangle_buffer();
+    let uri = format!("data:application/octet-stream;base64,{}", base64::engine::general_purpose::STANDARD.encode(&buffer));
+    let json = triangle_gltf(Some(uri), buffer.len());
+    let parse = |from: &str, to: &str| {
+        assert!(json.contains(from));
+        gltf::parse(json.replacen(from, to, 1).as_bytes(), |uri| anyhow::bail!("unexpected file {uri}"))
+    };
+    assert!(parse("VEC3", "VEC3").is_ok());
+    // counts and offsets that would allocate too much, overflow or read past the end
+    assert!(parse(r#""count": 3, "type": "VEC3""#, r#""count": 18446744073709551615, "type": "VEC3""#).is_err());
+    assert!(parse(r#""count": 3, "type": "VEC3""#, r#""count": 7, "type": "VEC3""#).is_err());
+    assert!(parse(r#""byteOffset": 36"#, r#""byteOffset": 18446744073709551600"#).is_err());
+    assert!(parse(r#""byteOffset": 72, "byteLength": 6"#, r#""byteOffset": 18446744073709551615, "byteLength": 6"#).is_err());
+    assert!(parse(r#""bufferView": 0, "componentType""#, r#""componentType""#).is_ok(), "accessors without a view are zeros");
+    assert!(parse(r#""bufferView": 0, "componentType": 5126, "count": 3"#, r#""componentType": 5126, "count": 18446744073709551615"#).is_err());
+}
+
+#[test]
+fn glb_files_read_the_binary_chunk() {
+    let buffer = triangle_buffer();
+    let mut json = triangle_gltf(None, buffer.len()).into_bytes();
+    json.resize(json.len().next_multiple_of(4), b' ');
+    let mut binary = buffer.clone();
+    binary.resize(binary.len().next_multiple_of(4), 0);
+    let mut glb = Vec::new();
+    glb.extend(b"glTF");
+    glb.extend(2u32.to_le_bytes());
+    glb.extend(((12 + 8 + json.len() + 8 + binary.len()) as u32).to_le_bytes());
+    glb.extend((json.len() as u32).to_le_bytes());
+    glb.extend(b"JSON");
+    glb.extend(&json);
+    glb.extend((binary.len() as u32).to_le_bytes());
+    glb.extend(b"BIN\0");
+    glb.extend(&binary);
+
+    let directory = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("glb_model");
+    std::fs::create_dir_all(&directory).unwrap();
+    std::fs::write(directory.join("triangle.glb"), &glb).unwrap();
+    let model = Model::load(&directory.join("triangle.glb")).unwrap();
+    let without_binary = gltf::parse(triangle_gltf(None, 0).as_bytes(), |_| Ok(buffer.clone()));
+    assert!(without_binary.is_err(), "a .gltf file has no binary chunk for buffers without a uri");
+    assert_eq!(model.triangles.len(), 1);
+    assert!(model.triangles[0].positions[2].abs_diff_eq(vec3(14.0, 10.5, 14.0), 1e-5));
+}
+
+// a heightmap rising along x, from black to white
+fn ramp(width: u32, depth: u32) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+    ImageBuffer::from_fn(width, depth, |x, _| Luma([(x * u16::MAX as u32 / (width - 1)) as u16]))
+}
+
+fn column_height(scene: &Scene, x: u32, z: u32) -> usize {
+    filled_count(scene, uvec3(x, 0, z), uvec3(x + 1, 64, z + 1))
+}
+
+#[test]
+fn heightmaps_fill_columns_up_to_the_surface() {
+    let colors = RgbaImage::from_fn(2, 2, |x, _| if x == 0 { Rgba([255, 0, 0, 255]) } else { Rgba([0, 0, 255, 255]) });
+    let heightmap = Heightmap::new(ramp(5, 3), Some(colors)).unwrap();
+    let options = HeightmapOptions { offset: uvec3(2, 1, 2), scale: 2.0, height: 16.0, material: 4, ..Default::default() };
+    let mut scene = Scene::new();
+    heightmap.import(&mut scene, &options);
+
+    // 10 by 6 columns, the first at the bottom of the ramp and the last at the top
+    assert_eq!(filled_count(&scene, UVec3::ZERO, uvec3(2, 64, 64)), 0);
+    assert_eq!(filled_count(&scene, uvec3(12, 0, 0), UVec3::splat(64)), 0);
+    assert!(scene.get_voxel(uvec3(11, 0, 7)).is_empty(), "the columns start at the offset");
+    assert_eq!(column_height(&scene, 2, 2), 0);
+    assert_eq!(column_height(&scene, 11, 7), 16);
+    let heights: Vec<usize> = (2..12).map(|x| column_height(&scene, x, 4)).collect();
+    assert!(heights.windows(2).all(|pair| pair[0] <= pair[1]), "{heights:?}");
+
+    // on the slope the normals lean back down the ramp, and the colors are stretched over the heightmap
+    let voxel = scene.get_voxel(uvec3(6, 1, 4));
+    assert_eq!(voxel.material(), 4);
+    let expected = vec3(-2.0, 1.0, 0.0).normalize();
+    assert!(voxel.normal().normalize().dot(expected) > 0.999, "{}", voxel.normal());
+    assert_eq!(voxel.albedo(), uvec3(255, 0, 0));
+    assert_eq!(scene.get_voxel(uvec3(7, 1, 4)).albedo(), uvec3(0, 0, 255));
+}
+
+#[test]
+fn heightmaps_load_16_bit_images() {
+    let directory = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("heightmap");
+    std::fs::create_dir_all(&directory).unwrap();
+    let heights = ImageBuffer::from_fn(2, 1, |x, _| Luma([if x == 0 { 1u16 } else { u16::MAX - 1 }]));
+    heights.save(directory.join("heights.png")).unwrap();
+    let heightmap = Heightmap::load(&directory.join("heights.png"), None).unwrap();
+    assert_eq!(heightmap.heights, heights);
+    assert!(Heightmap::load(&directory.join("heights.png"), Some(&directory.join("missing.png"))).is_err());
+}
+
+// a stack of slices of a ball with a dense core, bright in the middle and dark outside
+fn ball_slices(size: u32) -> Vec<ImageBuffer<Luma<u16>, Vec<u16>>> {
+    let center = Vec3::splat(size as f32 / 2.0);
+    (0..size)
+        .map(|y| ImageBuffer::from_fn(size, size, |x, z| {
+            let distance = (vec3(x as f32, y as f32, z as f32) + 0.5).distance(center) / (size as f32 / 2.0);
+            Luma([((1.0 - distance).clamp(0.0, 1.0) * u16::MAX as f32) as u16])
+        }))
+        .collect()
+}
+
+#[test]
+fn transfer_functions_pick_materials_by_intensity() {
+    let transfer = TransferFunction {
+        bands: vec![
+            Band { threshold: 0.2, material: 1, albedo: (uvec3(100, 0, 0), uvec3(200, 0, 0)) },
+            Band { threshold: 0.6, material: 2, albedo: (UVec3::splat(250), UVec3::splat(250)) },
+        ],
+    };
+    assert_eq!(transfer.map(0.1), None);
+    assert_eq!(transfer.map(0.2), Some((1, uvec3(100, 0, 0))));
+    assert_eq!(transfer.map(0.4), Some((1, uvec3(150, 0, 0))));
+    assert_eq!(transfer.map(0.9), Some((2, UVec3::splat(250))));
+    assert_eq!(TransferFunction::threshold(0.5, 3, UVec3::ONE).map(1.0), Some((3, UVec3::ONE)));
+
+    let stack = ImageStack::new(ball_slices(16)).unwrap();
+    assert_eq!(stack.size(), uvec3(16, 16, 16));
+    let mut scene = Scene::new();
+    let offset = UVec3::splat(8);
+    stack.import(&mut scene, offset, &transfer);
+    let center = offset + 8;
+    assert_eq!(scene.get_voxel(center).material(), 2);
+    assert_eq!(scene.get_voxel(center + uvec3(5, 0, 0)).material(), 1);
+    assert!(scene.get_voxel(offset).is_empty());
+    let side = scene.get_voxel(center + uvec3(0, 0, 5));
+    assert!(side.normal().normalize().dot(Vec3::Z) > 0.9, "the normal of the shell points out, {}", side.normal());
+}
+
+#[test]
+fn image_stacks_load_slices_in_name_order() {
+    let directory = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("slices");
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    let slices = ball_slices(8);
+    // written out of order, with a file that isn't an image
+    for i in [3, 0, 7, 1, 2, 6, 5, 4] {
+        slices[i].save(directory.join(format!("slice_{i:03}.png"))).unwrap();
+    }
+    std::fs::write(directory.join("notes.txt"), "scanned sideways").unwrap();
+    let stack = ImageStack::load(&directory).unwrap();
+    assert_eq!(stack.slices, slices);
+
+    ImageBuffer::<Luma<u16>, _>::new(4, 4).save(directory.join("slice_008.png")).unwrap();
+    assert!(ImageStack::load(&directory).is_err(), "the slices have to be the same size");
+}
+
+// a scene with a few shapes in different looks and materials, and the region around them
+fn exported_scene() -> (Scene, UVec3, UVec3) {
+    let mut scene = Scene::new();
+    scene.set_material(1, Material { metallic: 1.0, roughness: 0.25, ..Default::default() });
+    scene.set_material(2, Material { transmission: 0.5, ior: 1.25, emission_strength: 4.0, roughness: 0.5, ..Default::default() });
+    scene.fill_box(uvec3(10, 10, 10), uvec3(16, 13, 14), &Brush::union(1, uvec3(200, 40, 40)));
+    scene.fill_sphere(vec3(17.0, 14.0, 12.0), 3.5, &Brush::union(2, uvec3(40, 200, 40)));
+    scene.fill_box(uvec3(12, 13, 10), uvec3(13, 16, 11), &Brush::union(1, uvec3(40, 40, 200)));
+    (scene, uvec3(9, 9, 9), uvec3(22, 20, 17))
+}
+
+// the material and albedo of every filled voxel in a region, relative to its minimum corner
+fn looks(scene: &Scene, min: UVec3, max: UVec3) -> HashMap<UVec3, (u32, UVec3)> {
+    let mut looks = HashMap::new();
+    for z in min.z..max.z {
+        for y in min.y..max.y {
+            for x in min.x..max.x {
+                let voxel = scene.get_voxel(uvec3(x, y, z));
+                if !voxel.is_empty() {
+                    looks.insert(uvec3(x, y, z) - min, (voxel.material(), voxel.albedo()));
+                }
+            }
+        }
+    }
+    looks
+}
+
+#[test]
+fn vox_files_keep_voxels_colors_and_materials() {
+    let (scene, min, max) = exported_scene();
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("exported.vox");
+    VoxModel::from_scene(&scene, min, max).unwrap().save(&path).unwrap();
+    let model = VoxModel::load(&path).unwrap();
+    assert_eq!(model.size, max - min);
+    assert_eq!(model.materials.len(), 2);
+    let materials = model.scene_materials();
+    assert_eq!((materials[0].metallic, materials[0].roughness), (1.0, 0.25));
+    assert_eq!((materials[1].transmission, materials[1].ior, materials[1].emission_strength), (0.5, 1.25, 4.0));
+
+    // somewhere else in an empty scene
+    let mut imported = Scene::new();
+    let offset = uvec3(30, 2, 5);
+    model.import(&mut imported, offset, &[1, 2]);
+    assert_eq!(looks(&imported, offset, offset + max - min), looks(&scene, min, max));
+    // the top of the box faces up
+    assert!(imported.get_voxel(offset + uvec3(2, 3, 2)).normal().normalize().dot(Vec3::Y) > 0.9);
+
+    assert!(VoxModel::from_scene(&scene, UVec3::ZERO, UVec3::splat(300)).is_err(), "too large for MagicaVoxel");
+}
+
+#[test]
+fn vox_palettes_are_quantized_per_material() {
+    let mut scene = Scene::new();
+    // 32 * 32 colors, half of them in each material
+    for x in 0..32 {
+        for z in 0..32 {
+            let material = (x / 16) + 1;
+            scene.set_voxel(uvec3(x, 0, z), Voxel::new(material, uvec3(x * 8, 100, z * 8), Vec3::Y));
+        }
+    }
+    let model = VoxModel::from_scene(&scene, UVec3::ZERO, uvec3(32, 1, 32)).unwrap();
+    let model = VoxModel::parse(&model.write()).unwrap();
+    let indices: HashSet<u8> = model.voxels.iter().map(|(_, index)| *index).collect();
+    assert!(indices.len() <= 255 && indices.len() > 200, "{} palette entries", indices.len());
+    let mut imported = Scene::new();
+    model.import(&mut imported, UVec3::ZERO, &[1, 2]);
+    // about 4 colors share an entry, the colors next to each other are 8 apart
+    let mut total_error = 0.0;
+    for (pos, (material, albedo)) in looks(&scene, UVec3::ZERO, uvec3(32, 1, 32)) {
+        let voxel = imported.get_voxel(pos);
+        assert_eq!(voxel.material(), material);
+        let error = voxel.albedo().as_vec3().distance(albedo.as_vec3());
+        assert!(error < 20.0, "{} for {}", voxel.albedo(), albedo);
+        total_error += error;
+    }
+    assert!(total_error / 1024.0 < 8.0, "the average error is {}", total_error / 1024.0);
+}
+
+#[test]
+fn greedy_meshes_merge_faces_of_the_same_look() {
+    let mut scene = Scene::new();
+    scene.fill_box(uvec3(2, 2, 2), uvec3(6, 5, 9), &Brush::union(1, UVec3::splat(200)));
+    let mesh = VoxelMesh::from_scene(&scene, UVec3::ZERO, UVec3::splat(16));
+    assert_eq!(mesh.quads.len(), 6);
+    assert_eq!(mesh.face_count(), 2 * (4 * 3 + 3 * 7 + 4 * 7));
+    // cut through the middle, the cut side is closed
+    let half = VoxelMesh::from_scene(&scene, UVec3::ZERO, uvec3(16, 16, 5));
+    assert_eq!(half.quads.len(), 6);
+    assert_eq!(half.face_count(), 2 * (4 * 3 + 3 * 3 + 4 * 3));
+    // a voxel of another color splits the top
+    scene.set_voxel(uvec3(3, 4, 5), Voxel::new(1, UVec3::splat(10), Vec3::Y));
+    let mesh = VoxelMesh::from_scene(&scene, UVec3::ZERO, UVec3::splat(16));
+    assert!(mesh.quads.len() > 6);
+    assert_eq!(mesh.face_count(), 2 * (4 * 3 + 3 * 7 + 4 * 7));
+}
+
+#[test]
+fn faces_behind_glass_and_water_are_kept() {
+    let mut scene = Scene::new();
+    scene.set_material(2, Material { transmission: 1.0, ior: 1.5, ..Default::default() });
+    scene.set_material(3, Material { density: 0.5, ..Default::default() });
+    scene.fill_box(uvec3(2, 2, 2), uvec3(4, 4, 4), &Brush::union(1, UVec3::splat(200)));
+    let faces = VoxelMesh::from_scene(&scene, UVec3::ZERO, UVec3::splat(16)).face_count();
+    assert_eq!(faces, 6 * 4);
+    // a pane of glass on top shows the top of the box through it. The box hides the bottom of the pane,
+    // and the faces between the voxels of the pane are left out
+    scene.fill_box(uvec3(2, 4, 2), uvec3(4, 5, 4), &Brush::union(2, UVec3::splat(250)));
+    let mesh = VoxelMesh::from_scene(&scene, UVec3::ZERO, UVec3::splat(16));
+    assert_eq!(mesh.face_count(), faces + 4 + 4 * 2);
+    // a layer of fog below hides nothing either
+    scene.fill_box(uvec3(2, 1, 2), uvec3(4, 2, 4), &Brush::union(3, UVec3::splat(250)));
+    let mesh = VoxelMesh::from_scene(&scene, UVec3::ZERO, UVec3::splat(16));
+    assert_eq!(mesh.face_count(), faces + 2 * (4 + 4 * 2));
+    // an opaque voxel on the pane hides a face of the pane, but not its own face behind the pane
+    scene.set_voxel(uvec3(2, 5, 2), Voxel::new(1, UVec3::splat(200), Vec3::Y));
+    let mesh = VoxelMesh::from_scene(&scene, UVec3::ZERO, UVec3::splat(16));
+    assert_eq!(mesh.face_count(), faces + 2 * (4 + 4 * 2) + 6 - 1);
+}
+
+// check that every triangle of an exported mesh lies on a face between a filled voxel with its look and an empty one,
+// or one of another, see-through material
+fn assert_mesh_matches(model: &Model, looks: &HashMap<UVec3, (u32, UVec3)>, see_through: &[u32], face_count: usize) {
+    let mut area = 0.0;
+    for triangle in &model.triangles {
+        let [a, b, c] = triangle.positions;
+        area += (b - a).cross(c - a).length() / 2.0;
+        let normal = (b - a).cross(c - a).normalize();
+        let center = (a + b + c) / 3.0;
+        let inside = (center - normal * 0.5).floor().as_uvec3();
+        let outside = (center + normal * 0.5).floor();
+        let (material, albedo) = looks.get(&inside).unwrap_or_else(|| panic!("no voxel behind the face at {center}"));
+        let hidden = outside.cmpge(Vec3::ZERO).all() && looks.get(&outside.as_uvec3()).is_some_and(|(other, _)| other == material || !see_through.contains(other));
+        assert!(!hidden, "the face at {center} is hidden");
+        let name = &model.materials[triangle.material.unwrap()].name;
+        assert_eq!(*name, format!("material_{material}"));
+        let color = (triangle.colors.unwrap()[0] * 255.0).round().as_uvec3();
+        assert_eq!(color, *albedo);
+    }
+    assert_eq!(area.round() as usize, face_count);
+}
+
+#[test]
+fn mesh_exports_load_back_with_their_looks() {
+    let (scene, min, max) = exported_scene();
+    let looks = looks(&scene, min, max);
+    let mesh = VoxelMesh::from_scene(&scene, min, max);
+    let directory = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("exported_mesh");
+    std::fs::create_dir_all(&directory).unwrap();
+    for file in ["voxels.obj", "voxels.glb", "voxels.gltf"] {
+        let path = directory.join(file);
+        match file {
+            "voxels.obj" => obj::save(&mesh, &path).unwrap(),
+            _ => gltf::save(&mesh, &path).unwrap(),
+        }
+        let model = Model::load(&path).unwrap();
+        assert_eq!(model.triangles.len(), mesh.quads.len() * 2, "{file}");
+        assert_mesh_matches(&model, &looks, &[2], mesh.face_count());
+        let materials = model.scene_materials();
+        assert_eq!(materials.len(), 2);
+        assert_eq!((materials[0].metallic, materials[0].roughness), (1.0, 0.25));
+        assert_eq!((materials[1].transmission, materials[1].ior, materials[1].emission_strength), (0.5, 1.25, 4.0));
+    }
+}
+
+#[test]
+fn point_clouds_keep_every_voxel() {
+    let (scene, min, max) = exported_scene();
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("exported.ply");
+    PointCloud::from_scene(&scene, min, max).save(&path).unwrap();
+    let cloud = PointCloud::load(&path).unwrap();
+    let mut imported = Scene::new();
+    cloud.import(&mut imported, min);
+    assert_eq!(looks(&imported, min, max), looks(&scene, min, max));
+    let pos = uvec3(11, 12, 11);
+    assert!(imported.get_voxel(pos).normal().normalize().dot(scene.get_voxel(pos).normal().normalize()) > 0.999);
+
+    // ASCII files without normals or materials
+    let ascii = "ply\nformat ascii 1.0\nelement vertex 2\nproperty float x\nproperty float y\nproperty float z\n\
+        property uchar red\nproperty uchar green\nproperty uchar blue\nelement face 0\nproperty list uchar int vertex_indices\nend_header\n\
+        1.5 2.5 3.5 255 0 0\n2.5 2.5 3.5 0 255 0\n";
+    assert!(PointCloud::parse(ascii.as_bytes()).is_err(), "faces aren't supported");
+    let ascii = ascii.replace("element face 0\nproperty list uchar int vertex_indices\n", "");
+    let cloud = PointCloud::parse(ascii.as_bytes()).unwrap();
+    let mut imported = Scene::new();
+    cloud.import(&mut imported, UVec3::ZERO);
+    let voxel = imported.get_voxel(uvec3(1, 2, 3));
+    assert_eq!((voxel.material(), voxel.albedo()), (0, uvec3(255, 0, 0)));
+    assert!(voxel.normal().normalize().dot(Vec3::NEG_X) > 0.9, "pointing away from its neighbour");
+}
+
+// a .vox chunk without children
+fn vox_chunk(id: &[u8; 4], content: &[u8]) -> Vec<u8> {
+    [id.as_slice(), &(content.len() as u32).to_le_bytes(), &0u32.to_le_bytes(), content].concat()
+}
+
+#[test]
+fn vox_animations_load_every_model_as_a_frame() {
+    // two 2x2x2 models with one voxel each, moving up from z 0 to z 1 of the file, which is y in the scene
+    let mut children = Vec::new();
+    for z in 0..2u8 {
+        children.extend(vox_chunk(b"SIZE", &[2u32.to_le_bytes(), 2u32.to_le_bytes(), 2u32.to_le_bytes()].concat()));
+        children.extend(vox_chunk(b"XYZI", &[1u32.to_le_bytes().as_slice(), &[1, 0, z, 3]].concat()));
+    }
+    let mut colors = vec![255u8; 1024];
+    colors[8..12].copy_from_slice(&[0, 255, 0, 255]);
+    children.extend(vox_chunk(b"RGBA", &colors));
+    let file = [b"VOX ".as_slice(), &150u32.to_le_bytes(), b"MAIN", &0u32.to_le_bytes(), &(children.len() as u32).to_le_bytes(), &children].concat();
+
+    let frames = VoxModel::parse_frames(&file).unwrap();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].voxels, vec![(uvec3(1, 0, 1), 3)]);
+    assert_eq!(frames[1].voxels, vec![(uvec3(1, 1, 1), 3)]);
+    assert_eq!(frames[1].palette[3].albedo, uvec3(0, 255, 0));
+    assert_eq!(VoxModel::parse(&file).unwrap(), frames[0]);
+
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("animation.vox");
+    std::fs::write(&path, &file).unwrap();
+    let models = VoxelModel::load_frames(&path, &[4]).unwrap();
+    assert_eq!(models.len(), 2);
+    assert_eq!(models[1].palette()[0].material, 4);
+    assert!(models[1].get(uvec3(1, 1, 1)).is_some() && models[1].get(uvec3(1, 0, 1)).is_none());
+}
@@ -0,0 +1,843 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use glam::{uvec3, vec3, Affine3A, BVec3, Quat, UVec3, Vec3};
+use instant::Duration;
+use voxel_raytracer_lib::camera::Camera;
+use voxel_raytracer_lib::model::vox::VoxModel;
+use voxel_raytracer_lib::scene::animation::{AlbedoScroll, Curve, FrameAnimation, Interpolation, MaterialAnimation, MaterialParameter};
+use voxel_raytracer_lib::scene::brush::{Brush, Shape};
+use voxel_raytracer_lib::scene::objects::VoxelObject;
+use voxel_raytracer_lib::scene::prefab::{ModelLibrary, ModelVoxel, PaletteEntry, Rotation90, VoxelModel};
+use voxel_raytracer_lib::scene::schedule::LightingSchedule;
+use voxel_raytracer_lib::scene::sdf::{Cuboid, Plane, RoundedBox, Sdf, Sphere, Torus};
+use voxel_raytracer_lib::scene::simulation::{Rule, Simulation};
+use voxel_raytracer_lib::scene::terrain::Terrain;
+use voxel_raytracer_lib::scene::{flatten_index, Chunk, LightingRegion, Material, MaterialId, Scene, Voxel, CHUNK_SIZE, SCENE_SIZE};
+
+// Tests for the bookkeeping of the scene that doesn't need a renderer
+
+const CHUNK_COUNT: usize = SCENE_SIZE * SCENE_SIZE * SCENE_SIZE;
+const CHUNK_VOXELS: u32 = (CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE) as u32;
+
+// a scene with its faces laid out and nothing left to upload
+fn clean_scene() -> Scene {
+    let mut scene = Scene::new();
+    scene.update_faces();
+    scene.take_dirty_chunks();
+    scene
+}
+
+fn dirty_chunk_count(scene: &mut Scene) -> usize {
+    scene.take_dirty_chunks().into_iter().map(|run| run.len()).sum()
+}
+
+#[test]
+fn voxel_edits_invalidate_the_chunks_around_them() {
+    let mut scene = clean_scene();
+    let chunk = uvec3(4, 4, 4);
+    scene.set_voxel(chunk * CHUNK_SIZE as u32, Voxel::new(0, UVec3::splat(200), vec3(0.0, 1.0, 0.0)));
+    assert!(scene.is_chunk_dirty(chunk));
+    assert!(scene.is_chunk_dirty(uvec3(3, 3, 3)));
+    assert!(scene.is_chunk_dirty(uvec3(5, 5, 5)));
+    assert!(!scene.is_chunk_dirty(uvec3(2, 4, 4)));
+    assert!(!scene.is_chunk_dirty(uvec3(4, 6, 4)));
+    assert_eq!(dirty_chunk_count(&mut scene), 27);
+    assert_eq!(dirty_chunk_count(&mut scene), 0);
+}
+
+#[test]
+fn invalidation_radius_is_clamped_to_the_scene() {
+    let mut scene = clean_scene();
+    scene.set_invalidation_radius(2);
+    scene.invalidate_lighting(LightingRegion::voxels(UVec3::ZERO, UVec3::splat(CHUNK_SIZE as u32 - 1)));
+    assert_eq!(dirty_chunk_count(&mut scene), 27);
+    scene.set_invalidation_radius(0);
+    scene.invalidate_lighting(LightingRegion::chunk(UVec3::splat(SCENE_SIZE as u32 - 1)));
+    assert_eq!(dirty_chunk_count(&mut scene), 1);
+}
+
+#[test]
+fn lighting_changes_invalidate_everything() {
+    let mut scene = clean_scene();
+    scene.set_sun(vec3(1.0, 1.0, 0.0), vec3(1.0, 0.9, 0.8));
+    // neighbouring chunks are written in one go
+    assert_eq!(scene.take_dirty_chunks(), vec![0..CHUNK_COUNT]);
+    scene.set_ambient_light(vec3(0.1, 0.1, 0.1));
+    assert_eq!(dirty_chunk_count(&mut scene), CHUNK_COUNT);
+    scene.set_material(0, *scene.material(1));
+    scene.update_faces();
+    assert_eq!(dirty_chunk_count(&mut scene), CHUNK_COUNT);
+}
+
+// a camera at the middle of the -z side of the scene, looking along +z
+fn camera_looking_in() -> Camera {
+    Camera::new(vec3(4.0, 4.0, -1.0), 90f32.to_radians(), 0.0, 16.0 / 9.0, 30f32.to_radians(), 0.1, 100.0)
+}
+
+// a scene with a filled chunk at every position
+fn full_scene() -> Scene {
+    let mut scene = Scene::new();
+    for idx in 0..CHUNK_COUNT as u32 {
+        let pos = uvec3(idx % 8, idx / 8 % 8, idx / 64);
+        scene.set_voxel(pos * CHUNK_SIZE as u32, Voxel::new(0, UVec3::splat(200), vec3(0.0, 1.0, 0.0)));
+    }
+    scene
+}
+
+#[test]
+fn lighting_schedule_stays_within_budget() {
+    let scene = full_scene();
+    let camera = camera_looking_in();
+    let mut schedule = LightingSchedule::with_budget(10 * CHUNK_VOXELS);
+    assert_eq!(schedule.plan(&scene, &camera.frustum(), camera.view.position).len(), 10);
+    // a budget smaller than a chunk still lights one
+    let mut schedule = LightingSchedule::with_budget(1);
+    assert_eq!(schedule.plan(&scene, &camera.frustum(), camera.view.position).len(), 1);
+}
+
+#[test]
+fn lighting_schedule_lights_visible_chunks_first() {
+    let scene = full_scene();
+    let camera = camera_looking_in();
+    let frustum = camera.frustum();
+    let mut schedule = LightingSchedule::with_budget(16 * CHUNK_VOXELS);
+    let chunks = schedule.plan(&scene, &frustum, camera.view.position);
+    for idx in chunks {
+        let pos = uvec3(idx % 8, idx / 8 % 8, idx / 64).as_vec3();
+        assert!(frustum.intersects_box(pos, pos + 1.0), "chunk {pos} is not visible");
+    }
+    // the chunks in the near corners are out of view, so not everything is visible
+    assert!(!frustum.intersects_box(Vec3::ZERO, Vec3::ONE));
+}
+
+#[test]
+fn lighting_schedule_gets_to_every_chunk() {
+    let mut scene = full_scene();
+    let camera = camera_looking_in();
+    let mut schedule = LightingSchedule::with_budget(32 * CHUNK_VOXELS);
+    let mut lit = vec![false; CHUNK_COUNT];
+    for _ in 0..200 {
+        scene.update(Duration::from_millis(16));
+        for idx in schedule.plan(&scene, &camera.frustum(), camera.view.position) {
+            lit[idx as usize] = true;
+        }
+    }
+    assert!(lit.iter().all(|&lit| lit), "{} chunks were never lit", lit.iter().filter(|&&lit| !lit).count());
+}
+
+#[test]
+fn lighting_schedule_skips_empty_chunks() {
+    let mut scene = Scene::new();
+    scene.set_voxel(uvec3(20, 20, 20), Voxel::new(0, UVec3::splat(200), vec3(0.0, 1.0, 0.0)));
+    let camera = camera_looking_in();
+    let mut schedule = LightingSchedule::default();
+    let expected = (2 + 8 * (2 + 8 * 2)) as u32;
+    assert_eq!(schedule.plan(&scene, &camera.frustum(), camera.view.position), vec![expected]);
+}
+
+fn filled_voxels(scene: &Scene, min: UVec3, max: UVec3) -> Vec<UVec3> {
+    let mut voxels = Vec::new();
+    for z in min.z..max.z {
+        for y in min.y..max.y {
+            for x in min.x..max.x {
+                if !scene.get_voxel(uvec3(x, y, z)).is_empty() {
+                    voxels.push(uvec3(x, y, z));
+                }
+            }
+        }
+    }
+    voxels
+}
+
+#[test]
+fn brushes_span_chunks() {
+    let mut scene = Scene::new();
+    let center = Vec3::splat(CHUNK_SIZE as f32);
+    let touched = scene.fill_sphere(center, 3.0, &Brush::union(1, UVec3::splat(200)));
+    assert_eq!(touched.len(), 8);
+    for pos in filled_voxels(&scene, UVec3::ZERO, UVec3::splat(2 * CHUNK_SIZE as u32)) {
+        let voxel = scene.get_voxel(pos);
+        assert_eq!(voxel.material(), 1);
+        assert!(voxel.normal().dot(pos.as_vec3() + 0.5 - center) > 0.0, "the normal of {pos} points inward");
+    }
+    // nothing outside the scene
+    assert!(scene.get_voxel(UVec3::splat(1000)).is_empty());
+}
+
+#[test]
+fn subtracting_points_the_uncovered_voxels_into_the_hole() {
+    let mut scene = Scene::new();
+    scene.fill_box(UVec3::ZERO, UVec3::splat(16), &Brush::union(0, UVec3::splat(200)));
+    let center = vec3(8.0, 16.0, 8.0);
+    scene.fill_sphere(center, 5.0, &Brush::subtract());
+    assert!(scene.get_voxel(uvec3(8, 15, 8)).is_empty());
+    assert!(scene.get_voxel(uvec3(8, 11, 8)).is_empty());
+    let bottom = scene.get_voxel(uvec3(8, 10, 8));
+    assert!(!bottom.is_empty());
+    assert!(bottom.normal().y > 0.9, "{}", bottom.normal());
+    let side = scene.get_voxel(uvec3(2, 14, 8));
+    assert!(side.normal().x > 0.9, "{}", side.normal());
+}
+
+#[test]
+fn intersecting_trims_everything_outside() {
+    let mut scene = Scene::new();
+    scene.spawn_ground_plane();
+    scene.fill_box(UVec3::ZERO, UVec3::splat(16), &Brush::union(0, UVec3::splat(200)));
+    let touched = scene.fill_sphere(Vec3::splat(8.0), 6.0, &Brush::intersect());
+    assert!(touched.len() > 8, "the ground plane outside the sphere is trimmed too");
+    assert!(filled_voxels(&scene, UVec3::ZERO, UVec3::splat(64)).iter()
+        .all(|pos| pos.as_vec3().distance(Vec3::splat(7.5)) < 6.0));
+    assert!(!scene.get_voxel(UVec3::splat(8)).is_empty());
+}
+
+#[test]
+fn lines_have_no_gaps() {
+    let mut scene = Scene::new();
+    let (from, to) = (uvec3(2, 3, 4), uvec3(20, 9, 13));
+    scene.line(from, to, &Brush::union(0, UVec3::splat(200)));
+    let mut voxels = filled_voxels(&scene, UVec3::ZERO, UVec3::splat(32));
+    assert_eq!(voxels.len(), 19, "one voxel per step along x");
+    assert!(voxels.contains(&from) && voxels.contains(&to));
+    // walk the voxels in order along the line, each one has to touch the one before it
+    let direction = (to - from).as_vec3();
+    voxels.sort_by(|a, b| (a.as_vec3() - from.as_vec3()).dot(direction).total_cmp(&(b.as_vec3() - from.as_vec3()).dot(direction)));
+    assert_eq!((voxels[0], voxels[18]), (from, to));
+    for pair in voxels.windows(2) {
+        let step = pair[1].as_ivec3() - pair[0].as_ivec3();
+        assert!(step.abs().max_element() == 1, "{} and {} are not neighbours", pair[0], pair[1]);
+    }
+}
+
+#[test]
+fn cylinders_and_capsules_have_flat_and_round_ends() {
+    let cylinder = Shape::Cylinder { base: vec3(8.0, 2.0, 8.0), top: vec3(8.0, 12.0, 8.0), radius: 3.0 };
+    let capsule = Shape::Capsule { a: vec3(8.0, 2.0, 8.0), b: vec3(8.0, 12.0, 8.0), radius: 3.0 };
+    // the capsule reaches past the flat ends of the cylinder, but not as far out as its corners
+    let rim = uvec3(10, 11, 8);
+    assert!(cylinder.contains(rim) && capsule.contains(rim));
+    assert!(!cylinder.contains(uvec3(10, 13, 8)) && capsule.contains(uvec3(10, 13, 8)));
+    assert!(!capsule.contains(uvec3(10, 14, 10)));
+    assert!(cylinder.normal(vec3(8.0, 12.5, 8.0)).y > 0.99);
+}
+
+#[test]
+fn sdf_normals_follow_the_gradient() {
+    let mut scene = Scene::new();
+    let center = vec3(16.0, 16.0, 16.0);
+    let ball = Sphere { radius: 6.0 }.translate(center).with_material(1, UVec3::splat(200));
+    let touched = scene.voxelize(center - 8.0, center + 8.0, |p| ball.sample(p));
+    assert_eq!(touched.len(), 8);
+    let voxels = filled_voxels(&scene, UVec3::ZERO, UVec3::splat(40));
+    // the same voxels as the sphere brush
+    let mut brushed = Scene::new();
+    brushed.fill_sphere(center, 6.0, &Brush::union(1, UVec3::splat(200)));
+    assert_eq!(voxels, filled_voxels(&brushed, UVec3::ZERO, UVec3::splat(40)));
+    for pos in voxels {
+        let expected = (pos.as_vec3() + 0.5 - center).normalize();
+        // within the precision of the compressed normals
+        assert!(scene.get_voxel(pos).normal().normalize().dot(expected) > 0.9995, "{pos}");
+    }
+}
+
+#[test]
+fn sdf_operators_compose() {
+    let rounded = RoundedBox { half_size: Vec3::splat(4.0), radius: 1.0 };
+    assert!(rounded.distance(vec3(3.9, 0.0, 0.0)) < 0.0);
+    assert!(rounded.distance(vec3(3.9, 3.9, 3.9)) > 0.0, "the corners are rounded off");
+    // a torus lying flat, with a hole where its center is
+    let torus = Torus { major_radius: 6.0, minor_radius: 1.5 };
+    assert!(torus.distance(Vec3::ZERO) > 0.0 && torus.distance(vec3(0.0, 0.0, 6.0)) < 0.0);
+    // the smooth union fills in the gap between two spheres that a plain union leaves
+    let a = Sphere { radius: 2.0 }.translate(vec3(-2.5, 0.0, 0.0));
+    let b = Sphere { radius: 2.0 }.translate(vec3(2.5, 0.0, 0.0));
+    assert!(a.union(b).distance(Vec3::ZERO) > 0.0);
+    assert!(a.smooth_union(b, 3.0).distance(Vec3::ZERO) < 0.0);
+    let hollow = Cuboid { half_size: Vec3::splat(4.0) }.subtract(Sphere { radius: 3.0 });
+    assert!(hollow.distance(Vec3::ZERO) > 0.0 && hollow.distance(vec3(3.5, 3.5, 0.0)) < 0.0);
+    let posts = Cuboid { half_size: vec3(1.0, 5.0, 1.0) }.repeat(vec3(8.0, 0.0, 8.0));
+    assert!(posts.distance(vec3(16.0, 0.0, -24.0)) < 0.0 && posts.distance(vec3(16.0, 6.0, -24.0)) > 0.0);
+    let tilted = Plane { normal: Vec3::Y }.rotate(Quat::from_rotation_z(std::f32::consts::FRAC_PI_2));
+    assert!(tilted.gradient(vec3(3.0, 2.0, 1.0)).distance(-Vec3::X) < 1e-3);
+    let big = Sphere { radius: 1.0 }.scale(5.0);
+    assert!((big.distance(vec3(8.0, 0.0, 0.0)) - 3.0).abs() < 1e-5);
+}
+
+#[test]
+fn closures_voxelize_with_their_own_materials() {
+    let mut scene = Scene::new();
+    // a ground plane whose material changes with the height of a wave
+    let field = |p: Vec3| {
+        let height = 4.0 + (p.x * 0.3).sin() * 2.0;
+        let material = if p.y < 3.0 { 0 } else { 1 };
+        (p.y - height, material, UVec3::splat(200))
+    };
+    scene.voxelize(Vec3::ZERO, vec3(64.0, 8.0, 64.0), field);
+    assert_eq!(scene.get_voxel(uvec3(10, 0, 10)).material(), 0);
+    let top = scene.get_voxel(uvec3(5, 5, 10)); // the crest of the wave at x=5.5
+    assert_eq!(top.material(), 1);
+    assert!(top.normal().y > 0.9);
+    assert!(scene.get_voxel(uvec3(5, 7, 10)).is_empty());
+}
+
+// every voxel of the scene, as material, albedo and normal, for comparing scenes
+fn snapshot(scene: &Scene) -> Vec<(u32, UVec3, Vec3)> {
+    let size = (SCENE_SIZE * CHUNK_SIZE) as u32;
+    let mut voxels = Vec::new();
+    for z in 0..size {
+        for y in 0..size {
+            for x in 0..size {
+                let voxel = scene.get_voxel(uvec3(x, y, z));
+                voxels.push((voxel.material(), voxel.albedo(), voxel.normal()));
+            }
+        }
+    }
+    voxels
+}
+
+#[test]
+fn terrain_is_deterministic() {
+    let generate = |seed| {
+        let mut scene = Scene::new();
+        Terrain { seed, ..Default::default() }.generate(&mut scene);
+        snapshot(&scene)
+    };
+    assert!(generate(3) == generate(3));
+    assert!(generate(3) != generate(4));
+}
+
+#[test]
+fn terrain_follows_its_heightmap_and_biomes() {
+    let terrain = Terrain { seed: 11, caves: None, ..Default::default() };
+    let mut scene = Scene::new();
+    terrain.generate(&mut scene);
+    let mut water_columns = 0;
+    for (x, z) in [(3, 5), (17, 40), (33, 12), (50, 50), (60, 2)] {
+        let (column_x, column_z) = (x as f32 + 0.5, z as f32 + 0.5);
+        let height = terrain.height(column_x, column_z);
+        let top = (height - 0.5).ceil() as u32 - 1; // the highest voxel with its center below the ground
+        let voxel = scene.get_voxel(uvec3(x, top, z));
+        let (dx, dz) = terrain.gradient(column_x, column_z);
+        let biome = terrain.biome(height, (dx * dx + dz * dz).sqrt());
+        assert_eq!(voxel.albedo(), biome.albedo, "column {x}, {z}");
+        assert!(voxel.normal().normalize().dot(vec3(-dx, 1.0, -dz).normalize()) > 0.999);
+        let above = scene.get_voxel(uvec3(x, top + 1, z));
+        if (top + 1) as f32 + 0.5 < terrain.water_level {
+            assert_eq!(above.material(), terrain.water.0);
+            water_columns += 1;
+        } else {
+            assert!(above.is_empty());
+        }
+    }
+    assert!(water_columns < 5, "everything is under water");
+}
+
+// an L of 4 voxels along x with one on top of its first voxel. The far end of the long arm faces +x and is red
+fn l_model() -> VoxelModel {
+    let mut model = VoxelModel::sparse(uvec3(3, 2, 1));
+    let gray = model.add_color(1, UVec3::splat(128)).unwrap();
+    let red = model.add_color(2, uvec3(255, 0, 0)).unwrap();
+    for pos in [uvec3(0, 0, 0), uvec3(1, 0, 0), uvec3(0, 1, 0)] {
+        model.set(pos, Some(ModelVoxel { palette: gray, normal: Vec3::Y }));
+    }
+    model.set(uvec3(2, 0, 0), Some(ModelVoxel { palette: red, normal: Vec3::X }));
+    model
+}
+
+#[test]
+fn stamps_turn_and_mirror_models() {
+    let model = l_model();
+    let at = uvec3(20, 20, 20);
+    let stamped = |rotation, mirror| {
+        let mut scene = Scene::new();
+        scene.stamp(&model, at, rotation, mirror);
+        let voxels: Vec<UVec3> = filled_voxels(&scene, at, at + 4).into_iter().map(|pos| pos - at).collect();
+        let red = voxels.iter().copied().find(|&pos| scene.get_voxel(at + pos).material() == 2).unwrap();
+        (voxels, red, scene.get_voxel(at + red).normal().normalize())
+    };
+
+    let (voxels, red, normal) = stamped(Rotation90::IDENTITY, BVec3::FALSE);
+    assert_eq!(voxels, vec![uvec3(0, 0, 0), uvec3(1, 0, 0), uvec3(2, 0, 0), uvec3(0, 1, 0)]);
+    assert_eq!(red, uvec3(2, 0, 0));
+    assert!(normal.dot(Vec3::X) > 0.99);
+
+    // a quarter turn around y takes +x to -z, so the arm reaches from z = 2 down to z = 0
+    let (voxels, red, normal) = stamped(Rotation90::y(1), BVec3::FALSE);
+    assert_eq!(voxels, vec![uvec3(0, 0, 0), uvec3(0, 0, 1), uvec3(0, 0, 2), uvec3(0, 1, 2)]);
+    assert_eq!(red, uvec3(0, 0, 0));
+    assert!(normal.dot(Vec3::NEG_Z) > 0.99);
+
+    let (voxels, red, normal) = stamped(Rotation90::IDENTITY, BVec3::new(true, false, false));
+    assert_eq!(voxels, vec![uvec3(0, 0, 0), uvec3(1, 0, 0), uvec3(2, 0, 0), uvec3(2, 1, 0)]);
+    assert_eq!(red, uvec3(0, 0, 0));
+    assert!(normal.dot(Vec3::NEG_X) > 0.99);
+
+    // the voxel on top ends up below after half a turn around x
+    let (voxels, _, _) = stamped(Rotation90 { x: 2, ..Default::default() }, BVec3::FALSE);
+    assert_eq!(voxels, vec![uvec3(0, 0, 0), uvec3(0, 1, 0), uvec3(1, 1, 0), uvec3(2, 1, 0)]);
+    assert_eq!(stamped(Rotation90::y(4), BVec3::FALSE), stamped(Rotation90::IDENTITY, BVec3::FALSE));
+}
+
+#[test]
+fn stamps_are_cut_off_at_the_edge_of_the_scene() {
+    let mut scene = Scene::new();
+    let edge = (SCENE_SIZE * CHUNK_SIZE) as u32 - 2;
+    let touched = scene.stamp(&l_model(), uvec3(edge, 0, 0), Rotation90::IDENTITY, BVec3::FALSE);
+    assert_eq!(touched, vec![uvec3(SCENE_SIZE as u32 - 1, 0, 0)]);
+    assert_eq!(filled_voxels(&scene, uvec3(edge, 0, 0), uvec3(edge + 2, 2, 1)).len(), 3);
+}
+
+#[test]
+fn model_libraries_load_every_format() {
+    let mut scene = Scene::new();
+    scene.fill_sphere(vec3(8.0, 7.0, 8.0), 4.0, &Brush::union(1, uvec3(30, 120, 30)));
+    scene.fill_box(uvec3(7, 0, 7), uvec3(9, 6, 9), &Brush::union(2, uvec3(100, 60, 20)));
+    let tree = VoxelModel::from_scene(&scene, uvec3(4, 0, 4), uvec3(12, 12, 12)).unwrap();
+    assert!(!tree.is_sparse());
+    assert_eq!(tree.palette().len(), 2);
+    let lamp = l_model();
+
+    let directory = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("prefabs");
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    tree.save(&directory.join("tree.ron")).unwrap();
+    lamp.save(&directory.join("lamp.json")).unwrap();
+    VoxModel::from_scene(&scene, uvec3(4, 0, 4), uvec3(12, 12, 12)).unwrap().save(&directory.join("bush.vox")).unwrap();
+    std::fs::write(directory.join("readme.txt"), "not a model").unwrap();
+
+    let library = ModelLibrary::load(&directory, &[1, 2]).unwrap();
+    assert_eq!(library.names(), vec!["bush", "lamp", "tree"]);
+    assert_eq!(library.get("tree"), Some(&tree));
+    assert_eq!(library.get("lamp"), Some(&lamp));
+    assert!(library.get("rock").is_none());
+    // the .vox file has the same voxels, with normals guessed from their neighbours
+    let bush = library.get("bush").unwrap();
+    let mut stamped = Scene::new();
+    stamped.stamp(bush, uvec3(4, 0, 4), Rotation90::IDENTITY, BVec3::FALSE);
+    for pos in filled_voxels(&scene, UVec3::ZERO, UVec3::splat(16)) {
+        let (expected, voxel) = (scene.get_voxel(pos), stamped.get_voxel(pos));
+        assert_eq!((voxel.material(), voxel.albedo()), (expected.material(), expected.albedo()));
+    }
+    assert_eq!(filled_voxels(&stamped, UVec3::ZERO, UVec3::splat(16)).len(), filled_voxels(&scene, UVec3::ZERO, UVec3::splat(16)).len());
+
+    std::fs::write(directory.join("broken.ron"), "(size: (1, 1, 1), palette: [], voxels: Dense([]))").unwrap();
+    assert!(ModelLibrary::load(&directory, &[1, 2]).is_err());
+}
+
+fn small_object(scene: &mut Scene) -> usize {
+    let mut object = VoxelObject::new(UVec3::splat(4));
+    object.set_voxel(uvec3(1, 2, 3), Voxel::new(1, uvec3(10, 20, 30), Vec3::Y));
+    scene.add_object(object)
+}
+
+#[test]
+fn objects_keep_their_voxels() {
+    let mut model = VoxelModel::sparse(uvec3(2, 3, 4));
+    let palette = model.add_color(3, uvec3(200, 100, 0)).unwrap();
+    model.set(uvec3(1, 2, 3), Some(ModelVoxel { palette, normal: Vec3::X }));
+    let object = VoxelObject::from_model(&model);
+    assert_eq!(object.size(), uvec3(2, 3, 4));
+    let voxel = object.get_voxel(uvec3(1, 2, 3));
+    assert_eq!((voxel.material(), voxel.albedo()), (3, uvec3(200, 100, 0)));
+    assert!(voxel.normal().dot(Vec3::X) > 0.99);
+    assert!(object.get_voxel(uvec3(0, 0, 0)).is_empty());
+    assert!(object.get_voxel(uvec3(5, 0, 0)).is_empty());
+}
+
+#[test]
+fn every_instance_ends_up_in_the_bvh_once() {
+    let mut scene = Scene::new();
+    let bvh = scene.object_bvh();
+    assert_eq!((bvh.nodes.len(), bvh.instances.len()), (1, 0));
+
+    let object = small_object(&mut scene);
+    let instances: Vec<usize> = (0..8)
+        .map(|i| scene.add_instance(object, Affine3A::from_translation(vec3(i as f32 * 7.0, 10.0, (i * i) as f32))))
+        .collect();
+    scene.remove_instance(instances[3]);
+    scene.remove_instance(instances[3]);
+    assert!(scene.instance(instances[3]).is_none());
+    assert_eq!(scene.instance(instances[5]).unwrap().object, object);
+    let bvh = scene.object_bvh();
+    // split into halves until at most 2 are left: 7 becomes 3 and 4, which become 1, 2, 2 and 2
+    assert_eq!((bvh.nodes.len(), bvh.instances.len()), (7, 7));
+    assert_eq!(scene.object_voxels().len(), 64);
+}
+
+#[test]
+fn moving_instances_invalidates_the_lighting_around_them() {
+    let mut scene = clean_scene();
+    let object = small_object(&mut scene);
+    assert_eq!(dirty_chunk_count(&mut scene), 0);
+    let instance = scene.add_instance(object, Affine3A::from_translation(vec3(30.0, 24.0, 30.0)));
+    assert!(scene.is_chunk_dirty(uvec3(3, 3, 3)));
+    assert!(scene.is_chunk_dirty(uvec3(4, 2, 4)), "chunks within the invalidation radius");
+    // the sun shines from -x and -z, so the shadow falls toward +x and +z, but the ground far below keeps its light
+    assert!(!scene.is_chunk_dirty(uvec3(5, 0, 5)));
+    assert!(!scene.is_chunk_dirty(uvec3(3, 7, 3)));
+    assert_eq!(dirty_chunk_count(&mut scene), 4 * 3 * 4, "the object reaches into the next chunks in x and z");
+
+    scene.set_instance_transform(instance, Affine3A::from_rotation_translation(Quat::from_rotation_y(1.0), vec3(2.0, 2.0, 2.0)));
+    assert!(scene.is_chunk_dirty(uvec3(3, 3, 3)));
+    assert!(scene.is_chunk_dirty(uvec3(0, 0, 0)));
+    assert!(!scene.is_chunk_dirty(uvec3(7, 7, 7)));
+    assert_eq!(scene.instance(instance).unwrap().transform.translation, vec3(2.0, 2.0, 2.0).into());
+}
+
+#[test]
+fn curves_interpolate_between_their_keys() {
+    let keys = vec![(2.0, 10.0), (0.0, 0.0), (4.0, 0.0)];
+    let linear = Curve::new(Interpolation::Linear, keys.clone());
+    assert_eq!(linear.keys()[1], (2.0, 10.0));
+    assert_eq!([linear.sample(-1.0), linear.sample(1.0), linear.sample(3.5), linear.sample(9.0)], [0.0, 5.0, 2.5, 0.0]);
+    assert_eq!(Curve::new(Interpolation::Step, keys.clone()).sample(3.9), 10.0);
+    let smooth = Curve::new(Interpolation::Smooth, keys.clone());
+    assert!(smooth.sample(0.5) < linear.sample(0.5) && smooth.sample(1.0) == 5.0);
+    let looping = linear.looping();
+    assert_eq!(looping.sample(5.0), looping.sample(1.0));
+    assert_eq!(Curve::default().sample(1.0), 0.0);
+
+    let pulse = Curve::pulse(1.0, 3.0, 2.0);
+    assert_eq!([pulse.sample(0.0), pulse.sample(1.0), pulse.sample(2.0), pulse.sample(7.0)], [1.0, 3.0, 1.0, 3.0]);
+    let flicker = Curve::flicker(0.5, 1.0, 0.1, 7);
+    let samples: Vec<f32> = (0..100).map(|i| flicker.sample(i as f32 * 0.037)).collect();
+    assert!(samples.iter().all(|value| (0.5..=1.0).contains(value)));
+    assert!(samples.windows(2).any(|pair| pair[0] != pair[1]));
+    assert_eq!(flicker, Curve::flicker(0.5, 1.0, 0.1, 7));
+    assert_ne!(flicker, Curve::flicker(0.5, 1.0, 0.1, 8));
+    assert_eq!(flicker.sample(0.0), flicker.sample(flicker.duration()));
+}
+
+#[test]
+fn material_animations_invalidate_the_chunks_with_the_material() {
+    let mut scene = Scene::new();
+    scene.set_invalidation_radius(0);
+    scene.chunk_at(uvec3(1, 1, 1)).fill_sphere(3, UVec3::splat(255));
+    scene.chunk_at(uvec3(5, 1, 1)).fill_sphere(0, UVec3::splat(255));
+    scene.update_faces();
+    scene.take_dirty_chunks();
+    let pulse = scene.add_animation(MaterialAnimation { material: 3, parameter: MaterialParameter::EmissionStrength, curve: Curve::pulse(1.0, 5.0, 2.0) });
+    scene.update(Duration::from_millis(1000));
+    assert_eq!(scene.material(3).emission_strength, 5.0);
+    assert!(scene.is_chunk_dirty(uvec3(1, 1, 1)));
+    assert_eq!(dirty_chunk_count(&mut scene), 1);
+
+    // at the same value again nothing changes
+    scene.update(Duration::from_millis(2000));
+    assert_eq!(dirty_chunk_count(&mut scene), 0);
+    scene.remove_animation(pulse);
+    assert!(scene.animation(pulse).is_none());
+    scene.update(Duration::from_millis(500));
+    assert_eq!((scene.material(3).emission_strength, dirty_chunk_count(&mut scene)), (5.0, 0));
+}
+
+#[test]
+fn material_animations_only_relight_at_keys_and_big_changes() {
+    let mut scene = Scene::new();
+    scene.set_invalidation_radius(0);
+    scene.chunk_at(uvec3(1, 1, 1)).fill_sphere(3, UVec3::splat(255));
+    scene.update_faces();
+    scene.take_dirty_chunks();
+    let ramp = Curve::new(Interpolation::Linear, vec![(0.0, 1.0), (1.0, 1.05), (2.0, 2.0)]);
+    scene.add_animation(MaterialAnimation { material: 3, parameter: MaterialParameter::EmissionStrength, curve: ramp });
+    scene.add_animation(MaterialAnimation { material: 3, parameter: MaterialParameter::Roughness, curve: Curve::pulse(0.2, 0.8, 1.0) });
+    let mut relit = Vec::new();
+    for _ in 0..20 {
+        scene.update(Duration::from_millis(100));
+        relit.push(dirty_chunk_count(&mut scene));
+    }
+    // the key at 1s, then every 10% on the way up to the last key at 2s
+    assert_eq!(relit, [0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1]);
+    assert_eq!(scene.material(3).emission_strength, 2.0);
+    assert!(Curve::pulse(0.0, 1.0, 2.0).crosses_key(0.9, 1.0));
+    assert!(Curve::pulse(0.0, 1.0, 2.0).crosses_key(3.9, 4.1), "looping curves pass their keys every time around");
+    assert!(!Curve::pulse(0.0, 1.0, 2.0).crosses_key(1.0, 1.9));
+}
+
+#[test]
+fn frame_animations_swap_only_the_voxels_that_change() {
+    let frame = |albedo| {
+        let mut model = VoxelModel::sparse(uvec3(6, 1, 1));
+        let gray = model.add_color(0, UVec3::splat(128)).unwrap();
+        let color = model.add_color(1, albedo).unwrap();
+        model.set(uvec3(0, 0, 0), Some(ModelVoxel { palette: gray, normal: Vec3::Y }));
+        model.set(uvec3(5, 0, 0), Some(ModelVoxel { palette: color, normal: Vec3::Y }));
+        model
+    };
+    let mut moving = frame(uvec3(255, 0, 0));
+    moving.set(uvec3(5, 0, 0), None);
+    moving.set(uvec3(4, 0, 0), Some(ModelVoxel { palette: 1, normal: Vec3::Y }));
+    let frames = vec![frame(uvec3(255, 0, 0)), frame(uvec3(0, 0, 255)), moving];
+    let at = uvec3(4, 8, 8);
+    let mut animation = FrameAnimation::new(frames, 0.5, at);
+    assert_eq!([animation.frame_at(0.4), animation.frame_at(1.2), animation.frame_at(1.5)], [0, 2, 0]);
+    animation.looping = false;
+    assert_eq!(animation.frame_at(9.0), 2);
+
+    let mut scene = clean_scene();
+    scene.set_invalidation_radius(0);
+    scene.add_animation(animation);
+    scene.update(Duration::ZERO);
+    assert_eq!(scene.get_voxel(at + uvec3(5, 0, 0)).albedo(), uvec3(255, 0, 0));
+    assert_eq!(dirty_chunk_count(&mut scene), 2);
+
+    // only the chunk of the voxel that changed color is written
+    scene.update(Duration::from_millis(600));
+    assert_eq!(scene.get_voxel(at + uvec3(5, 0, 0)).albedo(), uvec3(0, 0, 255));
+    assert!(scene.is_chunk_dirty(uvec3(1, 1, 1)));
+    assert_eq!(dirty_chunk_count(&mut scene), 1);
+    scene.update_faces();
+    scene.take_dirty_chunks();
+
+    // voxels that are gone in the next frame are cleared
+    scene.update(Duration::from_millis(500));
+    assert!(scene.get_voxel(at + uvec3(5, 0, 0)).is_empty());
+    assert_eq!(scene.get_voxel(at + uvec3(4, 0, 0)).material(), 1);
+    assert!(!scene.get_voxel(at).is_empty());
+    scene.update(Duration::from_millis(5000));
+    assert_eq!(filled_voxels(&scene, at, at + uvec3(6, 1, 1)).len(), 2);
+}
+
+#[test]
+fn albedo_scrolls_recolor_one_material() {
+    let mut scene = Scene::new();
+    scene.fill_box(uvec3(8, 8, 8), uvec3(24, 9, 16), &Brush::union(2, UVec3::splat(255)));
+    scene.set_voxel(uvec3(9, 8, 9), Voxel::new(1, UVec3::splat(255), Vec3::Y));
+    scene.set_invalidation_radius(0);
+    scene.update_faces();
+    scene.take_dirty_chunks();
+    let faces = scene.face_capacity();
+
+    let scroll = AlbedoScroll {
+        min: uvec3(0, 0, 0),
+        max: uvec3(20, 20, 20),
+        material: 2,
+        colors: vec![uvec3(255, 80, 0), uvec3(120, 0, 0)],
+        wavelength: 6.0,
+        velocity: vec3(2.0, 0.0, 0.0),
+        turbulence: 0.3,
+        seed: 1,
+    };
+    let lava = scroll.albedo_at(uvec3(10, 8, 8), 0.0);
+    assert_eq!(scroll.albedo_at(uvec3(14, 8, 8), 2.0), lava, "the pattern moves 2 voxels per second");
+    assert_ne!(scroll.albedo_at(uvec3(10, 8, 8), 1.0), lava);
+    scene.add_animation(scroll.clone());
+    scene.update(Duration::from_millis(1250));
+    assert_eq!(scene.get_voxel(uvec3(10, 8, 8)).albedo(), scroll.albedo_at(uvec3(10, 8, 8), 1.25));
+    assert_eq!(scene.get_voxel(uvec3(9, 8, 9)).albedo(), UVec3::splat(255), "other materials keep their color");
+    assert_eq!(scene.get_voxel(uvec3(22, 8, 8)).albedo(), UVec3::splat(255), "voxels outside the box keep their color");
+    // recoloring doesn't lay out the faces again or throw the lighting away
+    scene.update_faces();
+    assert_eq!(scene.face_capacity(), faces);
+    assert_eq!(dirty_chunk_count(&mut scene), 0);
+}
+
+#[test]
+fn albedo_scrolls_keep_the_lighting_of_their_chunks() {
+    let mut scene = Scene::new();
+    scene.fill_box(uvec3(8, 8, 8), uvec3(16, 9, 16), &Brush::union(2, UVec3::splat(255)));
+    scene.update_faces();
+    scene.take_writes();
+    // the scene buffer on the GPU, after the lighting gathered some samples in the chunk
+    let mut buffer = scene.to_buffer();
+    let samples_offset = Scene::CHUNKS_OFFSET as usize + flatten_index(uvec3(1, 1, 1), UVec3::splat(SCENE_SIZE as u32)) * std::mem::size_of::<Chunk>();
+    buffer[samples_offset..samples_offset + 4].copy_from_slice(&50u32.to_ne_bytes());
+    let samples = |buffer: &[u8]| u32::from_ne_bytes(buffer[samples_offset..samples_offset + 4].try_into().unwrap());
+    let write = |scene: &mut Scene, buffer: &mut Vec<u8>| {
+        for (offset, data) in scene.take_writes() {
+            buffer[offset as usize..offset as usize + data.len()].copy_from_slice(&data);
+        }
+    };
+
+    scene.add_animation(AlbedoScroll {
+        min: UVec3::ZERO,
+        max: UVec3::splat(32),
+        material: 2,
+        colors: vec![uvec3(255, 80, 0), uvec3(120, 0, 0)],
+        wavelength: 4.0,
+        velocity: vec3(4.0, 0.0, 0.0),
+        turbulence: 0.0,
+        seed: 0,
+    });
+    for _ in 0..10 {
+        scene.update(Duration::from_millis(100));
+        scene.update_faces();
+        write(&mut scene, &mut buffer);
+        assert_eq!(samples(&buffer), 50, "the samples of a recolored chunk are kept");
+    }
+    let mut expected = scene.to_buffer();
+    expected[samples_offset..samples_offset + 4].copy_from_slice(&50u32.to_ne_bytes());
+    let chunks = Scene::CHUNKS_OFFSET as usize..Scene::CHUNKS_OFFSET as usize + CHUNK_COUNT * std::mem::size_of::<Chunk>();
+    assert!(buffer[chunks.clone()] == expected[chunks], "the new colors are written");
+
+    // changing the voxels themselves starts the lighting over
+    scene.set_voxel(uvec3(9, 10, 9), Voxel::new(0, UVec3::splat(255), Vec3::Y));
+    scene.update_faces();
+    write(&mut scene, &mut buffer);
+    assert_eq!(samples(&buffer), 0);
+}
+
+const SAND: MaterialId = 5;
+const WATER: MaterialId = 6;
+const WOOD: MaterialId = 7;
+const FIRE: MaterialId = 8;
+const EMBERS: MaterialId = 9;
+const ASH: MaterialId = 10;
+
+// a scene with a floor and materials for falling sand, and a simulation with rules for them
+fn sandbox(seed: u32) -> (Scene, Simulation) {
+    let mut scene = Scene::new();
+    scene.fill_box(UVec3::ZERO, uvec3(64, 1, 64), &Brush::union(0, UVec3::splat(200)));
+    scene.set_material(WATER, Material::water());
+    scene.set_material(FIRE, Material { emission_strength: 4.0, ..Default::default() });
+    scene.set_material(EMBERS, Material { emission_strength: 1.0, ..Default::default() });
+    let mut simulation = Simulation::new(seed);
+    simulation.set_rule(SAND, Rule::Powder);
+    simulation.set_rule(ASH, Rule::Powder);
+    simulation.set_rule(WATER, Rule::Liquid { reach: 16 });
+    let look = |material| PaletteEntry { material, albedo: UVec3::splat(255) };
+    simulation.set_rule(WOOD, Rule::Flammable { ignition: 0.5, fire: look(FIRE) });
+    simulation.set_rule(FIRE, Rule::Fire { burnout: 0.2, leaves: Some(look(EMBERS)) });
+    simulation.set_rule(EMBERS, Rule::Fire { burnout: 0.05, leaves: Some(look(ASH)) });
+    (scene, simulation)
+}
+
+// run ticks until nothing moves. Returns the number of ticks
+fn settle(scene: &mut Scene, simulation: &mut Simulation) -> u32 {
+    let start = simulation.tick();
+    while !simulation.is_settled() {
+        simulation.step(scene);
+        assert!(simulation.tick() - start < 1000, "the simulation doesn't settle");
+    }
+    simulation.tick() - start
+}
+
+fn voxels_of(scene: &Scene, material: MaterialId) -> Vec<UVec3> {
+    filled_voxels(scene, UVec3::ZERO, UVec3::splat(64)).into_iter().filter(|pos| scene.get_voxel(*pos).material() == material).collect()
+}
+
+#[test]
+fn sand_falls_and_piles_up() {
+    let (mut scene, mut simulation) = sandbox(1);
+    scene.fill_box(uvec3(20, 30, 20), uvec3(21, 50, 21), &Brush::union(SAND, uvec3(220, 200, 120)));
+    settle(&mut scene, &mut simulation);
+    let sand = voxels_of(&scene, SAND);
+    assert_eq!(sand.len(), 20);
+    assert!(sand.iter().all(|pos| !scene.get_voxel(*pos - UVec3::Y).is_empty()), "nothing floats");
+    let height = sand.iter().map(|pos| pos.y).max().unwrap();
+    assert!((2..10).contains(&height), "a pile, not a column: {}", height);
+
+    // sand sinks in water, which rises above it
+    let (mut scene, mut simulation) = sandbox(1);
+    scene.fill_box(uvec3(8, 1, 8), uvec3(16, 2, 16), &Brush::union(WATER, UVec3::splat(255)));
+    scene.set_voxel(uvec3(10, 4, 10), Voxel::new(SAND, UVec3::splat(255), Vec3::Y));
+    settle(&mut scene, &mut simulation);
+    assert_eq!(voxels_of(&scene, SAND), vec![uvec3(10, 1, 10)]);
+    assert_eq!(voxels_of(&scene, WATER).len(), 64);
+}
+
+#[test]
+fn water_flows_until_it_is_level() {
+    let (mut scene, mut simulation) = sandbox(2);
+    // a basin with 6x6 voxels inside, and as much water as fills one layer of it poured into a corner
+    scene.fill_box(uvec3(8, 1, 8), uvec3(16, 6, 16), &Brush::union(0, UVec3::splat(100)));
+    scene.fill_box(uvec3(9, 1, 9), uvec3(15, 6, 15), &Brush::subtract());
+    scene.fill_box(uvec3(9, 2, 9), uvec3(12, 6, 12), &Brush::union(WATER, UVec3::splat(255)));
+    let water = voxels_of(&scene, WATER).len();
+    settle(&mut scene, &mut simulation);
+    let level: Vec<UVec3> = voxels_of(&scene, WATER);
+    assert_eq!(level.len(), water);
+    assert!(level.iter().all(|pos| pos.y == 1), "{:?}", level);
+}
+
+#[test]
+fn water_levels_out_on_an_open_floor() {
+    for reach in [16, 2] {
+        let (mut scene, mut simulation) = sandbox(5);
+        simulation.set_rule(WATER, Rule::Liquid { reach });
+        scene.fill_box(uvec3(30, 1, 30), uvec3(34, 13, 34), &Brush::union(WATER, UVec3::splat(255)));
+        settle(&mut scene, &mut simulation);
+        let water = voxels_of(&scene, WATER);
+        assert_eq!(water.len(), 192);
+        assert!(water.iter().all(|pos| !scene.get_voxel(*pos - UVec3::Y).is_empty()), "nothing floats");
+        // the top of every column is within a voxel of every other, however short the reach
+        let mut tops = HashMap::new();
+        for pos in &water {
+            let top = tops.entry((pos.x, pos.z)).or_insert(pos.y);
+            *top = (*top).max(pos.y);
+        }
+        let (lowest, highest) = (tops.values().min().unwrap(), tops.values().max().unwrap());
+        assert!(highest - lowest <= 1, "the surface goes from {} to {} with a reach of {}", lowest, highest, reach);
+    }
+}
+
+#[test]
+fn fire_burns_wood_to_embers_and_ash() {
+    let (mut scene, mut simulation) = sandbox(3);
+    scene.fill_box(uvec3(10, 1, 10), uvec3(20, 2, 11), &Brush::union(WOOD, uvec3(120, 80, 40)));
+    scene.set_voxel(uvec3(10, 1, 10), Voxel::new(FIRE, UVec3::splat(255), Vec3::Y));
+    let mut saw_embers = false;
+    while !simulation.is_settled() {
+        simulation.step(&mut scene);
+        saw_embers |= !voxels_of(&scene, EMBERS).is_empty();
+        assert!(simulation.tick() < 1000);
+    }
+    assert!(saw_embers);
+    for material in [WOOD, FIRE, EMBERS] {
+        assert!(voxels_of(&scene, material).is_empty(), "material {} is left", material);
+    }
+    assert_eq!(voxels_of(&scene, ASH).len(), 10);
+
+    // water puts fire out before it spreads
+    let (mut scene, mut simulation) = sandbox(3);
+    scene.fill_box(uvec3(10, 1, 10), uvec3(20, 2, 11), &Brush::union(WOOD, uvec3(120, 80, 40)));
+    scene.set_voxel(uvec3(10, 1, 10), Voxel::new(FIRE, UVec3::splat(255), Vec3::Y));
+    scene.set_voxel(uvec3(10, 2, 10), Voxel::new(WATER, UVec3::splat(255), Vec3::Y));
+    simulation.step(&mut scene);
+    assert_eq!(scene.get_voxel(uvec3(10, 1, 10)).material(), EMBERS);
+    assert_eq!(voxels_of(&scene, WOOD).len(), 9);
+}
+
+#[test]
+fn simulations_are_deterministic() {
+    let play = |seed| {
+        let (mut scene, mut simulation) = sandbox(seed);
+        scene.fill_box(uvec3(20, 10, 20), uvec3(24, 20, 24), &Brush::union(SAND, UVec3::splat(255)));
+        scene.fill_box(uvec3(30, 10, 20), uvec3(34, 14, 24), &Brush::union(WATER, UVec3::splat(255)));
+        scene.fill_box(uvec3(40, 1, 20), uvec3(44, 3, 24), &Brush::union(WOOD, UVec3::splat(255)));
+        scene.set_voxel(uvec3(40, 3, 20), Voxel::new(FIRE, UVec3::splat(255), Vec3::Y));
+        for _ in 0..60 {
+            simulation.step(&mut scene);
+        }
+        VoxelModel::from_scene(&scene, UVec3::ZERO, UVec3::splat(64)).unwrap()
+    };
+    assert_eq!(play(7), play(7));
+    assert_ne!(play(7), play(8));
+}
+
+#[test]
+fn settled_simulations_sleep_until_woken() {
+    let (mut scene, mut simulation) = sandbox(4);
+    scene.fill_box(uvec3(20, 1, 20), uvec3(22, 3, 22), &Brush::union(SAND, UVec3::splat(255)));
+    assert!(!simulation.is_settled());
+    settle(&mut scene, &mut simulation);
+    scene.update_faces();
+    scene.take_dirty_chunks();
+    assert!(simulation.step(&mut scene).is_empty());
+    assert_eq!(dirty_chunk_count(&mut scene), 0);
+
+    // an edit wakes up only the chunks it touched
+    let changed = scene.fill_box(uvec3(40, 20, 40), uvec3(41, 21, 41), &Brush::union(SAND, UVec3::splat(255)));
+    simulation.wake(&scene, &changed);
+    assert!(!simulation.is_settled());
+    assert_eq!(simulation.step(&mut scene), vec![uvec3(5, 2, 5)]);
+    // 18 more voxels down to the floor, and a tick that finds nothing to do
+    assert_eq!(settle(&mut scene, &mut simulation), 19);
+    assert!(!scene.get_voxel(uvec3(40, 1, 40)).is_empty());
+
+    // update runs whole ticks of scene time
+    let tick = simulation.tick();
+    simulation.update(&mut scene, Duration::from_millis(120));
+    simulation.update(&mut scene, Duration::from_millis(40));
+    assert_eq!(simulation.tick(), tick + 3);
+}